@@ -36,3 +36,372 @@ CREATE TABLE users (
 );
 
 
+-- users can hold several roles, user_roles replaces users.role_id
+CREATE TABLE user_roles (
+  user_id INT NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  role_id INT NOT NULL REFERENCES roles(role_id) ON DELETE RESTRICT,
+  created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, role_id)
+);
+
+INSERT INTO user_roles (user_id, role_id)
+SELECT user_id, role_id
+FROM users
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE users DROP COLUMN role_id;
+
+-- roles inherit every permission of their parent roles
+CREATE TABLE role_parents (
+  role_id INT NOT NULL REFERENCES roles(role_id) ON DELETE CASCADE,
+  parent_role_id INT NOT NULL REFERENCES roles(role_id) ON DELETE CASCADE,
+  PRIMARY KEY (role_id, parent_role_id),
+  CHECK (role_id <> parent_role_id)
+);
+
+-- bumped on every role or grant edit so holders of a token can detect a
+-- stale permission set
+CREATE TABLE permission_version (
+  singleton BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (singleton),
+  version BIGINT NOT NULL DEFAULT 0
+);
+
+INSERT INTO permission_version DEFAULT VALUES;
+
+CREATE FUNCTION bump_permission_version() RETURNS TRIGGER AS $$
+BEGIN
+  UPDATE permission_version SET version = version + 1;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER role_permissions_bump_version
+AFTER INSERT OR UPDATE OR DELETE ON role_permissions
+FOR EACH STATEMENT EXECUTE FUNCTION bump_permission_version();
+
+CREATE TRIGGER role_parents_bump_version
+AFTER INSERT OR UPDATE OR DELETE ON role_parents
+FOR EACH STATEMENT EXECUTE FUNCTION bump_permission_version();
+
+CREATE TRIGGER user_roles_bump_version
+AFTER INSERT OR UPDATE OR DELETE ON user_roles
+FOR EACH STATEMENT EXECUTE FUNCTION bump_permission_version();
+
+-- organizations, every user, role grant and custom role belongs to one
+CREATE TABLE organizations (
+  org_id SERIAL PRIMARY KEY,
+  name VARCHAR(100) UNIQUE NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO organizations (name) VALUES ('default');
+
+CREATE TABLE org_members (
+  org_id INT NOT NULL REFERENCES organizations(org_id) ON DELETE CASCADE,
+  user_id INT NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (org_id, user_id)
+);
+
+INSERT INTO org_members (org_id, user_id)
+SELECT o.org_id, u.user_id
+FROM organizations o
+CROSS JOIN users u
+WHERE o.name = 'default';
+
+ALTER TABLE user_roles ADD COLUMN org_id INT;
+UPDATE user_roles SET org_id = (SELECT org_id FROM organizations WHERE name = 'default');
+ALTER TABLE user_roles ALTER COLUMN org_id SET NOT NULL;
+ALTER TABLE user_roles DROP CONSTRAINT user_roles_pkey;
+ALTER TABLE user_roles ADD PRIMARY KEY (user_id, org_id, role_id);
+ALTER TABLE user_roles ADD CONSTRAINT user_roles_membership_fkey
+  FOREIGN KEY (org_id, user_id) REFERENCES org_members(org_id, user_id) ON DELETE CASCADE;
+
+-- NULL org_id marks the shared roles and permissions every organization sees
+ALTER TABLE roles ADD COLUMN org_id INT REFERENCES organizations(org_id) ON DELETE CASCADE;
+ALTER TABLE roles DROP CONSTRAINT roles_name_key;
+CREATE UNIQUE INDEX roles_org_name_key ON roles (COALESCE(org_id, 0), name);
+
+ALTER TABLE permissions ADD COLUMN org_id INT REFERENCES organizations(org_id) ON DELETE CASCADE;
+ALTER TABLE permissions DROP CONSTRAINT permissions_name_key;
+CREATE UNIQUE INDEX permissions_org_name_key ON permissions (COALESCE(org_id, 0), name);
+
+-- per-resource relations (owner, facilitator, participant) on rooms and
+-- quizzes held by other koois services
+CREATE TABLE resource_grants (
+  grant_id SERIAL PRIMARY KEY,
+  org_id INT NOT NULL REFERENCES organizations(org_id) ON DELETE CASCADE,
+  user_id INT NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  relation VARCHAR(50) NOT NULL,
+  resource_type VARCHAR(50) NOT NULL,
+  resource_id TEXT NOT NULL,
+  granted_by INT REFERENCES users(user_id) ON DELETE SET NULL,
+  created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (org_id, user_id, relation, resource_type, resource_id)
+);
+
+CREATE INDEX resource_grants_resource_idx ON resource_grants (org_id, resource_type, resource_id);
+-- a resource has one owner, two callers claiming a fresh one cannot both win
+CREATE UNIQUE INDEX resource_grants_owner_key ON resource_grants (org_id, resource_type, resource_id) WHERE relation = 'owner';
+
+-- attribute based rules, see policy::model::PolicyRule for the definition
+-- format; a NULL org_id applies the rule to every organization
+CREATE TABLE policy_rules (
+  rule_id SERIAL PRIMARY KEY,
+  org_id INT REFERENCES organizations(org_id) ON DELETE CASCADE,
+  name VARCHAR(100) NOT NULL,
+  definition JSONB NOT NULL,
+  enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- time-bound grants, NULL bounds are open ended; rows past valid_until are
+-- ignored when resolving permissions and removed by the grant sweeper
+ALTER TABLE user_roles ADD COLUMN valid_from TIMESTAMPTZ;
+ALTER TABLE user_roles ADD COLUMN valid_until TIMESTAMPTZ;
+ALTER TABLE user_roles ADD CONSTRAINT user_roles_valid_window
+  CHECK (valid_from IS NULL OR valid_until IS NULL OR valid_from < valid_until);
+CREATE INDEX user_roles_valid_until_idx ON user_roles (valid_until) WHERE valid_until IS NOT NULL;
+
+ALTER TABLE role_permissions ADD COLUMN valid_from TIMESTAMPTZ;
+ALTER TABLE role_permissions ADD COLUMN valid_until TIMESTAMPTZ;
+ALTER TABLE role_permissions ADD CONSTRAINT role_permissions_valid_window
+  CHECK (valid_from IS NULL OR valid_until IS NULL OR valid_from < valid_until);
+CREATE INDEX role_permissions_valid_until_idx ON role_permissions (valid_until) WHERE valid_until IS NOT NULL;
+
+-- append-only record of security relevant events
+CREATE TABLE audit_events (
+  event_id BIGSERIAL PRIMARY KEY,
+  org_id INT REFERENCES organizations(org_id) ON DELETE SET NULL,
+  actor_id INT REFERENCES users(user_id) ON DELETE SET NULL,
+  action VARCHAR(100) NOT NULL,
+  target TEXT,
+  ip VARCHAR(45),
+  user_agent TEXT,
+  outcome VARCHAR(20) NOT NULL,
+  metadata JSONB NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX audit_events_org_created_idx ON audit_events (org_id, created_at);
+
+-- audit_events is append-only: every row stores the hash of the previous row
+-- so edits or deletions break the chain. Foreign keys are dropped because
+-- their ON DELETE actions would have to rewrite history.
+ALTER TABLE audit_events DROP CONSTRAINT audit_events_org_id_fkey;
+ALTER TABLE audit_events DROP CONSTRAINT audit_events_actor_id_fkey;
+ALTER TABLE audit_events ADD COLUMN prev_hash VARCHAR(64) NOT NULL DEFAULT '';
+ALTER TABLE audit_events ADD COLUMN hash VARCHAR(64) NOT NULL DEFAULT '';
+CREATE INDEX audit_events_actor_idx ON audit_events (actor_id);
+
+CREATE OR REPLACE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_no_update
+BEFORE UPDATE OR DELETE ON audit_events
+FOR EACH ROW EXECUTE FUNCTION audit_events_append_only();
+
+CREATE TRIGGER audit_events_no_truncate
+BEFORE TRUNCATE ON audit_events
+FOR EACH STATEMENT EXECUTE FUNCTION audit_events_append_only();
+
+-- one row per successful sign-in, login tokens carry the id as `sid` and are
+-- rejected once the session is revoked or expired
+CREATE TABLE sessions (
+  session_id BIGSERIAL PRIMARY KEY,
+  user_id INT NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  org_id INT REFERENCES organizations(org_id) ON DELETE SET NULL,
+  user_agent TEXT,
+  ip VARCHAR(45),
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL,
+  revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX sessions_user_idx ON sessions (user_id, created_at);
+
+-- relying parties of the OpenID Connect provider. Confidential clients hold a
+-- bcrypt hashed secret, public clients have none and rely on PKCE alone.
+CREATE TABLE oauth_clients (
+  client_id VARCHAR(64) PRIMARY KEY,
+  org_id INT NOT NULL REFERENCES organizations(org_id) ON DELETE CASCADE,
+  name VARCHAR(255) NOT NULL,
+  secret_hash TEXT,
+  redirect_uris TEXT[] NOT NULL,
+  allowed_scopes TEXT[] NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX oauth_clients_org_idx ON oauth_clients (org_id);
+
+-- authorization codes are stored as sha256 hashes and redeemed once
+CREATE TABLE oauth_codes (
+  code_hash VARCHAR(64) PRIMARY KEY,
+  client_id VARCHAR(64) NOT NULL REFERENCES oauth_clients(client_id) ON DELETE CASCADE,
+  user_id INT NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  org_id INT NOT NULL REFERENCES organizations(org_id) ON DELETE CASCADE,
+  redirect_uri TEXT NOT NULL,
+  scope TEXT NOT NULL,
+  nonce TEXT,
+  code_challenge VARCHAR(128) NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL,
+  used_at TIMESTAMPTZ
+);
+
+-- machine clients use the client-credentials grant, their tokens name a
+-- service principal instead of a user and need no redirect URIs
+ALTER TABLE oauth_clients ADD COLUMN grant_types TEXT[] NOT NULL DEFAULT '{authorization_code}';
+
+-- tokens are stateless JWTs, revoking one records its sha256 hash until the
+-- token would have expired anyway
+CREATE TABLE revoked_tokens (
+  token_hash VARCHAR(64) PRIMARY KEY,
+  client_id VARCHAR(64) REFERENCES oauth_clients(client_id) ON DELETE SET NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  revoked_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX revoked_tokens_expires_idx ON revoked_tokens (expires_at);
+
+-- long-lived credentials for scripts. Only the sha256 of the key is stored,
+-- `prefix` identifies it in listings. A key is limited to `permissions`, a
+-- subset of its owner's permissions when it was created.
+CREATE TABLE api_keys (
+  api_key_id BIGSERIAL PRIMARY KEY,
+  user_id INT NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  org_id INT NOT NULL REFERENCES organizations(org_id) ON DELETE CASCADE,
+  name VARCHAR(100) NOT NULL,
+  prefix VARCHAR(16) NOT NULL,
+  key_hash VARCHAR(64) NOT NULL UNIQUE,
+  permissions TEXT[] NOT NULL,
+  expires_at TIMESTAMPTZ,
+  last_used_at TIMESTAMPTZ,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX api_keys_user_idx ON api_keys (user_id, created_at);
+
+-- device authorization grant (RFC 8628) for input constrained devices, the
+-- device polls with the hashed device code while a signed-in user approves
+-- the short user code
+CREATE TABLE device_codes (
+  device_code_hash VARCHAR(64) PRIMARY KEY,
+  user_code VARCHAR(8) NOT NULL UNIQUE,
+  client_id VARCHAR(64) NOT NULL REFERENCES oauth_clients(client_id) ON DELETE CASCADE,
+  org_id INT NOT NULL REFERENCES organizations(org_id) ON DELETE CASCADE,
+  scope TEXT NOT NULL,
+  status VARCHAR(16) NOT NULL DEFAULT 'pending',
+  user_id INT REFERENCES users(user_id) ON DELETE CASCADE,
+  interval_secs INT NOT NULL,
+  last_polled_at TIMESTAMPTZ,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX device_codes_expires_idx ON device_codes (expires_at);
+
+-- sign-ins redirected to a provider without ID tokens (GitHub). The state is
+-- single use and keeps the PKCE verifier on the server, role_id marks a
+-- sign-up
+CREATE TABLE upstream_auth_states (
+  state_hash VARCHAR(64) PRIMARY KEY,
+  provider VARCHAR(30) NOT NULL,
+  code_verifier TEXT NOT NULL,
+  org_id INT REFERENCES organizations(org_id) ON DELETE CASCADE,
+  role_id INT REFERENCES roles(role_id) ON DELETE CASCADE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL
+);
+
+-- accounts can sign in through several providers, each identity points at
+-- one user and a provider account can only belong to one user
+CREATE TABLE user_identities (
+  identity_id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  provider VARCHAR(30) NOT NULL,
+  provider_id TEXT NOT NULL,
+  email VARCHAR(255),
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (provider, provider_id),
+  UNIQUE (user_id, provider)
+);
+
+INSERT INTO user_identities (user_id, provider, provider_id, email, created_at)
+SELECT user_id, provider, provider_id, email, COALESCE(created_at, CURRENT_TIMESTAMP)
+FROM users
+WHERE provider <> 'local' AND provider_id IS NOT NULL;
+
+ALTER TABLE users DROP COLUMN provider, DROP COLUMN provider_id;
+
+-- SCIM 2.0 provisioning. Every directory integration authenticates with its
+-- own bearer token bound to one organization, only the sha256 is stored.
+CREATE TABLE scim_tokens (
+  scim_token_id BIGSERIAL PRIMARY KEY,
+  org_id INT NOT NULL REFERENCES organizations(org_id) ON DELETE CASCADE,
+  name VARCHAR(100) NOT NULL,
+  prefix VARCHAR(16) NOT NULL,
+  token_hash VARCHAR(64) NOT NULL UNIQUE,
+  created_by INT REFERENCES users(user_id) ON DELETE SET NULL,
+  last_used_at TIMESTAMPTZ,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX scim_tokens_org_idx ON scim_tokens (org_id, created_at);
+
+-- an inactive membership keeps its roles but no longer signs in to the
+-- organization; external_id is the directory's own id of the user
+ALTER TABLE org_members ADD COLUMN active BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE org_members ADD COLUMN external_id TEXT;
+CREATE UNIQUE INDEX org_members_external_id_key ON org_members (org_id, external_id);
+
+-- invitations are the way into privileged roles, self sign-up only gets
+-- SELF_SIGNUP_ROLE_ID. The token travels by mail, only its sha256 is stored.
+CREATE TABLE invitations (
+  invitation_id BIGSERIAL PRIMARY KEY,
+  org_id INT NOT NULL REFERENCES organizations(org_id) ON DELETE CASCADE,
+  email VARCHAR(255) NOT NULL,
+  role_id INT NOT NULL REFERENCES roles(role_id) ON DELETE CASCADE,
+  token_hash VARCHAR(64) NOT NULL UNIQUE,
+  invited_by INT REFERENCES users(user_id) ON DELETE SET NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL,
+  accepted_at TIMESTAMPTZ,
+  revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX invitations_org_idx ON invitations (org_id, created_at);
+
+-- join codes a facilitator shows on the projector, learners trade them for
+-- a guest token scoped to the room
+CREATE TABLE room_codes (
+  room_code_id BIGSERIAL PRIMARY KEY,
+  org_id INT NOT NULL REFERENCES organizations(org_id) ON DELETE CASCADE,
+  room_id TEXT NOT NULL,
+  code VARCHAR(16) NOT NULL UNIQUE,
+  created_by INT REFERENCES users(user_id) ON DELETE SET NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL,
+  revoked_at TIMESTAMPTZ
+);
+
+-- guest:<guest_id> is the subject of guest tokens, results stay attributed
+-- to it after the guest is upgraded and user_id links the account
+CREATE TABLE guests (
+  guest_id BIGSERIAL PRIMARY KEY,
+  org_id INT NOT NULL REFERENCES organizations(org_id) ON DELETE CASCADE,
+  room_id TEXT NOT NULL,
+  display_name VARCHAR(50) NOT NULL,
+  room_code_id BIGINT REFERENCES room_codes(room_code_id) ON DELETE SET NULL,
+  user_id INT REFERENCES users(user_id) ON DELETE SET NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  upgraded_at TIMESTAMPTZ
+);
+
+CREATE INDEX guests_user_idx ON guests (user_id) WHERE user_id IS NOT NULL;
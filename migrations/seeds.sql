@@ -16,7 +16,7 @@ INSERT INTO role_permissions (role_id, permission_id)
 SELECT r.role_id, p.permission_id
 FROM roles r
 JOIN permissions p ON (
-    (r.name = 'admin' AND p.name IN ('manage_users', 'create_room', 'create_quiz', 'join_room', 'complete_quiz')) OR
+    (r.name = 'admin' AND p.name IN ('manage_users')) OR
     (r.name = 'facilitator' AND p.name IN ('create_room', 'create_quiz')) OR
     (r.name = 'learner' AND p.name IN ('join_room', 'complete_quiz'))
 );
@@ -38,3 +38,24 @@ JOIN permissions p ON (
     (r.name = 'learner' AND p.name IN ('menu_quiz'))
 );
 
+
+
+-- manage_room on a role covers every room, otherwise it comes from a grant
+INSERT INTO permissions (name, description)
+VALUES
+  ('manage_room', 'Role can manage any room');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT r.role_id, p.permission_id
+FROM roles r
+JOIN permissions p ON r.name = 'admin' AND p.name = 'manage_room';
+
+
+INSERT INTO permissions (name, description)
+VALUES
+  ('view_audit', 'Role can read the audit log');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT r.role_id, p.permission_id
+FROM roles r
+JOIN permissions p ON r.name = 'admin' AND p.name = 'view_audit';
@@ -0,0 +1,48 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Every key starts with this, so middleware can tell keys from JWTs
+pub const API_KEY_PREFIX: &str = "kp_";
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct ApiKey {
+    pub api_key_id: Option<i64>,
+    pub user_id: i32,
+    pub org_id: i32,
+    pub name: String,
+    /// first characters of the key, enough to recognise it in a list
+    pub prefix: String,
+    #[serde(skip)]
+    pub key_hash: String,
+    /// permission names the key is limited to
+    pub permissions: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateApiKey {
+    pub name: String,
+    pub permissions: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Creation result, the only time the key itself is shown
+#[derive(Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+/// Owner of a live key as resolved on each request
+#[derive(Debug, sqlx::FromRow)]
+pub struct ApiKeyOwner {
+    pub api_key_id: i64,
+    pub user_id: i32,
+    pub org_id: i32,
+    pub username: String,
+    pub permissions: Vec<String>,
+}
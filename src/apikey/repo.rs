@@ -0,0 +1,46 @@
+use super::model::{ApiKey, ApiKeyOwner};
+use crate::{db::DBConn, error::CustomError};
+
+pub struct ApiKeyRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> ApiKeyRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        ApiKeyRepository { db }
+    }
+
+    pub async fn insert_api_key(&self, api_key: &ApiKey) -> Result<i64, CustomError> {
+        self.db.insert_api_key(api_key).await.map_err(|e| match e {
+            sqlx::Error::Database(err) if err.is_unique_violation() => CustomError::ApiKeyExists,
+            _ => CustomError::DBError(e),
+        })
+    }
+
+    pub async fn fetch_user_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, CustomError> {
+        self.db
+            .fetch_user_api_keys(user_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn revoke_api_key(&self, user_id: i32, api_key_id: i64) -> Result<(), CustomError> {
+        self.db
+            .revoke_api_key(user_id, api_key_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::ApiKeyNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    /// Looks up a live key and records its use, ApiKeyNotFound when it is
+    /// unknown, expired or revoked
+    pub async fn use_api_key(&self, key_hash: &str) -> Result<ApiKeyOwner, CustomError> {
+        self.db.use_api_key(key_hash).await.map_err(|e| match e {
+            sqlx::Error::RowNotFound => CustomError::ApiKeyNotFound,
+            _ => CustomError::DBError(e),
+        })
+    }
+}
@@ -0,0 +1,253 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use request_http_parser::parser::Request;
+use serde_json::json;
+
+use super::{
+    model::{API_KEY_PREFIX, ApiKey, CreateApiKey, CreatedApiKey},
+    repo::ApiKeyRepository,
+};
+use crate::{
+    audit::{
+        model::{AuditEvent, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    constants::{BAD_REQUEST, FORBIDDEN, INTERNAL_ERROR, NO_CONTENT, NOT_FOUND, OK_RESPONSE},
+    db::DBConn,
+    error::CustomError,
+    rolepermissions::service::RolePermissionSvc,
+    utils::{
+        ClaimType, Claims, des_from_str, extract_token, random_token, ser_to_str, sha256_hex,
+        token_expiry,
+    },
+};
+
+pub const API_KEYS_PATH: &str = "/protected/me/api-keys";
+
+/// Keys list their permissions one by one, keep that list readable
+const MAX_KEY_PERMISSIONS: usize = 50;
+
+pub struct ApiKeySvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: ApiKeyRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
+}
+
+impl<DB> ApiKeySvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>) -> Self {
+        ApiKeySvc {
+            repository: ApiKeyRepository::new(pool),
+            audit,
+        }
+    }
+
+    /// Key sent as `X-API-Key` or as a `kp_` bearer token
+    pub fn extract_key(request: &Request) -> Option<String> {
+        request
+            .headers
+            .get("x-api-key")
+            .cloned()
+            .or_else(|| extract_token(&request.headers))
+            .filter(|key| key.starts_with(API_KEY_PREFIX))
+    }
+
+    /// Claims of the key's owner for this request, limited to the key's
+    /// permissions. They carry no roles, every check goes through the
+    /// permission service which applies the key's scope. Records the key as
+    /// used.
+    pub async fn authenticate(&self, key: &str) -> Result<Claims, CustomError> {
+        let owner = self.repository.use_api_key(&sha256_hex(key)).await?;
+        Ok(Claims {
+            sub: owner.user_id.to_string(),
+            exp: token_expiry(&ClaimType::ApiKey).timestamp() as usize,
+            username: owner.username,
+            role_ids: vec![],
+            org_id: Some(owner.org_id),
+            claim_type: ClaimType::ApiKey,
+            permissions: Some(owner.permissions),
+            perm_bitmap: None,
+            perm_version: None,
+            sid: None,
+            scope: None,
+            api_key_id: Some(owner.api_key_id),
+            room_id: None,
+        })
+    }
+
+    pub async fn get_api_keys(&self, claims: Option<Claims>) -> (String, String) {
+        let (user_id, _) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let api_keys = match self.repository.fetch_user_api_keys(user_id).await {
+            Ok(api_keys) => api_keys,
+            Err(error) => {
+                eprintln!("Error api key db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&api_keys) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// Creates a key limited to permissions the caller holds right now
+    pub async fn create_api_key(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (user_id, org_id) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let req_key: CreateApiKey = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(req_key) => req_key,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if req_key.name.trim().is_empty() || req_key.name.len() > 100 {
+            return (BAD_REQUEST.to_string(), "Invalid name".to_string());
+        }
+        if req_key.permissions.is_empty() || req_key.permissions.len() > MAX_KEY_PERMISSIONS {
+            return (BAD_REQUEST.to_string(), "Invalid permissions".to_string());
+        }
+        if req_key
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+        {
+            return (BAD_REQUEST.to_string(), "Expiry is in the past".to_string());
+        }
+        for permission in &req_key.permissions {
+            match rp_svc
+                .user_has_permission(org_id, user_id, permission)
+                .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    return (
+                        FORBIDDEN.to_string(),
+                        format!("Permission {} not held", permission),
+                    );
+                }
+                Err(error) => {
+                    eprintln!("Error role permission db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            }
+        }
+
+        let key = format!("{}{}", API_KEY_PREFIX, random_token(32));
+        let mut api_key = ApiKey {
+            api_key_id: None,
+            user_id,
+            org_id,
+            name: req_key.name,
+            prefix: key[..API_KEY_PREFIX.len() + 8].to_string(),
+            key_hash: sha256_hex(&key),
+            permissions: req_key.permissions,
+            expires_at: req_key.expires_at,
+            last_used_at: None,
+            created_at: Utc::now(),
+            revoked_at: None,
+        };
+        match self.repository.insert_api_key(&api_key).await {
+            Ok(api_key_id) => api_key.api_key_id = Some(api_key_id),
+            Err(error) => {
+                eprintln!("Error insert api key db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: Some(user_id),
+                target: Some(format!(
+                    "api_key:{}",
+                    api_key.api_key_id.unwrap_or_default()
+                )),
+                metadata: json!({
+                    "name": api_key.name,
+                    "prefix": api_key.prefix,
+                    "permissions": api_key.permissions,
+                    "expires_at": api_key.expires_at,
+                }),
+                ..AuditEvent::new("api_key.create", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        let response_json = match ser_to_str(&CreatedApiKey { api_key, key }) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// `DELETE /protected/me/api-keys/{id}`
+    pub async fn revoke_api_key(
+        &self,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (user_id, org_id) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let api_key_id = match request
+            .path
+            .strip_prefix(API_KEYS_PATH)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .and_then(|id| id.parse::<i64>().ok())
+        {
+            Some(api_key_id) => api_key_id,
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        match self.repository.revoke_api_key(user_id, api_key_id).await {
+            Ok(_) => {
+                self.audit
+                    .record(AuditEvent {
+                        org_id: Some(org_id),
+                        actor_id: Some(user_id),
+                        target: Some(format!("api_key:{}", api_key_id)),
+                        ..AuditEvent::new("api_key.revoke", OUTCOME_SUCCESS, Some(request))
+                    })
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(CustomError::ApiKeyNotFound) => (NOT_FOUND.to_string(), "".to_string()),
+            Err(error) => {
+                eprintln!("Error api key db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// Keys are managed with a login token, a key cannot mint or revoke keys
+    fn caller(claims: Option<Claims>) -> Result<(i32, i32), (String, String)> {
+        match claims {
+            Some(claims) if claims.claim_type == ClaimType::Login => {
+                match (claims.user_id(), claims.org_id) {
+                    (Some(user_id), Some(org_id)) => Ok((user_id, org_id)),
+                    _ => Err((FORBIDDEN.to_string(), "".to_string())),
+                }
+            }
+            _ => Err((FORBIDDEN.to_string(), "".to_string())),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use chrono::{DateTime, SubsecRound, Utc};
+use request_http_parser::parser::Request;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::utils::client_ip;
+
+pub const OUTCOME_SUCCESS: &str = "success";
+pub const OUTCOME_FAILURE: &str = "failure";
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct AuditEvent {
+    pub event_id: Option<i64>,
+    pub org_id: Option<i32>,
+    /// None for events raised by the server itself or by anonymous callers
+    pub actor_id: Option<i32>,
+    pub action: String,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: String,
+    pub metadata: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+    /// set when the event is appended to the chain
+    #[serde(default)]
+    pub prev_hash: String,
+    #[serde(default)]
+    pub hash: String,
+}
+
+impl AuditEvent {
+    /// An event stamped now, with the client address and user agent of the
+    /// request when there is one. Other fields are filled with struct update
+    /// syntax by the caller.
+    pub fn new(action: &str, outcome: &str, request: Option<&Request>) -> Self {
+        AuditEvent {
+            event_id: None,
+            org_id: None,
+            actor_id: None,
+            action: action.to_string(),
+            target: None,
+            ip: request.and_then(client_ip).map(|ip| ip.to_string()),
+            user_agent: request.and_then(|r| r.headers.get("user-agent").cloned()),
+            outcome: outcome.to_string(),
+            metadata: serde_json::Value::Object(Default::default()),
+            // Postgres keeps microseconds, hash what will be stored
+            created_at: Utc::now().trunc_subsecs(6),
+            prev_hash: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    /// SHA-256 over the previous hash and every stored field except the id
+    pub fn chain_hash(&self, prev_hash: &str) -> String {
+        let content = serde_json::to_string(&(
+            prev_hash,
+            self.org_id,
+            self.actor_id,
+            &self.action,
+            &self.target,
+            &self.ip,
+            &self.user_agent,
+            &self.outcome,
+            &self.metadata,
+            self.created_at.timestamp_micros(),
+        ))
+        .unwrap_or_default();
+        hex::encode(Sha256::digest(content.as_bytes()))
+    }
+}
+
+/// Query params of `GET /protected/audit`, events are returned newest first
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub org_id: i32,
+    pub actor_id: Option<i32>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub outcome: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// page backwards from this event id
+    pub before_id: Option<i64>,
+    pub limit: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditVerification {
+    pub valid: bool,
+    pub checked: u64,
+    /// first event whose hash does not match the chain
+    pub broken_at: Option<i64>,
+}
@@ -0,0 +1,37 @@
+use super::model::{AuditEvent, AuditFilter};
+use crate::{db::DBConn, error::CustomError};
+
+pub struct AuditRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> AuditRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        AuditRepository { db }
+    }
+
+    pub async fn insert_event(&self, event: &AuditEvent) -> Result<i64, CustomError> {
+        self.db
+            .insert_audit_event(event)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn fetch_events(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, CustomError> {
+        self.db
+            .fetch_audit_events(filter)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn fetch_chain(
+        &self,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, CustomError> {
+        self.db
+            .fetch_audit_chain(after_id, limit)
+            .await
+            .map_err(CustomError::DBError)
+    }
+}
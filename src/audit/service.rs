@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use request_http_parser::parser::Request;
+
+use super::{
+    model::{AuditEvent, AuditFilter, AuditVerification},
+    repo::AuditRepository,
+};
+use crate::{
+    constants::{BAD_REQUEST, INTERNAL_ERROR, OK_RESPONSE, VIEW_AUDIT},
+    db::DBConn,
+    error::CustomError,
+    rolepermissions::service::RolePermissionSvc,
+    utils::{Claims, ser_to_str},
+};
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+const VERIFY_BATCH: i64 = 1000;
+
+pub struct AuditSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: AuditRepository<DB>,
+}
+
+impl<DB> AuditSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB) -> Self {
+        AuditSvc {
+            repository: AuditRepository::new(pool),
+        }
+    }
+
+    /// Appends the event to the log. A failed write never fails the request
+    /// that raised it, it is reported on stderr instead.
+    pub async fn record(&self, event: AuditEvent) {
+        if let Err(error) = self.repository.insert_event(&event).await {
+            eprintln!("Error audit db: {:#?} for {:?}", error, event);
+        }
+    }
+
+    pub async fn get_events(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let org_id = match rp_svc.authorize(claims, request, VIEW_AUDIT).await {
+            Ok((_, org_id)) => org_id,
+            Err(response) => return response,
+        };
+        let filter = match Self::filter(org_id, request) {
+            Some(filter) => filter,
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let events = match self.repository.fetch_events(&filter).await {
+            Ok(events) => events,
+            Err(error) => {
+                eprintln!("Error audit db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&events) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// Checks the caller's organization's events against the chain and
+    /// reports the first one that was altered or follows a removed event.
+    /// The chain runs through every organization, their events are read to
+    /// link it up but never reported on.
+    pub async fn verify_chain(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let org_id = match rp_svc.authorize(claims, request, VIEW_AUDIT).await {
+            Ok((_, org_id)) => org_id,
+            Err(response) => return response,
+        };
+        let verification = match self.verify(org_id).await {
+            Ok(verification) => verification,
+            Err(error) => {
+                eprintln!("Error audit db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&verification) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    async fn verify(&self, org_id: i32) -> Result<AuditVerification, CustomError> {
+        let mut prev_hash = String::new();
+        let mut after_id = 0;
+        let mut checked = 0;
+        loop {
+            let events = self.repository.fetch_chain(after_id, VERIFY_BATCH).await?;
+            for event in &events {
+                let event_id = event.event_id.unwrap_or_default();
+                let intact =
+                    event.prev_hash == prev_hash && event.hash == event.chain_hash(&prev_hash);
+                // links on from the stored hash so a break elsewhere does not
+                // show up on the next event of this organization
+                prev_hash = event.hash.clone();
+                after_id = event_id;
+                if event.org_id != Some(org_id) {
+                    continue;
+                }
+                if !intact {
+                    return Ok(AuditVerification {
+                        valid: false,
+                        checked,
+                        broken_at: Some(event_id),
+                    });
+                }
+                checked += 1;
+            }
+            if (events.len() as i64) < VERIFY_BATCH {
+                return Ok(AuditVerification {
+                    valid: true,
+                    checked,
+                    broken_at: None,
+                });
+            }
+        }
+    }
+
+    fn filter(org_id: i32, request: &Request) -> Option<AuditFilter> {
+        let mut filter = AuditFilter {
+            org_id,
+            limit: DEFAULT_LIMIT,
+            ..Default::default()
+        };
+        let params = match &request.params {
+            Some(params) => params,
+            None => return Some(filter),
+        };
+        for (key, value) in params {
+            match key.as_str() {
+                "actor_id" => filter.actor_id = Some(value.parse().ok()?),
+                "action" => filter.action = Some(value.clone()),
+                "target" => filter.target = Some(value.clone()),
+                "outcome" => filter.outcome = Some(value.clone()),
+                "since" => filter.since = Some(value.parse().ok()?),
+                "until" => filter.until = Some(value.parse().ok()?),
+                "before_id" => filter.before_id = Some(value.parse().ok()?),
+                "limit" => filter.limit = value.parse::<i64>().ok()?.clamp(1, MAX_LIMIT),
+                _ => {}
+            }
+        }
+        Some(filter)
+    }
+}
@@ -1,3 +1,4 @@
 pub mod model;
 pub mod repo;
 pub mod service;
+pub mod verifier;
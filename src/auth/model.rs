@@ -7,9 +7,8 @@ pub struct User {
     pub username: String,
     pub password: Option<String>,
     pub email: Option<String>,
-    pub provider: String,
-    pub provider_id: Option<String>,
-    pub role_id: i32,
+    pub org_id: Option<i32>,
+    pub role_ids: Vec<i32>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -17,13 +16,19 @@ pub struct User {
 pub struct Login {
     pub username: String,
     pub password: String,
+    pub org_id: Option<i32>,
+    /// Browser clients ask for the token as an HttpOnly cookie
+    #[serde(default)]
+    pub cookie: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginRegister {
     pub username: String,
     pub password: String,
-    pub role_id: i32,
+    /// token from an invitation mail, grants the invited role
+    #[serde(default)]
+    pub invitation: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,13 +42,56 @@ pub struct ResetPassword {
     pub token: String,
 }
 
+/// ID token of an upstream provider, `/signin/{provider}`
 #[derive(Serialize, Deserialize)]
-pub struct SigninGoogle {
+pub struct SigninProvider {
     pub token: String,
+    pub org_id: Option<i32>,
+    #[serde(default)]
+    pub cookie: bool,
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct RegisterGoogle {
+pub struct RegisterProvider {
     pub token: String,
-    pub role_id: i32,
+    #[serde(default)]
+    pub invitation: Option<String>,
+}
+
+/// Sign-in redirected to GitHub or the SAML IdP, waiting for the browser
+/// to come back
+#[derive(Debug, sqlx::FromRow)]
+pub struct UpstreamState {
+    pub state_hash: String,
+    pub provider: String,
+    /// PKCE verifier, empty for SAML
+    pub code_verifier: String,
+    /// organization to sign in to, sign-ups always land in the default one
+    pub org_id: Option<i32>,
+    /// set when the user signs up, the role they register with
+    pub role_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Code and state GitHub redirected the browser back with
+#[derive(Serialize, Deserialize)]
+pub struct UpstreamCallback {
+    pub code: String,
+    pub state: String,
+    #[serde(default)]
+    pub cookie: bool,
+}
+
+/// Base64 `SAMLResponse` the IdP posted to the frontend's ACS page
+#[derive(Serialize, Deserialize)]
+pub struct SamlCallback {
+    pub saml_response: String,
+    #[serde(default)]
+    pub cookie: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SwitchOrg {
+    pub org_id: i32,
 }
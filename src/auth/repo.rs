@@ -1,5 +1,12 @@
-use super::model::User;
-use crate::{db::DBConn, error::CustomError};
+use super::model::{UpstreamState, User};
+use crate::{
+    cfg::CONFIG,
+    db::DBConn,
+    error::CustomError,
+    identity::model::UserIdentity,
+    invitation::model::Invitation,
+    utils::{PermissionClaimMode, TokenPermissions},
+};
 
 pub struct AuthRepository<DB: DBConn> {
     db: DB,
@@ -14,26 +21,122 @@ impl<DB: DBConn> AuthRepository<DB> {
         self.db.print_pool_stats();
     }
 
-    pub async fn query_user(&self, username: &str) -> Result<User, CustomError> {
-        self.db.fetch_user(username).await.map_err(|e| match e {
-            sqlx::Error::RowNotFound => CustomError::UserNotFound,
-            _ => CustomError::DBError(e),
-        })
+    pub async fn query_user(
+        &self,
+        username: &str,
+        org_id: Option<i32>,
+    ) -> Result<User, CustomError> {
+        self.db
+            .fetch_user(username, org_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    /// The user a provider account is linked to, as a member of `org_id`
+    pub async fn query_identity_user(
+        &self,
+        provider: &str,
+        provider_id: &str,
+        org_id: Option<i32>,
+    ) -> Result<User, CustomError> {
+        self.db
+            .fetch_identity_user(provider, provider_id, org_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserNotFound,
+                _ => CustomError::DBError(e),
+            })
     }
 
-    pub async fn insert_user(&self, new_user: &User) -> Result<i32, CustomError> {
-        let user_id = match self.db.insert_user(new_user).await {
+    pub async fn insert_user(
+        &self,
+        new_user: &User,
+        identity: Option<&UserIdentity>,
+    ) -> Result<i32, CustomError> {
+        let user_id = match self.db.insert_user(new_user, identity).await {
             Ok(user_id) => user_id,
             Err(e) => match e {
                 sqlx::Error::Database(err) if err.is_unique_violation() => {
                     return Err(CustomError::UsernameExists);
                 }
+                sqlx::Error::Database(err) if err.is_foreign_key_violation() => {
+                    return Err(CustomError::OrgNotFound);
+                }
+                sqlx::Error::RowNotFound => return Err(CustomError::RoleNotFound),
                 _ => return Err(CustomError::DBError(e)),
             },
         };
         Ok(user_id)
     }
 
+    pub async fn sync_user_roles(
+        &self,
+        org_id: i32,
+        user_id: i32,
+        managed_role_ids: &[i32],
+        role_ids: &[i32],
+    ) -> Result<(), CustomError> {
+        self.db
+            .sync_user_roles(org_id, user_id, managed_role_ids, role_ids)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn insert_upstream_state(&self, state: &UpstreamState) -> Result<(), CustomError> {
+        self.db
+            .insert_upstream_state(state)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(err) if err.is_foreign_key_violation() => {
+                    match err.constraint() {
+                        Some(constraint) if constraint.contains("role_id") => {
+                            CustomError::RoleNotFound
+                        }
+                        _ => CustomError::OrgNotFound,
+                    }
+                }
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    /// Redeems a sign-in state, UpstreamStateInvalid when it is unknown,
+    /// expired or was already used
+    pub async fn consume_upstream_state(
+        &self,
+        state_hash: &str,
+        provider: &str,
+    ) -> Result<UpstreamState, CustomError> {
+        self.db
+            .consume_upstream_state(state_hash, provider)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UpstreamStateInvalid,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    /// Claims an invitation for a registration, InvitationInvalid when it
+    /// is unknown, expired, revoked or already accepted
+    pub async fn claim_invitation(&self, token_hash: &str) -> Result<Invitation, CustomError> {
+        self.db
+            .claim_invitation(token_hash)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::InvitationInvalid,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn release_invitation(&self, invitation_id: i64) -> Result<(), CustomError> {
+        self.db
+            .release_invitation(invitation_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
     pub async fn update_password(&self, user_id: &str, password: &str) -> Result<i32, CustomError> {
         let user_id = match self.db.update_password(user_id, password).await {
             Ok(user_id) => user_id,
@@ -43,4 +146,23 @@ impl<DB: DBConn> AuthRepository<DB> {
         };
         Ok(user_id)
     }
+
+    pub async fn query_token_permissions(
+        &self,
+        user: &User,
+    ) -> Result<TokenPermissions, CustomError> {
+        let version = self.db.fetch_perm_version().await?;
+        let permissions = match (&CONFIG.jwt_permission_claims, user.org_id) {
+            (PermissionClaimMode::None, _) | (_, None) => vec![],
+            (_, Some(org_id)) => {
+                self.db
+                    .fetch_role_permissions(org_id, &user.role_ids)
+                    .await?
+            }
+        };
+        Ok(TokenPermissions {
+            permissions,
+            version,
+        })
+    }
 }
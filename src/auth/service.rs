@@ -1,34 +1,64 @@
 use super::{
-    model::{ForgotPassword, LoginRegister, RegisterGoogle, ResetPassword, SigninGoogle},
+    model::{
+        ForgotPassword, LoginRegister, RegisterProvider, ResetPassword, SamlCallback,
+        SigninProvider, SwitchOrg, UpstreamCallback, UpstreamState, User,
+    },
     repo::AuthRepository,
+    verifier::{CredentialVerifier, ExternalAccount, Verdict},
 };
 use crate::{
+    audit::{
+        model::{AuditEvent, OUTCOME_FAILURE, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
     auth::model::Login,
+    cfg::CONFIG,
     constants::{
-        BAD_REQUEST, GOOGLE, INTERNAL_ERROR, LOCAL, NO_CONTENT, OK_RESPONSE, UNAUTHORIZED,
+        BAD_REQUEST, FORBIDDEN, FOUND, INTERNAL_ERROR, NO_CONTENT, NOT_FOUND, OK_RESPONSE,
+        OK_STATUS, UNAUTHORIZED,
     },
+    cookie,
     db::DBConn,
     error::CustomError,
-    google::GoogleTokenVerifier,
+    identity::model::UserIdentity,
+    idp::{
+        IdentityProviders,
+        github::{GITHUB, GithubProvider},
+    },
     mail::{Attribs, ForgotPasswordMail, Mail},
+    saml::{SAML, SamlLogin},
+    session::service::SessionSvc,
     utils::{
-        ClaimType, create_jwt, des_from_str, encrypt, extract_token, is_password_valid, ser_to_str,
-        verify_jwt,
+        ClaimType, Claims, create_jwt, des_from_str, encrypt, extract_token, ser_to_str,
+        sha256_hex, verify_jwt, with_headers,
     },
 };
-use chrono::Utc;
+use chrono::{Duration, Utc};
 use request_http_parser::parser::Request;
+use serde_json::json;
 use std::sync::Arc;
 
+/// Time the user has to sign in at GitHub and come back
+const UPSTREAM_STATE_LIFETIME_MINUTES: i64 = 10;
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct Response {
     pub token: String,
 }
 
+/// Body of a cookie session sign-in, the token itself only travels in the
+/// HttpOnly cookie
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
-struct ResponseSignGoogle {
+struct ResponseCookie {
+    pub csrf_token: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct ResponseSignProvider {
     pub token: Option<String>,
     pub is_registered: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub csrf_token: Option<String>,
 }
 
 pub struct AuthService<DB>
@@ -36,15 +66,84 @@ where
     DB: DBConn + Send + Sync + 'static,
 {
     repository: AuthRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
+    sessions: Arc<SessionSvc<DB>>,
+    verifiers: Vec<Arc<dyn CredentialVerifier>>,
 }
 
 impl<DB> AuthService<DB>
 where
     DB: DBConn + Send + Sync + 'static,
 {
-    pub fn new(pool: DB) -> Self {
+    /// `verifiers` check login passwords in order, see [`CredentialVerifier`]
+    pub fn new(
+        pool: DB,
+        audit: Arc<AuditSvc<DB>>,
+        sessions: Arc<SessionSvc<DB>>,
+        verifiers: Vec<Arc<dyn CredentialVerifier>>,
+    ) -> Self {
         AuthService {
             repository: AuthRepository::new(pool),
+            audit,
+            sessions,
+            verifiers,
+        }
+    }
+
+    /// Records a failed attempt against `username`, `reason` ends up in the
+    /// event metadata
+    async fn audit_failure(&self, request: &Request, action: &str, username: &str, reason: &str) {
+        self.audit
+            .record(AuditEvent {
+                target: Some(format!("user:{}", username)),
+                metadata: json!({ "reason": reason }),
+                ..AuditEvent::new(action, OUTCOME_FAILURE, Some(request))
+            })
+            .await;
+    }
+
+    async fn audit_success(&self, request: &Request, action: &str, user: &User) {
+        self.audit
+            .record(AuditEvent {
+                org_id: user.org_id,
+                actor_id: user.user_id,
+                target: Some(format!("user:{}", user.username)),
+                ..AuditEvent::new(action, OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+    }
+
+    async fn create_login_jwt(&self, user: &User, session_id: i64) -> anyhow::Result<String> {
+        let token_permissions = self.repository.query_token_permissions(user).await?;
+        create_jwt(
+            user,
+            ClaimType::Login,
+            Some(&token_permissions),
+            Some(session_id),
+        )
+    }
+
+    /// Opens a session for the sign-in and issues its login token
+    async fn start_session(&self, user: &User, request: &Request) -> anyhow::Result<String> {
+        let session_id = self.sessions.start(user, request).await?;
+        self.create_login_jwt(user, session_id).await
+    }
+
+    /// Hands a login token to the client, as cookies when it runs a browser
+    /// session
+    fn token_response(token: String, cookie: bool) -> (String, String) {
+        let (status_line, response_json) = if cookie {
+            let (status_line, csrf_token) = cookie::attach_session(OK_RESPONSE, &token);
+            (status_line, ser_to_str(&ResponseCookie { csrf_token }))
+        } else {
+            (OK_RESPONSE.to_string(), ser_to_str(&Response { token }))
+        };
+        match response_json {
+            Ok(json) => (status_line, json),
+            Err(_) => {
+                println!("serde error");
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
         }
     }
 
@@ -58,50 +157,190 @@ where
             },
             None => return (BAD_REQUEST.to_string(), "".to_string()),
         };
-        let user_db = match self.repository.query_user(&req_user.username).await {
-            Ok(user) => user,
-            Err(why) => match why {
-                CustomError::UserNotFound => {
-                    println!("User {} not found", req_user.username);
-                    return (UNAUTHORIZED.to_string(), "".to_string());
-                }
-                error => {
-                    eprintln!("Error user db: {:#?}", error);
+        if req_user.cookie && !CONFIG.cookie_mode {
+            return (BAD_REQUEST.to_string(), "Cookie mode disabled".to_string());
+        }
+        let local_user = match self
+            .repository
+            .query_user(&req_user.username, req_user.org_id)
+            .await
+        {
+            Ok(user) => Some(user),
+            Err(CustomError::UserNotFound) => None,
+            Err(error) => {
+                eprintln!("Error user db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+
+        let mut verdict = Verdict::Unknown;
+        for verifier in &self.verifiers {
+            verdict = match verifier
+                .verify(&req_user.username, &req_user.password, local_user.as_ref())
+                .await
+            {
+                Ok(verdict) => verdict,
+                Err(e) => {
+                    eprintln!("Error credential verifier: {}", e);
                     return (INTERNAL_ERROR.to_string(), "".to_string());
                 }
-            },
-        };
+            };
+            if !matches!(verdict, Verdict::Unknown) {
+                break;
+            }
+        }
 
-        let password = match &user_db.password {
-            Some(body) => body,
-            None => return (UNAUTHORIZED.to_string(), "".to_string()),
+        let user_db = match (verdict, local_user) {
+            (Verdict::Password, Some(user)) => user,
+            (Verdict::External(account), local_user) => {
+                match self
+                    .external_user(
+                        request,
+                        &req_user.username,
+                        req_user.org_id,
+                        account,
+                        local_user,
+                    )
+                    .await
+                {
+                    Ok(user) => user,
+                    Err(response) => return response,
+                }
+            }
+            (Verdict::Rejected(reason), _) => {
+                println!("User {} rejected: {}", req_user.username, reason);
+                self.audit_failure(request, "login", &req_user.username, reason)
+                    .await;
+                return (
+                    UNAUTHORIZED.to_string(),
+                    "Username or password is incorrect".to_string(),
+                );
+            }
+            (_, None) => {
+                println!("User {} not found", req_user.username);
+                self.audit_failure(request, "login", &req_user.username, "user_not_found")
+                    .await;
+                return (UNAUTHORIZED.to_string(), "".to_string());
+            }
+            (_, Some(_)) => {
+                self.audit_failure(request, "login", &req_user.username, "no_password")
+                    .await;
+                return (UNAUTHORIZED.to_string(), "".to_string());
+            }
         };
 
-        if !is_password_valid(&req_user.password, password) {
-            println!("User {} wrong password", req_user.username);
-            return (
-                UNAUTHORIZED.to_string(),
-                "Username or password is incorrect".to_string(),
+        if user_db.org_id.is_none() {
+            println!(
+                "User {} is not a member of the organization",
+                req_user.username
             );
+            self.audit_failure(request, "login", &req_user.username, "not_a_member")
+                .await;
+            return (FORBIDDEN.to_string(), "Not a member".to_string());
         }
 
-        let token = match create_jwt(&user_db, ClaimType::Login) {
+        let token = match self.start_session(&user_db, request).await {
             Ok(token) => token,
             Err(e) => {
                 eprintln!("Error creating JWT: {:#?}", e);
                 return (INTERNAL_ERROR.to_string(), "".to_string());
             }
         };
-        let response = Response { token };
-        let response_json = match ser_to_str(&response) {
-            Ok(json) => json,
-            Err(_) => {
-                println!("serde error");
-                return (INTERNAL_ERROR.to_string(), "".to_string());
+        println!("{} succeed login", req_user.username);
+        self.audit_success(request, "login", &user_db).await;
+        Self::token_response(token, req_user.cookie)
+    }
+
+    /// The local user behind a directory account, provisioned on its first
+    /// login. Its mapped roles are synced on every login.
+    async fn external_user(
+        &self,
+        request: &Request,
+        username: &str,
+        org_id: Option<i32>,
+        account: ExternalAccount,
+        local_user: Option<User>,
+    ) -> Result<User, (String, String)> {
+        let action = format!("login.{}", account.provider);
+        let linked_user_id = match self
+            .repository
+            .query_identity_user(&account.provider, &account.provider_id, None)
+            .await
+        {
+            // the directory must not sign in as an unrelated local account
+            Ok(user)
+                if local_user
+                    .as_ref()
+                    .is_some_and(|local| local.user_id != user.user_id) =>
+            {
+                self.audit_failure(request, &action, username, "identity_mismatch")
+                    .await;
+                return Err((UNAUTHORIZED.to_string(), "".to_string()));
+            }
+            Ok(user) => user.user_id,
+            Err(CustomError::UserNotFound) => {
+                if local_user.is_some() {
+                    self.audit_failure(request, &action, username, "username_taken")
+                        .await;
+                    return Err((UNAUTHORIZED.to_string(), "".to_string()));
+                }
+                if account.role_ids.is_empty() {
+                    self.audit_failure(request, &action, username, "no_mapped_group")
+                        .await;
+                    return Err((FORBIDDEN.to_string(), "Not a member".to_string()));
+                }
+                let new_user = User {
+                    username: username.to_string(),
+                    password: None,
+                    user_id: None,
+                    org_id: Some(account.org_id),
+                    role_ids: account.role_ids.clone(),
+                    created_at: Utc::now(),
+                    email: account.email.clone(),
+                };
+                let identity = UserIdentity {
+                    identity_id: None,
+                    user_id: None,
+                    provider: account.provider.clone(),
+                    provider_id: account.provider_id.clone(),
+                    email: account.email.clone(),
+                    created_at: Utc::now(),
+                };
+                let register_action = format!("register.{}", account.provider);
+                let (status_line, content) = self
+                    .insert_user(request, &register_action, new_user, Some(identity))
+                    .await;
+                if status_line != NO_CONTENT {
+                    return Err((status_line, content));
+                }
+                None
+            }
+            Err(error) => {
+                eprintln!("Error user db: {:#?}", error);
+                return Err((INTERNAL_ERROR.to_string(), "".to_string()));
             }
         };
-        println!("{} succeed login", req_user.username);
-        (OK_RESPONSE.to_string(), response_json)
+        if let Some(user_id) = linked_user_id
+            && let Err(error) = self
+                .repository
+                .sync_user_roles(
+                    account.org_id,
+                    user_id,
+                    &account.managed_role_ids,
+                    &account.role_ids,
+                )
+                .await
+        {
+            eprintln!("Error sync roles db: {:#?}", error);
+            return Err((INTERNAL_ERROR.to_string(), "".to_string()));
+        }
+        self.repository
+            .query_identity_user(&account.provider, &account.provider_id, org_id)
+            .await
+            .map_err(|error| {
+                eprintln!("Error user db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            })
     }
 
     pub async fn register(&self, request: &Request) -> (String, String) {
@@ -121,19 +360,117 @@ where
             username: req_user.username,
             password: Some(encrypt(&req_user.password)),
             user_id: None,
-            role_id: req_user.role_id,
+            org_id: Some(CONFIG.default_org_id),
+            role_ids: self_signup_role_ids(),
             created_at: Utc::now(),
             email: None,
-            provider: LOCAL.to_string(),
-            provider_id: None,
         };
-        match self.repository.insert_user(&new_user).await {
-            Ok(_) => (NO_CONTENT.to_string(), "".to_string()),
+        self.register_user(
+            request,
+            "register",
+            new_user,
+            None,
+            req_user.invitation.as_deref(),
+        )
+        .await
+    }
+
+    /// Registration with an optional invitation, which replaces the
+    /// organization and self sign-up role with the invited ones. Provider
+    /// accounts must have the invited email, local ones take it over.
+    async fn register_user(
+        &self,
+        request: &Request,
+        action: &str,
+        mut new_user: User,
+        identity: Option<UserIdentity>,
+        invitation: Option<&str>,
+    ) -> (String, String) {
+        let invitation = match invitation {
+            Some(token) => match self.repository.claim_invitation(&sha256_hex(token)).await {
+                Ok(invitation) => invitation,
+                Err(CustomError::InvitationInvalid) => {
+                    self.audit_failure(request, action, &new_user.username, "invitation_invalid")
+                        .await;
+                    return (BAD_REQUEST.to_string(), "Invitation invalid".to_string());
+                }
+                Err(error) => {
+                    eprintln!("Error invitation db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            },
+            None => return self.insert_user(request, action, new_user, identity).await,
+        };
+        let invitation_id = invitation.invitation_id.unwrap_or_default();
+        if new_user
+            .email
+            .as_ref()
+            .is_some_and(|email| !email.eq_ignore_ascii_case(&invitation.email))
+        {
+            self.release_invitation(invitation_id).await;
+            self.audit_failure(request, action, &new_user.username, "invitation_email")
+                .await;
+            return (
+                FORBIDDEN.to_string(),
+                "Invitation is for another email".to_string(),
+            );
+        }
+        new_user.email = new_user.email.or(Some(invitation.email));
+        new_user.org_id = Some(invitation.org_id);
+        new_user.role_ids = vec![invitation.role_id];
+        let username = new_user.username.clone();
+        let (status_line, content) = self.insert_user(request, action, new_user, identity).await;
+        if status_line != NO_CONTENT {
+            self.release_invitation(invitation_id).await;
+            return (status_line, content);
+        }
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(invitation.org_id),
+                target: Some(format!("invitation:{}", invitation_id)),
+                metadata: json!({ "username": username, "role_id": invitation.role_id }),
+                ..AuditEvent::new("invitation.accept", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        (status_line, content)
+    }
+
+    /// Opens a claimed invitation again after the registration failed
+    async fn release_invitation(&self, invitation_id: i64) {
+        if let Err(error) = self.repository.release_invitation(invitation_id).await {
+            eprintln!("Error invitation db: {:#?}", error);
+        }
+    }
+
+    /// Shared tail of both registration flows
+    async fn insert_user(
+        &self,
+        request: &Request,
+        action: &str,
+        mut new_user: User,
+        identity: Option<UserIdentity>,
+    ) -> (String, String) {
+        match self
+            .repository
+            .insert_user(&new_user, identity.as_ref())
+            .await
+        {
+            Ok(user_id) => {
+                new_user.user_id = Some(user_id);
+                self.audit_success(request, action, &new_user).await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
             Err(err) => match err {
                 CustomError::UsernameExists => {
                     eprintln!("Error insert: {:#?}", err);
+                    self.audit_failure(request, action, &new_user.username, "username_exists")
+                        .await;
                     (BAD_REQUEST.to_string(), "Already registered".to_string())
                 }
+                CustomError::RoleNotFound | CustomError::OrgNotFound => {
+                    eprintln!("Error insert: {:#?}", err);
+                    (BAD_REQUEST.to_string(), err.to_string())
+                }
                 error => {
                     eprintln!("Error insert user db: {:#?}", error);
                     (INTERNAL_ERROR.to_string(), "".to_string())
@@ -150,11 +487,18 @@ where
             },
             None => return (BAD_REQUEST.to_string(), "".to_string()),
         };
-        let user_db = match self.repository.query_user(&req_user.username).await {
+        let user_db = match self.repository.query_user(&req_user.username, None).await {
             Ok(user) => user,
             Err(why) => match why {
                 CustomError::UserNotFound => {
                     println!("User {} not found", req_user.username);
+                    self.audit_failure(
+                        request,
+                        "password.forgot",
+                        &req_user.username,
+                        "user_not_found",
+                    )
+                    .await;
                     return (UNAUTHORIZED.to_string(), "".to_string());
                 }
                 error => {
@@ -171,7 +515,7 @@ where
                 return (BAD_REQUEST.to_string(), "You have no email".to_string());
             }
         };
-        let token = match create_jwt(&user_db, ClaimType::ForgotPassword) {
+        let token = match create_jwt(&user_db, ClaimType::ForgotPassword, None, None) {
             Ok(token) => token,
             Err(e) => {
                 eprintln!("Error creating JWT: {:#?}", e);
@@ -198,6 +542,8 @@ where
             },
         };
         let _ = Mail::send_email(reset_email).await;
+        self.audit_success(request, "password.forgot", &user_db)
+            .await;
         (OK_RESPONSE.to_string(), response_json)
     }
 
@@ -213,10 +559,23 @@ where
             Ok(claims) => claims,
             Err(err) => {
                 println!("Verification failed: {}", err);
+                self.audit
+                    .record(AuditEvent {
+                        metadata: json!({ "reason": "invalid_token" }),
+                        ..AuditEvent::new("password.reset", OUTCOME_FAILURE, Some(request))
+                    })
+                    .await;
                 return (UNAUTHORIZED.to_string(), "".to_string());
             }
         };
-        if claims.claim_type == ClaimType::Login {
+        if claims.claim_type != ClaimType::ForgotPassword {
+            self.audit_failure(
+                request,
+                "password.reset",
+                &claims.username,
+                "wrong_token_type",
+            )
+            .await;
             return (UNAUTHORIZED.to_string(), "".to_string());
         }
         let new_password = encrypt(&reset_password.password);
@@ -226,7 +585,17 @@ where
             .update_password(&claims.sub, &new_password)
             .await
         {
-            Ok(_) => (OK_RESPONSE.to_string(), "".to_string()),
+            Ok(_) => {
+                self.audit
+                    .record(AuditEvent {
+                        org_id: claims.org_id,
+                        actor_id: claims.user_id(),
+                        target: Some(format!("user:{}", claims.username)),
+                        ..AuditEvent::new("password.reset", OUTCOME_SUCCESS, Some(request))
+                    })
+                    .await;
+                (OK_RESPONSE.to_string(), "".to_string())
+            }
             Err(err) => match err {
                 error => {
                     eprintln!("Error insert user db: {:#?}", error);
@@ -237,7 +606,8 @@ where
     }
 
     pub fn validate(&self, request: &Request) -> (String, String) {
-        let token = match extract_token(&request.headers) {
+        let token = match extract_token(&request.headers).or_else(|| cookie::session_token(request))
+        {
             Some(token) => token,
             None => {
                 println!("Missing Header");
@@ -246,7 +616,10 @@ where
         };
 
         match verify_jwt(&token) {
-            Ok(_) => (OK_RESPONSE.to_string(), "".to_string()),
+            Ok(claims) if claims.claim_type != ClaimType::ForgotPassword => {
+                (OK_RESPONSE.to_string(), "".to_string())
+            }
+            Ok(_) => (UNAUTHORIZED.to_string(), "".to_string()),
             Err(err) => {
                 println!("Verification failed: {}", err);
                 (UNAUTHORIZED.to_string(), "".to_string())
@@ -254,35 +627,70 @@ where
         }
     }
 
-    pub async fn signin_google(
+    /// Signs in with an ID token of an upstream provider. Unknown users get
+    /// `is_registered: false` and can register with the same token.
+    pub async fn signin_provider(
         &self,
         request: &Request,
-        go_ver: &Arc<GoogleTokenVerifier>,
+        idps: &IdentityProviders,
+        provider_name: &str,
     ) -> (String, String) {
-        let signin_goole: SigninGoogle = match &request.body {
+        let provider = match idps.get(provider_name) {
+            Some(provider) => provider,
+            None => return (NOT_FOUND.to_string(), "Unknown provider".to_string()),
+        };
+        let action = format!("login.{}", provider.name());
+        let signin: SigninProvider = match &request.body {
             Some(body) => match des_from_str(body) {
                 Ok(user) => user,
                 Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
             },
             None => return (BAD_REQUEST.to_string(), "".to_string()),
         };
-        let google_data = match go_ver.verify(&signin_goole.token).await {
-            Ok(payload) => {
-                println!("✓ Token valid");
-                println!("User ID: {}", payload.sub);
-                println!("Email: {:?}", payload.email);
-                payload
-            }
-            Err(e) => {
-                println!("✗ Token invalid: {}", e);
-                return (BAD_REQUEST.to_string(), "token invalid".to_string());
+        if signin.cookie && !CONFIG.cookie_mode {
+            return (BAD_REQUEST.to_string(), "Cookie mode disabled".to_string());
+        }
+        let identity = match provider.verify(&signin.token).await {
+            Ok(identity) => identity,
+            Err(rejection) => {
+                println!("✗ Token rejected: {}", rejection);
+                self.audit
+                    .record(AuditEvent {
+                        metadata: json!({ "reason": rejection.code() }),
+                        ..AuditEvent::new(&action, OUTCOME_FAILURE, Some(request))
+                    })
+                    .await;
+                return (BAD_REQUEST.to_string(), rejection.code().to_string());
             }
         };
-        let user_db = match self.repository.query_user(&google_data.email).await {
+        self.provider_session(
+            request,
+            &action,
+            (provider.name(), &identity.sub),
+            signin.org_id,
+            signin.cookie,
+        )
+        .await
+    }
+
+    /// Signs in the user a verified `(provider, provider_id)` is linked to
+    async fn provider_session(
+        &self,
+        request: &Request,
+        action: &str,
+        (provider, provider_id): (&str, &str),
+        org_id: Option<i32>,
+        use_cookie: bool,
+    ) -> (String, String) {
+        let user_db = match self
+            .repository
+            .query_identity_user(provider, provider_id, org_id)
+            .await
+        {
             Ok(user) => Some(user),
             Err(why) => match why {
                 CustomError::UserNotFound => {
-                    println!("User {} not found", google_data.email);
+                    println!("No user linked to {} {}", provider, provider_id);
                     None
                 }
                 error => {
@@ -293,17 +701,35 @@ where
         };
 
         match user_db {
+            Some(user) if user.org_id.is_none() => {
+                println!("User {} is not a member of the organization", user.username);
+                self.audit_failure(request, action, &user.username, "not_a_member")
+                    .await;
+                (FORBIDDEN.to_string(), "Not a member".to_string())
+            }
             Some(user) => {
-                let token = match create_jwt(&user, ClaimType::Login) {
+                let token = match self.start_session(&user, request).await {
                     Ok(token) => token,
                     Err(e) => {
                         eprintln!("Error creating JWT: {:#?}", e);
                         return (INTERNAL_ERROR.to_string(), "".to_string());
                     }
                 };
-                let response = ResponseSignGoogle {
-                    token: Some(token),
-                    is_registered: true,
+                let (status_line, response) = if use_cookie {
+                    let (status_line, csrf_token) = cookie::attach_session(OK_RESPONSE, &token);
+                    let response = ResponseSignProvider {
+                        token: None,
+                        is_registered: true,
+                        csrf_token: Some(csrf_token),
+                    };
+                    (status_line, response)
+                } else {
+                    let response = ResponseSignProvider {
+                        token: Some(token),
+                        is_registered: true,
+                        csrf_token: None,
+                    };
+                    (OK_RESPONSE.to_string(), response)
                 };
                 let response_json = match ser_to_str(&response) {
                     Ok(json) => json,
@@ -313,12 +739,14 @@ where
                     }
                 };
                 println!("{} succeed login", user.username);
-                return (OK_RESPONSE.to_string(), response_json);
+                self.audit_success(request, action, &user).await;
+                (status_line, response_json)
             }
             None => {
-                let response = ResponseSignGoogle {
+                let response = ResponseSignProvider {
                     token: None,
                     is_registered: false,
+                    csrf_token: None,
                 };
                 let response_json = match ser_to_str(&response) {
                     Ok(json) => json,
@@ -327,57 +755,464 @@ where
                         return (INTERNAL_ERROR.to_string(), "".to_string());
                     }
                 };
-                return (OK_RESPONSE.to_string(), response_json);
+                (OK_RESPONSE.to_string(), response_json)
             }
         }
     }
 
-    pub async fn register_google(
+    pub async fn register_provider(
         &self,
         request: &Request,
-        go_ver: &Arc<GoogleTokenVerifier>,
+        idps: &IdentityProviders,
+        provider_name: &str,
     ) -> (String, String) {
-        let register_google: RegisterGoogle = match &request.body {
+        let provider = match idps.get(provider_name) {
+            Some(provider) => provider,
+            None => return (NOT_FOUND.to_string(), "Unknown provider".to_string()),
+        };
+        let register: RegisterProvider = match &request.body {
             Some(body) => match des_from_str(body) {
                 Ok(user) => user,
                 Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
             },
             None => return (BAD_REQUEST.to_string(), "".to_string()),
         };
-        let google_data = match go_ver.verify(&register_google.token).await {
-            Ok(payload) => {
-                println!("✓ Token valid");
-                println!("User ID: {}", payload.sub);
-                println!("Email: {:?}", payload.email);
-                payload
-            }
-            Err(e) => {
-                println!("✗ Token invalid: {}", e);
-                return (BAD_REQUEST.to_string(), "token invalid".to_string());
+        let identity = match provider.verify(&register.token).await {
+            Ok(identity) => identity,
+            Err(rejection) => {
+                println!("✗ Token rejected: {}", rejection);
+                return (BAD_REQUEST.to_string(), rejection.code().to_string());
             }
         };
+        let email = match identity.email {
+            Some(email) => email,
+            None => return (BAD_REQUEST.to_string(), "email claim missing".to_string()),
+        };
         let new_user = super::model::User {
-            username: google_data.email.clone(),
+            username: email.clone(),
             password: None,
             user_id: None,
-            role_id: register_google.role_id,
+            org_id: Some(CONFIG.default_org_id),
+            role_ids: self_signup_role_ids(),
             created_at: Utc::now(),
-            email: Some(google_data.email),
-            provider: GOOGLE.to_string(),
-            provider_id: Some(google_data.sub),
+            email: Some(email.clone()),
         };
-        match self.repository.insert_user(&new_user).await {
-            Ok(_) => (NO_CONTENT.to_string(), "".to_string()),
-            Err(err) => match err {
-                CustomError::UsernameExists => {
-                    eprintln!("Error insert: {:#?}", err);
-                    (BAD_REQUEST.to_string(), "Already registered".to_string())
+        let identity = UserIdentity {
+            identity_id: None,
+            user_id: None,
+            provider: provider.name().to_string(),
+            provider_id: identity.sub,
+            email: Some(email),
+            created_at: Utc::now(),
+        };
+        let action = format!("register.{}", provider.name());
+        self.register_user(
+            request,
+            &action,
+            new_user,
+            Some(identity),
+            register.invitation.as_deref(),
+        )
+        .await
+    }
+
+    /// `GET /github/authorize?org_id=&role_id=`, redirects the browser to
+    /// GitHub. `org_id` picks the organization to sign in to. A `role_id`
+    /// signs the user up into the default organization when they have no
+    /// account yet, it has to be the self sign-up role.
+    pub async fn github_authorize(
+        &self,
+        request: &Request,
+        idps: &IdentityProviders,
+    ) -> (String, String) {
+        let github = match &idps.github {
+            Some(github) => github,
+            None => return (NOT_FOUND.to_string(), "Unknown provider".to_string()),
+        };
+        let param = |key: &str| -> Result<Option<i32>, ()> {
+            match request.params.as_ref().and_then(|params| params.get(key)) {
+                Some(value) => value.parse().map(Some).map_err(|_| ()),
+                None => Ok(None),
+            }
+        };
+        let (org_id, role_id) = match (param("org_id"), param("role_id")) {
+            (Ok(org_id), Ok(role_id)) => (org_id, role_id),
+            _ => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if role_id.is_some_and(|role_id| Some(role_id) != CONFIG.self_signup_role_id) {
+            return (FORBIDDEN.to_string(), "Invitation required".to_string());
+        }
+
+        let authorization = github.authorization();
+        let now = Utc::now();
+        let state = UpstreamState {
+            state_hash: sha256_hex(&authorization.state),
+            provider: GITHUB.to_string(),
+            code_verifier: authorization.code_verifier,
+            org_id,
+            role_id,
+            created_at: now,
+            expires_at: now + Duration::minutes(UPSTREAM_STATE_LIFETIME_MINUTES),
+        };
+        match self.repository.insert_upstream_state(&state).await {
+            Ok(_) => (
+                with_headers(
+                    FOUND,
+                    &[
+                        format!("Location: {}", authorization.url),
+                        cookie::bind_upstream_state(
+                            &authorization.state,
+                            UPSTREAM_STATE_LIFETIME_MINUTES * 60,
+                        ),
+                    ],
+                ),
+                "".to_string(),
+            ),
+            Err(err @ (CustomError::OrgNotFound | CustomError::RoleNotFound)) => {
+                (BAD_REQUEST.to_string(), err.to_string())
+            }
+            Err(error) => {
+                eprintln!("Error upstream state db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// `POST /github/callback`, posted by the page GitHub redirected back to.
+    /// Answers like `/signin/{provider}`. The state has to come from the
+    /// browser that started the sign-in, or anyone could sign a victim in to
+    /// their own account by handing them a callback link.
+    pub async fn github_callback(
+        &self,
+        request: &Request,
+        idps: &IdentityProviders,
+    ) -> (String, String) {
+        let github = match &idps.github {
+            Some(github) => github,
+            None => return (NOT_FOUND.to_string(), "Unknown provider".to_string()),
+        };
+        let callback: UpstreamCallback = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(callback) => callback,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if callback.cookie && !CONFIG.cookie_mode {
+            return (BAD_REQUEST.to_string(), "Cookie mode disabled".to_string());
+        }
+        if !cookie::upstream_state_bound(request, &callback.state) {
+            return (BAD_REQUEST.to_string(), "state invalid".to_string());
+        }
+        let (status_line, content) = self.github_sign_in(request, github, &callback).await;
+        (cookie::clear_upstream_state(&status_line), content)
+    }
+
+    async fn github_sign_in(
+        &self,
+        request: &Request,
+        github: &GithubProvider,
+        callback: &UpstreamCallback,
+    ) -> (String, String) {
+        let action = format!("login.{}", GITHUB);
+        let state = match self
+            .repository
+            .consume_upstream_state(&sha256_hex(&callback.state), GITHUB)
+            .await
+        {
+            Ok(state) => state,
+            Err(CustomError::UpstreamStateInvalid) => {
+                return (BAD_REQUEST.to_string(), "state invalid".to_string());
+            }
+            Err(error) => {
+                eprintln!("Error upstream state db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let identity = match github.identity(&callback.code, &state.code_verifier).await {
+            Ok(identity) => identity,
+            Err(e) => {
+                println!("✗ GitHub sign-in failed: {}", e);
+                self.audit
+                    .record(AuditEvent {
+                        metadata: json!({ "reason": "invalid_code" }),
+                        ..AuditEvent::new(&action, OUTCOME_FAILURE, Some(request))
+                    })
+                    .await;
+                return (BAD_REQUEST.to_string(), "code invalid".to_string());
+            }
+        };
+        let mut org_id = state.org_id;
+        if let Some(role_id) = state.role_id {
+            match self
+                .repository
+                .query_identity_user(GITHUB, &identity.sub, None)
+                .await
+            {
+                Ok(_) => {}
+                Err(CustomError::UserNotFound) => {
+                    let email = match identity.email {
+                        Some(email) => email,
+                        None => {
+                            return (
+                                BAD_REQUEST.to_string(),
+                                "verified email required".to_string(),
+                            );
+                        }
+                    };
+                    let new_user = User {
+                        username: email.clone(),
+                        password: None,
+                        user_id: None,
+                        org_id: Some(CONFIG.default_org_id),
+                        role_ids: vec![role_id],
+                        created_at: Utc::now(),
+                        email: Some(email.clone()),
+                    };
+                    let user_identity = UserIdentity {
+                        identity_id: None,
+                        user_id: None,
+                        provider: GITHUB.to_string(),
+                        provider_id: identity.sub.clone(),
+                        email: Some(email),
+                        created_at: Utc::now(),
+                    };
+                    let register_action = format!("register.{}", GITHUB);
+                    let (status_line, content) = self
+                        .insert_user(request, &register_action, new_user, Some(user_identity))
+                        .await;
+                    if status_line != NO_CONTENT {
+                        return (status_line, content);
+                    }
+                    // a new account is only a member of the default organization
+                    org_id = None;
+                }
+                Err(error) => {
+                    eprintln!("Error user db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            }
+        }
+        self.provider_session(
+            request,
+            &action,
+            (GITHUB, &identity.sub),
+            org_id,
+            callback.cookie,
+        )
+        .await
+    }
+
+    /// `GET /saml/metadata`, the SP description to register with the IdP
+    pub fn saml_metadata(&self, idps: &IdentityProviders) -> (String, String) {
+        match &idps.saml {
+            Some(saml) => (
+                with_headers(
+                    OK_STATUS,
+                    &["Content-Type: application/samlmetadata+xml".to_string()],
+                ),
+                saml.metadata(),
+            ),
+            None => (NOT_FOUND.to_string(), "".to_string()),
+        }
+    }
+
+    /// `GET /saml/login`, redirects to the IdP with an AuthnRequest whose
+    /// ID the Response has to answer
+    pub async fn saml_login(&self, idps: &IdentityProviders) -> (String, String) {
+        let saml = match &idps.saml {
+            Some(saml) => saml,
+            None => return (NOT_FOUND.to_string(), "Unknown provider".to_string()),
+        };
+        let (request_id, url) = saml.authn_request();
+        let now = Utc::now();
+        let state = UpstreamState {
+            state_hash: sha256_hex(&request_id),
+            provider: SAML.to_string(),
+            code_verifier: "".to_string(),
+            org_id: None,
+            role_id: None,
+            created_at: now,
+            expires_at: now + Duration::minutes(UPSTREAM_STATE_LIFETIME_MINUTES),
+        };
+        match self.repository.insert_upstream_state(&state).await {
+            Ok(_) => (
+                with_headers(
+                    FOUND,
+                    &[
+                        format!("Location: {}", url),
+                        cookie::bind_upstream_state(
+                            &request_id,
+                            UPSTREAM_STATE_LIFETIME_MINUTES * 60,
+                        ),
+                    ],
+                ),
+                "".to_string(),
+            ),
+            Err(error) => {
+                eprintln!("Error upstream state db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// `POST /saml/acs`, posted by the frontend's ACS page with the Response
+    /// the IdP sent there. Users are provisioned on their first sign-in and
+    /// their roles follow the mapped attribute. Like the GitHub callback, the
+    /// request it answers has to have been started by the same browser.
+    pub async fn saml_acs(&self, request: &Request, idps: &IdentityProviders) -> (String, String) {
+        let saml = match &idps.saml {
+            Some(saml) => saml,
+            None => return (NOT_FOUND.to_string(), "Unknown provider".to_string()),
+        };
+        let action = format!("login.{}", SAML);
+        let callback: SamlCallback = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(callback) => callback,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if callback.cookie && !CONFIG.cookie_mode {
+            return (BAD_REQUEST.to_string(), "Cookie mode disabled".to_string());
+        }
+        let login = match saml.login(&callback.saml_response, Utc::now()) {
+            Ok(login) => login,
+            Err(e) => {
+                println!("✗ SAML sign-in failed: {}", e);
+                self.audit
+                    .record(AuditEvent {
+                        metadata: json!({ "reason": "invalid_response" }),
+                        ..AuditEvent::new(&action, OUTCOME_FAILURE, Some(request))
+                    })
+                    .await;
+                return (BAD_REQUEST.to_string(), "response invalid".to_string());
+            }
+        };
+        if !cookie::upstream_state_bound(request, &login.in_response_to) {
+            return (BAD_REQUEST.to_string(), "state invalid".to_string());
+        }
+        let (status_line, content) = self
+            .saml_sign_in(request, &action, login, callback.cookie)
+            .await;
+        (cookie::clear_upstream_state(&status_line), content)
+    }
+
+    async fn saml_sign_in(
+        &self,
+        request: &Request,
+        action: &str,
+        login: SamlLogin,
+        cookie: bool,
+    ) -> (String, String) {
+        // single use, a replayed Response finds its request consumed
+        match self
+            .repository
+            .consume_upstream_state(&sha256_hex(&login.in_response_to), SAML)
+            .await
+        {
+            Ok(_) => {}
+            Err(CustomError::UpstreamStateInvalid) => {
+                return (BAD_REQUEST.to_string(), "state invalid".to_string());
+            }
+            Err(error) => {
+                eprintln!("Error upstream state db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        let local_user = match self.repository.query_user(&login.username, None).await {
+            Ok(user) => Some(user),
+            Err(CustomError::UserNotFound) => None,
+            Err(error) => {
+                eprintln!("Error user db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let user_db = match self
+            .external_user(request, &login.username, None, login.account, local_user)
+            .await
+        {
+            Ok(user) => user,
+            Err(response) => return response,
+        };
+        if user_db.org_id.is_none() {
+            self.audit_failure(request, action, &user_db.username, "not_a_member")
+                .await;
+            return (FORBIDDEN.to_string(), "Not a member".to_string());
+        }
+        let token = match self.start_session(&user_db, request).await {
+            Ok(token) => token,
+            Err(e) => {
+                eprintln!("Error creating JWT: {:#?}", e);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        self.audit_success(request, action, &user_db).await;
+        Self::token_response(token, cookie)
+    }
+
+    pub async fn switch_org(&self, claims: Option<Claims>, request: &Request) -> (String, String) {
+        let claims = match claims {
+            Some(claims) => claims,
+            None => return (UNAUTHORIZED.to_string(), "".to_string()),
+        };
+        let switch_org: SwitchOrg = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(switch_org) => switch_org,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let user_db = match self
+            .repository
+            .query_user(&claims.username, Some(switch_org.org_id))
+            .await
+        {
+            Ok(user) => user,
+            Err(why) => match why {
+                CustomError::UserNotFound => {
+                    println!("User {} not found", claims.username);
+                    return (UNAUTHORIZED.to_string(), "".to_string());
                 }
                 error => {
-                    eprintln!("Error insert user db: {:#?}", error);
-                    (INTERNAL_ERROR.to_string(), "".to_string())
+                    eprintln!("Error user db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
                 }
             },
+        };
+        if user_db.org_id.is_none() {
+            println!(
+                "User {} is not a member of organization {}",
+                claims.username, switch_org.org_id
+            );
+            return (FORBIDDEN.to_string(), "Not a member".to_string());
+        }
+
+        let session_id = match claims.sid {
+            Some(session_id) => session_id,
+            None => return (UNAUTHORIZED.to_string(), "".to_string()),
+        };
+        if let Err(error) = self.sessions.extend(session_id, user_db.org_id).await {
+            eprintln!("Error session db: {:#?}", error);
+            return (UNAUTHORIZED.to_string(), "".to_string());
         }
+        let token = match self.create_login_jwt(&user_db, session_id).await {
+            Ok(token) => token,
+            Err(e) => {
+                eprintln!("Error creating JWT: {:#?}", e);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        println!(
+            "{} switched to organization {}",
+            claims.username, switch_org.org_id
+        );
+        self.audit_success(request, "org.switch", &user_db).await;
+        Self::token_response(token, cookie::is_cookie_session(request))
     }
 }
+
+/// Roles of users registering on their own
+fn self_signup_role_ids() -> Vec<i32> {
+    CONFIG.self_signup_role_id.into_iter().collect()
+}
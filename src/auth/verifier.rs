@@ -0,0 +1,65 @@
+//! Checks behind `AuthService::login`. Verifiers are asked in order until
+//! one knows the account, the bcrypt check comes first so a directory
+//! outage never locks out local users.
+
+use super::model::User;
+use crate::{idp::VerifyError, utils::is_password_valid};
+use async_trait::async_trait;
+
+/// An account confirmed by an external directory, to be signed in or
+/// provisioned
+#[derive(Debug)]
+pub struct ExternalAccount {
+    /// value of `user_identities.provider`
+    pub provider: String,
+    pub provider_id: String,
+    pub email: Option<String>,
+    /// organization the account is provisioned into
+    pub org_id: i32,
+    /// roles the account's groups map to
+    pub role_ids: Vec<i32>,
+    /// every role the mapping can grant, others are left alone when the
+    /// roles are synced
+    pub managed_role_ids: Vec<i32>,
+}
+
+#[derive(Debug)]
+pub enum Verdict {
+    /// not an account of this verifier, the next one is asked
+    Unknown,
+    /// audit reason of the refusal
+    Rejected(&'static str),
+    /// the local user's own password matched
+    Password,
+    External(ExternalAccount),
+}
+
+#[async_trait]
+pub trait CredentialVerifier: Send + Sync {
+    /// `user` is the local account of that username, if there is one
+    async fn verify(
+        &self,
+        username: &str,
+        password: &str,
+        user: Option<&User>,
+    ) -> Result<Verdict, VerifyError>;
+}
+
+/// The bcrypt hash in `users.password`
+pub struct PasswordVerifier;
+
+#[async_trait]
+impl CredentialVerifier for PasswordVerifier {
+    async fn verify(
+        &self,
+        _username: &str,
+        password: &str,
+        user: Option<&User>,
+    ) -> Result<Verdict, VerifyError> {
+        match user.and_then(|user| user.password.as_deref()) {
+            Some(hash) if is_password_valid(password, hash) => Ok(Verdict::Password),
+            Some(_) => Ok(Verdict::Rejected("wrong_password")),
+            None => Ok(Verdict::Unknown),
+        }
+    }
+}
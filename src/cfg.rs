@@ -1,3 +1,4 @@
+use crate::utils::PermissionClaimMode;
 use once_cell::sync::Lazy;
 
 #[derive(serde::Deserialize)]
@@ -6,9 +7,47 @@ pub struct AppConfig {
     pub database_url: String,
     pub jwt_private_key: String,
     pub google_client_id: String,
+    pub google_require_verified_email: bool,
+    pub google_allowed_hosted_domains: String,
+    pub google_allowed_email_domains: String,
+    pub google_jwks_uri: String,
+    pub idp_http_timeout_secs: u64,
     pub request_max_byte: usize,
+    /// limit of requests to the SAML ACS, a signed Response is usually
+    /// several kilobytes
+    pub saml_request_max_byte: usize,
     pub mail_server_url: String,
     pub mail_server_api_key: String,
+    pub jwt_permission_claims: PermissionClaimMode,
+    pub jwt_permission_claims_max_bytes: usize,
+    pub default_org_id: i32,
+    pub policy_file: String,
+    pub grant_sweep_interval_secs: u64,
+    pub cookie_mode: bool,
+    pub cookie_name: String,
+    pub cookie_domain: String,
+    pub cookie_secure: bool,
+    pub cookie_same_site: String,
+    pub csrf_cookie_name: String,
+    pub oidc_issuer: String,
+    pub device_verification_uri: String,
+    pub identity_providers_file: String,
+    pub ldap_config_file: String,
+    pub saml_config_file: String,
+    pub github_client_id: String,
+    pub github_client_secret: String,
+    pub github_authorize_url: String,
+    pub github_token_url: String,
+    pub github_user_url: String,
+    pub github_emails_url: String,
+    pub github_redirect_uri: String,
+    /// role of users registering without an invitation, None registers
+    /// them as members without a role
+    pub self_signup_role_id: Option<i32>,
+    pub invitation_uri: String,
+    /// proxies allowed to tell the client address through
+    /// `X-Forwarded-For`, comma separated addresses or CIDR ranges
+    pub trusted_proxies: String,
 }
 
 // Initialize config once
@@ -19,8 +58,92 @@ pub static CONFIG: Lazy<AppConfig> = Lazy::new(|| {
         .add_source(config::Environment::default())
         .set_default("request_max_byte", 2048)
         .expect("set valid env")
+        .set_default("saml_request_max_byte", 65536)
+        .expect("set valid env")
+        .set_default("google_require_verified_email", true)
+        .expect("set valid env")
+        .set_default("google_allowed_hosted_domains", "")
+        .expect("set valid env")
+        .set_default("google_allowed_email_domains", "")
+        .expect("set valid env")
+        .set_default(
+            "google_jwks_uri",
+            "https://www.googleapis.com/oauth2/v3/certs",
+        )
+        .expect("set valid env")
+        .set_default("idp_http_timeout_secs", 5)
+        .expect("set valid env")
+        .set_default("jwt_permission_claims", "none")
+        .expect("set valid env")
+        .set_default("jwt_permission_claims_max_bytes", 1024)
+        .expect("set valid env")
+        .set_default("default_org_id", 1)
+        .expect("set valid env")
+        .set_default("policy_file", "")
+        .expect("set valid env")
+        .set_default("grant_sweep_interval_secs", 60)
+        .expect("set valid env")
+        .set_default("cookie_mode", false)
+        .expect("set valid env")
+        .set_default("cookie_name", "koois_session")
+        .expect("set valid env")
+        .set_default("cookie_domain", "")
+        .expect("set valid env")
+        .set_default("cookie_secure", true)
+        .expect("set valid env")
+        .set_default("cookie_same_site", "Lax")
+        .expect("set valid env")
+        .set_default("csrf_cookie_name", "koois_csrf")
+        .expect("set valid env")
+        .set_default("oidc_issuer", "http://127.0.0.1:7879")
+        .expect("set valid env")
+        .set_default("device_verification_uri", "http://localhost:3000/en/device")
+        .expect("set valid env")
+        .set_default("identity_providers_file", "")
+        .expect("set valid env")
+        .set_default("ldap_config_file", "")
+        .expect("set valid env")
+        .set_default("saml_config_file", "")
+        .expect("set valid env")
+        .set_default("github_client_id", "")
+        .expect("set valid env")
+        .set_default("github_client_secret", "")
+        .expect("set valid env")
+        .set_default(
+            "github_authorize_url",
+            "https://github.com/login/oauth/authorize",
+        )
+        .expect("set valid env")
+        .set_default(
+            "github_token_url",
+            "https://github.com/login/oauth/access_token",
+        )
+        .expect("set valid env")
+        .set_default("github_user_url", "https://api.github.com/user")
+        .expect("set valid env")
+        .set_default("github_emails_url", "https://api.github.com/user/emails")
+        .expect("set valid env")
+        .set_default(
+            "github_redirect_uri",
+            "http://localhost:3000/en/github/callback",
+        )
+        .expect("set valid env")
+        .set_default("invitation_uri", "http://localhost:3000/en/register")
+        .expect("set valid env")
+        .set_default("trusted_proxies", "")
+        .expect("set valid env")
         .build()
         .expect("")
         .try_deserialize()
         .expect("env not ready")
 });
+
+/// Unit tests read their settings from `.env.test`, load it before the
+/// first use of [`CONFIG`]
+#[cfg(test)]
+pub fn load_test_env() {
+    static LOADED: std::sync::Once = std::sync::Once::new();
+    LOADED.call_once(|| {
+        dotenvy::from_filename(".env.test").ok();
+    });
+}
@@ -5,10 +5,16 @@ pub const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
             Access-Control-Max-Age: 86400\r\n\
             Content-Type: application/json\r\n\
             \r\n";
+/// Bare status line for responses that bring their own headers
+pub const OK_STATUS: &str = "HTTP/1.1 200 OK\r\n\r\n";
+pub const CREATED: &str = "HTTP/1.1 201 Created\r\n\r\n";
+pub const FOUND: &str = "HTTP/1.1 302 Found\r\n\r\n";
 pub const NO_CONTENT: &str = "HTTP/1.1 204 No Content\r\n\r\n";
 pub const BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request\r\n\r\n";
 pub const UNAUTHORIZED: &str = "HTTP/1.1 401 Unauthorized\r\n\r\n";
+pub const FORBIDDEN: &str = "HTTP/1.1 403 Forbidden\r\n\r\n";
 pub const NOT_FOUND: &str = "HTTP/1.1 404 Not Found\r\n\r\n";
+pub const CONFLICT: &str = "HTTP/1.1 409 Conflict\r\n\r\n";
 
 pub const INTERNAL_ERROR: &str = "HTTP/1.1 500 Internal Error\r\n\
             Access-Control-Allow-Origin: *\r\n\
@@ -18,10 +24,32 @@ pub const INTERNAL_ERROR: &str = "HTTP/1.1 500 Internal Error\r\n\
     \r\n";
 pub const OPTIONS_CORS: &str = "HTTP/1.1 204 No Content\r\n\
             Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: POST, GET, OPTIONS\r\n\
-            Access-Control-Allow-Headers: Authorization, Content-Type\r\n\
+            Access-Control-Allow-Methods: POST, GET, DELETE, OPTIONS\r\n\
+            Access-Control-Allow-Headers: Authorization, Content-Type, X-CSRF-Token, X-API-Key\r\n\
             Access-Control-Max-Age: 86400\r\n\
             \r\n";
 
 pub const LOCAL: &str = "local";
 pub const GOOGLE: &str = "google";
+
+pub const MANAGE_USERS: &str = "manage_users";
+pub const VIEW_AUDIT: &str = "view_audit";
+
+pub const OWNER: &str = "owner";
+
+pub const ROOM: &str = "room";
+pub const MANAGE_ROOM: &str = "manage_room";
+
+/// Everything a guest token allows on its room
+pub const GUEST_ACTIONS: &[&str] = &["join_room", "complete_quiz"];
+
+/// Relations a user can hold on a single resource and the actions each
+/// relation allows on it
+pub const RELATION_ACTIONS: &[(&str, &[&str])] = &[
+    (
+        OWNER,
+        &["manage_room", "create_quiz", "join_room", "complete_quiz"],
+    ),
+    ("facilitator", &["manage_room", "create_quiz"]),
+    ("participant", &["join_room", "complete_quiz"]),
+];
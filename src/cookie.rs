@@ -0,0 +1,170 @@
+//! Browser sessions carried in cookies instead of the `Authorization` header.
+//!
+//! The session cookie is `HttpOnly` so scripts never see the login token.
+//! Because the browser attaches it on its own, state changing requests must
+//! echo the readable CSRF cookie back in [`CSRF_HEADER`] (double submit).
+
+use chrono::Utc;
+use request_http_parser::parser::{Method, Request};
+
+use crate::{
+    cfg::CONFIG,
+    utils::{
+        ClaimType, constant_time_eq, extract_token, random_token, sha256_hex, token_expiry,
+        with_headers,
+    },
+};
+
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Holds the hash of the state of a sign-in started at an upstream provider
+pub const UPSTREAM_STATE_COOKIE: &str = "koois_upstream_state";
+
+/// Value of cookie `name` sent with the request
+pub fn get(request: &Request, name: &str) -> Option<String> {
+    request.headers.get("cookie").and_then(|cookies| {
+        cookies.split(';').find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name && !value.is_empty()).then(|| value.to_string())
+        })
+    })
+}
+
+/// Login token from the session cookie, only honored in cookie mode
+pub fn session_token(request: &Request) -> Option<String> {
+    if !CONFIG.cookie_mode {
+        return None;
+    }
+    get(request, &CONFIG.cookie_name)
+}
+
+/// Whether the caller authenticated with the session cookie rather than a
+/// bearer token
+pub fn is_cookie_session(request: &Request) -> bool {
+    extract_token(&request.headers).is_none() && session_token(request).is_some()
+}
+
+pub fn requires_csrf(method: &Method) -> bool {
+    !matches!(method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Double submit check, the header has to match the CSRF cookie
+pub fn csrf_valid(request: &Request) -> bool {
+    match (
+        request.headers.get(CSRF_HEADER),
+        get(request, &CONFIG.csrf_cookie_name),
+    ) {
+        (Some(header), Some(cookie)) => constant_time_eq(header, &cookie),
+        _ => false,
+    }
+}
+
+/// Adds the session and CSRF cookies for `token` to `status_line`. Returns
+/// the new status line and the CSRF token the client has to echo.
+pub fn attach_session(status_line: &str, token: &str) -> (String, String) {
+    let csrf_token = random_token(32);
+    let max_age = (token_expiry(&ClaimType::Login) - Utc::now()).num_seconds();
+    let status_line = with_headers(
+        status_line,
+        &[
+            set_cookie(&CONFIG.cookie_name, token, max_age, true),
+            set_cookie(&CONFIG.csrf_cookie_name, &csrf_token, max_age, false),
+        ],
+    );
+    (status_line, csrf_token)
+}
+
+/// Expires both cookies on the client
+pub fn clear_session(status_line: &str) -> String {
+    with_headers(
+        status_line,
+        &[
+            set_cookie(&CONFIG.cookie_name, "", 0, true),
+            set_cookie(&CONFIG.csrf_cookie_name, "", 0, false),
+        ],
+    )
+}
+
+/// `Set-Cookie` header tying the sign-in of `state` to this browser. The
+/// page posting the callback has to send credentials so it comes back.
+pub fn bind_upstream_state(state: &str, max_age: i64) -> String {
+    set_cookie(UPSTREAM_STATE_COOKIE, &sha256_hex(state), max_age, true)
+}
+
+/// Whether the browser sending the callback is the one that started the
+/// sign-in of `state`
+pub fn upstream_state_bound(request: &Request, state: &str) -> bool {
+    get(request, UPSTREAM_STATE_COOKIE)
+        .is_some_and(|cookie| constant_time_eq(&cookie, &sha256_hex(state)))
+}
+
+/// Expires the state cookie once the callback has used it
+pub fn clear_upstream_state(status_line: &str) -> String {
+    with_headers(
+        status_line,
+        &[set_cookie(UPSTREAM_STATE_COOKIE, "", 0, true)],
+    )
+}
+
+fn set_cookie(name: &str, value: &str, max_age: i64, http_only: bool) -> String {
+    let mut cookie = format!(
+        "Set-Cookie: {}={}; Path=/; Max-Age={}; SameSite={}",
+        name, value, max_age, CONFIG.cookie_same_site
+    );
+    if !CONFIG.cookie_domain.is_empty() {
+        cookie.push_str(&format!("; Domain={}", CONFIG.cookie_domain));
+    }
+    if CONFIG.cookie_secure {
+        cookie.push_str("; Secure");
+    }
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    cookie
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::load_test_env;
+
+    fn callback(cookie: Option<&str>) -> Request {
+        let cookie = cookie
+            .map(|cookie| format!("Cookie: {}\r\n", cookie))
+            .unwrap_or_default();
+        Request::new(&format!(
+            "POST /github/callback HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            cookie
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn bound_state_needs_the_cookie_of_the_starting_browser() {
+        load_test_env();
+        let header = bind_upstream_state("state-a", 600);
+        assert!(header.contains("HttpOnly"));
+        assert!(!header.contains("state-a"));
+        let value = header
+            .strip_prefix("Set-Cookie: ")
+            .and_then(|header| header.split(';').next())
+            .unwrap();
+
+        let request = callback(Some(&format!("other=1; {}", value)));
+        assert!(upstream_state_bound(&request, "state-a"));
+        assert!(!upstream_state_bound(&request, "state-b"));
+        assert!(!upstream_state_bound(&callback(None), "state-a"));
+        assert!(!upstream_state_bound(
+            &callback(Some(&format!("{}=", UPSTREAM_STATE_COOKIE))),
+            "state-a"
+        ));
+    }
+
+    #[test]
+    fn cleared_state_cookie_expires() {
+        load_test_env();
+        let status_line = clear_upstream_state("HTTP/1.1 200 OK\r\n\r\n");
+        assert!(status_line.contains(&format!("Set-Cookie: {}=;", UPSTREAM_STATE_COOKIE)));
+        assert!(status_line.contains("Max-Age=0"));
+        assert!(status_line.ends_with("\r\n\r\n"));
+    }
+}
@@ -1,9 +1,26 @@
-use crate::auth::model::User;
+use crate::apikey::model::{ApiKey, ApiKeyOwner};
+use crate::audit::model::{AuditEvent, AuditFilter};
+use crate::auth::model::{UpstreamState, User};
+use crate::constants::LOCAL;
+use crate::grant::model::ResourceGrant;
+use crate::guest::model::{Guest, RoomCode};
+use crate::identity::model::UserIdentity;
+use crate::invitation::model::Invitation;
+use crate::oidc::model::{
+    AuthorizationCode, DeviceAuthorization, DevicePoll, OAuthClient, RevokedToken, UserProfile,
+};
+use crate::org::model::Organization;
 use crate::permission::model::Permission;
+use crate::policy::model::SubjectAttributes;
 use crate::role::model::Role;
-use crate::rolepermissions::model::GetRolePermissions;
-use crate::user::model::GetUsers;
+use crate::rolepermissions::model::{
+    ExpiredRolePermission, GetRolePermissions, RolePermissionGrant,
+};
+use crate::scim::model::{ScimClient, ScimFilter, ScimMember, ScimRole, ScimToken};
+use crate::session::model::Session;
+use crate::user::model::{GetUsers, UserRole, UserRoleGrant};
 use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
 use sqlx::Pool;
 use sqlx::postgres::PgPoolOptions;
 
@@ -25,50 +42,385 @@ impl Database {
 
 #[async_trait]
 pub trait DBConn: Send + Sync + Clone {
-    async fn fetch_user(&self, username: &str) -> Result<User, sqlx::Error>;
-    async fn insert_user(&self, user: &User) -> Result<i32, sqlx::Error>;
-    async fn insert_role(&self, role: &Role) -> Result<i32, sqlx::Error>;
-    async fn fetch_roles(&self) -> Result<Vec<Role>, sqlx::Error>;
+    async fn fetch_user(&self, username: &str, org_id: Option<i32>) -> Result<User, sqlx::Error>;
+    async fn insert_user(
+        &self,
+        user: &User,
+        identity: Option<&UserIdentity>,
+    ) -> Result<i32, sqlx::Error>;
+    async fn fetch_identity_user(
+        &self,
+        provider: &str,
+        provider_id: &str,
+        org_id: Option<i32>,
+    ) -> Result<User, sqlx::Error>;
+    async fn insert_user_identity(&self, identity: &UserIdentity) -> Result<i32, sqlx::Error>;
+    async fn fetch_user_identities(&self, user_id: i32) -> Result<Vec<UserIdentity>, sqlx::Error>;
+    async fn delete_user_identity(&self, user_id: i32, provider: &str) -> Result<i32, sqlx::Error>;
+    async fn insert_upstream_state(&self, state: &UpstreamState) -> Result<(), sqlx::Error>;
+    async fn consume_upstream_state(
+        &self,
+        state_hash: &str,
+        provider: &str,
+    ) -> Result<UpstreamState, sqlx::Error>;
+    async fn insert_role(&self, role: &Role, permission_ids: &[i32]) -> Result<i32, sqlx::Error>;
+    async fn fetch_roles(&self, org_id: i32) -> Result<Vec<Role>, sqlx::Error>;
     async fn fetch_role_permissions(
         &self,
-        role_id: i32,
+        org_id: i32,
+        role_ids: &[i32],
     ) -> Result<Vec<GetRolePermissions>, sqlx::Error>;
-    async fn fetch_permissions(&self) -> Result<Vec<Permission>, sqlx::Error>;
+    async fn fetch_permissions(&self, org_id: i32) -> Result<Vec<Permission>, sqlx::Error>;
     async fn insert_permission(&self, permission: &Permission) -> Result<i32, sqlx::Error>;
     async fn insert_permission_role(
         &self,
-        role_id: i32,
-        permission_ids: Vec<i32>,
+        org_id: i32,
+        grant: &RolePermissionGrant,
     ) -> Result<(), sqlx::Error>;
     async fn update_password(&self, user_id: &str, password: &str) -> Result<i32, sqlx::Error>;
     fn print_pool_stats(&self);
-    async fn fetch_users(&self) -> Result<Vec<GetUsers>, sqlx::Error>;
+    async fn fetch_users(&self, org_id: i32) -> Result<Vec<GetUsers>, sqlx::Error>;
+    async fn insert_user_role(&self, org_id: i32, user_role: &UserRole) -> Result<(), sqlx::Error>;
+    async fn delete_user_role(
+        &self,
+        org_id: i32,
+        user_id: i32,
+        role_id: i32,
+    ) -> Result<i32, sqlx::Error>;
+    async fn sync_user_roles(
+        &self,
+        org_id: i32,
+        user_id: i32,
+        managed_role_ids: &[i32],
+        role_ids: &[i32],
+    ) -> Result<(), sqlx::Error>;
+    async fn fetch_perm_version(&self) -> Result<i64, sqlx::Error>;
+    async fn insert_role_parent(
+        &self,
+        org_id: i32,
+        role_id: i32,
+        parent_role_id: i32,
+    ) -> Result<bool, sqlx::Error>;
+    async fn delete_role_parent(
+        &self,
+        org_id: i32,
+        role_id: i32,
+        parent_role_id: i32,
+    ) -> Result<i32, sqlx::Error>;
+    async fn insert_org(
+        &self,
+        org: &Organization,
+        owner_user_id: i32,
+        owner_role_ids: &[i32],
+    ) -> Result<i32, sqlx::Error>;
+    async fn fetch_user_orgs(&self, user_id: i32) -> Result<Vec<Organization>, sqlx::Error>;
+    async fn fetch_user_role_ids(&self, org_id: i32, user_id: i32)
+    -> Result<Vec<i32>, sqlx::Error>;
+    async fn fetch_resource_grants(
+        &self,
+        org_id: i32,
+        resource_type: &str,
+        resource_id: &str,
+    ) -> Result<Vec<ResourceGrant>, sqlx::Error>;
+    async fn fetch_user_grants(
+        &self,
+        org_id: i32,
+        user_id: i32,
+    ) -> Result<Vec<ResourceGrant>, sqlx::Error>;
+    async fn insert_resource_grant(&self, grant: &ResourceGrant) -> Result<i32, sqlx::Error>;
+    async fn delete_resource_grant(&self, grant: &ResourceGrant) -> Result<i32, sqlx::Error>;
+    async fn fetch_policy_rules(&self) -> Result<Vec<String>, sqlx::Error>;
+    async fn fetch_subject_attributes(
+        &self,
+        org_id: i32,
+        user_id: i32,
+    ) -> Result<SubjectAttributes, sqlx::Error>;
+    async fn fetch_user_role_grants(
+        &self,
+        org_id: i32,
+        user_id: Option<i32>,
+    ) -> Result<Vec<UserRoleGrant>, sqlx::Error>;
+    async fn delete_expired_user_roles(&self) -> Result<Vec<UserRoleGrant>, sqlx::Error>;
+    async fn delete_expired_role_permissions(
+        &self,
+    ) -> Result<Vec<ExpiredRolePermission>, sqlx::Error>;
+    async fn insert_audit_event(&self, event: &AuditEvent) -> Result<i64, sqlx::Error>;
+    async fn fetch_audit_events(
+        &self,
+        filter: &AuditFilter,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error>;
+    async fn fetch_audit_chain(
+        &self,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error>;
+    async fn insert_session(&self, session: &Session) -> Result<i64, sqlx::Error>;
+    async fn touch_session(&self, session_id: i64, user_id: i32) -> Result<i64, sqlx::Error>;
+    async fn extend_session(
+        &self,
+        session_id: i64,
+        org_id: Option<i32>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<i64, sqlx::Error>;
+    async fn fetch_user_sessions(&self, user_id: i32) -> Result<Vec<Session>, sqlx::Error>;
+    async fn revoke_session(&self, user_id: i32, session_id: i64) -> Result<i64, sqlx::Error>;
+    async fn revoke_other_sessions(
+        &self,
+        user_id: i32,
+        keep_session_id: Option<i64>,
+    ) -> Result<u64, sqlx::Error>;
+    async fn insert_oauth_client(&self, client: &OAuthClient) -> Result<(), sqlx::Error>;
+    async fn fetch_oauth_clients(&self, org_id: i32) -> Result<Vec<OAuthClient>, sqlx::Error>;
+    async fn fetch_oauth_client(&self, client_id: &str) -> Result<OAuthClient, sqlx::Error>;
+    async fn insert_authorization_code(&self, code: &AuthorizationCode) -> Result<(), sqlx::Error>;
+    async fn consume_authorization_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<AuthorizationCode, sqlx::Error>;
+    async fn fetch_user_profile(&self, user_id: i32) -> Result<UserProfile, sqlx::Error>;
+    async fn insert_revoked_token(&self, token: &RevokedToken) -> Result<(), sqlx::Error>;
+    async fn is_token_revoked(&self, token_hash: &str) -> Result<bool, sqlx::Error>;
+    async fn insert_device_code(&self, device: &DeviceAuthorization) -> Result<(), sqlx::Error>;
+    async fn fetch_pending_device_code(
+        &self,
+        user_code: &str,
+    ) -> Result<DeviceAuthorization, sqlx::Error>;
+    async fn decide_device_code(
+        &self,
+        user_code: &str,
+        org_id: i32,
+        user_id: i32,
+        status: &str,
+    ) -> Result<DeviceAuthorization, sqlx::Error>;
+    async fn poll_device_code(
+        &self,
+        device_code_hash: &str,
+        client_id: &str,
+    ) -> Result<DevicePoll, sqlx::Error>;
+    async fn insert_api_key(&self, api_key: &ApiKey) -> Result<i64, sqlx::Error>;
+    async fn fetch_user_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, sqlx::Error>;
+    async fn revoke_api_key(&self, user_id: i32, api_key_id: i64) -> Result<i64, sqlx::Error>;
+    async fn use_api_key(&self, key_hash: &str) -> Result<ApiKeyOwner, sqlx::Error>;
+    async fn insert_scim_token(&self, scim_token: &ScimToken) -> Result<i64, sqlx::Error>;
+    async fn fetch_scim_tokens(&self, org_id: i32) -> Result<Vec<ScimToken>, sqlx::Error>;
+    async fn revoke_scim_token(&self, org_id: i32, scim_token_id: i64) -> Result<i64, sqlx::Error>;
+    async fn use_scim_token(&self, token_hash: &str) -> Result<ScimClient, sqlx::Error>;
+    async fn fetch_scim_members(
+        &self,
+        org_id: i32,
+        filter: &ScimFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(i64, Vec<ScimMember>), sqlx::Error>;
+    async fn fetch_scim_member(&self, org_id: i32, user_id: i32)
+    -> Result<ScimMember, sqlx::Error>;
+    async fn insert_scim_member(
+        &self,
+        org_id: i32,
+        member: &ScimMember,
+    ) -> Result<i32, sqlx::Error>;
+    async fn update_scim_member(&self, org_id: i32, member: &ScimMember)
+    -> Result<(), sqlx::Error>;
+    async fn delete_scim_member(&self, org_id: i32, user_id: i32) -> Result<i32, sqlx::Error>;
+    async fn fetch_scim_roles(
+        &self,
+        org_id: i32,
+        filter: &ScimFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(i64, Vec<ScimRole>), sqlx::Error>;
+    async fn fetch_scim_role(&self, org_id: i32, role_id: i32) -> Result<ScimRole, sqlx::Error>;
+    async fn rename_role(&self, org_id: i32, role_id: i32, name: &str) -> Result<i32, sqlx::Error>;
+    async fn delete_role(&self, org_id: i32, role_id: i32) -> Result<i32, sqlx::Error>;
+    async fn set_role_members(
+        &self,
+        org_id: i32,
+        role_id: i32,
+        user_ids: &[i32],
+    ) -> Result<(), sqlx::Error>;
+    async fn insert_invitation(&self, invitation: &Invitation) -> Result<i64, sqlx::Error>;
+    async fn fetch_invitations(&self, org_id: i32) -> Result<Vec<Invitation>, sqlx::Error>;
+    async fn revoke_invitation(&self, org_id: i32, invitation_id: i64) -> Result<i64, sqlx::Error>;
+    async fn claim_invitation(&self, token_hash: &str) -> Result<Invitation, sqlx::Error>;
+    async fn release_invitation(&self, invitation_id: i64) -> Result<(), sqlx::Error>;
+    async fn insert_room_code(&self, room_code: &RoomCode) -> Result<i64, sqlx::Error>;
+    async fn fetch_room_code(
+        &self,
+        org_id: i32,
+        room_code_id: i64,
+    ) -> Result<RoomCode, sqlx::Error>;
+    async fn revoke_room_code(&self, org_id: i32, room_code_id: i64) -> Result<i64, sqlx::Error>;
+    async fn insert_guest(&self, code: &str, guest: &Guest) -> Result<Guest, sqlx::Error>;
+    async fn upgrade_guest(
+        &self,
+        org_id: i32,
+        guest_id: i64,
+        user_id: i32,
+    ) -> Result<i64, sqlx::Error>;
 }
 
 #[async_trait]
 impl DBConn for sqlx::PgPool {
-    async fn fetch_user(&self, username: &str) -> Result<User, sqlx::Error> {
+    /// Loads the user together with their roles in `org_id`, or in their
+    /// oldest membership when no organization is given. `org_id` on the
+    /// returned user is None when they are not an active member.
+    async fn fetch_user(&self, username: &str, org_id: Option<i32>) -> Result<User, sqlx::Error> {
         sqlx::query_as::<_, User>(
-            r#"SELECT user_id, username, password, email, provider, provider_id, role_id, created_at FROM users WHERE username = $1"#,
+            r#"SELECT u.user_id, u.username, u.password, u.email, m.org_id,
+            COALESCE(ARRAY_AGG(ur.role_id) FILTER (WHERE ur.role_id IS NOT NULL), '{}') AS role_ids,
+            u.created_at
+            FROM users u
+            LEFT JOIN LATERAL (
+                SELECT om.org_id
+                FROM org_members om
+                WHERE om.user_id = u.user_id AND om.active
+                AND ($2::int IS NULL OR om.org_id = $2)
+                ORDER BY om.created_at, om.org_id
+                LIMIT 1
+            ) m ON TRUE
+            LEFT JOIN user_roles ur ON u.user_id = ur.user_id AND ur.org_id = m.org_id
+                AND (ur.valid_from IS NULL OR ur.valid_from <= NOW())
+                AND (ur.valid_until IS NULL OR ur.valid_until > NOW())
+            WHERE u.username = $1
+            GROUP BY u.user_id, m.org_id"#,
         )
         .bind(username)
+        .bind(org_id)
         .fetch_one(self)
         .await
     }
-    async fn insert_user(&self, user: &User) -> Result<i32, sqlx::Error> {
+
+    async fn insert_user(
+        &self,
+        user: &User,
+        identity: Option<&UserIdentity>,
+    ) -> Result<i32, sqlx::Error> {
+        let mut tx = self.begin().await?;
         let row: (i32,) = sqlx::query_as(
             r#"
-            INSERT INTO users (username, password, email, provider, provider_id, role_id, created_at) 
-            VALUES ($1, $2, $3, $4, $5, $6, $7) 
+            INSERT INTO users (username, password, email, created_at) 
+            VALUES ($1, $2, $3, $4) 
             RETURNING user_id"#,
         )
         .bind(&user.username)
         .bind(&user.password)
         .bind(&user.email)
-        .bind(&user.provider)
-        .bind(&user.provider_id)
-        .bind(&user.role_id)
-        .bind(&user.created_at)
+        .bind(user.created_at)
+        .fetch_one(&mut *tx)
+        .await?;
+        if let Some(identity) = identity {
+            sqlx::query(
+                r#"
+                INSERT INTO user_identities (user_id, provider, provider_id, email, created_at)
+                VALUES ($1, $2, $3, $4, $5)"#,
+            )
+            .bind(row.0)
+            .bind(&identity.provider)
+            .bind(&identity.provider_id)
+            .bind(&identity.email)
+            .bind(identity.created_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+        if let Some(org_id) = user.org_id {
+            sqlx::query(
+                r#"
+                INSERT INTO org_members (org_id, user_id)
+                VALUES ($1, $2)
+                "#,
+            )
+            .bind(org_id)
+            .bind(row.0)
+            .execute(&mut *tx)
+            .await?;
+            let granted = sqlx::query(
+                r#"
+                INSERT INTO user_roles (user_id, org_id, role_id)
+                SELECT $1, $2, r.role_id
+                FROM roles r
+                WHERE r.role_id = ANY($3::int[]) AND (r.org_id IS NULL OR r.org_id = $2)
+                "#,
+            )
+            .bind(row.0)
+            .bind(org_id)
+            .bind(&user.role_ids)
+            .execute(&mut *tx)
+            .await?;
+            // a role from another organization must not be granted silently
+            if granted.rows_affected() as usize != user.role_ids.len() {
+                return Err(sqlx::Error::RowNotFound);
+            }
+        }
+        tx.commit().await?;
+        Ok(row.0)
+    }
+
+    /// Like `fetch_user`, for the user a provider account is linked to
+    async fn fetch_identity_user(
+        &self,
+        provider: &str,
+        provider_id: &str,
+        org_id: Option<i32>,
+    ) -> Result<User, sqlx::Error> {
+        let row: (String,) = sqlx::query_as(
+            r#"
+            SELECT u.username FROM user_identities ui
+            JOIN users u ON u.user_id = ui.user_id
+            WHERE ui.provider = $1 AND ui.provider_id = $2"#,
+        )
+        .bind(provider)
+        .bind(provider_id)
+        .fetch_one(self)
+        .await?;
+        self.fetch_user(&row.0, org_id).await
+    }
+
+    async fn insert_user_identity(&self, identity: &UserIdentity) -> Result<i32, sqlx::Error> {
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            INSERT INTO user_identities (user_id, provider, provider_id, email, created_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING identity_id"#,
+        )
+        .bind(identity.user_id)
+        .bind(&identity.provider)
+        .bind(&identity.provider_id)
+        .bind(&identity.email)
+        .bind(identity.created_at)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn fetch_user_identities(&self, user_id: i32) -> Result<Vec<UserIdentity>, sqlx::Error> {
+        sqlx::query_as::<_, UserIdentity>(
+            r#"
+            SELECT identity_id, user_id, provider, provider_id, email, created_at
+            FROM user_identities WHERE user_id = $1
+            ORDER BY created_at, identity_id"#,
+        )
+        .bind(user_id)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn delete_user_identity(&self, user_id: i32, provider: &str) -> Result<i32, sqlx::Error> {
+        // the user must keep a way to sign in, a password or another identity
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            DELETE FROM user_identities ui
+            WHERE ui.user_id = $1 AND ui.provider = $2
+            AND (
+                EXISTS (SELECT 1 FROM users u WHERE u.user_id = $1 AND u.password IS NOT NULL)
+                OR EXISTS (
+                    SELECT 1 FROM user_identities other
+                    WHERE other.user_id = $1 AND other.provider <> $2
+                )
+            )
+            RETURNING ui.identity_id"#,
+        )
+        .bind(user_id)
+        .bind(provider)
         .fetch_one(self)
         .await?;
         Ok(row.0)
@@ -86,24 +438,43 @@ impl DBConn for sqlx::PgPool {
 
     async fn fetch_role_permissions(
         &self,
-        role_id: i32,
+        org_id: i32,
+        role_ids: &[i32],
     ) -> Result<Vec<GetRolePermissions>, sqlx::Error> {
         sqlx::query_as::<_, GetRolePermissions>(
-            r#"SELECT p.name
-            FROM permissions p
-            JOIN role_permissions rp ON p.permission_id = rp.permission_id
-            WHERE rp.role_id = $1"#,
+            r#"WITH RECURSIVE effective_roles (role_id, depth) AS (
+                SELECT r.role_id, 0
+                FROM roles r
+                WHERE r.role_id = ANY($1::int[]) AND (r.org_id IS NULL OR r.org_id = $2)
+                UNION
+                SELECT rp.parent_role_id, er.depth + 1
+                FROM role_parents rp
+                JOIN effective_roles er ON rp.role_id = er.role_id
+                WHERE er.depth < 32
+            )
+            SELECT p.permission_id, p.name, BOOL_AND(er.depth > 0) AS inherited,
+            CASE WHEN BOOL_OR(rp.valid_until IS NULL) THEN NULL
+                ELSE MAX(rp.valid_until) END AS valid_until
+            FROM effective_roles er
+            JOIN role_permissions rp ON er.role_id = rp.role_id
+                AND (rp.valid_from IS NULL OR rp.valid_from <= NOW())
+                AND (rp.valid_until IS NULL OR rp.valid_until > NOW())
+            JOIN permissions p ON p.permission_id = rp.permission_id
+            GROUP BY p.permission_id, p.name"#,
         )
-        .bind(role_id)
+        .bind(role_ids)
+        .bind(org_id)
         .fetch_all(self)
         .await
     }
 
-    async fn fetch_permissions(&self) -> Result<Vec<Permission>, sqlx::Error> {
+    async fn fetch_permissions(&self, org_id: i32) -> Result<Vec<Permission>, sqlx::Error> {
         sqlx::query_as::<_, Permission>(
-            r#"SELECT permission_id, name, description, created_at
-            FROM permissions"#,
+            r#"SELECT permission_id, org_id, name, description, created_at
+            FROM permissions
+            WHERE org_id IS NULL OR org_id = $1"#,
         )
+        .bind(org_id)
         .fetch_all(self)
         .await
     }
@@ -111,55 +482,152 @@ impl DBConn for sqlx::PgPool {
     async fn insert_permission(&self, permission: &Permission) -> Result<i32, sqlx::Error> {
         let row: (i32,) = sqlx::query_as(
             r#"
-            INSERT INTO permissions (name, description, created_at) 
-            VALUES ($1, $2, $3) 
+            INSERT INTO permissions (org_id, name, description, created_at) 
+            VALUES ($1, $2, $3, $4) 
             RETURNING permission_id"#,
         )
+        .bind(permission.org_id)
         .bind(&permission.name)
         .bind(&permission.description)
-        .bind(&permission.created_at)
+        .bind(permission.created_at)
         .fetch_one(self)
         .await?;
         Ok(row.0)
     }
 
-    async fn insert_role(&self, role: &Role) -> Result<i32, sqlx::Error> {
+    async fn insert_upstream_state(&self, state: &UpstreamState) -> Result<(), sqlx::Error> {
+        let mut tx = self.begin().await?;
+        // abandoned sign-ins are dropped whenever a new one starts
+        sqlx::query("DELETE FROM upstream_auth_states WHERE expires_at < NOW()")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO upstream_auth_states (state_hash, provider, code_verifier, org_id,
+            role_id, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+        )
+        .bind(&state.state_hash)
+        .bind(&state.provider)
+        .bind(&state.code_verifier)
+        .bind(state.org_id)
+        .bind(state.role_id)
+        .bind(state.created_at)
+        .bind(state.expires_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn consume_upstream_state(
+        &self,
+        state_hash: &str,
+        provider: &str,
+    ) -> Result<UpstreamState, sqlx::Error> {
+        sqlx::query_as::<_, UpstreamState>(
+            r#"
+            DELETE FROM upstream_auth_states
+            WHERE state_hash = $1 AND provider = $2 AND expires_at > NOW()
+            RETURNING state_hash, provider, code_verifier, org_id, role_id, created_at,
+            expires_at"#,
+        )
+        .bind(state_hash)
+        .bind(provider)
+        .fetch_one(self)
+        .await
+    }
+
+    /// Creates the role with its parents and permissions in one transaction.
+    /// Parents follow the rules of `insert_role_parent`, a parent outside
+    /// them fails the whole insert with RowNotFound.
+    async fn insert_role(&self, role: &Role, permission_ids: &[i32]) -> Result<i32, sqlx::Error> {
+        let mut tx = self.begin().await?;
         let row: (i32,) = sqlx::query_as(
             r#"
-            INSERT INTO roles (name, description, created_at) 
-            VALUES ($1, $2, $3) 
+            INSERT INTO roles (org_id, name, description, created_at) 
+            VALUES ($1, $2, $3, $4) 
             RETURNING role_id"#,
         )
+        .bind(role.org_id)
         .bind(&role.name)
         .bind(&role.description)
-        .bind(&role.created_at)
-        .fetch_one(self)
+        .bind(role.created_at)
+        .fetch_one(&mut *tx)
+        .await?;
+        if !role.parent_ids.is_empty() {
+            let linked = sqlx::query(
+                r#"
+                INSERT INTO role_parents (role_id, parent_role_id)
+                SELECT $1, p.role_id
+                FROM roles p
+                WHERE p.role_id = ANY($2::int[]) AND (p.org_id IS NULL OR p.org_id = $3)
+                "#,
+            )
+            .bind(row.0)
+            .bind(&role.parent_ids)
+            .bind(role.org_id)
+            .execute(&mut *tx)
+            .await?;
+            if linked.rows_affected() as usize != role.parent_ids.len() {
+                return Err(sqlx::Error::RowNotFound);
+            }
+        }
+        let granted = sqlx::query(
+            r#"
+            INSERT INTO role_permissions (role_id, permission_id)
+            SELECT $1, p.permission_id
+            FROM permissions p
+            WHERE p.permission_id = ANY($2::int[]) AND (p.org_id IS NULL OR p.org_id = $3)
+            "#,
+        )
+        .bind(row.0)
+        .bind(permission_ids)
+        .bind(role.org_id)
+        .execute(&mut *tx)
         .await?;
+        if granted.rows_affected() as usize != permission_ids.len() {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        tx.commit().await?;
         Ok(row.0)
     }
 
-    async fn fetch_roles(&self) -> Result<Vec<Role>, sqlx::Error> {
+    async fn fetch_roles(&self, org_id: i32) -> Result<Vec<Role>, sqlx::Error> {
         sqlx::query_as::<_, Role>(
-            r#"SELECT role_id, name, description, created_at
-            FROM roles"#,
+            r#"SELECT r.role_id, r.org_id, r.name, r.description, r.created_at,
+            COALESCE(ARRAY_AGG(rp.parent_role_id) FILTER (WHERE rp.parent_role_id IS NOT NULL), '{}') AS parent_ids
+            FROM roles r
+            LEFT JOIN role_parents rp ON r.role_id = rp.role_id
+            WHERE r.org_id IS NULL OR r.org_id = $1
+            GROUP BY r.role_id"#,
         )
+        .bind(org_id)
         .fetch_all(self)
         .await
     }
 
+    /// Only the organization's own roles can be edited and only permissions
+    /// visible to it are attached, anything else is skipped
     async fn insert_permission_role(
         &self,
-        role_id: i32,
-        permission_ids: Vec<i32>,
+        org_id: i32,
+        grant: &RolePermissionGrant,
     ) -> Result<(), sqlx::Error> {
         sqlx::query(
             r#"
-            INSERT INTO role_permissions (role_id, permission_id)
-            SELECT $1, UNNEST($2::int[])
+            INSERT INTO role_permissions (role_id, permission_id, valid_from, valid_until)
+            SELECT r.role_id, p.permission_id, $4, $5
+            FROM roles r
+            JOIN permissions p ON p.permission_id = ANY($2::int[])
+            WHERE r.role_id = $1 AND r.org_id = $3 AND (p.org_id IS NULL OR p.org_id = $3)
             "#,
         )
-        .bind(role_id)
-        .bind(&permission_ids)
+        .bind(grant.role_id)
+        .bind(&grant.permission_ids)
+        .bind(org_id)
+        .bind(grant.valid_from)
+        .bind(grant.valid_until)
         .execute(self)
         .await?;
         Ok(())
@@ -180,12 +648,1552 @@ impl DBConn for sqlx::PgPool {
         Ok(row.0)
     }
 
-    async fn fetch_users(&self) -> Result<Vec<GetUsers>, sqlx::Error> {
+    async fn fetch_users(&self, org_id: i32) -> Result<Vec<GetUsers>, sqlx::Error> {
         sqlx::query_as::<_, GetUsers>(
-            r#"SELECT user_id, username, email, provider, role_id, created_at
-            FROM users"#,
+            r#"SELECT u.user_id, u.username, u.email,
+            ARRAY(
+                SELECT $2::text WHERE u.password IS NOT NULL
+                UNION ALL
+                SELECT ui.provider FROM user_identities ui WHERE ui.user_id = u.user_id
+            ) AS providers,
+            COALESCE(ARRAY_AGG(ur.role_id) FILTER (WHERE ur.role_id IS NOT NULL), '{}') AS role_ids,
+            u.created_at
+            FROM org_members om
+            JOIN users u ON u.user_id = om.user_id
+            LEFT JOIN user_roles ur ON om.user_id = ur.user_id AND om.org_id = ur.org_id
+                AND (ur.valid_from IS NULL OR ur.valid_from <= NOW())
+                AND (ur.valid_until IS NULL OR ur.valid_until > NOW())
+            WHERE om.org_id = $1
+            GROUP BY u.user_id"#,
+        )
+        .bind(org_id)
+        .bind(LOCAL)
+        .fetch_all(self)
+        .await
+    }
+
+    /// Grants a role in the organization, adding the user as a member first
+    /// when needed. Fails with RowNotFound when the role is not visible to
+    /// the organization.
+    async fn insert_user_role(&self, org_id: i32, user_role: &UserRole) -> Result<(), sqlx::Error> {
+        let mut tx = self.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO org_members (org_id, user_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(org_id)
+        .bind(user_role.user_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO user_roles (user_id, org_id, role_id, valid_from, valid_until)
+            SELECT $1, $2, r.role_id, $4, $5
+            FROM roles r
+            WHERE r.role_id = $3 AND (r.org_id IS NULL OR r.org_id = $2)
+            RETURNING role_id"#,
+        )
+        .bind(user_role.user_id)
+        .bind(org_id)
+        .bind(user_role.role_id)
+        .bind(user_role.valid_from)
+        .bind(user_role.valid_until)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_user_role(
+        &self,
+        org_id: i32,
+        user_id: i32,
+        role_id: i32,
+    ) -> Result<i32, sqlx::Error> {
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            DELETE FROM user_roles
+            WHERE org_id = $1 AND user_id = $2 AND role_id = $3
+            RETURNING user_id"#,
+        )
+        .bind(org_id)
+        .bind(user_id)
+        .bind(role_id)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    /// Makes the user's roles among `managed_role_ids` exactly `role_ids`,
+    /// roles granted by other means stay
+    async fn sync_user_roles(
+        &self,
+        org_id: i32,
+        user_id: i32,
+        managed_role_ids: &[i32],
+        role_ids: &[i32],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO org_members (org_id, user_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(org_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM user_roles
+            WHERE org_id = $1 AND user_id = $2
+              AND role_id = ANY($3::int[]) AND NOT role_id = ANY($4::int[])
+            "#,
+        )
+        .bind(org_id)
+        .bind(user_id)
+        .bind(managed_role_ids)
+        .bind(role_ids)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO user_roles (user_id, org_id, role_id)
+            SELECT $2, $1, r.role_id
+            FROM roles r
+            WHERE r.role_id = ANY($3::int[]) AND (r.org_id IS NULL OR r.org_id = $1)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(org_id)
+        .bind(user_id)
+        .bind(role_ids)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// The child role must belong to the organization, the parent may also be
+    /// a shared role. Fails with RowNotFound otherwise. The organization's
+    /// roles stay locked from the cycle check to the insert so two edits
+    /// cannot close a cycle between them, returns false without inserting
+    /// when the edge would make a role its own ancestor.
+    async fn insert_role_parent(
+        &self,
+        org_id: i32,
+        role_id: i32,
+        parent_role_id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        sqlx::query("SELECT role_id FROM roles WHERE org_id = $1 ORDER BY role_id FOR UPDATE")
+            .bind(org_id)
+            .execute(&mut *tx)
+            .await?;
+        let cycle: (bool,) = sqlx::query_as(
+            r#"WITH RECURSIVE ancestors (role_id) AS (
+                SELECT $1::int
+                UNION
+                SELECT rp.parent_role_id
+                FROM role_parents rp
+                JOIN ancestors a ON rp.role_id = a.role_id
+            )
+            SELECT EXISTS (SELECT 1 FROM ancestors WHERE role_id = $2)"#,
+        )
+        .bind(parent_role_id)
+        .bind(role_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        if cycle.0 {
+            return Ok(false);
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO role_parents (role_id, parent_role_id)
+            SELECT r.role_id, p.role_id
+            FROM roles r
+            JOIN roles p ON p.role_id = $3
+            WHERE r.role_id = $2 AND r.org_id = $1 AND (p.org_id IS NULL OR p.org_id = $1)
+            RETURNING role_id"#,
+        )
+        .bind(org_id)
+        .bind(role_id)
+        .bind(parent_role_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn delete_role_parent(
+        &self,
+        org_id: i32,
+        role_id: i32,
+        parent_role_id: i32,
+    ) -> Result<i32, sqlx::Error> {
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            DELETE FROM role_parents rp
+            USING roles r
+            WHERE rp.role_id = r.role_id AND r.org_id = $1
+            AND rp.role_id = $2 AND rp.parent_role_id = $3
+            RETURNING rp.role_id"#,
+        )
+        .bind(org_id)
+        .bind(role_id)
+        .bind(parent_role_id)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn fetch_perm_version(&self) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(r#"SELECT version FROM permission_version"#)
+            .fetch_one(self)
+            .await?;
+        Ok(row.0)
+    }
+
+    /// Creates the organization with its creator as the first member, holding
+    /// the shared roles among `owner_role_ids`
+    async fn insert_org(
+        &self,
+        org: &Organization,
+        owner_user_id: i32,
+        owner_role_ids: &[i32],
+    ) -> Result<i32, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            INSERT INTO organizations (name, created_at)
+            VALUES ($1, $2)
+            RETURNING org_id"#,
+        )
+        .bind(&org.name)
+        .bind(org.created_at)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO org_members (org_id, user_id)
+            VALUES ($1, $2)
+            "#,
+        )
+        .bind(row.0)
+        .bind(owner_user_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO user_roles (user_id, org_id, role_id)
+            SELECT $1, $2, r.role_id
+            FROM roles r
+            WHERE r.role_id = ANY($3::int[]) AND r.org_id IS NULL
+            "#,
+        )
+        .bind(owner_user_id)
+        .bind(row.0)
+        .bind(owner_role_ids)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(row.0)
+    }
+
+    async fn fetch_user_orgs(&self, user_id: i32) -> Result<Vec<Organization>, sqlx::Error> {
+        sqlx::query_as::<_, Organization>(
+            r#"SELECT o.org_id, o.name, o.created_at
+            FROM organizations o
+            JOIN org_members om ON o.org_id = om.org_id
+            WHERE om.user_id = $1 AND om.active
+            ORDER BY om.created_at, o.org_id"#,
+        )
+        .bind(user_id)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn fetch_user_role_ids(
+        &self,
+        org_id: i32,
+        user_id: i32,
+    ) -> Result<Vec<i32>, sqlx::Error> {
+        let rows: Vec<(i32,)> = sqlx::query_as(
+            r#"SELECT role_id
+            FROM user_roles
+            WHERE org_id = $1 AND user_id = $2
+            AND (valid_from IS NULL OR valid_from <= NOW())
+            AND (valid_until IS NULL OR valid_until > NOW())"#,
+        )
+        .bind(org_id)
+        .bind(user_id)
+        .fetch_all(self)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.0).collect())
+    }
+
+    async fn fetch_resource_grants(
+        &self,
+        org_id: i32,
+        resource_type: &str,
+        resource_id: &str,
+    ) -> Result<Vec<ResourceGrant>, sqlx::Error> {
+        sqlx::query_as::<_, ResourceGrant>(
+            r#"SELECT grant_id, org_id, user_id, relation, resource_type, resource_id, granted_by, created_at
+            FROM resource_grants
+            WHERE org_id = $1 AND resource_type = $2 AND resource_id = $3"#,
+        )
+        .bind(org_id)
+        .bind(resource_type)
+        .bind(resource_id)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn fetch_user_grants(
+        &self,
+        org_id: i32,
+        user_id: i32,
+    ) -> Result<Vec<ResourceGrant>, sqlx::Error> {
+        sqlx::query_as::<_, ResourceGrant>(
+            r#"SELECT grant_id, org_id, user_id, relation, resource_type, resource_id, granted_by, created_at
+            FROM resource_grants
+            WHERE org_id = $1 AND user_id = $2"#,
+        )
+        .bind(org_id)
+        .bind(user_id)
+        .fetch_all(self)
+        .await
+    }
+
+    /// Only grants to active members of the organization, fails with
+    /// RowNotFound otherwise
+    async fn insert_resource_grant(&self, grant: &ResourceGrant) -> Result<i32, sqlx::Error> {
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            INSERT INTO resource_grants (org_id, user_id, relation, resource_type, resource_id, granted_by, created_at)
+            SELECT om.org_id, om.user_id, $3, $4, $5, $6, $7
+            FROM org_members om
+            WHERE om.org_id = $1 AND om.user_id = $2 AND om.active
+            RETURNING grant_id"#,
+        )
+        .bind(grant.org_id)
+        .bind(grant.user_id)
+        .bind(&grant.relation)
+        .bind(&grant.resource_type)
+        .bind(&grant.resource_id)
+        .bind(grant.granted_by)
+        .bind(grant.created_at)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn delete_resource_grant(&self, grant: &ResourceGrant) -> Result<i32, sqlx::Error> {
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            DELETE FROM resource_grants
+            WHERE org_id = $1 AND user_id = $2 AND relation = $3
+            AND resource_type = $4 AND resource_id = $5
+            RETURNING grant_id"#,
+        )
+        .bind(grant.org_id)
+        .bind(grant.user_id)
+        .bind(&grant.relation)
+        .bind(&grant.resource_type)
+        .bind(&grant.resource_id)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    /// Rules owned by an organization are narrowed to it by merging its id
+    /// into the subject condition
+    async fn fetch_policy_rules(&self) -> Result<Vec<String>, sqlx::Error> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            r#"SELECT (CASE WHEN org_id IS NULL THEN definition
+                ELSE definition || jsonb_build_object('subject',
+                    COALESCE(definition->'subject', '{}'::jsonb)
+                    || jsonb_build_object('org_ids', jsonb_build_array(org_id)))
+                END)::text
+            FROM policy_rules
+            WHERE enabled
+            ORDER BY rule_id"#,
+        )
+        .fetch_all(self)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.0).collect())
+    }
+
+    async fn fetch_subject_attributes(
+        &self,
+        org_id: i32,
+        user_id: i32,
+    ) -> Result<SubjectAttributes, sqlx::Error> {
+        sqlx::query_as::<_, SubjectAttributes>(
+            r#"
+            WITH RECURSIVE effective_roles (role_id, depth) AS (
+                SELECT role_id, 0 FROM user_roles
+                WHERE org_id = $1 AND user_id = $2
+                AND (valid_from IS NULL OR valid_from <= NOW())
+                AND (valid_until IS NULL OR valid_until > NOW())
+                UNION
+                SELECT rp.parent_role_id, er.depth + 1
+                FROM role_parents rp
+                JOIN effective_roles er ON rp.role_id = er.role_id
+                WHERE er.depth < 32
+            )
+            SELECT u.user_id, u.username, u.email,
+                COALESCE(u.created_at, CURRENT_TIMESTAMP) AS created_at,
+                COALESCE((SELECT ARRAY_AGG(DISTINCT r.name::text)
+                    FROM effective_roles er
+                    JOIN roles r ON r.role_id = er.role_id), '{}') AS role_names
+            FROM users u
+            WHERE u.user_id = $2"#,
+        )
+        .bind(org_id)
+        .bind(user_id)
+        .fetch_one(self)
+        .await
+    }
+
+    /// Every grant including the ones not yet started or lapsed but not swept
+    async fn fetch_user_role_grants(
+        &self,
+        org_id: i32,
+        user_id: Option<i32>,
+    ) -> Result<Vec<UserRoleGrant>, sqlx::Error> {
+        sqlx::query_as::<_, UserRoleGrant>(
+            r#"SELECT user_id, org_id, role_id, valid_from, valid_until
+            FROM user_roles
+            WHERE org_id = $1 AND ($2::int IS NULL OR user_id = $2)
+            ORDER BY user_id, role_id"#,
+        )
+        .bind(org_id)
+        .bind(user_id)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn delete_expired_user_roles(&self) -> Result<Vec<UserRoleGrant>, sqlx::Error> {
+        sqlx::query_as::<_, UserRoleGrant>(
+            r#"
+            DELETE FROM user_roles
+            WHERE valid_until <= NOW()
+            RETURNING user_id, org_id, role_id, valid_from, valid_until"#,
+        )
+        .fetch_all(self)
+        .await
+    }
+
+    async fn delete_expired_role_permissions(
+        &self,
+    ) -> Result<Vec<ExpiredRolePermission>, sqlx::Error> {
+        sqlx::query_as::<_, ExpiredRolePermission>(
+            r#"
+            DELETE FROM role_permissions rp
+            USING roles r
+            WHERE r.role_id = rp.role_id AND rp.valid_until <= NOW()
+            RETURNING rp.role_id, rp.permission_id, r.org_id, rp.valid_until"#,
         )
         .fetch_all(self)
         .await
     }
+
+    /// Appends under a transaction level advisory lock so concurrent writers
+    /// chain onto each other instead of the same predecessor
+    async fn insert_audit_event(&self, event: &AuditEvent) -> Result<i64, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('audit_events'))")
+            .execute(&mut *tx)
+            .await?;
+        let prev_hash: Option<(String,)> =
+            sqlx::query_as("SELECT hash FROM audit_events ORDER BY event_id DESC LIMIT 1")
+                .fetch_optional(&mut *tx)
+                .await?;
+        let prev_hash = prev_hash.map(|row| row.0).unwrap_or_default();
+        let hash = event.chain_hash(&prev_hash);
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO audit_events (org_id, actor_id, action, target, ip, user_agent, outcome, metadata, created_at, prev_hash, hash)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            RETURNING event_id"#,
+        )
+        .bind(event.org_id)
+        .bind(event.actor_id)
+        .bind(&event.action)
+        .bind(&event.target)
+        .bind(&event.ip)
+        .bind(&event.user_agent)
+        .bind(&event.outcome)
+        .bind(&event.metadata)
+        .bind(event.created_at)
+        .bind(&prev_hash)
+        .bind(&hash)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(row.0)
+    }
+
+    async fn fetch_audit_events(
+        &self,
+        filter: &AuditFilter,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error> {
+        sqlx::query_as::<_, AuditEvent>(
+            r#"SELECT event_id, org_id, actor_id, action, target, ip, user_agent, outcome,
+            metadata, created_at, prev_hash, hash
+            FROM audit_events
+            WHERE org_id = $1
+            AND ($2::int IS NULL OR actor_id = $2)
+            AND ($3::text IS NULL OR action = $3)
+            AND ($4::text IS NULL OR target = $4)
+            AND ($5::text IS NULL OR outcome = $5)
+            AND ($6::timestamptz IS NULL OR created_at >= $6)
+            AND ($7::timestamptz IS NULL OR created_at < $7)
+            AND ($8::bigint IS NULL OR event_id < $8)
+            ORDER BY event_id DESC
+            LIMIT $9"#,
+        )
+        .bind(filter.org_id)
+        .bind(filter.actor_id)
+        .bind(&filter.action)
+        .bind(&filter.target)
+        .bind(&filter.outcome)
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(filter.before_id)
+        .bind(filter.limit)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn fetch_audit_chain(
+        &self,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error> {
+        sqlx::query_as::<_, AuditEvent>(
+            r#"SELECT event_id, org_id, actor_id, action, target, ip, user_agent, outcome,
+            metadata, created_at, prev_hash, hash
+            FROM audit_events
+            WHERE event_id > $1
+            ORDER BY event_id
+            LIMIT $2"#,
+        )
+        .bind(after_id)
+        .bind(limit)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn insert_session(&self, session: &Session) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO sessions (user_id, org_id, user_agent, ip, created_at, last_seen_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING session_id"#,
+        )
+        .bind(session.user_id)
+        .bind(session.org_id)
+        .bind(&session.user_agent)
+        .bind(&session.ip)
+        .bind(session.created_at)
+        .bind(session.last_seen_at)
+        .bind(session.expires_at)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn touch_session(&self, session_id: i64, user_id: i32) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            UPDATE sessions SET last_seen_at = NOW()
+            WHERE session_id = $1 AND user_id = $2
+            AND revoked_at IS NULL AND expires_at > NOW()
+            RETURNING session_id"#,
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn extend_session(
+        &self,
+        session_id: i64,
+        org_id: Option<i32>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            UPDATE sessions SET org_id = $2, expires_at = $3, last_seen_at = NOW()
+            WHERE session_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+            RETURNING session_id"#,
+        )
+        .bind(session_id)
+        .bind(org_id)
+        .bind(expires_at)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn fetch_user_sessions(&self, user_id: i32) -> Result<Vec<Session>, sqlx::Error> {
+        sqlx::query_as::<_, Session>(
+            r#"SELECT session_id, user_id, org_id, user_agent, ip, created_at, last_seen_at,
+            expires_at, revoked_at
+            FROM sessions
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            LIMIT 100"#,
+        )
+        .bind(user_id)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn revoke_session(&self, user_id: i32, session_id: i64) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE session_id = $1 AND user_id = $2 AND revoked_at IS NULL
+            RETURNING session_id"#,
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn revoke_other_sessions(
+        &self,
+        user_id: i32,
+        keep_session_id: Option<i64>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+            AND ($2::bigint IS NULL OR session_id <> $2)"#,
+        )
+        .bind(user_id)
+        .bind(keep_session_id)
+        .execute(self)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn insert_oauth_client(&self, client: &OAuthClient) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO oauth_clients (client_id, org_id, name, secret_hash, redirect_uris,
+            allowed_scopes, grant_types, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"#,
+        )
+        .bind(&client.client_id)
+        .bind(client.org_id)
+        .bind(&client.name)
+        .bind(&client.secret_hash)
+        .bind(&client.redirect_uris)
+        .bind(&client.allowed_scopes)
+        .bind(&client.grant_types)
+        .bind(client.created_at)
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn fetch_oauth_clients(&self, org_id: i32) -> Result<Vec<OAuthClient>, sqlx::Error> {
+        sqlx::query_as::<_, OAuthClient>(
+            r#"SELECT client_id, org_id, name, secret_hash, redirect_uris, allowed_scopes,
+            grant_types, created_at
+            FROM oauth_clients
+            WHERE org_id = $1
+            ORDER BY created_at"#,
+        )
+        .bind(org_id)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn fetch_oauth_client(&self, client_id: &str) -> Result<OAuthClient, sqlx::Error> {
+        sqlx::query_as::<_, OAuthClient>(
+            r#"SELECT client_id, org_id, name, secret_hash, redirect_uris, allowed_scopes,
+            grant_types, created_at
+            FROM oauth_clients
+            WHERE client_id = $1"#,
+        )
+        .bind(client_id)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn insert_authorization_code(&self, code: &AuthorizationCode) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO oauth_codes (code_hash, client_id, user_id, org_id, redirect_uri, scope,
+            nonce, code_challenge, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)"#,
+        )
+        .bind(&code.code_hash)
+        .bind(&code.client_id)
+        .bind(code.user_id)
+        .bind(code.org_id)
+        .bind(&code.redirect_uri)
+        .bind(&code.scope)
+        .bind(&code.nonce)
+        .bind(&code.code_challenge)
+        .bind(code.created_at)
+        .bind(code.expires_at)
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn consume_authorization_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<AuthorizationCode, sqlx::Error> {
+        // marking the code used in the same statement makes it single use
+        // even under concurrent redemption
+        sqlx::query_as::<_, AuthorizationCode>(
+            r#"
+            UPDATE oauth_codes SET used_at = NOW()
+            WHERE code_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+            RETURNING code_hash, client_id, user_id, org_id, redirect_uri, scope, nonce,
+            code_challenge, created_at, expires_at"#,
+        )
+        .bind(code_hash)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn fetch_user_profile(&self, user_id: i32) -> Result<UserProfile, sqlx::Error> {
+        sqlx::query_as::<_, UserProfile>(
+            r#"SELECT user_id, username, email FROM users WHERE user_id = $1"#,
+        )
+        .bind(user_id)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn insert_revoked_token(&self, token: &RevokedToken) -> Result<(), sqlx::Error> {
+        let mut tx = self.begin().await?;
+        // entries of tokens that expired on their own are no longer needed
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < NOW()")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO revoked_tokens (token_hash, client_id, expires_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (token_hash) DO NOTHING"#,
+        )
+        .bind(&token.token_hash)
+        .bind(&token.client_id)
+        .bind(token.expires_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn is_token_revoked(&self, token_hash: &str) -> Result<bool, sqlx::Error> {
+        let row: (bool,) =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM revoked_tokens WHERE token_hash = $1)")
+                .bind(token_hash)
+                .fetch_one(self)
+                .await?;
+        Ok(row.0)
+    }
+
+    async fn insert_device_code(&self, device: &DeviceAuthorization) -> Result<(), sqlx::Error> {
+        let mut tx = self.begin().await?;
+        // expired requests free their user codes for new ones
+        sqlx::query("DELETE FROM device_codes WHERE expires_at < NOW()")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO device_codes (device_code_hash, user_code, client_id, org_id, scope,
+            status, interval_secs, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#,
+        )
+        .bind(&device.device_code_hash)
+        .bind(&device.user_code)
+        .bind(&device.client_id)
+        .bind(device.org_id)
+        .bind(&device.scope)
+        .bind(&device.status)
+        .bind(device.interval_secs)
+        .bind(device.created_at)
+        .bind(device.expires_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn fetch_pending_device_code(
+        &self,
+        user_code: &str,
+    ) -> Result<DeviceAuthorization, sqlx::Error> {
+        sqlx::query_as::<_, DeviceAuthorization>(
+            r#"
+            SELECT device_code_hash, user_code, client_id, org_id, scope, status, user_id,
+            interval_secs, last_polled_at, created_at, expires_at
+            FROM device_codes
+            WHERE user_code = $1 AND status = 'pending' AND expires_at > NOW()"#,
+        )
+        .bind(user_code)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn decide_device_code(
+        &self,
+        user_code: &str,
+        org_id: i32,
+        user_id: i32,
+        status: &str,
+    ) -> Result<DeviceAuthorization, sqlx::Error> {
+        sqlx::query_as::<_, DeviceAuthorization>(
+            r#"
+            UPDATE device_codes SET status = $4, user_id = $3
+            WHERE user_code = $1 AND org_id = $2 AND status = 'pending' AND expires_at > NOW()
+            RETURNING device_code_hash, user_code, client_id, org_id, scope, status, user_id,
+            interval_secs, last_polled_at, created_at, expires_at"#,
+        )
+        .bind(user_code)
+        .bind(org_id)
+        .bind(user_id)
+        .bind(status)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn poll_device_code(
+        &self,
+        device_code_hash: &str,
+        client_id: &str,
+    ) -> Result<DevicePoll, sqlx::Error> {
+        // the row lock orders concurrent polls, only one of them can see the
+        // approval before it is marked redeemed, and only by the client the
+        // code was issued to
+        let mut tx = self.begin().await?;
+        let authorization = sqlx::query_as::<_, DeviceAuthorization>(
+            r#"
+            SELECT device_code_hash, user_code, client_id, org_id, scope, status, user_id,
+            interval_secs, last_polled_at, created_at, expires_at
+            FROM device_codes WHERE device_code_hash = $1 AND client_id = $2 FOR UPDATE"#,
+        )
+        .bind(device_code_hash)
+        .bind(client_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        let now = Utc::now();
+        let slow_down = authorization.last_polled_at.is_some_and(|polled_at| {
+            now < polled_at + Duration::seconds(authorization.interval_secs as i64)
+        });
+        sqlx::query(
+            r#"
+            UPDATE device_codes SET last_polled_at = $2,
+            interval_secs = interval_secs + CASE WHEN $3 THEN 5 ELSE 0 END,
+            status = CASE WHEN status = 'approved' AND expires_at > $2 THEN 'redeemed'
+            ELSE status END
+            WHERE device_code_hash = $1 AND client_id = $4"#,
+        )
+        .bind(device_code_hash)
+        .bind(now)
+        .bind(slow_down)
+        .bind(client_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(DevicePoll {
+            authorization,
+            slow_down,
+        })
+    }
+
+    async fn insert_api_key(&self, api_key: &ApiKey) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO api_keys (user_id, org_id, name, prefix, key_hash, permissions,
+            expires_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING api_key_id"#,
+        )
+        .bind(api_key.user_id)
+        .bind(api_key.org_id)
+        .bind(&api_key.name)
+        .bind(&api_key.prefix)
+        .bind(&api_key.key_hash)
+        .bind(&api_key.permissions)
+        .bind(api_key.expires_at)
+        .bind(api_key.created_at)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn fetch_user_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, sqlx::Error> {
+        sqlx::query_as::<_, ApiKey>(
+            r#"SELECT api_key_id, user_id, org_id, name, prefix, key_hash, permissions,
+            expires_at, last_used_at, created_at, revoked_at
+            FROM api_keys
+            WHERE user_id = $1
+            ORDER BY created_at DESC"#,
+        )
+        .bind(user_id)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn revoke_api_key(&self, user_id: i32, api_key_id: i64) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            UPDATE api_keys SET revoked_at = NOW()
+            WHERE api_key_id = $1 AND user_id = $2 AND revoked_at IS NULL
+            RETURNING api_key_id"#,
+        )
+        .bind(api_key_id)
+        .bind(user_id)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn use_api_key(&self, key_hash: &str) -> Result<ApiKeyOwner, sqlx::Error> {
+        sqlx::query_as::<_, ApiKeyOwner>(
+            r#"
+            WITH used AS (
+                UPDATE api_keys SET last_used_at = NOW()
+                WHERE key_hash = $1 AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > NOW())
+                RETURNING api_key_id, user_id, org_id, permissions
+            )
+            SELECT used.api_key_id, used.user_id, used.org_id, u.username, used.permissions
+            FROM used
+            JOIN users u ON u.user_id = used.user_id
+            JOIN org_members om ON om.user_id = used.user_id AND om.org_id = used.org_id
+                AND om.active"#,
+        )
+        .bind(key_hash)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn insert_scim_token(&self, scim_token: &ScimToken) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO scim_tokens (org_id, name, prefix, token_hash, created_by, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING scim_token_id"#,
+        )
+        .bind(scim_token.org_id)
+        .bind(&scim_token.name)
+        .bind(&scim_token.prefix)
+        .bind(&scim_token.token_hash)
+        .bind(scim_token.created_by)
+        .bind(scim_token.created_at)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn fetch_scim_tokens(&self, org_id: i32) -> Result<Vec<ScimToken>, sqlx::Error> {
+        sqlx::query_as::<_, ScimToken>(
+            r#"SELECT scim_token_id, org_id, name, prefix, token_hash, created_by, last_used_at,
+            created_at, revoked_at
+            FROM scim_tokens
+            WHERE org_id = $1
+            ORDER BY created_at DESC"#,
+        )
+        .bind(org_id)
+        .fetch_all(self)
+        .await
+    }
+
+    async fn revoke_scim_token(&self, org_id: i32, scim_token_id: i64) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            UPDATE scim_tokens SET revoked_at = NOW()
+            WHERE scim_token_id = $1 AND org_id = $2 AND revoked_at IS NULL
+            RETURNING scim_token_id"#,
+        )
+        .bind(scim_token_id)
+        .bind(org_id)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn use_scim_token(&self, token_hash: &str) -> Result<ScimClient, sqlx::Error> {
+        sqlx::query_as::<_, ScimClient>(
+            r#"
+            UPDATE scim_tokens SET last_used_at = NOW()
+            WHERE token_hash = $1 AND revoked_at IS NULL
+            RETURNING scim_token_id, org_id"#,
+        )
+        .bind(token_hash)
+        .fetch_one(self)
+        .await
+    }
+
+    /// Members matching every set filter, ordered by id, with the total
+    /// count for paging
+    async fn fetch_scim_members(
+        &self,
+        org_id: i32,
+        filter: &ScimFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(i64, Vec<ScimMember>), sqlx::Error> {
+        let total: (i64,) = sqlx::query_as(
+            r#"SELECT COUNT(*)
+            FROM org_members om
+            JOIN users u ON u.user_id = om.user_id
+            WHERE om.org_id = $1
+            AND ($2::text IS NULL OR LOWER(u.username) = LOWER($2))
+            AND ($3::text IS NULL OR om.external_id = $3)
+            AND ($4::text IS NULL OR LOWER(u.email) = LOWER($4))"#,
+        )
+        .bind(org_id)
+        .bind(&filter.user_name)
+        .bind(&filter.external_id)
+        .bind(&filter.email)
+        .fetch_one(self)
+        .await?;
+        let members = sqlx::query_as::<_, ScimMember>(
+            r#"SELECT u.user_id, u.username, u.email, om.active, om.external_id,
+            EXISTS (
+                SELECT 1 FROM org_members other
+                WHERE other.user_id = u.user_id AND other.org_id <> om.org_id
+            ) AS shared,
+            ARRAY(
+                SELECT r.role_id FROM user_roles ur JOIN roles r ON r.role_id = ur.role_id
+                WHERE ur.user_id = u.user_id AND ur.org_id = om.org_id
+                AND (ur.valid_from IS NULL OR ur.valid_from <= NOW())
+                AND (ur.valid_until IS NULL OR ur.valid_until > NOW())
+                ORDER BY r.role_id
+            ) AS role_ids,
+            ARRAY(
+                SELECT r.name FROM user_roles ur JOIN roles r ON r.role_id = ur.role_id
+                WHERE ur.user_id = u.user_id AND ur.org_id = om.org_id
+                AND (ur.valid_from IS NULL OR ur.valid_from <= NOW())
+                AND (ur.valid_until IS NULL OR ur.valid_until > NOW())
+                ORDER BY r.role_id
+            ) AS role_names,
+            u.created_at
+            FROM org_members om
+            JOIN users u ON u.user_id = om.user_id
+            WHERE om.org_id = $1
+            AND ($2::text IS NULL OR LOWER(u.username) = LOWER($2))
+            AND ($3::text IS NULL OR om.external_id = $3)
+            AND ($4::text IS NULL OR LOWER(u.email) = LOWER($4))
+            ORDER BY u.user_id
+            OFFSET $5 LIMIT $6"#,
+        )
+        .bind(org_id)
+        .bind(&filter.user_name)
+        .bind(&filter.external_id)
+        .bind(&filter.email)
+        .bind(offset)
+        .bind(limit)
+        .fetch_all(self)
+        .await?;
+        Ok((total.0, members))
+    }
+
+    async fn fetch_scim_member(
+        &self,
+        org_id: i32,
+        user_id: i32,
+    ) -> Result<ScimMember, sqlx::Error> {
+        sqlx::query_as::<_, ScimMember>(
+            r#"SELECT u.user_id, u.username, u.email, om.active, om.external_id,
+            EXISTS (
+                SELECT 1 FROM org_members other
+                WHERE other.user_id = u.user_id AND other.org_id <> om.org_id
+            ) AS shared,
+            ARRAY(
+                SELECT r.role_id FROM user_roles ur JOIN roles r ON r.role_id = ur.role_id
+                WHERE ur.user_id = u.user_id AND ur.org_id = om.org_id
+                AND (ur.valid_from IS NULL OR ur.valid_from <= NOW())
+                AND (ur.valid_until IS NULL OR ur.valid_until > NOW())
+                ORDER BY r.role_id
+            ) AS role_ids,
+            ARRAY(
+                SELECT r.name FROM user_roles ur JOIN roles r ON r.role_id = ur.role_id
+                WHERE ur.user_id = u.user_id AND ur.org_id = om.org_id
+                AND (ur.valid_from IS NULL OR ur.valid_from <= NOW())
+                AND (ur.valid_until IS NULL OR ur.valid_until > NOW())
+                ORDER BY r.role_id
+            ) AS role_names,
+            u.created_at
+            FROM org_members om
+            JOIN users u ON u.user_id = om.user_id
+            WHERE om.org_id = $1 AND om.user_id = $2"#,
+        )
+        .bind(org_id)
+        .bind(user_id)
+        .fetch_one(self)
+        .await
+    }
+
+    /// Creates the account without a password as a member of `org_id`
+    async fn insert_scim_member(
+        &self,
+        org_id: i32,
+        member: &ScimMember,
+    ) -> Result<i32, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            INSERT INTO users (username, email, created_at)
+            VALUES ($1, $2, $3)
+            RETURNING user_id"#,
+        )
+        .bind(&member.username)
+        .bind(&member.email)
+        .bind(member.created_at)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO org_members (org_id, user_id, active, external_id)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(org_id)
+        .bind(row.0)
+        .bind(member.active)
+        .bind(&member.external_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(row.0)
+    }
+
+    /// Deactivating the membership also ends the user's sessions in the
+    /// organization
+    async fn update_scim_member(
+        &self,
+        org_id: i32,
+        member: &ScimMember,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE org_members SET active = $3, external_id = $4
+            WHERE org_id = $1 AND user_id = $2
+            RETURNING user_id"#,
+        )
+        .bind(org_id)
+        .bind(member.user_id)
+        .bind(member.active)
+        .bind(&member.external_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE users SET username = $2, email = $3
+            WHERE user_id = $1 AND (username <> $2 OR email IS DISTINCT FROM $3)
+            "#,
+        )
+        .bind(member.user_id)
+        .bind(&member.username)
+        .bind(&member.email)
+        .execute(&mut *tx)
+        .await?;
+        if !member.active {
+            sqlx::query(
+                r#"
+                UPDATE sessions SET revoked_at = NOW()
+                WHERE user_id = $1 AND org_id = $2 AND revoked_at IS NULL
+                "#,
+            )
+            .bind(member.user_id)
+            .bind(org_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Ends the membership with its roles, grants and sessions, the account
+    /// itself stays
+    async fn delete_scim_member(&self, org_id: i32, user_id: i32) -> Result<i32, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            DELETE FROM org_members
+            WHERE org_id = $1 AND user_id = $2
+            RETURNING user_id"#,
+        )
+        .bind(org_id)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query(r#"DELETE FROM resource_grants WHERE org_id = $1 AND user_id = $2"#)
+            .bind(org_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE user_id = $1 AND org_id = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(org_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(row.0)
+    }
+
+    /// Roles visible to the organization, with their members there
+    async fn fetch_scim_roles(
+        &self,
+        org_id: i32,
+        filter: &ScimFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(i64, Vec<ScimRole>), sqlx::Error> {
+        let total: (i64,) = sqlx::query_as(
+            r#"SELECT COUNT(*)
+            FROM roles r
+            WHERE (r.org_id IS NULL OR r.org_id = $1)
+            AND ($2::text IS NULL OR LOWER(r.name) = LOWER($2))"#,
+        )
+        .bind(org_id)
+        .bind(&filter.display_name)
+        .fetch_one(self)
+        .await?;
+        let roles = sqlx::query_as::<_, ScimRole>(
+            r#"SELECT r.role_id, r.org_id, r.name,
+            ARRAY(
+                SELECT ur.user_id FROM user_roles ur
+                WHERE ur.role_id = r.role_id AND ur.org_id = $1
+                AND (ur.valid_from IS NULL OR ur.valid_from <= NOW())
+                AND (ur.valid_until IS NULL OR ur.valid_until > NOW())
+                ORDER BY ur.user_id
+            ) AS member_ids,
+            ARRAY(
+                SELECT u.username FROM user_roles ur JOIN users u ON u.user_id = ur.user_id
+                WHERE ur.role_id = r.role_id AND ur.org_id = $1
+                AND (ur.valid_from IS NULL OR ur.valid_from <= NOW())
+                AND (ur.valid_until IS NULL OR ur.valid_until > NOW())
+                ORDER BY ur.user_id
+            ) AS member_names,
+            r.created_at
+            FROM roles r
+            WHERE (r.org_id IS NULL OR r.org_id = $1)
+            AND ($2::text IS NULL OR LOWER(r.name) = LOWER($2))
+            ORDER BY r.role_id
+            OFFSET $3 LIMIT $4"#,
+        )
+        .bind(org_id)
+        .bind(&filter.display_name)
+        .bind(offset)
+        .bind(limit)
+        .fetch_all(self)
+        .await?;
+        Ok((total.0, roles))
+    }
+
+    async fn fetch_scim_role(&self, org_id: i32, role_id: i32) -> Result<ScimRole, sqlx::Error> {
+        sqlx::query_as::<_, ScimRole>(
+            r#"SELECT r.role_id, r.org_id, r.name,
+            ARRAY(
+                SELECT ur.user_id FROM user_roles ur
+                WHERE ur.role_id = r.role_id AND ur.org_id = $1
+                AND (ur.valid_from IS NULL OR ur.valid_from <= NOW())
+                AND (ur.valid_until IS NULL OR ur.valid_until > NOW())
+                ORDER BY ur.user_id
+            ) AS member_ids,
+            ARRAY(
+                SELECT u.username FROM user_roles ur JOIN users u ON u.user_id = ur.user_id
+                WHERE ur.role_id = r.role_id AND ur.org_id = $1
+                AND (ur.valid_from IS NULL OR ur.valid_from <= NOW())
+                AND (ur.valid_until IS NULL OR ur.valid_until > NOW())
+                ORDER BY ur.user_id
+            ) AS member_names,
+            r.created_at
+            FROM roles r
+            WHERE (r.org_id IS NULL OR r.org_id = $1) AND r.role_id = $2"#,
+        )
+        .bind(org_id)
+        .bind(role_id)
+        .fetch_one(self)
+        .await
+    }
+
+    /// Only the organization's own roles can be renamed
+    async fn rename_role(&self, org_id: i32, role_id: i32, name: &str) -> Result<i32, sqlx::Error> {
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            UPDATE roles SET name = $3
+            WHERE role_id = $1 AND org_id = $2
+            RETURNING role_id"#,
+        )
+        .bind(role_id)
+        .bind(org_id)
+        .bind(name)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    /// Deletes one of the organization's own roles together with its grants
+    async fn delete_role(&self, org_id: i32, role_id: i32) -> Result<i32, sqlx::Error> {
+        let mut tx = self.begin().await?;
+        sqlx::query(
+            r#"
+            DELETE FROM user_roles ur
+            USING roles r
+            WHERE ur.role_id = r.role_id AND r.role_id = $1 AND r.org_id = $2
+            "#,
+        )
+        .bind(role_id)
+        .bind(org_id)
+        .execute(&mut *tx)
+        .await?;
+        let row: (i32,) = sqlx::query_as(
+            r#"
+            DELETE FROM roles
+            WHERE role_id = $1 AND org_id = $2
+            RETURNING role_id"#,
+        )
+        .bind(role_id)
+        .bind(org_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(row.0)
+    }
+
+    /// Makes `user_ids` the role's holders in the organization. Fails with
+    /// RowNotFound when one of them is not a member.
+    async fn set_role_members(
+        &self,
+        org_id: i32,
+        role_id: i32,
+        user_ids: &[i32],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.begin().await?;
+        let members: (i64,) = sqlx::query_as(
+            r#"SELECT COUNT(*) FROM org_members WHERE org_id = $1 AND user_id = ANY($2::int[])"#,
+        )
+        .bind(org_id)
+        .bind(user_ids)
+        .fetch_one(&mut *tx)
+        .await?;
+        if members.0 as usize != user_ids.len() {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        sqlx::query(
+            r#"
+            DELETE FROM user_roles
+            WHERE org_id = $1 AND role_id = $2 AND NOT user_id = ANY($3::int[])
+            "#,
+        )
+        .bind(org_id)
+        .bind(role_id)
+        .bind(user_ids)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO user_roles (user_id, org_id, role_id)
+            SELECT user_id, $1, $2 FROM UNNEST($3::int[]) AS user_id
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(org_id)
+        .bind(role_id)
+        .bind(user_ids)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Fails with RowNotFound when the role is not visible to the
+    /// organization
+    async fn insert_invitation(&self, invitation: &Invitation) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO invitations (org_id, email, role_id, token_hash, invited_by, created_at,
+                expires_at)
+            SELECT $1, $2, r.role_id, $4, $5, $6, $7
+            FROM roles r
+            WHERE r.role_id = $3 AND (r.org_id IS NULL OR r.org_id = $1)
+            RETURNING invitation_id"#,
+        )
+        .bind(invitation.org_id)
+        .bind(&invitation.email)
+        .bind(invitation.role_id)
+        .bind(&invitation.token_hash)
+        .bind(invitation.invited_by)
+        .bind(invitation.created_at)
+        .bind(invitation.expires_at)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn fetch_invitations(&self, org_id: i32) -> Result<Vec<Invitation>, sqlx::Error> {
+        sqlx::query_as::<_, Invitation>(
+            r#"SELECT invitation_id, org_id, email, role_id, token_hash, invited_by, created_at,
+            expires_at, accepted_at, revoked_at
+            FROM invitations
+            WHERE org_id = $1
+            ORDER BY created_at DESC"#,
+        )
+        .bind(org_id)
+        .fetch_all(self)
+        .await
+    }
+
+    /// Only pending invitations can be revoked
+    async fn revoke_invitation(&self, org_id: i32, invitation_id: i64) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            UPDATE invitations SET revoked_at = NOW()
+            WHERE invitation_id = $1 AND org_id = $2
+            AND accepted_at IS NULL AND revoked_at IS NULL
+            RETURNING invitation_id"#,
+        )
+        .bind(invitation_id)
+        .bind(org_id)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    /// Marks a pending, unexpired invitation accepted. Two registrations
+    /// racing for the same token cannot both claim it.
+    async fn claim_invitation(&self, token_hash: &str) -> Result<Invitation, sqlx::Error> {
+        sqlx::query_as::<_, Invitation>(
+            r#"
+            UPDATE invitations SET accepted_at = NOW()
+            WHERE token_hash = $1 AND accepted_at IS NULL AND revoked_at IS NULL
+            AND expires_at > NOW()
+            RETURNING invitation_id, org_id, email, role_id, token_hash, invited_by,
+            created_at, expires_at, accepted_at, revoked_at"#,
+        )
+        .bind(token_hash)
+        .fetch_one(self)
+        .await
+    }
+
+    /// Hands a claimed invitation back when the registration failed
+    async fn release_invitation(&self, invitation_id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(r#"UPDATE invitations SET accepted_at = NULL WHERE invitation_id = $1"#)
+            .bind(invitation_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_room_code(&self, room_code: &RoomCode) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO room_codes (org_id, room_id, code, created_by, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING room_code_id"#,
+        )
+        .bind(room_code.org_id)
+        .bind(&room_code.room_id)
+        .bind(&room_code.code)
+        .bind(room_code.created_by)
+        .bind(room_code.created_at)
+        .bind(room_code.expires_at)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn fetch_room_code(
+        &self,
+        org_id: i32,
+        room_code_id: i64,
+    ) -> Result<RoomCode, sqlx::Error> {
+        sqlx::query_as::<_, RoomCode>(
+            r#"SELECT room_code_id, org_id, room_id, code, created_by, created_at, expires_at,
+            revoked_at
+            FROM room_codes
+            WHERE room_code_id = $1 AND org_id = $2"#,
+        )
+        .bind(room_code_id)
+        .bind(org_id)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn revoke_room_code(&self, org_id: i32, room_code_id: i64) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            UPDATE room_codes SET revoked_at = NOW()
+            WHERE room_code_id = $1 AND org_id = $2 AND revoked_at IS NULL
+            RETURNING room_code_id"#,
+        )
+        .bind(room_code_id)
+        .bind(org_id)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
+
+    /// Creates a guest in the room of a live `code`, RowNotFound otherwise
+    async fn insert_guest(&self, code: &str, guest: &Guest) -> Result<Guest, sqlx::Error> {
+        sqlx::query_as::<_, Guest>(
+            r#"
+            INSERT INTO guests (org_id, room_id, display_name, room_code_id, created_at)
+            SELECT c.org_id, c.room_id, $2, c.room_code_id, $3
+            FROM room_codes c
+            WHERE c.code = $1 AND c.revoked_at IS NULL AND c.expires_at > NOW()
+            RETURNING guest_id, org_id, room_id, display_name, room_code_id, user_id,
+            created_at, upgraded_at"#,
+        )
+        .bind(code)
+        .bind(&guest.display_name)
+        .bind(guest.created_at)
+        .fetch_one(self)
+        .await
+    }
+
+    async fn upgrade_guest(
+        &self,
+        org_id: i32,
+        guest_id: i64,
+        user_id: i32,
+    ) -> Result<i64, sqlx::Error> {
+        let row: (i64,) = sqlx::query_as(
+            r#"
+            UPDATE guests SET user_id = $3, upgraded_at = NOW()
+            WHERE guest_id = $1 AND org_id = $2 AND user_id IS NULL
+            RETURNING guest_id"#,
+        )
+        .bind(guest_id)
+        .bind(org_id)
+        .bind(user_id)
+        .fetch_one(self)
+        .await?;
+        Ok(row.0)
+    }
 }
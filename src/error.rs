@@ -31,6 +31,90 @@ pub enum CustomError {
 
     #[error("Role Permission already exists")]
     RolePermissionExists,
+
+    #[error("User Role already exists")]
+    UserRoleExists,
+
+    #[error("User Role not found")]
+    UserRoleNotFound,
+
+    #[error("Role parent already exists")]
+    RoleParentExists,
+
+    #[error("Role parent not found")]
+    RoleParentNotFound,
+
+    #[error("Role hierarchy would contain a cycle")]
+    RoleCycle,
+
+    #[error("Organization not found")]
+    OrgNotFound,
+
+    #[error("Organization already exists")]
+    OrgExists,
+
+    #[error("Grant already exists")]
+    GrantExists,
+
+    #[error("Grant not found")]
+    GrantNotFound,
+
+    #[error("Resource already has an owner")]
+    ResourceOwned,
+
+    #[error("Session not found")]
+    SessionNotFound,
+
+    #[error("Policy rules could not be loaded: {0}")]
+    PolicyLoad(String),
+
+    #[error("Identity already linked")]
+    IdentityExists,
+
+    #[error("Identity not found")]
+    IdentityNotFound,
+
+    #[error("Sign-in state invalid, expired or already used")]
+    UpstreamStateInvalid,
+
+    #[error("Identity providers could not be loaded: {0}")]
+    ProviderLoad(String),
+
+    #[error("OAuth client not found")]
+    OAuthClientNotFound,
+
+    #[error("Authorization code invalid, expired or already used")]
+    AuthorizationCodeInvalid,
+
+    #[error("Device code invalid, expired or already decided")]
+    DeviceCodeInvalid,
+
+    #[error("API key not found")]
+    ApiKeyNotFound,
+
+    #[error("API key already exists")]
+    ApiKeyExists,
+
+    #[error("SCIM token not found")]
+    ScimTokenNotFound,
+
+    #[error("Invitation not found")]
+    InvitationNotFound,
+
+    #[error("Invitation invalid")]
+    InvitationInvalid,
+
+    #[error("Room code already exists")]
+    RoomCodeExists,
+
+    #[error("Room code not found")]
+    RoomCodeNotFound,
+
+    #[error("Room code invalid")]
+    RoomCodeInvalid,
+
+    #[error("Guest not found")]
+    GuestNotFound,
 }
 
 impl Debug for CustomError {
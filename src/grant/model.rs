@@ -0,0 +1,49 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::policy::model::Evaluation;
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct ResourceGrant {
+    pub grant_id: Option<i32>,
+    pub org_id: i32,
+    pub user_id: i32,
+    pub relation: String,
+    pub resource_type: String,
+    pub resource_id: String,
+    pub granted_by: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreateGrant {
+    pub user_id: i32,
+    pub relation: String,
+    pub resource_type: String,
+    pub resource_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthorizeCheck {
+    /// defaults to the caller
+    pub user_id: Option<i32>,
+    pub action: String,
+    pub resource_type: String,
+    pub resource_id: String,
+    /// resource attributes matched by policy rules
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// include the per-rule policy trace in the decision
+    #[serde(default)]
+    pub explain: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthorizeDecision {
+    pub allowed: bool,
+    /// `policy:<rule>`, `role`, `grant:<relation>`, `guest` or `none`
+    pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy: Option<Evaluation>,
+}
@@ -0,0 +1,67 @@
+use super::model::ResourceGrant;
+use crate::{db::DBConn, error::CustomError};
+
+pub struct GrantRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> GrantRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        GrantRepository { db }
+    }
+
+    pub async fn fetch_resource_grants(
+        &self,
+        org_id: i32,
+        resource_type: &str,
+        resource_id: &str,
+    ) -> Result<Vec<ResourceGrant>, CustomError> {
+        self.db
+            .fetch_resource_grants(org_id, resource_type, resource_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn fetch_user_grants(
+        &self,
+        org_id: i32,
+        user_id: i32,
+    ) -> Result<Vec<ResourceGrant>, CustomError> {
+        self.db
+            .fetch_user_grants(org_id, user_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    /// UserNotFound when the grantee is not an active member of the
+    /// grant's organization
+    pub async fn insert_resource_grant(
+        &self,
+        new_grant: &ResourceGrant,
+    ) -> Result<i32, CustomError> {
+        let grant_id = match self.db.insert_resource_grant(new_grant).await {
+            Ok(grant_id) => grant_id,
+            Err(e) => match e {
+                sqlx::Error::Database(err) if err.is_unique_violation() => {
+                    return Err(match err.constraint() {
+                        Some("resource_grants_owner_key") => CustomError::ResourceOwned,
+                        _ => CustomError::GrantExists,
+                    });
+                }
+                sqlx::Error::RowNotFound => return Err(CustomError::UserNotFound),
+                _ => return Err(CustomError::DBError(e)),
+            },
+        };
+        Ok(grant_id)
+    }
+
+    pub async fn delete_resource_grant(&self, grant: &ResourceGrant) -> Result<i32, CustomError> {
+        self.db
+            .delete_resource_grant(grant)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::GrantNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+}
@@ -0,0 +1,439 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use request_http_parser::parser::Request;
+
+use super::{
+    model::{AuthorizeCheck, AuthorizeDecision, CreateGrant, ResourceGrant},
+    repo::GrantRepository,
+};
+use crate::{
+    constants::{
+        BAD_REQUEST, CONFLICT, FORBIDDEN, INTERNAL_ERROR, MANAGE_USERS, NO_CONTENT, NOT_FOUND,
+        OK_RESPONSE, OWNER, RELATION_ACTIONS, ROOM, UNAUTHORIZED,
+    },
+    db::DBConn,
+    error::CustomError,
+    policy::model::{Decision, PolicyContext, ResourceAttributes},
+    rolepermissions::service::RolePermissionSvc,
+    utils::{ClaimType, Claims, des_from_str, ser_to_str},
+};
+
+pub struct GrantSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: GrantRepository<DB>,
+}
+
+impl<DB> GrantSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB) -> Self {
+        GrantSvc {
+            repository: GrantRepository::new(pool),
+        }
+    }
+
+    pub async fn create_grant(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id, user_id) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let req_grant: CreateGrant = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(grant) => grant,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if Self::relation_actions(&req_grant.relation).is_none() {
+            return (BAD_REQUEST.to_string(), "Unknown relation".to_string());
+        }
+
+        let grants = match self
+            .repository
+            .fetch_resource_grants(org_id, &req_grant.resource_type, &req_grant.resource_id)
+            .await
+        {
+            Ok(grants) => grants,
+            Err(error) => {
+                eprintln!("Error grant db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let allowed = match self.can_manage(rp_svc, &claims, request, &grants).await {
+            Ok(true) => true,
+            // whoever may create this kind of resource claims ownership of a
+            // fresh one, e.g. a facilitator with create_room on a new room
+            Ok(false) if req_grant.relation == OWNER && req_grant.user_id == user_id => {
+                let create_permission = format!("create_{}", req_grant.resource_type);
+                let unowned = !grants.iter().any(|grant| grant.relation == OWNER);
+                match rp_svc.permits(&claims, request, &create_permission).await {
+                    Ok(can_create) => unowned && can_create,
+                    Err(error) => {
+                        eprintln!("Error role permission db: {:#?}", error);
+                        return (INTERNAL_ERROR.to_string(), "".to_string());
+                    }
+                }
+            }
+            Ok(false) => false,
+            Err(error) => {
+                eprintln!("Error role permission db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        if !allowed {
+            return (FORBIDDEN.to_string(), "".to_string());
+        }
+
+        let new_grant = ResourceGrant {
+            grant_id: None,
+            org_id,
+            user_id: req_grant.user_id,
+            relation: req_grant.relation,
+            resource_type: req_grant.resource_type,
+            resource_id: req_grant.resource_id,
+            granted_by: Some(user_id),
+            created_at: Utc::now(),
+        };
+        match self.repository.insert_resource_grant(&new_grant).await {
+            Ok(_) => (NO_CONTENT.to_string(), "".to_string()),
+            Err(err) => match err {
+                CustomError::GrantExists => {
+                    eprintln!("Error insert: {:#?}", err);
+                    (BAD_REQUEST.to_string(), "Already granted".to_string())
+                }
+                CustomError::ResourceOwned => (CONFLICT.to_string(), err.to_string()),
+                CustomError::UserNotFound => (NOT_FOUND.to_string(), err.to_string()),
+                error => {
+                    eprintln!("Error insert grant db: {:#?}", error);
+                    (INTERNAL_ERROR.to_string(), "".to_string())
+                }
+            },
+        }
+    }
+
+    pub async fn revoke_grant(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id, _) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let req_grant: CreateGrant = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(grant) => grant,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+
+        let grants = match self
+            .repository
+            .fetch_resource_grants(org_id, &req_grant.resource_type, &req_grant.resource_id)
+            .await
+        {
+            Ok(grants) => grants,
+            Err(error) => {
+                eprintln!("Error grant db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        match self.can_manage(rp_svc, &claims, request, &grants).await {
+            Ok(true) => {}
+            Ok(false) => return (FORBIDDEN.to_string(), "".to_string()),
+            Err(error) => {
+                eprintln!("Error role permission db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+
+        let grant = ResourceGrant {
+            grant_id: None,
+            org_id,
+            user_id: req_grant.user_id,
+            relation: req_grant.relation,
+            resource_type: req_grant.resource_type,
+            resource_id: req_grant.resource_id,
+            granted_by: None,
+            created_at: Utc::now(),
+        };
+        match self.repository.delete_resource_grant(&grant).await {
+            Ok(_) => (NO_CONTENT.to_string(), "".to_string()),
+            Err(err) => match err {
+                CustomError::GrantNotFound => (NOT_FOUND.to_string(), "".to_string()),
+                error => {
+                    eprintln!("Error delete grant db: {:#?}", error);
+                    (INTERNAL_ERROR.to_string(), "".to_string())
+                }
+            },
+        }
+    }
+
+    /// Lists the grants on `?resource_type=&resource_id=` for its managers, or
+    /// the caller's own grants without query params
+    pub async fn get_grants(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id, user_id) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let resource = request
+            .params
+            .as_ref()
+            .and_then(|params| Some((params.get("resource_type")?, params.get("resource_id")?)));
+
+        let grants = match resource {
+            Some((resource_type, resource_id)) => {
+                let grants = match self
+                    .repository
+                    .fetch_resource_grants(org_id, resource_type, resource_id)
+                    .await
+                {
+                    Ok(grants) => grants,
+                    Err(error) => {
+                        eprintln!("Error grant db: {:#?}", error);
+                        return (INTERNAL_ERROR.to_string(), "".to_string());
+                    }
+                };
+                match self.can_manage(rp_svc, &claims, request, &grants).await {
+                    Ok(true) => grants,
+                    Ok(false) => return (FORBIDDEN.to_string(), "".to_string()),
+                    Err(error) => {
+                        eprintln!("Error role permission db: {:#?}", error);
+                        return (INTERNAL_ERROR.to_string(), "".to_string());
+                    }
+                }
+            }
+            None => match self.repository.fetch_user_grants(org_id, user_id).await {
+                Ok(grants) => grants,
+                Err(error) => {
+                    eprintln!("Error grant db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            },
+        };
+        let response_json = match ser_to_str(&grants) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// Answers "can user X do action Y on resource Z". A matching policy rule
+    /// decides first, then a role permission named after the action allows it
+    /// on every resource, otherwise a grant on the resource whose relation
+    /// covers the action is needed.
+    pub async fn check(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        if let Some(claims) = claims.as_ref().filter(|c| c.claim_type == ClaimType::Guest) {
+            return Self::check_guest(claims, request);
+        }
+        let (claims, org_id, user_id) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let check: AuthorizeCheck = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(check) => check,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let subject_id = check.user_id.unwrap_or(user_id);
+        if subject_id != user_id {
+            // asking about someone else is reserved to user managers
+            match rp_svc.permits(&claims, request, MANAGE_USERS).await {
+                Ok(true) => {}
+                Ok(false) => return (FORBIDDEN.to_string(), "".to_string()),
+                Err(error) => {
+                    eprintln!("Error role permission db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            }
+        }
+
+        let context = PolicyContext::from_request(request);
+        let decision = match self
+            .decide(rp_svc, org_id, subject_id, &check, &context)
+            .await
+        {
+            Ok(decision) => decision,
+            Err(error) => {
+                eprintln!("Error authorize db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&decision) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// Guests have no roles or grants, their token alone decides: the
+    /// guest actions on their own room
+    fn check_guest(claims: &Claims, request: &Request) -> (String, String) {
+        let check: AuthorizeCheck = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(check) => check,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if check.user_id.is_some() {
+            return (FORBIDDEN.to_string(), "".to_string());
+        }
+        let allowed = check.resource_type == ROOM
+            && claims.room_id.as_deref() == Some(check.resource_id.as_str())
+            && claims
+                .permissions
+                .iter()
+                .flatten()
+                .any(|action| *action == check.action);
+        let decision = AuthorizeDecision {
+            allowed,
+            reason: if allowed { "guest" } else { "none" }.to_string(),
+            policy: None,
+        };
+        let response_json = match ser_to_str(&decision) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    pub(crate) async fn decide(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        org_id: i32,
+        subject_id: i32,
+        check: &AuthorizeCheck,
+        context: &PolicyContext,
+    ) -> Result<AuthorizeDecision, CustomError> {
+        let resource = ResourceAttributes {
+            resource_type: Some(check.resource_type.clone()),
+            id: Some(check.resource_id.clone()),
+            attributes: check.attributes.clone(),
+        };
+        let evaluation = rp_svc
+            .policy()
+            .evaluate(org_id, subject_id, &check.action, &resource, context)
+            .await?;
+        if evaluation.decision != Decision::NotApplicable {
+            return Ok(AuthorizeDecision {
+                allowed: evaluation.decision == Decision::Allow,
+                reason: format!(
+                    "policy:{}",
+                    evaluation.decided_by.as_deref().unwrap_or_default()
+                ),
+                policy: check.explain.then_some(evaluation),
+            });
+        }
+        let policy = check.explain.then_some(evaluation);
+        if rp_svc
+            .user_has_permission(org_id, subject_id, &check.action)
+            .await?
+        {
+            return Ok(AuthorizeDecision {
+                allowed: true,
+                reason: "role".to_string(),
+                policy,
+            });
+        }
+        let grants = self
+            .repository
+            .fetch_resource_grants(org_id, &check.resource_type, &check.resource_id)
+            .await?;
+        let relation = grants
+            .iter()
+            .filter(|grant| grant.user_id == subject_id)
+            .find(|grant| {
+                Self::relation_actions(&grant.relation)
+                    .is_some_and(|actions| actions.contains(&check.action.as_str()))
+            })
+            .map(|grant| grant.relation.clone());
+        Ok(match relation {
+            Some(relation) => AuthorizeDecision {
+                allowed: true,
+                reason: format!("grant:{}", relation),
+                policy,
+            },
+            None => AuthorizeDecision {
+                allowed: false,
+                reason: "none".to_string(),
+                policy,
+            },
+        })
+    }
+
+    /// User managers and owners of the resource may hand out and revoke
+    /// grants, an API key only through its own scope
+    async fn can_manage(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: &Claims,
+        request: &Request,
+        grants: &[ResourceGrant],
+    ) -> Result<bool, CustomError> {
+        let owner = claims.user_id().is_some_and(|user_id| {
+            grants
+                .iter()
+                .any(|grant| grant.user_id == user_id && grant.relation == OWNER)
+        });
+        if owner && claims.api_key_id.is_none() {
+            return Ok(true);
+        }
+        rp_svc.permits(claims, request, MANAGE_USERS).await
+    }
+
+    fn relation_actions(relation: &str) -> Option<&'static [&'static str]> {
+        RELATION_ACTIONS
+            .iter()
+            .find(|(name, _)| *name == relation)
+            .map(|(_, actions)| *actions)
+    }
+
+    fn caller(claims: Option<Claims>) -> Result<(Claims, i32, i32), (String, String)> {
+        let claims = match claims {
+            Some(claims) => claims,
+            None => return Err((UNAUTHORIZED.to_string(), "".to_string())),
+        };
+        let org_id = match claims.org_id {
+            Some(org_id) => org_id,
+            None => return Err((FORBIDDEN.to_string(), "".to_string())),
+        };
+        let user_id = match claims.sub.parse::<i32>() {
+            Ok(user_id) => user_id,
+            Err(_) => return Err((UNAUTHORIZED.to_string(), "".to_string())),
+        };
+        Ok((claims, org_id, user_id))
+    }
+}
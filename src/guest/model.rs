@@ -0,0 +1,55 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Code learners type to join a room as guests
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct RoomCode {
+    pub room_code_id: Option<i64>,
+    pub org_id: i32,
+    pub room_id: String,
+    pub code: String,
+    pub created_by: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateRoomCode {
+    pub room_id: String,
+    /// defaults to a few hours, enough for one session
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct Guest {
+    pub guest_id: Option<i64>,
+    pub org_id: i32,
+    pub room_id: String,
+    pub display_name: String,
+    pub room_code_id: Option<i64>,
+    /// account the guest was upgraded to
+    pub user_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub upgraded_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JoinRoom {
+    pub code: String,
+    pub display_name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GuestToken {
+    pub token: String,
+    pub guest_id: i64,
+    pub room_id: String,
+    pub expires_in: i64,
+}
+
+/// Guest token of the learner the signed-in account used to be
+#[derive(Serialize, Deserialize)]
+pub struct UpgradeGuest {
+    pub token: String,
+}
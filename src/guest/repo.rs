@@ -0,0 +1,81 @@
+use super::model::{Guest, RoomCode};
+use crate::{db::DBConn, error::CustomError};
+
+pub struct GuestRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> GuestRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        GuestRepository { db }
+    }
+
+    pub async fn insert_room_code(&self, room_code: &RoomCode) -> Result<i64, CustomError> {
+        self.db
+            .insert_room_code(room_code)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(err) if err.is_unique_violation() => {
+                    CustomError::RoomCodeExists
+                }
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn fetch_room_code(
+        &self,
+        org_id: i32,
+        room_code_id: i64,
+    ) -> Result<RoomCode, CustomError> {
+        self.db
+            .fetch_room_code(org_id, room_code_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::RoomCodeNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn revoke_room_code(
+        &self,
+        org_id: i32,
+        room_code_id: i64,
+    ) -> Result<(), CustomError> {
+        self.db
+            .revoke_room_code(org_id, room_code_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::RoomCodeNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    /// RoomCodeInvalid when the code is unknown, expired or revoked
+    pub async fn insert_guest(&self, code: &str, guest: &Guest) -> Result<Guest, CustomError> {
+        self.db
+            .insert_guest(code, guest)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::RoomCodeInvalid,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    /// GuestNotFound when the guest is unknown or already upgraded
+    pub async fn upgrade_guest(
+        &self,
+        org_id: i32,
+        guest_id: i64,
+        user_id: i32,
+    ) -> Result<(), CustomError> {
+        self.db
+            .upgrade_guest(org_id, guest_id, user_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::GuestNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+}
@@ -0,0 +1,406 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{Duration, Utc};
+use rand::Rng;
+use request_http_parser::parser::Request;
+use serde_json::json;
+
+use super::{
+    model::{CreateRoomCode, Guest, GuestToken, JoinRoom, RoomCode, UpgradeGuest},
+    repo::GuestRepository,
+};
+use crate::{
+    audit::{
+        model::{AuditEvent, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    constants::{
+        BAD_REQUEST, CONFLICT, FORBIDDEN, INTERNAL_ERROR, MANAGE_ROOM, NO_CONTENT, NOT_FOUND,
+        OK_RESPONSE, ROOM, UNAUTHORIZED,
+    },
+    db::DBConn,
+    error::CustomError,
+    grant::{model::AuthorizeCheck, service::GrantSvc},
+    policy::model::PolicyContext,
+    rolepermissions::service::RolePermissionSvc,
+    utils::{
+        ClaimType, Claims, create_guest_jwt, des_from_str, ser_to_str, token_expiry, verify_jwt,
+    },
+};
+
+pub const ROOM_CODES_PATH: &str = "/protected/rooms/codes";
+pub const GUEST_TOKEN_PATH: &str = "/guest/token";
+pub const GUESTS_PATH: &str = "/protected/me/guests";
+
+/// Consonants only like device user codes, they are read off a projector
+const ROOM_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const ROOM_CODE_LENGTH: usize = 8;
+
+const ROOM_CODE_LIFETIME_HOURS: i64 = 4;
+const MAX_ROOM_CODE_DAYS: i64 = 7;
+
+pub struct GuestSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: GuestRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
+}
+
+impl<DB> GuestSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>) -> Self {
+        GuestSvc {
+            repository: GuestRepository::new(pool),
+            audit,
+        }
+    }
+
+    /// Opens a room to guests, for whoever may manage the room
+    pub async fn create_room_code(
+        &self,
+        grant_svc: &Arc<GrantSvc<DB>>,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (user_id, org_id) = match Self::caller(&claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let req_code: CreateRoomCode = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(req_code) => req_code,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let room_id = req_code.room_id.trim().to_string();
+        if room_id.is_empty() || room_id.len() > 255 {
+            return (BAD_REQUEST.to_string(), "Invalid room".to_string());
+        }
+        let now = Utc::now();
+        let expires_at = req_code
+            .expires_at
+            .unwrap_or(now + Duration::hours(ROOM_CODE_LIFETIME_HOURS));
+        if expires_at <= now || expires_at > now + Duration::days(MAX_ROOM_CODE_DAYS) {
+            return (BAD_REQUEST.to_string(), "Invalid expiry".to_string());
+        }
+        if let Err(response) =
+            Self::manages_room(grant_svc, rp_svc, &claims, &room_id, request).await
+        {
+            return response;
+        }
+
+        let mut room_code = RoomCode {
+            room_code_id: None,
+            org_id,
+            room_id,
+            code: String::new(),
+            created_by: Some(user_id),
+            created_at: now,
+            expires_at,
+            revoked_at: None,
+        };
+        // a fresh code colliding with an old one is rare, a few draws do
+        for _ in 0..3 {
+            room_code.code = room_code_value();
+            match self.repository.insert_room_code(&room_code).await {
+                Ok(room_code_id) => {
+                    room_code.room_code_id = Some(room_code_id);
+                    break;
+                }
+                Err(CustomError::RoomCodeExists) => continue,
+                Err(error) => {
+                    eprintln!("Error insert room code db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            }
+        }
+        let room_code_id = match room_code.room_code_id {
+            Some(room_code_id) => room_code_id,
+            None => return (INTERNAL_ERROR.to_string(), "".to_string()),
+        };
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: Some(user_id),
+                target: Some(format!("room_code:{}", room_code_id)),
+                metadata: json!({ "room_id": room_code.room_id }),
+                ..AuditEvent::new("room_code.create", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        let response_json = match ser_to_str(&room_code) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// `DELETE /protected/rooms/codes/{id}`, guests already in keep their
+    /// tokens until they expire
+    pub async fn revoke_room_code(
+        &self,
+        grant_svc: &Arc<GrantSvc<DB>>,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (user_id, org_id) = match Self::caller(&claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let room_code_id = match request
+            .path
+            .strip_prefix(ROOM_CODES_PATH)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .and_then(|id| id.parse::<i64>().ok())
+        {
+            Some(room_code_id) => room_code_id,
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let room_code = match self.repository.fetch_room_code(org_id, room_code_id).await {
+            Ok(room_code) => room_code,
+            Err(CustomError::RoomCodeNotFound) => return (NOT_FOUND.to_string(), "".to_string()),
+            Err(error) => {
+                eprintln!("Error room code db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        if let Err(response) =
+            Self::manages_room(grant_svc, rp_svc, &claims, &room_code.room_id, request).await
+        {
+            return response;
+        }
+        match self.repository.revoke_room_code(org_id, room_code_id).await {
+            Ok(_) => {
+                self.audit
+                    .record(AuditEvent {
+                        org_id: Some(org_id),
+                        actor_id: Some(user_id),
+                        target: Some(format!("room_code:{}", room_code_id)),
+                        metadata: json!({ "room_id": room_code.room_id }),
+                        ..AuditEvent::new("room_code.revoke", OUTCOME_SUCCESS, Some(request))
+                    })
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(CustomError::RoomCodeNotFound) => (NOT_FOUND.to_string(), "".to_string()),
+            Err(error) => {
+                eprintln!("Error room code db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// `POST /guest/token`, trades a room code and a display name for a
+    /// guest token in that room. No account needed.
+    pub async fn guest_token(&self, request: &Request) -> (String, String) {
+        let join: JoinRoom = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(join) => join,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let display_name = join.display_name.trim().to_string();
+        if display_name.is_empty() || display_name.chars().count() > 50 {
+            return (BAD_REQUEST.to_string(), "Invalid display name".to_string());
+        }
+        let guest = Guest {
+            guest_id: None,
+            org_id: 0,
+            room_id: String::new(),
+            display_name,
+            room_code_id: None,
+            user_id: None,
+            created_at: Utc::now(),
+            upgraded_at: None,
+        };
+        let guest = match self
+            .repository
+            .insert_guest(&normalize_room_code(&join.code), &guest)
+            .await
+        {
+            Ok(guest) => guest,
+            Err(CustomError::RoomCodeInvalid) => {
+                return (BAD_REQUEST.to_string(), "Room code invalid".to_string());
+            }
+            Err(error) => {
+                eprintln!("Error insert guest db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let guest_id = guest.guest_id.unwrap_or_default();
+        let token =
+            match create_guest_jwt(guest_id, &guest.display_name, guest.org_id, &guest.room_id) {
+                Ok(token) => token,
+                Err(e) => {
+                    eprintln!("Error creating JWT: {:#?}", e);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            };
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(guest.org_id),
+                target: Some(format!("guest:{}", guest_id)),
+                metadata: json!({ "room_id": guest.room_id, "room_code_id": guest.room_code_id }),
+                ..AuditEvent::new("guest.join", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        let response = GuestToken {
+            token,
+            guest_id,
+            room_id: guest.room_id,
+            expires_in: (token_expiry(&ClaimType::Guest) - Utc::now()).num_seconds(),
+        };
+        let response_json = match ser_to_str(&response) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// Links a guest to the signed-in account. The guest keeps its
+    /// `guest:<id>` subject, results recorded under it now lead to the user.
+    pub async fn upgrade_guest(
+        &self,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (user_id, org_id) = match claims {
+            Some(claims) if claims.claim_type == ClaimType::Login => {
+                match (claims.user_id(), claims.org_id) {
+                    (Some(user_id), Some(org_id)) => (user_id, org_id),
+                    _ => return (FORBIDDEN.to_string(), "".to_string()),
+                }
+            }
+            Some(_) => return (FORBIDDEN.to_string(), "".to_string()),
+            None => return (UNAUTHORIZED.to_string(), "".to_string()),
+        };
+        let upgrade: UpgradeGuest = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(upgrade) => upgrade,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let guest_claims = match verify_jwt(&upgrade.token) {
+            Ok(guest_claims) => guest_claims,
+            Err(_) => return (BAD_REQUEST.to_string(), "token invalid".to_string()),
+        };
+        let guest_id = match guest_claims.guest_id() {
+            Some(guest_id) => guest_id,
+            None => return (BAD_REQUEST.to_string(), "token invalid".to_string()),
+        };
+        // results belong to the organization the guest joined
+        if guest_claims.org_id != Some(org_id) {
+            return (FORBIDDEN.to_string(), "".to_string());
+        }
+        match self
+            .repository
+            .upgrade_guest(org_id, guest_id, user_id)
+            .await
+        {
+            Ok(_) => {
+                self.audit
+                    .record(AuditEvent {
+                        org_id: Some(org_id),
+                        actor_id: Some(user_id),
+                        target: Some(format!("guest:{}", guest_id)),
+                        ..AuditEvent::new("guest.upgrade", OUTCOME_SUCCESS, Some(request))
+                    })
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(CustomError::GuestNotFound) => {
+                (CONFLICT.to_string(), "Guest already upgraded".to_string())
+            }
+            Err(error) => {
+                eprintln!("Error upgrade guest db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// Same decision as `/protected/authorize` for `manage_room` on the
+    /// room, an API key also needs it in its scope
+    async fn manages_room(
+        grant_svc: &Arc<GrantSvc<DB>>,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: &Option<Claims>,
+        room_id: &str,
+        request: &Request,
+    ) -> Result<(), (String, String)> {
+        let (user_id, org_id) = Self::caller(claims)?;
+        if claims.as_ref().is_some_and(|claims| {
+            claims.api_key_id.is_some()
+                && !claims
+                    .permissions
+                    .iter()
+                    .flatten()
+                    .any(|permission| permission == MANAGE_ROOM)
+        }) {
+            return Err((FORBIDDEN.to_string(), "".to_string()));
+        }
+        let check = AuthorizeCheck {
+            user_id: None,
+            action: MANAGE_ROOM.to_string(),
+            resource_type: ROOM.to_string(),
+            resource_id: room_id.to_string(),
+            attributes: HashMap::new(),
+            explain: false,
+        };
+        match grant_svc
+            .decide(
+                rp_svc,
+                org_id,
+                user_id,
+                &check,
+                &PolicyContext::from_request(request),
+            )
+            .await
+        {
+            Ok(decision) if decision.allowed => Ok(()),
+            Ok(_) => Err((FORBIDDEN.to_string(), "".to_string())),
+            Err(error) => {
+                eprintln!("Error authorize db: {:#?}", error);
+                Err((INTERNAL_ERROR.to_string(), "".to_string()))
+            }
+        }
+    }
+
+    fn caller(claims: &Option<Claims>) -> Result<(i32, i32), (String, String)> {
+        let claims = match claims {
+            Some(claims) => claims,
+            None => return Err((UNAUTHORIZED.to_string(), "".to_string())),
+        };
+        match (claims.user_id(), claims.org_id) {
+            (Some(user_id), Some(org_id)) => Ok((user_id, org_id)),
+            _ => Err((FORBIDDEN.to_string(), "".to_string())),
+        }
+    }
+}
+
+fn room_code_value() -> String {
+    let mut rng = rand::thread_rng();
+    (0..ROOM_CODE_LENGTH)
+        .map(|_| ROOM_CODE_ALPHABET[rng.gen_range(0..ROOM_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Learners type codes in any case and with spaces or dashes
+fn normalize_room_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
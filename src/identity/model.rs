@@ -0,0 +1,39 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// An upstream provider account a user signs in with
+#[derive(Serialize, Deserialize, sqlx::FromRow, Debug)]
+pub struct UserIdentity {
+    pub identity_id: Option<i32>,
+    pub user_id: Option<i32>,
+    pub provider: String,
+    /// subject of the provider account, stable across email changes
+    pub provider_id: String,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Proof that the caller is still the account owner. Accounts with a
+/// password confirm it, the others present a fresh ID token of a provider
+/// already linked to them.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Reauthentication {
+    pub password: Option<String>,
+    pub reauth_provider: Option<String>,
+    pub reauth_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LinkIdentity {
+    pub provider: String,
+    /// ID token of the account to link
+    pub token: String,
+    #[serde(flatten)]
+    pub reauth: Reauthentication,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnlinkIdentity {
+    #[serde(flatten)]
+    pub reauth: Reauthentication,
+}
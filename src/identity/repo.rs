@@ -0,0 +1,53 @@
+use super::model::UserIdentity;
+use crate::{auth::model::User, db::DBConn, error::CustomError};
+
+pub struct IdentityRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> IdentityRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        IdentityRepository { db }
+    }
+
+    pub async fn query_user(&self, username: &str) -> Result<User, CustomError> {
+        self.db
+            .fetch_user(username, None)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn insert_identity(&self, identity: &UserIdentity) -> Result<i32, CustomError> {
+        self.db
+            .insert_user_identity(identity)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(err) if err.is_unique_violation() => {
+                    CustomError::IdentityExists
+                }
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn fetch_identities(&self, user_id: i32) -> Result<Vec<UserIdentity>, CustomError> {
+        self.db
+            .fetch_user_identities(user_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    /// IdentityNotFound as well when it is the user's last way to sign in
+    pub async fn delete_identity(&self, user_id: i32, provider: &str) -> Result<(), CustomError> {
+        self.db
+            .delete_user_identity(user_id, provider)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::IdentityNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+}
@@ -0,0 +1,291 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use request_http_parser::parser::Request;
+use serde_json::json;
+
+use super::{
+    model::{LinkIdentity, Reauthentication, UnlinkIdentity, UserIdentity},
+    repo::IdentityRepository,
+};
+use crate::{
+    audit::{
+        model::{AuditEvent, OUTCOME_FAILURE, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    constants::{
+        BAD_REQUEST, FORBIDDEN, INTERNAL_ERROR, NO_CONTENT, NOT_FOUND, OK_RESPONSE, UNAUTHORIZED,
+    },
+    db::DBConn,
+    error::CustomError,
+    idp::IdentityProviders,
+    utils::{ClaimType, Claims, des_from_str, is_password_valid, percent_decode, ser_to_str},
+};
+
+pub const IDENTITIES_PATH: &str = "/protected/me/identities";
+
+pub struct IdentitySvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: IdentityRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
+}
+
+impl<DB> IdentitySvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>) -> Self {
+        IdentitySvc {
+            repository: IdentityRepository::new(pool),
+            audit,
+        }
+    }
+
+    pub async fn get_identities(&self, claims: Option<Claims>) -> (String, String) {
+        let (user_id, _, _) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let identities = match self.repository.fetch_identities(user_id).await {
+            Ok(identities) => identities,
+            Err(error) => {
+                eprintln!("Error identity db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&identities) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// Links the account behind an ID token to the logged-in user
+    pub async fn link_identity(
+        &self,
+        idps: &IdentityProviders,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (user_id, org_id, username) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let link: LinkIdentity = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(link) => link,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if let Err(response) = self
+            .reauthenticate(idps, user_id, &username, &link.reauth)
+            .await
+        {
+            self.record_reauth_failure("identity.link", org_id, user_id, request)
+                .await;
+            return response;
+        }
+        let provider = match idps.get(&link.provider) {
+            Some(provider) => provider,
+            None => return (NOT_FOUND.to_string(), "Unknown provider".to_string()),
+        };
+        let verified = match provider.verify(&link.token).await {
+            Ok(verified) => verified,
+            Err(rejection) => {
+                println!("✗ {} token rejected: {}", provider.name(), rejection);
+                return (BAD_REQUEST.to_string(), rejection.code().to_string());
+            }
+        };
+        let mut identity = UserIdentity {
+            identity_id: None,
+            user_id: Some(user_id),
+            provider: provider.name().to_string(),
+            provider_id: verified.sub,
+            email: verified.email,
+            created_at: Utc::now(),
+        };
+        match self.repository.insert_identity(&identity).await {
+            Ok(identity_id) => identity.identity_id = Some(identity_id),
+            Err(CustomError::IdentityExists) => {
+                return (
+                    BAD_REQUEST.to_string(),
+                    "Provider already linked".to_string(),
+                );
+            }
+            Err(error) => {
+                eprintln!("Error insert identity db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: Some(user_id),
+                target: Some(format!("user:{}", user_id)),
+                metadata: json!({ "provider": identity.provider }),
+                ..AuditEvent::new("identity.link", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        let response_json = match ser_to_str(&identity) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// `DELETE /protected/me/identities/{provider}`, refused for the last
+    /// way the user has to sign in
+    pub async fn unlink_identity(
+        &self,
+        idps: &IdentityProviders,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (user_id, org_id, username) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let provider = match request
+            .path
+            .strip_prefix(IDENTITIES_PATH)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .filter(|provider| !provider.is_empty())
+        {
+            Some(provider) => percent_decode(provider),
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let unlink: UnlinkIdentity = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(unlink) => unlink,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if let Err(response) = self
+            .reauthenticate(idps, user_id, &username, &unlink.reauth)
+            .await
+        {
+            self.record_reauth_failure("identity.unlink", org_id, user_id, request)
+                .await;
+            return response;
+        }
+        match self.repository.delete_identity(user_id, &provider).await {
+            Ok(_) => {
+                self.audit
+                    .record(AuditEvent {
+                        org_id: Some(org_id),
+                        actor_id: Some(user_id),
+                        target: Some(format!("user:{}", user_id)),
+                        metadata: json!({ "provider": provider }),
+                        ..AuditEvent::new("identity.unlink", OUTCOME_SUCCESS, Some(request))
+                    })
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(CustomError::IdentityNotFound) => (
+                BAD_REQUEST.to_string(),
+                "Provider not linked or last sign-in method".to_string(),
+            ),
+            Err(error) => {
+                eprintln!("Error identity db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// A stolen session must not be enough to attach an attacker's account.
+    /// Users with a password confirm it, the others present a fresh ID token
+    /// of a provider already linked to them.
+    async fn reauthenticate(
+        &self,
+        idps: &IdentityProviders,
+        user_id: i32,
+        username: &str,
+        reauth: &Reauthentication,
+    ) -> Result<(), (String, String)> {
+        let user = match self.repository.query_user(username).await {
+            Ok(user) => user,
+            Err(CustomError::UserNotFound) => {
+                return Err((UNAUTHORIZED.to_string(), "".to_string()));
+            }
+            Err(error) => {
+                eprintln!("Error user db: {:#?}", error);
+                return Err((INTERNAL_ERROR.to_string(), "".to_string()));
+            }
+        };
+        if let Some(hash) = &user.password {
+            return match &reauth.password {
+                Some(password) if is_password_valid(password, hash) => Ok(()),
+                _ => Err((UNAUTHORIZED.to_string(), "password invalid".to_string())),
+            };
+        }
+        let (provider, token) = match (&reauth.reauth_provider, &reauth.reauth_token) {
+            (Some(provider), Some(token)) => match idps.get(provider) {
+                Some(provider) => (provider, token),
+                None => return Err((UNAUTHORIZED.to_string(), "".to_string())),
+            },
+            _ => return Err((UNAUTHORIZED.to_string(), "reauth required".to_string())),
+        };
+        let verified = match provider.verify(token).await {
+            Ok(verified) => verified,
+            Err(rejection) => {
+                println!("✗ {} reauth failed: {}", provider.name(), rejection);
+                return Err((UNAUTHORIZED.to_string(), rejection.code().to_string()));
+            }
+        };
+        let identities = match self.repository.fetch_identities(user_id).await {
+            Ok(identities) => identities,
+            Err(error) => {
+                eprintln!("Error identity db: {:#?}", error);
+                return Err((INTERNAL_ERROR.to_string(), "".to_string()));
+            }
+        };
+        if identities.iter().any(|identity| {
+            identity.provider == provider.name() && identity.provider_id == verified.sub
+        }) {
+            Ok(())
+        } else {
+            Err((UNAUTHORIZED.to_string(), "token invalid".to_string()))
+        }
+    }
+
+    async fn record_reauth_failure(
+        &self,
+        action: &str,
+        org_id: i32,
+        user_id: i32,
+        request: &Request,
+    ) {
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: Some(user_id),
+                target: Some(format!("user:{}", user_id)),
+                metadata: json!({ "reason": "reauth_failed" }),
+                ..AuditEvent::new(action, OUTCOME_FAILURE, Some(request))
+            })
+            .await;
+    }
+
+    /// Identities are managed with a login token only
+    fn caller(claims: Option<Claims>) -> Result<(i32, i32, String), (String, String)> {
+        match claims {
+            Some(claims) if claims.claim_type == ClaimType::Login => {
+                match (claims.user_id(), claims.org_id) {
+                    (Some(user_id), Some(org_id)) => Ok((user_id, org_id, claims.username)),
+                    _ => Err((FORBIDDEN.to_string(), "".to_string())),
+                }
+            }
+            _ => Err((FORBIDDEN.to_string(), "".to_string())),
+        }
+    }
+}
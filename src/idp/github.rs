@@ -0,0 +1,271 @@
+//! GitHub sign-in. GitHub issues no ID tokens, the server exchanges the
+//! authorization code itself and reads the user from the REST API.
+
+use super::{VerifyError, fetch_json, oidc::ProviderIdentity};
+use crate::{
+    cfg::CONFIG,
+    utils::{percent_encode, random_token},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rumbo_http_client::HttpMethod;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+pub const GITHUB: &str = "github";
+pub const GITHUB_AUTHORIZE_PATH: &str = "/github/authorize";
+pub const GITHUB_CALLBACK_PATH: &str = "/github/callback";
+
+/// `user:email` lets us read private addresses, most users hide theirs
+const SCOPE: &str = "read:user user:email";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct AccessTokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct GithubUser {
+    id: i64,
+    login: String,
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+/// Values of a started sign-in, the verifier never leaves the server
+pub struct Authorization {
+    pub url: String,
+    pub state: String,
+    pub code_verifier: String,
+}
+
+pub struct GithubProvider {
+    client_id: String,
+    client_secret: String,
+    authorize_url: String,
+    token_url: String,
+    user_url: String,
+    emails_url: String,
+    redirect_uri: String,
+}
+
+impl GithubProvider {
+    /// Configured when `github_client_id` is set
+    pub fn from_config() -> Option<Self> {
+        (!CONFIG.github_client_id.is_empty()).then(|| GithubProvider {
+            client_id: CONFIG.github_client_id.clone(),
+            client_secret: CONFIG.github_client_secret.clone(),
+            authorize_url: CONFIG.github_authorize_url.clone(),
+            token_url: CONFIG.github_token_url.clone(),
+            user_url: CONFIG.github_user_url.clone(),
+            emails_url: CONFIG.github_emails_url.clone(),
+            redirect_uri: CONFIG.github_redirect_uri.clone(),
+        })
+    }
+
+    /// Where to send the browser, with a fresh state and PKCE verifier
+    pub fn authorization(&self) -> Authorization {
+        let state = random_token(32);
+        let code_verifier = random_token(32);
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        let url = format!(
+            "{}?client_id={}&redirect_uri={}&scope={}&state={}&code_challenge={}&code_challenge_method=S256&allow_signup=false",
+            self.authorize_url,
+            percent_encode(&self.client_id),
+            percent_encode(&self.redirect_uri),
+            percent_encode(SCOPE),
+            state,
+            code_challenge,
+        );
+        Authorization {
+            url,
+            state,
+            code_verifier,
+        }
+    }
+
+    /// Exchanges the code and reads the user it belongs to
+    pub async fn identity(
+        &self,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<ProviderIdentity, VerifyError> {
+        let (token, _) = fetch_json::<AccessTokenResponse>(
+            HttpMethod::POST,
+            &self.token_url,
+            vec![("Accept", "application/json".to_string())],
+            Some(json!({
+                "client_id": self.client_id,
+                "client_secret": self.client_secret,
+                "code": code,
+                "redirect_uri": self.redirect_uri,
+                "code_verifier": code_verifier,
+            })),
+        )
+        .await?;
+        // GitHub answers 200 with an error body for bad codes
+        let access_token = match (token.access_token, token.error) {
+            (Some(access_token), None) => access_token,
+            (_, error) => {
+                return Err(format!(
+                    "Code exchange failed: {} {}",
+                    error.unwrap_or_default(),
+                    token.error_description.unwrap_or_default()
+                )
+                .into());
+            }
+        };
+        let headers = || {
+            vec![
+                ("Accept", "application/vnd.github+json".to_string()),
+                ("Authorization", format!("Bearer {}", access_token)),
+            ]
+        };
+        let (user, _) =
+            fetch_json::<GithubUser>(HttpMethod::GET, &self.user_url, headers(), None).await?;
+        // the profile only shows a public address, the verified primary one
+        // comes from the emails endpoint
+        let (emails, _) =
+            fetch_json::<Vec<GithubEmail>>(HttpMethod::GET, &self.emails_url, headers(), None)
+                .await?;
+        let email = emails
+            .into_iter()
+            .find(|email| email.primary && email.verified)
+            .map(|email| email.email);
+        Ok(ProviderIdentity {
+            sub: user.id.to_string(),
+            email_verified: Some(email.is_some()),
+            email,
+            name: user.name.or(Some(user.login)),
+            hosted_domain: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        cfg::load_test_env,
+        idp::stub::{Stub, json_response},
+    };
+
+    const TOKEN_PATH: &str = "/login/oauth/access_token";
+    const USER_PATH: &str = "/user";
+    const EMAILS_PATH: &str = "/user/emails";
+
+    fn provider(stub: &Stub) -> GithubProvider {
+        GithubProvider {
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            authorize_url: stub.url("/login/oauth/authorize"),
+            token_url: stub.url(TOKEN_PATH),
+            user_url: stub.url(USER_PATH),
+            emails_url: stub.url(EMAILS_PATH),
+            redirect_uri: "http://localhost:3000/en/github/callback".to_string(),
+        }
+    }
+
+    async fn github(token: &str, emails: &str) -> Stub {
+        load_test_env();
+        Stub::start(
+            vec![
+                (TOKEN_PATH, json_response(token)),
+                (
+                    USER_PATH,
+                    json_response(r#"{"id":42,"login":"octocat","name":null}"#),
+                ),
+                (EMAILS_PATH, json_response(emails)),
+            ],
+            Duration::ZERO,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn identity_uses_the_verified_primary_email() {
+        let stub = github(
+            r#"{"access_token":"gho_token"}"#,
+            r#"[{"email":"old@example.com","primary":false,"verified":true},
+                {"email":"octo@example.com","primary":true,"verified":true}]"#,
+        )
+        .await;
+        let identity = provider(&stub)
+            .identity("the-code", "the-verifier")
+            .await
+            .unwrap();
+        assert_eq!(identity.sub, "42");
+        assert_eq!(identity.email.as_deref(), Some("octo@example.com"));
+        assert_eq!(identity.email_verified, Some(true));
+        assert_eq!(identity.name.as_deref(), Some("octocat"));
+
+        let exchange = stub.requests(TOKEN_PATH);
+        assert_eq!(exchange.len(), 1);
+        assert!(exchange[0].contains(r#""code":"the-code""#));
+        assert!(exchange[0].contains(r#""code_verifier":"the-verifier""#));
+        for path in [USER_PATH, EMAILS_PATH] {
+            assert!(stub.requests(path)[0].contains("Bearer gho_token"));
+        }
+    }
+
+    #[tokio::test]
+    async fn identity_without_a_verified_primary_email_has_none() {
+        let stub = github(
+            r#"{"access_token":"gho_token"}"#,
+            r#"[{"email":"octo@example.com","primary":true,"verified":false}]"#,
+        )
+        .await;
+        let identity = provider(&stub).identity("code", "verifier").await.unwrap();
+        assert_eq!(identity.email, None);
+        assert_eq!(identity.email_verified, Some(false));
+    }
+
+    #[tokio::test]
+    async fn identity_fails_when_github_refuses_the_code() {
+        let stub = github(
+            r#"{"error":"bad_verification_code","error_description":"The code is incorrect"}"#,
+            "[]",
+        )
+        .await;
+        let error = provider(&stub)
+            .identity("stale", "verifier")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("bad_verification_code"));
+        assert!(stub.requests(USER_PATH).is_empty());
+    }
+
+    #[tokio::test]
+    async fn authorization_sends_state_and_challenge() {
+        let stub = github("{}", "[]").await;
+        let authorization = provider(&stub).authorization();
+        let challenge =
+            URL_SAFE_NO_PAD.encode(Sha256::digest(authorization.code_verifier.as_bytes()));
+        assert!(
+            authorization
+                .url
+                .starts_with(&stub.url("/login/oauth/authorize?"))
+        );
+        assert!(
+            authorization
+                .url
+                .contains(&format!("state={}", authorization.state))
+        );
+        assert!(
+            authorization
+                .url
+                .contains(&format!("code_challenge={}", challenge))
+        );
+        assert_ne!(authorization.state, provider(&stub).authorization().state);
+    }
+}
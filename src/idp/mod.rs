@@ -0,0 +1,229 @@
+//! Upstream identity providers users can sign in with instead of a password.
+//!
+//! Providers come from `identity_providers_file` (a JSON array of
+//! [`ProviderConfig`]). Google stays configured through `google_client_id`
+//! unless the file defines its own `google` entry. GitHub has no ID tokens
+//! and is configured on its own through the `github_*` settings, SAML
+//! through `saml_config_file`.
+
+pub mod github;
+pub mod oidc;
+#[cfg(test)]
+mod stub;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    cfg::CONFIG, constants::GOOGLE, error::CustomError, saml::SamlProvider, utils::des_from_str,
+};
+use github::GithubProvider;
+use oidc::{OidcProvider, ProviderConfig};
+use rumbo_http_client::{HttpClient, HttpMethod};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tokio::time::timeout;
+
+pub type VerifyError = Box<dyn std::error::Error + Send + Sync>;
+
+pub const SIGNIN_PATH: &str = "/signin/";
+pub const REGISTER_PATH: &str = "/register/";
+
+pub struct IdentityProviders {
+    providers: HashMap<String, Arc<OidcProvider>>,
+    pub github: Option<GithubProvider>,
+    pub saml: Option<SamlProvider>,
+}
+
+impl IdentityProviders {
+    pub fn new(configs: Vec<ProviderConfig>) -> Result<Self, CustomError> {
+        let mut providers = HashMap::new();
+        for config in configs {
+            validate_provider(&config).map_err(CustomError::ProviderLoad)?;
+            if config.name == github::GITHUB {
+                return Err(CustomError::ProviderLoad(
+                    "github is configured through the github_* settings".to_string(),
+                ));
+            }
+            let name = config.name.clone();
+            if providers
+                .insert(name.clone(), Arc::new(OidcProvider::new(config)))
+                .is_some()
+            {
+                return Err(CustomError::ProviderLoad(format!(
+                    "Provider {} is defined twice",
+                    name
+                )));
+            }
+        }
+        Ok(IdentityProviders {
+            providers,
+            github: GithubProvider::from_config(),
+            saml: None,
+        })
+    }
+
+    pub fn from_config() -> Result<Self, CustomError> {
+        let mut configs: Vec<ProviderConfig> = vec![];
+        if !CONFIG.identity_providers_file.is_empty() {
+            let content = std::fs::read_to_string(&CONFIG.identity_providers_file)
+                .map_err(|e| CustomError::ProviderLoad(e.to_string()))?;
+            configs =
+                des_from_str(&content).map_err(|e| CustomError::ProviderLoad(e.to_string()))?;
+        }
+        if !CONFIG.google_client_id.is_empty() && !configs.iter().any(|c| c.name == GOOGLE) {
+            configs.push(google());
+        }
+        let mut providers = Self::new(configs)?;
+        providers.saml = SamlProvider::from_config()?;
+        println!("Loaded {} identity providers", providers.providers.len());
+        Ok(providers)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<OidcProvider>> {
+        self.providers.get(name)
+    }
+}
+
+/// Google from the `google_*` settings, `google_client_id` lists the web,
+/// Android and iOS client ids separated by commas
+fn google() -> ProviderConfig {
+    ProviderConfig {
+        name: GOOGLE.to_string(),
+        issuer: "https://accounts.google.com".to_string(),
+        issuer_aliases: vec!["accounts.google.com".to_string()],
+        discovery_url: None,
+        jwks_uri: Some(CONFIG.google_jwks_uri.clone()).filter(|uri| !uri.is_empty()),
+        client_ids: comma_list(&CONFIG.google_client_id),
+        claims: Default::default(),
+        require_verified_email: CONFIG.google_require_verified_email,
+        allowed_hosted_domains: comma_list(&CONFIG.google_allowed_hosted_domains),
+        allowed_email_domains: comma_list(&CONFIG.google_allowed_email_domains),
+    }
+}
+
+fn comma_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Names end up in URLs and in `user_identities.provider` (VARCHAR(30))
+fn validate_provider(config: &ProviderConfig) -> Result<(), String> {
+    if config.name.is_empty()
+        || config.name.len() > 30
+        || !config
+            .name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err(format!("Invalid provider name {:?}", config.name));
+    }
+    if config.client_ids.is_empty() {
+        return Err(format!("Provider {} has no client ids", config.name));
+    }
+    if !config.issuer.starts_with("https://") && !config.issuer.starts_with("http://") {
+        return Err(format!("Provider {} has an invalid issuer", config.name));
+    }
+    Ok(())
+}
+
+/// Calls a provider endpoint that answers JSON, returns the document with
+/// the response's Cache-Control
+async fn fetch_json<T: DeserializeOwned + Serialize>(
+    method: HttpMethod,
+    url: &str,
+    headers: Vec<(&str, String)>,
+    body: Option<Value>,
+) -> Result<(T, Option<String>), VerifyError> {
+    let headers: HashMap<String, String> = headers
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+    // a provider that stops answering must not hold sign-ins forever
+    let response = timeout(
+        Duration::from_secs(CONFIG.idp_http_timeout_secs),
+        HttpClient::fetch(method, url.to_string(), Some(headers), body),
+    )
+    .await
+    .map_err(|_| format!("{} timed out", url))??;
+    if !response.is_success() {
+        return Err(format!("{} answered {}", url, response.status).into());
+    }
+    let body = response.body.as_deref().ok_or("Empty response body")?;
+    let chunked = response
+        .headers
+        .get("transfer-encoding")
+        .is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"));
+    let document = if chunked {
+        des_from_str(&decode_chunked(body)?)?
+    } else {
+        des_from_str(body)?
+    };
+    Ok((document, response.headers.get("cache-control").cloned()))
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body. The client cuts responses
+/// at the first blank line, so the last chunk may miss its final CRLF.
+fn decode_chunked(body: &str) -> Result<String, VerifyError> {
+    let mut rest = body.as_bytes();
+    let mut decoded = Vec::with_capacity(rest.len());
+    while !rest.is_empty() {
+        let line_end = rest
+            .windows(2)
+            .position(|pair| pair == b"\r\n")
+            .unwrap_or(rest.len());
+        // chunk extensions follow the size after a ';'
+        let size_line = std::str::from_utf8(&rest[..line_end])?;
+        let size_hex = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_hex, 16)
+            .map_err(|_| format!("Invalid chunk size {:?}", size_hex))?;
+        if size == 0 {
+            break;
+        }
+        // the size comes from the wire, it may point anywhere
+        let data_start = line_end + 2;
+        let data_end = data_start
+            .checked_add(size)
+            .filter(|data_end| *data_end <= rest.len())
+            .ok_or("Truncated chunk")?;
+        decoded.extend_from_slice(&rest[data_start..data_end]);
+        rest = rest[data_end..]
+            .strip_prefix(b"\r\n")
+            .ok_or("Chunk not terminated by CRLF")?;
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_chunked_joins_chunks() {
+        let body = "5\r\nhello\r\n7;ext=1\r\n, world\r\n0";
+        assert_eq!(decode_chunked(body).unwrap(), "hello, world");
+        assert_eq!(decode_chunked("3\r\n{}\n\r\n0\r\n").unwrap(), "{}\n");
+        assert_eq!(decode_chunked("").unwrap(), "");
+    }
+
+    #[test]
+    fn decode_chunked_rejects_truncated_chunks() {
+        assert!(decode_chunked("a\r\nhello").is_err());
+        assert!(decode_chunked("5\r\nhello").is_err());
+        assert!(decode_chunked("5\r\nhelloX0").is_err());
+        assert!(decode_chunked("5").is_err());
+    }
+
+    #[test]
+    fn decode_chunked_rejects_oversized_chunks() {
+        assert!(decode_chunked("ffffffffffffffff\r\nhello\r\n0").is_err());
+        assert!(decode_chunked("fffffffffffffffe\r\nhello\r\n0").is_err());
+        assert!(decode_chunked("10000000000000000\r\nhello\r\n0").is_err());
+        assert!(decode_chunked("zz\r\nhello\r\n0").is_err());
+    }
+}
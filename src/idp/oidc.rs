@@ -0,0 +1,563 @@
+use super::{VerifyError, fetch_json};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header, errors::ErrorKind};
+use rumbo_http_client::HttpMethod;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Mutex, RwLock};
+
+/// Keys are not refetched for an unknown `kid` more often than this, a
+/// forged header must not turn every sign-in into a JWKS request
+const MIN_REFRESH_SECS: u64 = 60;
+
+/// An OpenID Connect provider whose ID tokens we accept, one entry of
+/// `identity_providers_file`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderConfig {
+    /// path segment of `/signin/{provider}` and the value of `user_identities.provider`
+    pub name: String,
+    pub issuer: String,
+    /// other spellings of the issuer found in tokens, Google also issues
+    /// `accounts.google.com` without a scheme
+    #[serde(default)]
+    pub issuer_aliases: Vec<String>,
+    /// defaults to `{issuer}/.well-known/openid-configuration`
+    #[serde(default)]
+    pub discovery_url: Option<String>,
+    /// skips discovery, also how tests point a provider at a local JWKS stub
+    #[serde(default)]
+    pub jwks_uri: Option<String>,
+    /// accepted audiences, one per app registered with the provider
+    pub client_ids: Vec<String>,
+    #[serde(default)]
+    pub claims: ClaimMapping,
+    /// refuse tokens whose email the provider has not verified
+    #[serde(default)]
+    pub require_verified_email: bool,
+    /// Workspace domains (Google's `hd` claim) allowed to sign in
+    #[serde(default)]
+    pub allowed_hosted_domains: Vec<String>,
+    /// domains of verified emails allowed to sign in. With neither list set
+    /// any account of the provider is accepted.
+    #[serde(default)]
+    pub allowed_email_domains: Vec<String>,
+}
+
+/// Names of the token claims holding the identity, providers disagree on
+/// where the email lives
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClaimMapping {
+    pub subject: String,
+    pub email: String,
+    pub email_verified: String,
+    pub name: String,
+    pub hosted_domain: String,
+}
+
+impl Default for ClaimMapping {
+    fn default() -> Self {
+        ClaimMapping {
+            subject: "sub".to_string(),
+            email: "email".to_string(),
+            email_verified: "email_verified".to_string(),
+            name: "name".to_string(),
+            hosted_domain: "hd".to_string(),
+        }
+    }
+}
+
+/// Identity asserted by a verified ID token
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProviderIdentity {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub hosted_domain: Option<String>,
+}
+
+/// Why a provider token was refused, clients get `code()`
+#[derive(Debug)]
+pub enum TokenRejection {
+    Invalid(VerifyError),
+    Expired,
+    Audience,
+    Issuer,
+    EmailUnverified,
+    DomainNotAllowed,
+    KeysUnavailable(VerifyError),
+}
+
+impl TokenRejection {
+    pub fn code(&self) -> &'static str {
+        match self {
+            TokenRejection::Invalid(_) => "token_invalid",
+            TokenRejection::Expired => "token_expired",
+            TokenRejection::Audience => "audience_not_allowed",
+            TokenRejection::Issuer => "issuer_invalid",
+            TokenRejection::EmailUnverified => "email_unverified",
+            TokenRejection::DomainNotAllowed => "domain_not_allowed",
+            TokenRejection::KeysUnavailable(_) => "provider_unavailable",
+        }
+    }
+}
+
+impl fmt::Display for TokenRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenRejection::Invalid(e) | TokenRejection::KeysUnavailable(e) => {
+                write!(f, "{}: {}", self.code(), e)
+            }
+            _ => write!(f, "{}", self.code()),
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for TokenRejection {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        match e.kind() {
+            ErrorKind::ExpiredSignature => TokenRejection::Expired,
+            ErrorKind::InvalidAudience => TokenRejection::Audience,
+            ErrorKind::InvalidIssuer => TokenRejection::Issuer,
+            _ => TokenRejection::Invalid(Box::new(e)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DiscoveryDocument {
+    issuer: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// Providers publish EC keys next to RSA ones, those have no `n`/`e`
+#[derive(Debug, Deserialize, Serialize)]
+struct Jwk {
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Clone)]
+struct CachedCerts {
+    jwks_uri: String,
+    certs: HashMap<String, (String, String)>,
+    fetched_at: SystemTime,
+    expires_at: SystemTime,
+}
+
+pub struct OidcProvider {
+    config: ProviderConfig,
+    cert_cache: RwLock<Option<CachedCerts>>,
+    /// held while refetching keys, concurrent sign-ins wait for that one
+    /// request instead of sending their own
+    refresh_lock: Mutex<()>,
+}
+
+impl OidcProvider {
+    pub fn new(config: ProviderConfig) -> Self {
+        OidcProvider {
+            config,
+            cert_cache: RwLock::new(None),
+            refresh_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn discovery_url(&self) -> String {
+        match &self.config.discovery_url {
+            Some(url) => url.clone(),
+            None => format!(
+                "{}/.well-known/openid-configuration",
+                self.config.issuer.trim_end_matches('/')
+            ),
+        }
+    }
+
+    /// The provider's signing keys by `kid`, cached for the max-age of the
+    /// JWKS response. `refresh` refetches them when a token names a key we
+    /// have not seen, providers rotate keys without notice. When a refetch
+    /// fails the previous keys keep being served.
+    async fn fetch_public_keys(
+        &self,
+        refresh: bool,
+    ) -> Result<HashMap<String, (String, String)>, VerifyError> {
+        if let Some(certs) = self.cached_keys(refresh).await {
+            return Ok(certs);
+        }
+        let _refreshing = self.refresh_lock.lock().await;
+        // the request we waited for may have brought the keys already
+        if let Some(certs) = self.cached_keys(refresh).await {
+            return Ok(certs);
+        }
+        match self.load_keys().await {
+            Ok(certs) => Ok(certs),
+            Err(e) => {
+                let mut cache = self.cert_cache.write().await;
+                match cache.as_mut() {
+                    Some(cached) => {
+                        eprintln!(
+                            "Error refreshing {} keys, serving stale ones: {}",
+                            self.config.name, e
+                        );
+                        // retry after a pause rather than on every sign-in
+                        let now = SystemTime::now();
+                        cached.fetched_at = now;
+                        cached.expires_at = now + Duration::from_secs(MIN_REFRESH_SECS);
+                        Ok(cached.certs.clone())
+                    }
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    async fn cached_keys(&self, refresh: bool) -> Option<HashMap<String, (String, String)>> {
+        let cache = self.cert_cache.read().await;
+        let cached = cache.as_ref()?;
+        let now = SystemTime::now();
+        let recently_fetched = now < cached.fetched_at + Duration::from_secs(MIN_REFRESH_SECS);
+        (now < cached.expires_at && (!refresh || recently_fetched)).then(|| cached.certs.clone())
+    }
+
+    async fn load_keys(&self) -> Result<HashMap<String, (String, String)>, VerifyError> {
+        let known_uri = match &self.config.jwks_uri {
+            Some(jwks_uri) => Some(jwks_uri.clone()),
+            None => self
+                .cert_cache
+                .read()
+                .await
+                .as_ref()
+                .map(|cached| cached.jwks_uri.clone()),
+        };
+        let jwks_uri = match known_uri {
+            Some(jwks_uri) => jwks_uri,
+            None => {
+                let (discovery, _) = fetch_json::<DiscoveryDocument>(
+                    HttpMethod::GET,
+                    &self.discovery_url(),
+                    vec![],
+                    None,
+                )
+                .await?;
+                if discovery.issuer != self.config.issuer {
+                    return Err(format!(
+                        "Discovery issuer {} does not match {}",
+                        discovery.issuer, self.config.issuer
+                    )
+                    .into());
+                }
+                discovery.jwks_uri
+            }
+        };
+
+        let (jwk_set, cache_control) =
+            fetch_json::<JwkSet>(HttpMethod::GET, &jwks_uri, vec![], None).await?;
+        let ttl_seconds = extract_ttl(cache_control.as_deref().unwrap_or("max-age=3600"));
+        let now = SystemTime::now();
+        let certs: HashMap<String, (String, String)> = jwk_set
+            .keys
+            .into_iter()
+            .filter_map(|jwk| Some((jwk.kid?, (jwk.n?, jwk.e?))))
+            .collect();
+        if certs.is_empty() {
+            return Err(format!("{} has no RSA keys", jwks_uri).into());
+        }
+
+        let mut cache = self.cert_cache.write().await;
+        *cache = Some(CachedCerts {
+            jwks_uri,
+            certs: certs.clone(),
+            fetched_at: now,
+            expires_at: now + Duration::from_secs(ttl_seconds as u64),
+        });
+        Ok(certs)
+    }
+
+    /// Verifies an ID token issued by this provider to one of our clients
+    /// and checks the account against the provider's email and domain rules
+    pub async fn verify(&self, token: &str) -> Result<ProviderIdentity, TokenRejection> {
+        let header = decode_header(token)?;
+        if !matches!(
+            header.alg,
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+        ) {
+            return Err(TokenRejection::Invalid(
+                "Unsupported token algorithm".into(),
+            ));
+        }
+        let kid = header.kid.ok_or(TokenRejection::Invalid(
+            "Missing 'kid' in token header".into(),
+        ))?;
+
+        let mut certs = self
+            .fetch_public_keys(false)
+            .await
+            .map_err(TokenRejection::KeysUnavailable)?;
+        if !certs.contains_key(&kid) {
+            certs = self
+                .fetch_public_keys(true)
+                .await
+                .map_err(TokenRejection::KeysUnavailable)?;
+        }
+        let (n, e) = certs
+            .get(&kid)
+            .ok_or(TokenRejection::Invalid("Certificate kid not found".into()))?;
+        let decoding_key = DecodingKey::from_rsa_components(n, e)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&self.config.client_ids);
+        let mut issuers = vec![self.config.issuer.clone()];
+        issuers.extend(self.config.issuer_aliases.iter().cloned());
+        validation.set_issuer(&issuers);
+
+        let claims = decode::<Map<String, Value>>(token, &decoding_key, &validation)?.claims;
+        let mapping = &self.config.claims;
+        let string_claim = |name: &str| claims.get(name).and_then(Value::as_str).map(String::from);
+        let identity = ProviderIdentity {
+            sub: string_claim(&mapping.subject)
+                .ok_or(TokenRejection::Invalid("Missing subject claim".into()))?,
+            email: string_claim(&mapping.email),
+            // Apple sends the flag as the string "true"
+            email_verified: match claims.get(&mapping.email_verified) {
+                Some(Value::Bool(verified)) => Some(*verified),
+                Some(Value::String(verified)) => Some(verified == "true"),
+                _ => None,
+            },
+            name: string_claim(&mapping.name),
+            hosted_domain: string_claim(&mapping.hosted_domain),
+        };
+        self.check_account(&identity)?;
+        Ok(identity)
+    }
+
+    /// A hosted domain alone is enough, Google only sets `hd` for Workspace
+    /// accounts. An email domain only counts once the email is verified.
+    fn check_account(&self, identity: &ProviderIdentity) -> Result<(), TokenRejection> {
+        let email_verified = identity.email_verified == Some(true);
+        if self.config.require_verified_email && !email_verified {
+            return Err(TokenRejection::EmailUnverified);
+        }
+        let hosted_domains = &self.config.allowed_hosted_domains;
+        let email_domains = &self.config.allowed_email_domains;
+        if hosted_domains.is_empty() && email_domains.is_empty() {
+            return Ok(());
+        }
+        let allowed = |domains: &[String], domain: &str| {
+            domains
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+        };
+        let hosted_allowed = identity
+            .hosted_domain
+            .as_deref()
+            .is_some_and(|hd| allowed(hosted_domains, hd));
+        let email_allowed = email_verified
+            && identity
+                .email
+                .as_deref()
+                .and_then(|email| email.rsplit_once('@'))
+                .is_some_and(|(_, domain)| allowed(email_domains, domain));
+        if hosted_allowed || email_allowed {
+            Ok(())
+        } else {
+            Err(TokenRejection::DomainNotAllowed)
+        }
+    }
+}
+
+/// Extract TTL from Cache-Control header
+fn extract_ttl(cache_control: &str) -> usize {
+    cache_control
+        .split(',')
+        .find_map(|part| {
+            let part = part.trim();
+            if part.starts_with("max-age=") {
+                part.strip_prefix("max-age=")
+                    .and_then(|s| s.parse::<usize>().ok())
+            } else {
+                None
+            }
+        })
+        .unwrap_or(3600)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use rsa::{RsaPublicKey, pkcs8::DecodePublicKey, traits::PublicKeyParts};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        cfg::{CONFIG, load_test_env},
+        idp::stub::{Stub, json_response},
+    };
+
+    const JWKS_PATH: &str = "/certs";
+    const ISSUER: &str = "https://idp.example.com";
+    const FAILURE: &str = "HTTP/1.1 500 Internal Server Error\r\nConnection: close\r\n\r\n";
+
+    fn provider(stub: &Stub) -> Arc<OidcProvider> {
+        Arc::new(OidcProvider::new(ProviderConfig {
+            name: "test".to_string(),
+            issuer: ISSUER.to_string(),
+            issuer_aliases: vec![],
+            discovery_url: None,
+            jwks_uri: Some(stub.url(JWKS_PATH)),
+            client_ids: vec!["client".to_string()],
+            claims: ClaimMapping::default(),
+            require_verified_email: false,
+            allowed_hosted_domains: vec![],
+            allowed_email_domains: vec![],
+        }))
+    }
+
+    /// JWKS publishing the test signing key under `kid`
+    fn jwks(kid: &str) -> String {
+        let pem = CONFIG.jwt_public_key.replace("\\n", "\n");
+        let key = RsaPublicKey::from_public_key_pem(&pem).unwrap();
+        json!({
+            "keys": [{
+                "kid": kid,
+                "kty": "RSA",
+                "n": URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+                "e": URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+            }]
+        })
+        .to_string()
+    }
+
+    fn id_token(kid: &str) -> String {
+        let pem = CONFIG.jwt_private_key.replace("\\n", "\n");
+        let header = Header {
+            kid: Some(kid.to_string()),
+            ..Header::new(Algorithm::RS256)
+        };
+        let exp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        let claims = json!({ "iss": ISSUER, "aud": "client", "sub": "user-1", "exp": exp });
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_rsa_pem(pem.as_bytes()).unwrap(),
+        )
+        .unwrap()
+    }
+
+    async fn seed(provider: &OidcProvider, kid: &str, fetched_ago: u64, expires_in: i64) {
+        let now = SystemTime::now();
+        let expires_at = if expires_in >= 0 {
+            now + Duration::from_secs(expires_in as u64)
+        } else {
+            now - Duration::from_secs(expires_in.unsigned_abs())
+        };
+        *provider.cert_cache.write().await = Some(CachedCerts {
+            jwks_uri: String::new(),
+            certs: HashMap::from([(kid.to_string(), ("n".to_string(), "e".to_string()))]),
+            fetched_at: now - Duration::from_secs(fetched_ago),
+            expires_at,
+        });
+    }
+
+    #[tokio::test]
+    async fn concurrent_sign_ins_share_one_jwks_request() {
+        load_test_env();
+        let stub = Stub::start(
+            vec![(JWKS_PATH, json_response(&jwks("k1")))],
+            Duration::from_millis(200),
+        )
+        .await;
+        let provider = provider(&stub);
+        let lookups: Vec<_> = (0..8)
+            .map(|_| {
+                let provider = provider.clone();
+                tokio::spawn(async move { provider.fetch_public_keys(false).await.unwrap() })
+            })
+            .collect();
+        for lookup in lookups {
+            assert!(lookup.await.unwrap().contains_key("k1"));
+        }
+        assert_eq!(stub.requests(JWKS_PATH).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_refetches_rotated_keys() {
+        load_test_env();
+        let stub = Stub::start(
+            vec![(JWKS_PATH, json_response(&jwks("k2")))],
+            Duration::ZERO,
+        )
+        .await;
+        let provider = provider(&stub);
+        seed(&provider, "k1", MIN_REFRESH_SECS * 2, 3600).await;
+
+        let identity = provider.verify(&id_token("k2")).await.unwrap();
+        assert_eq!(identity.sub, "user-1");
+        assert_eq!(stub.requests(JWKS_PATH).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_does_not_refetch_recent_keys() {
+        load_test_env();
+        let stub = Stub::start(
+            vec![(JWKS_PATH, json_response(&jwks("k2")))],
+            Duration::ZERO,
+        )
+        .await;
+        let provider = provider(&stub);
+        seed(&provider, "k1", 0, 3600).await;
+
+        assert!(matches!(
+            provider.verify(&id_token("k2")).await,
+            Err(TokenRejection::Invalid(_))
+        ));
+        assert!(stub.requests(JWKS_PATH).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_serves_stale_keys_for_a_while() {
+        load_test_env();
+        let stub = Stub::start(vec![(JWKS_PATH, FAILURE.to_string())], Duration::ZERO).await;
+        let provider = provider(&stub);
+        seed(&provider, "k1", 7200, -1).await;
+
+        let certs = provider.fetch_public_keys(false).await.unwrap();
+        assert!(certs.contains_key("k1"));
+        // the next sign-ins get the stale keys without asking again
+        let certs = provider.fetch_public_keys(true).await.unwrap();
+        assert!(certs.contains_key("k1"));
+        assert_eq!(stub.requests(JWKS_PATH).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_first_fetch_is_an_error() {
+        load_test_env();
+        let stub = Stub::start(vec![(JWKS_PATH, FAILURE.to_string())], Duration::ZERO).await;
+        assert!(provider(&stub).fetch_public_keys(false).await.is_err());
+    }
+
+    #[test]
+    fn extract_ttl_reads_max_age() {
+        assert_eq!(extract_ttl("public, max-age=19770, must-revalidate"), 19770);
+        assert_eq!(extract_ttl("no-store"), 3600);
+        assert_eq!(extract_ttl("max-age=soon"), 3600);
+    }
+}
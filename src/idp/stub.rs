@@ -0,0 +1,109 @@
+//! Local stand-in for an upstream provider in unit tests. Answers canned
+//! responses by path and remembers every request it served.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+pub struct Stub {
+    pub base_url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl Stub {
+    /// Serves `routes` (path, raw response) on a free local port, each
+    /// response held back by `delay`
+    pub async fn start(routes: Vec<(&str, String)>, delay: Duration) -> Stub {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let routes: Arc<HashMap<String, String>> = Arc::new(
+            routes
+                .into_iter()
+                .map(|(path, response)| (path.to_string(), response))
+                .collect(),
+        );
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let served = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let routes = routes.clone();
+                let served = served.clone();
+                tokio::spawn(async move {
+                    let request = read_request(&mut stream).await;
+                    let path = request
+                        .split_whitespace()
+                        .nth(1)
+                        .unwrap_or_default()
+                        .to_string();
+                    served.lock().unwrap().push(request);
+                    tokio::time::sleep(delay).await;
+                    let response = routes
+                        .get(&path)
+                        .cloned()
+                        .unwrap_or_else(|| "HTTP/1.1 404 Not Found\r\n\r\n".to_string());
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        Stub { base_url, requests }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Requests received for `path`, head and body
+    pub fn requests(&self, path: &str) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.split_whitespace().nth(1) == Some(path))
+            .cloned()
+            .collect()
+    }
+}
+
+/// A `200 OK` carrying `body` as JSON
+pub fn json_response(body: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+async fn read_request(stream: &mut tokio::net::TcpStream) -> String {
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let size = stream.read(&mut buffer).await.unwrap_or(0);
+        if size == 0 {
+            break;
+        }
+        data.extend_from_slice(&buffer[..size]);
+        let text = String::from_utf8_lossy(&data);
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let length = head
+                .lines()
+                .find_map(|line| {
+                    let (key, value) = line.split_once(':')?;
+                    key.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse::<usize>().ok())?
+                })
+                .unwrap_or(0);
+            if body.len() >= length {
+                break;
+            }
+        }
+    }
+    String::from_utf8_lossy(&data).into_owned()
+}
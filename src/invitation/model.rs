@@ -0,0 +1,25 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct Invitation {
+    pub invitation_id: Option<i64>,
+    pub org_id: i32,
+    pub email: String,
+    /// role the invitee registers with
+    pub role_id: i32,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub invited_by: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub accepted_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateInvitation {
+    pub email: String,
+    pub role_id: i32,
+    pub expires_at: DateTime<Utc>,
+}
@@ -0,0 +1,44 @@
+use super::model::Invitation;
+use crate::{db::DBConn, error::CustomError};
+
+pub struct InvitationRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> InvitationRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        InvitationRepository { db }
+    }
+
+    pub async fn insert_invitation(&self, invitation: &Invitation) -> Result<i64, CustomError> {
+        self.db
+            .insert_invitation(invitation)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::RoleNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn fetch_invitations(&self, org_id: i32) -> Result<Vec<Invitation>, CustomError> {
+        self.db
+            .fetch_invitations(org_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn revoke_invitation(
+        &self,
+        org_id: i32,
+        invitation_id: i64,
+    ) -> Result<(), CustomError> {
+        self.db
+            .revoke_invitation(org_id, invitation_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::InvitationNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+}
@@ -0,0 +1,223 @@
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+use request_http_parser::parser::Request;
+use serde_json::json;
+
+use super::{
+    model::{CreateInvitation, Invitation},
+    repo::InvitationRepository,
+};
+use crate::{
+    audit::{
+        model::{AuditEvent, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    cfg::CONFIG,
+    constants::{
+        BAD_REQUEST, FORBIDDEN, INTERNAL_ERROR, MANAGE_USERS, NO_CONTENT, NOT_FOUND, OK_RESPONSE,
+    },
+    db::DBConn,
+    error::CustomError,
+    mail::{InvitationAttribs, InvitationMail, Mail},
+    rolepermissions::service::RolePermissionSvc,
+    utils::{Claims, des_from_str, random_token, ser_to_str, sha256_hex},
+};
+
+pub const INVITATIONS_PATH: &str = "/protected/orgs/invitations";
+
+/// Longest an invitation may stay open
+const MAX_INVITATION_DAYS: i64 = 30;
+
+pub struct InvitationSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: InvitationRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
+}
+
+impl<DB> InvitationSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>) -> Self {
+        InvitationSvc {
+            repository: InvitationRepository::new(pool),
+            audit,
+        }
+    }
+
+    pub async fn get_invitations(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let org_id = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok((_, org_id)) => org_id,
+            Err(response) => return response,
+        };
+        let invitations = match self.repository.fetch_invitations(org_id).await {
+            Ok(invitations) => invitations,
+            Err(error) => {
+                eprintln!("Error invitation db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&invitations) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// Invites `email` into the caller's organization with a role, the link
+    /// goes out by mail and registering through it grants the role
+    pub async fn create_invitation(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id) = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+        let req_invitation: CreateInvitation = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(req_invitation) => req_invitation,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let email = req_invitation.email.trim().to_lowercase();
+        if !email.contains('@') || email.len() > 255 {
+            return (BAD_REQUEST.to_string(), "Invalid email".to_string());
+        }
+        let now = Utc::now();
+        if req_invitation.expires_at <= now
+            || req_invitation.expires_at > now + Duration::days(MAX_INVITATION_DAYS)
+        {
+            return (BAD_REQUEST.to_string(), "Invalid expiry".to_string());
+        }
+        // an invitation hands out no more than the inviter holds
+        match rp_svc
+            .permissions_beyond(&claims, request, org_id, req_invitation.role_id)
+            .await
+        {
+            Ok(beyond) => {
+                if let Some(permission) = beyond.first() {
+                    return (
+                        FORBIDDEN.to_string(),
+                        format!("Permission {} not held", permission),
+                    );
+                }
+            }
+            Err(error) => {
+                eprintln!("Error role permission db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+
+        let token = random_token(32);
+        let mut invitation = Invitation {
+            invitation_id: None,
+            org_id,
+            email,
+            role_id: req_invitation.role_id,
+            token_hash: sha256_hex(&token),
+            invited_by: claims.user_id(),
+            created_at: now,
+            expires_at: req_invitation.expires_at,
+            accepted_at: None,
+            revoked_at: None,
+        };
+        match self.repository.insert_invitation(&invitation).await {
+            Ok(invitation_id) => invitation.invitation_id = Some(invitation_id),
+            Err(CustomError::RoleNotFound) => {
+                return (NOT_FOUND.to_string(), CustomError::RoleNotFound.to_string());
+            }
+            Err(error) => {
+                eprintln!("Error insert invitation db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        let invitation_mail = InvitationMail {
+            recipient: invitation.email.clone(),
+            addresser: String::from("noreply@koois.id"),
+            attribs: InvitationAttribs {
+                invitation_link: format!("{}?invitation={}", CONFIG.invitation_uri, token),
+            },
+        };
+        let _ = Mail::send_email(invitation_mail).await;
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: claims.user_id(),
+                target: Some(format!(
+                    "invitation:{}",
+                    invitation.invitation_id.unwrap_or_default()
+                )),
+                metadata: json!({ "email": invitation.email, "role_id": invitation.role_id }),
+                ..AuditEvent::new("invitation.create", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        let response_json = match ser_to_str(&invitation) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// `DELETE /protected/orgs/invitations/{id}`, for invitations not
+    /// accepted yet
+    pub async fn revoke_invitation(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id) = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+        let invitation_id = match request
+            .path
+            .strip_prefix(INVITATIONS_PATH)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .and_then(|id| id.parse::<i64>().ok())
+        {
+            Some(invitation_id) => invitation_id,
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        match self
+            .repository
+            .revoke_invitation(org_id, invitation_id)
+            .await
+        {
+            Ok(_) => {
+                self.audit
+                    .record(AuditEvent {
+                        org_id: Some(org_id),
+                        actor_id: claims.user_id(),
+                        target: Some(format!("invitation:{}", invitation_id)),
+                        ..AuditEvent::new("invitation.revoke", OUTCOME_SUCCESS, Some(request))
+                    })
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(CustomError::InvitationNotFound) => (NOT_FOUND.to_string(), "".to_string()),
+            Err(error) => {
+                eprintln!("Error invitation db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+}
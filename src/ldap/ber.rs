@@ -0,0 +1,441 @@
+//! The few LDAPv3 messages (RFC 4511) a search-then-bind needs, BER encoded
+//! by hand. Only definite lengths occur in LDAP.
+
+use super::LdapError;
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const ENUMERATED: u8 = 0x0a;
+const BOOLEAN: u8 = 0x01;
+
+const BIND_REQUEST: u8 = 0x60;
+pub const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SEARCH_REQUEST: u8 = 0x63;
+pub const SEARCH_RESULT_ENTRY: u8 = 0x64;
+pub const SEARCH_RESULT_DONE: u8 = 0x65;
+/// `simple` choice of AuthenticationChoice
+const AUTH_SIMPLE: u8 = 0x80;
+const FILTER_AND: u8 = 0xa0;
+const FILTER_EQUALITY: u8 = 0xa3;
+
+const SCOPE_SUBTREE: u8 = 2;
+const NEVER_DEREF_ALIASES: u8 = 0;
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn integer(tag: u8, value: u32) -> Vec<u8> {
+    let mut bytes: Vec<u8> = value
+        .to_be_bytes()
+        .into_iter()
+        .skip_while(|byte| *byte == 0)
+        .collect();
+    // a leading high bit would make the value negative
+    if bytes.first().is_none_or(|byte| byte & 0x80 != 0) {
+        bytes.insert(0, 0);
+    }
+    tlv(tag, &bytes)
+}
+
+fn octets(tag: u8, value: &str) -> Vec<u8> {
+    tlv(tag, value.as_bytes())
+}
+
+fn message(message_id: u32, protocol_op: Vec<u8>) -> Vec<u8> {
+    let mut content = integer(INTEGER, message_id);
+    content.extend(protocol_op);
+    tlv(SEQUENCE, &content)
+}
+
+pub fn bind_request(message_id: u32, dn: &str, password: &str) -> Vec<u8> {
+    let mut content = integer(INTEGER, 3);
+    content.extend(octets(OCTET_STRING, dn));
+    content.extend(octets(AUTH_SIMPLE, password));
+    message(message_id, tlv(BIND_REQUEST, &content))
+}
+
+pub fn unbind_request(message_id: u32) -> Vec<u8> {
+    message(message_id, tlv(UNBIND_REQUEST, &[]))
+}
+
+/// Subtree search for `(&(objectClass=<object_class>)(<attribute>=<value>))`.
+/// The value travels as raw octets, no filter escaping is involved.
+pub fn search_request(
+    message_id: u32,
+    base_dn: &str,
+    object_class: &str,
+    attribute: &str,
+    value: &str,
+    time_limit_secs: u32,
+    attributes: &[&str],
+) -> Vec<u8> {
+    let equality = |attribute: &str, value: &str| {
+        let mut assertion = octets(OCTET_STRING, attribute);
+        assertion.extend(octets(OCTET_STRING, value));
+        tlv(FILTER_EQUALITY, &assertion)
+    };
+    let mut filter = equality("objectClass", object_class);
+    filter.extend(equality(attribute, value));
+
+    let mut content = octets(OCTET_STRING, base_dn);
+    content.extend(tlv(ENUMERATED, &[SCOPE_SUBTREE]));
+    content.extend(tlv(ENUMERATED, &[NEVER_DEREF_ALIASES]));
+    // two results are enough to tell an ambiguous login
+    content.extend(integer(INTEGER, 2));
+    content.extend(integer(INTEGER, time_limit_secs));
+    content.extend(tlv(BOOLEAN, &[0]));
+    content.extend(tlv(FILTER_AND, &filter));
+    let attribute_list: Vec<u8> = attributes
+        .iter()
+        .flat_map(|attribute| octets(OCTET_STRING, attribute))
+        .collect();
+    content.extend(tlv(SEQUENCE, &attribute_list));
+    message(message_id, tlv(SEARCH_REQUEST, &content))
+}
+
+/// Length of the element starting at `header`, with the size of its tag and
+/// length octets. None while more bytes are needed.
+pub fn element_len(header: &[u8]) -> Result<Option<(usize, usize)>, LdapError> {
+    let Some(&first) = header.get(1) else {
+        return Ok(None);
+    };
+    if first < 0x80 {
+        return Ok(Some((2, first as usize)));
+    }
+    let count = (first & 0x7f) as usize;
+    if count == 0 || count > 4 {
+        return Err(LdapError::Protocol("unsupported length"));
+    }
+    match header.get(2..2 + count) {
+        Some(bytes) => Ok(Some((
+            2 + count,
+            bytes
+                .iter()
+                .fold(0usize, |len, byte| (len << 8) | *byte as usize),
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Reads elements one after another out of a constructed value
+pub struct Reader<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { rest: bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
+
+    /// Next element as `(tag, content)`
+    pub fn next_element(&mut self) -> Result<(u8, &'a [u8]), LdapError> {
+        let (header_len, len) =
+            element_len(self.rest)?.ok_or(LdapError::Protocol("truncated element"))?;
+        let tag = self.rest[0];
+        let content = self
+            .rest
+            .get(header_len..header_len + len)
+            .ok_or(LdapError::Protocol("truncated element"))?;
+        self.rest = &self.rest[header_len + len..];
+        Ok((tag, content))
+    }
+
+    pub fn expect(&mut self, tag: u8) -> Result<&'a [u8], LdapError> {
+        match self.next_element()? {
+            (found, content) if found == tag => Ok(content),
+            _ => Err(LdapError::Protocol("unexpected element")),
+        }
+    }
+
+    pub fn integer(&mut self, tag: u8) -> Result<u32, LdapError> {
+        let content = self.expect(tag)?;
+        if content.is_empty() || content.len() > 4 {
+            return Err(LdapError::Protocol("unsupported integer"));
+        }
+        Ok(content
+            .iter()
+            .fold(0u32, |value, byte| (value << 8) | *byte as u32))
+    }
+}
+
+/// `(message_id, protocol op tag, protocol op content)` of a whole message
+pub fn parse_message(bytes: &[u8]) -> Result<(u32, u8, &[u8]), LdapError> {
+    let mut message = Reader::new(Reader::new(bytes).expect(SEQUENCE)?);
+    let message_id = message.integer(INTEGER)?;
+    let (tag, content) = message.next_element()?;
+    Ok((message_id, tag, content))
+}
+
+/// resultCode and diagnosticMessage of an LDAPResult
+pub fn parse_result(content: &[u8]) -> Result<(u32, String), LdapError> {
+    let mut result = Reader::new(content);
+    let code = result.integer(ENUMERATED)?;
+    result.expect(OCTET_STRING)?;
+    let diagnostic = String::from_utf8_lossy(result.expect(OCTET_STRING)?).into_owned();
+    Ok((code, diagnostic))
+}
+
+/// DN of an entry and its attributes with their values
+pub type EntryParts = (String, Vec<(String, Vec<String>)>);
+
+/// DN and attributes of a SearchResultEntry, values that are not UTF-8
+/// (AD's `objectGUID`) come back hex encoded
+pub fn parse_entry(content: &[u8]) -> Result<EntryParts, LdapError> {
+    let mut entry = Reader::new(content);
+    let dn = String::from_utf8_lossy(entry.expect(OCTET_STRING)?).into_owned();
+    let mut attributes = Reader::new(entry.expect(SEQUENCE)?);
+    let mut parsed = vec![];
+    while !attributes.is_empty() {
+        let mut attribute = Reader::new(attributes.expect(SEQUENCE)?);
+        let name = String::from_utf8_lossy(attribute.expect(OCTET_STRING)?).into_owned();
+        let mut values = Reader::new(attribute.expect(SET)?);
+        let mut parsed_values = vec![];
+        while !values.is_empty() {
+            let value = values.expect(OCTET_STRING)?;
+            parsed_values.push(match std::str::from_utf8(value) {
+                Ok(value) => value.to_string(),
+                Err(_) => hex::encode(value),
+            });
+        }
+        parsed.push((name, parsed_values));
+    }
+    Ok((dn, parsed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Elements of a constructed value, in order
+    fn elements(content: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut reader = Reader::new(content);
+        let mut elements = vec![];
+        while !reader.is_empty() {
+            elements.push(reader.next_element().unwrap());
+        }
+        elements
+    }
+
+    #[test]
+    fn lengths_use_the_short_and_long_forms() {
+        for (len, header) in [
+            (0, vec![OCTET_STRING, 0x00]),
+            (0x7f, vec![OCTET_STRING, 0x7f]),
+            (0x80, vec![OCTET_STRING, 0x81, 0x80]),
+            (0xff, vec![OCTET_STRING, 0x81, 0xff]),
+            (0x100, vec![OCTET_STRING, 0x82, 0x01, 0x00]),
+            (0x1_0000, vec![OCTET_STRING, 0x83, 0x01, 0x00, 0x00]),
+        ] {
+            let encoded = tlv(OCTET_STRING, &vec![b'a'; len]);
+            assert_eq!(encoded[..header.len()], header[..]);
+            assert_eq!(
+                element_len(&encoded).unwrap(),
+                Some((header.len(), len)),
+                "length {}",
+                len
+            );
+            let (tag, content) = Reader::new(&encoded).next_element().unwrap();
+            assert_eq!((tag, content.len()), (OCTET_STRING, len));
+        }
+    }
+
+    #[test]
+    fn element_len_waits_for_the_whole_header() {
+        assert_eq!(element_len(&[SEQUENCE]).unwrap(), None);
+        assert_eq!(element_len(&[SEQUENCE, 0x82, 0x01]).unwrap(), None);
+        // indefinite lengths and lengths past 4 octets are refused
+        assert!(element_len(&[SEQUENCE, 0x80]).is_err());
+        assert!(element_len(&[SEQUENCE, 0x85, 1, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn reader_refuses_truncated_and_unexpected_elements() {
+        assert!(
+            Reader::new(&[OCTET_STRING, 0x05, b'a'])
+                .next_element()
+                .is_err()
+        );
+        assert!(Reader::new(&[OCTET_STRING, 0x81]).next_element().is_err());
+        assert!(
+            Reader::new(&octets(OCTET_STRING, "a"))
+                .expect(SEQUENCE)
+                .is_err()
+        );
+        assert!(Reader::new(&tlv(INTEGER, &[])).integer(INTEGER).is_err());
+        assert!(
+            Reader::new(&tlv(INTEGER, &[1, 0, 0, 0, 0]))
+                .integer(INTEGER)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn integers_round_trip_without_turning_negative() {
+        for (value, encoded) in [
+            (0, vec![INTEGER, 1, 0x00]),
+            (3, vec![INTEGER, 1, 0x03]),
+            (0x7f, vec![INTEGER, 1, 0x7f]),
+            (0x80, vec![INTEGER, 2, 0x00, 0x80]),
+            (0x1234, vec![INTEGER, 2, 0x12, 0x34]),
+            (u32::MAX, vec![INTEGER, 5, 0x00, 0xff, 0xff, 0xff, 0xff]),
+        ] {
+            assert_eq!(integer(INTEGER, value), encoded);
+            let decoded = Reader::new(&encoded).integer(INTEGER);
+            // five content octets are past what the reader takes
+            if encoded[1] <= 4 {
+                assert_eq!(decoded.unwrap(), value);
+            } else {
+                assert!(decoded.is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn bind_request_round_trips() {
+        let request = bind_request(7, "cn=svc,dc=example,dc=com", "s3cret");
+        let (message_id, tag, content) = parse_message(&request).unwrap();
+        assert_eq!((message_id, tag), (7, BIND_REQUEST));
+        let mut bind = Reader::new(content);
+        assert_eq!(bind.integer(INTEGER).unwrap(), 3);
+        assert_eq!(
+            bind.expect(OCTET_STRING).unwrap(),
+            b"cn=svc,dc=example,dc=com"
+        );
+        assert_eq!(bind.expect(AUTH_SIMPLE).unwrap(), b"s3cret");
+        assert!(bind.is_empty());
+    }
+
+    #[test]
+    fn unbind_request_round_trips() {
+        let request = unbind_request(300);
+        let (message_id, tag, content) = parse_message(&request).unwrap();
+        assert_eq!((message_id, tag, content), (300, UNBIND_REQUEST, &[][..]));
+    }
+
+    #[test]
+    fn search_request_round_trips() {
+        let value = "a*)(uid=*";
+        let request = search_request(
+            2,
+            "dc=example,dc=com",
+            "person",
+            "uid",
+            value,
+            5,
+            &["mail", "memberOf"],
+        );
+        let (message_id, tag, content) = parse_message(&request).unwrap();
+        assert_eq!((message_id, tag), (2, SEARCH_REQUEST));
+        let mut search = Reader::new(content);
+        assert_eq!(search.expect(OCTET_STRING).unwrap(), b"dc=example,dc=com");
+        assert_eq!(search.integer(ENUMERATED).unwrap(), SCOPE_SUBTREE as u32);
+        assert_eq!(
+            search.integer(ENUMERATED).unwrap(),
+            NEVER_DEREF_ALIASES as u32
+        );
+        assert_eq!(search.integer(INTEGER).unwrap(), 2);
+        assert_eq!(search.integer(INTEGER).unwrap(), 5);
+        assert_eq!(search.expect(BOOLEAN).unwrap(), &[0]);
+
+        let filter = elements(search.expect(FILTER_AND).unwrap());
+        let assertions: Vec<Vec<&[u8]>> = filter
+            .iter()
+            .map(|(tag, content)| {
+                assert_eq!(*tag, FILTER_EQUALITY);
+                elements(content)
+                    .into_iter()
+                    .map(|(tag, value)| {
+                        assert_eq!(tag, OCTET_STRING);
+                        value
+                    })
+                    .collect()
+            })
+            .collect();
+        // the value is sent as is, not parsed as filter syntax
+        assert_eq!(
+            assertions,
+            vec![
+                vec![&b"objectClass"[..], &b"person"[..]],
+                vec![&b"uid"[..], value.as_bytes()],
+            ]
+        );
+
+        let attributes: Vec<&[u8]> = elements(search.expect(SEQUENCE).unwrap())
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+        assert_eq!(attributes, vec![&b"mail"[..], &b"memberOf"[..]]);
+        assert!(search.is_empty());
+    }
+
+    #[test]
+    fn search_result_entry_parses() {
+        let attribute = |name: &str, values: &[&[u8]]| {
+            let mut content = octets(OCTET_STRING, name);
+            let values: Vec<u8> = values
+                .iter()
+                .flat_map(|value| tlv(OCTET_STRING, value))
+                .collect();
+            content.extend(tlv(SET, &values));
+            tlv(SEQUENCE, &content)
+        };
+        let mut attributes = attribute("mail", &[b"jo@example.com"]);
+        attributes.extend(attribute("objectGUID", &[&[0xde, 0xad, 0xbe, 0xef]]));
+        attributes.extend(attribute("memberOf", &[b"cn=a", b"cn=b"]));
+        let mut content = octets(OCTET_STRING, "uid=jo,dc=example,dc=com");
+        content.extend(tlv(SEQUENCE, &attributes));
+        let response = message(4, tlv(SEARCH_RESULT_ENTRY, &content));
+
+        let (message_id, tag, content) = parse_message(&response).unwrap();
+        assert_eq!((message_id, tag), (4, SEARCH_RESULT_ENTRY));
+        let (dn, attributes) = parse_entry(content).unwrap();
+        assert_eq!(dn, "uid=jo,dc=example,dc=com");
+        assert_eq!(
+            attributes,
+            vec![
+                ("mail".to_string(), vec!["jo@example.com".to_string()]),
+                ("objectGUID".to_string(), vec!["deadbeef".to_string()]),
+                (
+                    "memberOf".to_string(),
+                    vec!["cn=a".to_string(), "cn=b".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn ldap_result_parses() {
+        let mut content = tlv(ENUMERATED, &[49]);
+        content.extend(octets(OCTET_STRING, ""));
+        content.extend(octets(OCTET_STRING, "invalid credentials"));
+        let response = message(1, tlv(BIND_RESPONSE, &content));
+        let (message_id, tag, content) = parse_message(&response).unwrap();
+        assert_eq!((message_id, tag), (1, BIND_RESPONSE));
+        assert_eq!(
+            parse_result(content).unwrap(),
+            (49, "invalid credentials".to_string())
+        );
+    }
+}
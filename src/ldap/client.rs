@@ -0,0 +1,190 @@
+use super::{LdapError, ber};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// Result code of a bind with a wrong DN or password
+pub const INVALID_CREDENTIALS: u32 = 49;
+const SUCCESS: u32 = 0;
+/// more entries matched than asked for, the ones sent are still valid
+const SIZE_LIMIT_EXCEEDED: u32 = 4;
+
+/// Responses larger than this are not a single user entry
+const MAX_MESSAGE_BYTES: usize = 1 << 20;
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// One directory entry, attribute names as the server spells them
+pub struct Entry {
+    pub dn: String,
+    pub attributes: Vec<(String, Vec<String>)>,
+}
+
+impl Entry {
+    pub fn values(&self, name: &str) -> &[String] {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute.eq_ignore_ascii_case(name))
+            .map(|(_, values)| values.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+/// A connection for a single login, operations run one at a time
+pub struct LdapConnection {
+    stream: Box<dyn Stream>,
+    message_id: u32,
+    timeout: Duration,
+}
+
+impl LdapConnection {
+    /// `ldaps://` is wrapped in TLS with certificate checks, `ldap://` is
+    /// meant for a local test directory
+    pub async fn connect(url: &str, timeout_secs: u64) -> Result<Self, LdapError> {
+        let (tls, address) = match (url.strip_prefix("ldaps://"), url.strip_prefix("ldap://")) {
+            (Some(address), _) => (true, address),
+            (None, Some(address)) => (false, address),
+            _ => {
+                return Err(LdapError::Protocol(
+                    "url must start with ldap:// or ldaps://",
+                ));
+            }
+        };
+        let address = address.trim_end_matches('/');
+        let host = address
+            .rsplit_once(':')
+            .map_or(address, |(host, _)| host)
+            .to_string();
+        let address = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("{}:{}", address, if tls { 636 } else { 389 })
+        };
+        let duration = Duration::from_secs(timeout_secs);
+        let tcp = timeout(duration, TcpStream::connect(&address))
+            .await
+            .map_err(|_| LdapError::Timeout)??;
+        let stream: Box<dyn Stream> = if tls {
+            let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+            Box::new(
+                timeout(duration, connector.connect(&host, tcp))
+                    .await
+                    .map_err(|_| LdapError::Timeout)??,
+            )
+        } else {
+            Box::new(tcp)
+        };
+        Ok(LdapConnection {
+            stream,
+            message_id: 0,
+            timeout: duration,
+        })
+    }
+
+    fn next_id(&mut self) -> u32 {
+        self.message_id += 1;
+        self.message_id
+    }
+
+    async fn send(&mut self, bytes: &[u8]) -> Result<(), LdapError> {
+        timeout(self.timeout, self.stream.write_all(bytes))
+            .await
+            .map_err(|_| LdapError::Timeout)??;
+        Ok(())
+    }
+
+    /// Reads one whole LDAPMessage
+    async fn receive(&mut self) -> Result<Vec<u8>, LdapError> {
+        let mut message = vec![];
+        let mut byte = [0u8; 1];
+        let (header_len, len) = loop {
+            if let Some(lengths) = ber::element_len(&message)? {
+                break lengths;
+            }
+            timeout(self.timeout, self.stream.read_exact(&mut byte))
+                .await
+                .map_err(|_| LdapError::Timeout)??;
+            message.push(byte[0]);
+        };
+        if len > MAX_MESSAGE_BYTES {
+            return Err(LdapError::Protocol("response too large"));
+        }
+        message.resize(header_len + len, 0);
+        timeout(
+            self.timeout,
+            self.stream.read_exact(&mut message[header_len..]),
+        )
+        .await
+        .map_err(|_| LdapError::Timeout)??;
+        Ok(message)
+    }
+
+    /// Simple bind, an error carries the result code
+    pub async fn bind(&mut self, dn: &str, password: &str) -> Result<(), LdapError> {
+        let message_id = self.next_id();
+        self.send(&ber::bind_request(message_id, dn, password))
+            .await?;
+        let response = self.receive().await?;
+        let (response_id, tag, content) = ber::parse_message(&response)?;
+        if response_id != message_id || tag != ber::BIND_RESPONSE {
+            return Err(LdapError::Protocol("unexpected bind response"));
+        }
+        match ber::parse_result(content)? {
+            (SUCCESS, _) => Ok(()),
+            (code, diagnostic) => Err(LdapError::Result(code, diagnostic)),
+        }
+    }
+
+    /// Entries matching `(&(objectClass=..)(attribute=value))` under `base_dn`
+    pub async fn search(
+        &mut self,
+        base_dn: &str,
+        object_class: &str,
+        attribute: &str,
+        value: &str,
+        attributes: &[&str],
+    ) -> Result<Vec<Entry>, LdapError> {
+        let message_id = self.next_id();
+        let time_limit = self.timeout.as_secs() as u32;
+        self.send(&ber::search_request(
+            message_id,
+            base_dn,
+            object_class,
+            attribute,
+            value,
+            time_limit,
+            attributes,
+        ))
+        .await?;
+        let mut entries = vec![];
+        loop {
+            let response = self.receive().await?;
+            let (response_id, tag, content) = ber::parse_message(&response)?;
+            if response_id != message_id {
+                return Err(LdapError::Protocol("unexpected search response"));
+            }
+            match tag {
+                ber::SEARCH_RESULT_ENTRY => {
+                    let (dn, attributes) = ber::parse_entry(content)?;
+                    entries.push(Entry { dn, attributes });
+                }
+                ber::SEARCH_RESULT_DONE => {
+                    return match ber::parse_result(content)? {
+                        (SUCCESS | SIZE_LIMIT_EXCEEDED, _) => Ok(entries),
+                        (code, diagnostic) => Err(LdapError::Result(code, diagnostic)),
+                    };
+                }
+                // referrals to other servers are not followed
+                _ => {}
+            }
+        }
+    }
+
+    pub async fn unbind(mut self) {
+        let message_id = self.next_id();
+        let _ = self.send(&ber::unbind_request(message_id)).await;
+        let _ = self.stream.shutdown().await;
+    }
+}
@@ -0,0 +1,478 @@
+//! LDAP / Active Directory as a password backend.
+//!
+//! Configured through `ldap_config_file` (a JSON [`LdapConfig`]). A login
+//! binds with the service account, searches the user by name, then binds
+//! as the found DN with the given password. Directory groups map to roles,
+//! users are provisioned on their first login with `provider = "ldap"`.
+
+pub mod ber;
+pub mod client;
+
+use crate::{
+    auth::{
+        model::User,
+        verifier::{CredentialVerifier, ExternalAccount, Verdict},
+    },
+    cfg::CONFIG,
+    error::CustomError,
+    idp::VerifyError,
+    utils::des_from_str,
+};
+use async_trait::async_trait;
+use client::{INVALID_CREDENTIALS, LdapConnection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const LDAP: &str = "ldap";
+
+#[derive(Debug, thiserror::Error)]
+pub enum LdapError {
+    #[error("LDAP connection failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("LDAP TLS failed: {0}")]
+    Tls(#[from] native_tls::Error),
+
+    #[error("LDAP server timed out")]
+    Timeout,
+
+    #[error("LDAP protocol error: {0}")]
+    Protocol(&'static str),
+
+    #[error("LDAP result {0}: {1}")]
+    Result(u32, String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LdapConfig {
+    /// `ldaps://host[:port]`
+    pub url: String,
+    /// plain `ldap://` is refused unless set, for a local test directory
+    pub allow_plaintext: bool,
+    /// service account for the search, an anonymous bind when empty
+    pub bind_dn: String,
+    pub bind_password: String,
+    pub base_dn: String,
+    pub object_class: String,
+    /// attribute matched against the login username, `sAMAccountName` on AD
+    pub user_attribute: String,
+    /// stable id of the entry, `objectGUID` on AD. The DN is used when the
+    /// entry has none.
+    pub id_attribute: String,
+    pub email_attribute: String,
+    pub group_attribute: String,
+    /// group DN to the roles its members get
+    pub group_roles: HashMap<String, Vec<i32>>,
+    /// organization users are provisioned into, `default_org_id` when unset
+    pub org_id: Option<i32>,
+    pub timeout_secs: u64,
+}
+
+impl Default for LdapConfig {
+    fn default() -> Self {
+        LdapConfig {
+            url: "".to_string(),
+            allow_plaintext: false,
+            bind_dn: "".to_string(),
+            bind_password: "".to_string(),
+            base_dn: "".to_string(),
+            object_class: "person".to_string(),
+            user_attribute: "uid".to_string(),
+            id_attribute: "entryUUID".to_string(),
+            email_attribute: "mail".to_string(),
+            group_attribute: "memberOf".to_string(),
+            group_roles: HashMap::new(),
+            org_id: None,
+            timeout_secs: 5,
+        }
+    }
+}
+
+pub struct LdapVerifier {
+    config: LdapConfig,
+}
+
+impl LdapVerifier {
+    pub fn new(config: LdapConfig) -> Result<Self, CustomError> {
+        let secure = config.url.starts_with("ldaps://")
+            || (config.allow_plaintext && config.url.starts_with("ldap://"));
+        if !secure {
+            return Err(CustomError::ProviderLoad(format!(
+                "LDAP url {:?} must use ldaps://",
+                config.url
+            )));
+        }
+        if config.base_dn.is_empty() {
+            return Err(CustomError::ProviderLoad(
+                "LDAP base_dn is missing".to_string(),
+            ));
+        }
+        Ok(LdapVerifier { config })
+    }
+
+    /// Configured when `ldap_config_file` is set
+    pub fn from_config() -> Result<Option<Self>, CustomError> {
+        if CONFIG.ldap_config_file.is_empty() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&CONFIG.ldap_config_file)
+            .map_err(|e| CustomError::ProviderLoad(e.to_string()))?;
+        let config =
+            des_from_str(&content).map_err(|e| CustomError::ProviderLoad(e.to_string()))?;
+        Self::new(config).map(Some)
+    }
+
+    async fn search_then_bind(
+        &self,
+        connection: &mut LdapConnection,
+        username: &str,
+        password: &str,
+    ) -> Result<Verdict, VerifyError> {
+        let config = &self.config;
+        if !config.bind_dn.is_empty() {
+            connection
+                .bind(&config.bind_dn, &config.bind_password)
+                .await?;
+        }
+        let mut entries = connection
+            .search(
+                &config.base_dn,
+                &config.object_class,
+                &config.user_attribute,
+                username,
+                &[
+                    &config.id_attribute,
+                    &config.email_attribute,
+                    &config.group_attribute,
+                ],
+            )
+            .await?;
+        let entry = match entries.len() {
+            0 => return Ok(Verdict::Unknown),
+            1 => entries.remove(0),
+            _ => return Ok(Verdict::Rejected("ambiguous_account")),
+        };
+        match connection.bind(&entry.dn, password).await {
+            Ok(_) => {}
+            Err(LdapError::Result(INVALID_CREDENTIALS, _)) => {
+                return Ok(Verdict::Rejected("wrong_password"));
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let groups = entry.values(&config.group_attribute);
+        let mut role_ids: Vec<i32> = config
+            .group_roles
+            .iter()
+            .filter(|(group, _)| groups.iter().any(|dn| dn.eq_ignore_ascii_case(group)))
+            .flat_map(|(_, role_ids)| role_ids.iter().copied())
+            .collect();
+        role_ids.sort_unstable();
+        role_ids.dedup();
+        let mut managed_role_ids: Vec<i32> =
+            config.group_roles.values().flatten().copied().collect();
+        managed_role_ids.sort_unstable();
+        managed_role_ids.dedup();
+        Ok(Verdict::External(ExternalAccount {
+            provider: LDAP.to_string(),
+            provider_id: entry
+                .values(&config.id_attribute)
+                .first()
+                .cloned()
+                .unwrap_or_else(|| entry.dn.clone()),
+            email: entry.values(&config.email_attribute).first().cloned(),
+            org_id: config.org_id.unwrap_or(CONFIG.default_org_id),
+            role_ids,
+            managed_role_ids,
+        }))
+    }
+}
+
+#[async_trait]
+impl CredentialVerifier for LdapVerifier {
+    async fn verify(
+        &self,
+        username: &str,
+        password: &str,
+        user: Option<&User>,
+    ) -> Result<Verdict, VerifyError> {
+        if user.is_some_and(|user| user.password.is_some()) {
+            return Ok(Verdict::Unknown);
+        }
+        // a simple bind with an empty password is an anonymous bind and
+        // succeeds for any DN
+        if password.is_empty() {
+            return Ok(Verdict::Rejected("wrong_password"));
+        }
+        let mut connection =
+            LdapConnection::connect(&self.config.url, self.config.timeout_secs).await?;
+        let verdict = self
+            .search_then_bind(&mut connection, username, password)
+            .await;
+        connection.unbind().await;
+        verdict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::load_test_env;
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+        time::timeout,
+    };
+
+    const SERVICE_DN: &str = "cn=svc,dc=example,dc=com";
+    const JO_DN: &str = "uid=jo,ou=people,dc=example,dc=com";
+    const STAFF_DN: &str = "cn=Staff,ou=groups,dc=example,dc=com";
+
+    /// BER element with a definite length, the long form past 127 bytes
+    fn element(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut element = match content.len() {
+            len @ 0..0x80 => vec![tag, len as u8],
+            len @ 0x80..0x100 => vec![tag, 0x81, len as u8],
+            len => vec![tag, 0x82, (len >> 8) as u8, len as u8],
+        };
+        element.extend_from_slice(content);
+        element
+    }
+
+    fn message(message_id: u8, protocol_op: Vec<u8>) -> Vec<u8> {
+        let mut content = vec![0x02, 0x01, message_id];
+        content.extend(protocol_op);
+        element(0x30, &content)
+    }
+
+    /// LDAPResult with an empty matchedDN
+    fn result(message_id: u8, tag: u8, code: u8, diagnostic: &str) -> Vec<u8> {
+        let mut content = vec![0x0a, 0x01, code, 0x04, 0x00];
+        content.extend(element(0x04, diagnostic.as_bytes()));
+        message(message_id, element(tag, &content))
+    }
+
+    fn bind_response(message_id: u8, code: u8) -> Vec<u8> {
+        result(message_id, 0x61, code, "")
+    }
+
+    fn search_done(message_id: u8) -> Vec<u8> {
+        result(message_id, 0x65, 0, "")
+    }
+
+    fn entry(message_id: u8, dn: &str, attributes: &[(&str, &[&str])]) -> Vec<u8> {
+        let attributes: Vec<u8> = attributes
+            .iter()
+            .flat_map(|(name, values)| {
+                let values: Vec<u8> = values
+                    .iter()
+                    .flat_map(|value| element(0x04, value.as_bytes()))
+                    .collect();
+                let mut attribute = element(0x04, name.as_bytes());
+                attribute.extend(element(0x31, &values));
+                element(0x30, &attribute)
+            })
+            .collect();
+        let mut content = element(0x04, dn.as_bytes());
+        content.extend(element(0x30, &attributes));
+        message(message_id, element(0x64, &content))
+    }
+
+    fn jo(message_id: u8) -> Vec<u8> {
+        entry(
+            message_id,
+            JO_DN,
+            &[
+                ("entryUUID", &["5f1b0c2e-jo"]),
+                ("mail", &["jo@example.com"]),
+                ("memberOf", &[STAFF_DN, "cn=other,dc=example,dc=com"]),
+            ],
+        )
+    }
+
+    /// Directory answering each request of one connection with the next
+    /// canned reply, the unbind excepted. Yields the requests it got, none
+    /// when nobody connected.
+    async fn directory(replies: Vec<Vec<u8>>) -> (String, JoinHandle<Vec<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut requests = vec![];
+            let Ok(Ok((mut stream, _))) =
+                timeout(Duration::from_millis(500), listener.accept()).await
+            else {
+                return requests;
+            };
+            let mut replies = replies.into_iter();
+            let mut buffer = vec![];
+            let mut chunk = [0u8; 1024];
+            loop {
+                while let Ok(Some((header_len, len))) = ber::element_len(&buffer) {
+                    if buffer.len() < header_len + len {
+                        break;
+                    }
+                    let request: Vec<u8> = buffer.drain(..header_len + len).collect();
+                    let (_, tag, _) = ber::parse_message(&request).unwrap();
+                    requests.push(request);
+                    // UnbindRequest, the client closes next
+                    if tag == 0x42 {
+                        return requests;
+                    }
+                    let reply = replies.next().expect("no reply left");
+                    stream.write_all(&reply).await.unwrap();
+                }
+                match stream.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return requests,
+                    Ok(size) => buffer.extend_from_slice(&chunk[..size]),
+                }
+            }
+        });
+        (url, handle)
+    }
+
+    fn verifier(url: String) -> LdapVerifier {
+        load_test_env();
+        LdapVerifier::new(LdapConfig {
+            url,
+            allow_plaintext: true,
+            bind_dn: SERVICE_DN.to_string(),
+            bind_password: "svc-secret".to_string(),
+            base_dn: "dc=example,dc=com".to_string(),
+            group_roles: HashMap::from([(STAFF_DN.to_ascii_lowercase(), vec![3])]),
+            org_id: Some(1),
+            ..LdapConfig::default()
+        })
+        .unwrap()
+    }
+
+    /// `(dn, password)` of a BindRequest
+    fn bind_request(request: &[u8]) -> (String, String) {
+        let (_, tag, content) = ber::parse_message(request).unwrap();
+        assert_eq!(tag, 0x60);
+        let mut bind = ber::Reader::new(content);
+        bind.integer(0x02).unwrap();
+        let dn = bind.expect(0x04).unwrap();
+        let password = bind.expect(0x80).unwrap();
+        (
+            String::from_utf8_lossy(dn).into_owned(),
+            String::from_utf8_lossy(password).into_owned(),
+        )
+    }
+
+    /// Assertion value of the user attribute in a SearchRequest's filter
+    fn searched_value(request: &[u8]) -> Vec<u8> {
+        let (_, tag, content) = ber::parse_message(request).unwrap();
+        assert_eq!(tag, 0x63);
+        let mut search = ber::Reader::new(content);
+        for tag in [0x04, 0x0a, 0x0a, 0x02, 0x02, 0x01] {
+            search.expect(tag).unwrap();
+        }
+        let mut filter = ber::Reader::new(search.expect(0xa0).unwrap());
+        filter.expect(0xa3).unwrap();
+        let mut equality = ber::Reader::new(filter.expect(0xa3).unwrap());
+        assert_eq!(equality.expect(0x04).unwrap(), b"uid");
+        equality.expect(0x04).unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn found_user_with_the_right_password_is_external() {
+        let (url, server) = directory(vec![
+            bind_response(1, 0),
+            [jo(2), search_done(2)].concat(),
+            bind_response(3, 0),
+        ])
+        .await;
+        let verdict = verifier(url).verify("jo", "pa55", None).await.unwrap();
+        let Verdict::External(account) = verdict else {
+            panic!("expected an external account, got {:?}", verdict);
+        };
+        assert_eq!(account.provider, LDAP);
+        assert_eq!(account.provider_id, "5f1b0c2e-jo");
+        assert_eq!(account.email.as_deref(), Some("jo@example.com"));
+        assert_eq!(account.role_ids, vec![3]);
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(
+            bind_request(&requests[0]),
+            (SERVICE_DN.to_string(), "svc-secret".to_string())
+        );
+        assert_eq!(searched_value(&requests[1]), b"jo");
+        assert_eq!(
+            bind_request(&requests[2]),
+            (JO_DN.to_string(), "pa55".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn no_entry_is_unknown() {
+        let (url, server) = directory(vec![bind_response(1, 0), search_done(2)]).await;
+        let verdict = verifier(url).verify("nobody", "pa55", None).await.unwrap();
+        assert!(matches!(verdict, Verdict::Unknown));
+        // no bind as a user follows, only the unbind
+        assert_eq!(server.await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn several_entries_are_ambiguous() {
+        let second = entry(2, "uid=jo,ou=contractors,dc=example,dc=com", &[]);
+        let (url, server) = directory(vec![
+            bind_response(1, 0),
+            [jo(2), second, search_done(2)].concat(),
+        ])
+        .await;
+        let verdict = verifier(url).verify("jo", "pa55", None).await.unwrap();
+        assert!(matches!(verdict, Verdict::Rejected("ambiguous_account")));
+        assert_eq!(server.await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn invalid_credentials_are_a_wrong_password() {
+        let (url, server) = directory(vec![
+            bind_response(1, 0),
+            [jo(2), search_done(2)].concat(),
+            bind_response(3, 49),
+        ])
+        .await;
+        let verdict = verifier(url).verify("jo", "wrong", None).await.unwrap();
+        assert!(matches!(verdict, Verdict::Rejected("wrong_password")));
+        server.await.unwrap();
+
+        // any other refusal is the directory failing, not the user
+        let (url, server) = directory(vec![
+            bind_response(1, 0),
+            [jo(2), search_done(2)].concat(),
+            bind_response(3, 53),
+        ])
+        .await;
+        assert!(verifier(url).verify("jo", "pa55", None).await.is_err());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_password_never_reaches_the_directory() {
+        // the bind it would end in is an anonymous one, which succeeds
+        let (url, server) = directory(vec![
+            bind_response(1, 0),
+            [jo(2), search_done(2)].concat(),
+            bind_response(3, 0),
+        ])
+        .await;
+        let verdict = verifier(url).verify("jo", "", None).await.unwrap();
+        assert!(matches!(verdict, Verdict::Rejected("wrong_password")));
+        assert!(server.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn filter_syntax_in_the_username_is_a_plain_value() {
+        let username = r"*)(uid=*))(|(cn=*\00";
+        let (url, server) = directory(vec![bind_response(1, 0), search_done(2)]).await;
+        let verdict = verifier(url).verify(username, "pa55", None).await.unwrap();
+        assert!(matches!(verdict, Verdict::Unknown));
+        let requests = server.await.unwrap();
+        assert_eq!(searched_value(&requests[1]), username.as_bytes());
+    }
+}
@@ -1,14 +1,29 @@
+pub mod apikey;
+pub mod audit;
 pub mod auth;
 pub mod cfg;
 pub mod constants;
+pub mod cookie;
 pub mod db;
 pub mod error;
-pub mod google;
+pub mod grant;
+pub mod guest;
+pub mod identity;
+pub mod idp;
+pub mod invitation;
+pub mod ldap;
 pub mod mail;
 pub mod mdw;
+pub mod oidc;
+pub mod org;
 pub mod permission;
+pub mod policy;
 pub mod role;
 pub mod rolepermissions;
+pub mod saml;
+pub mod scim;
 pub mod server;
+pub mod session;
+pub mod sweeper;
 pub mod user;
 pub mod utils;
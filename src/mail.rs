@@ -16,16 +16,28 @@ pub struct Attribs {
     pub reset_link: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct InvitationMail {
+    pub recipient: String,
+    pub addresser: String,
+    pub attribs: InvitationAttribs,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InvitationAttribs {
+    pub invitation_link: String,
+}
+
 pub struct Mail {}
 
 impl Mail {
-    pub async fn send_email(mail: ForgotPasswordMail) -> Result<()> {
+    pub async fn send_email<T: Serialize>(mail: T) -> Result<()> {
         let mut headers = HashMap::new();
         headers.insert(
             "X-API-Key".to_string(),
             CONFIG.mail_server_api_key.to_string(),
         );
-        match HttpClient::fetch::<ForgotPasswordMail>(
+        match HttpClient::fetch::<T>(
             HttpMethod::POST,
             format!("{}/api/batch_mail/api/send", CONFIG.mail_server_url),
             Some(headers),
@@ -1,37 +1,48 @@
 use crate::{
+    apikey::service::ApiKeySvc,
     cfg::CONFIG,
-    constants::{BAD_REQUEST, UNAUTHORIZED},
-    utils::{Claims, extract_token, verify_jwt},
+    constants::{BAD_REQUEST, FORBIDDEN, INTERNAL_ERROR, UNAUTHORIZED},
+    cookie,
+    db::DBConn,
+    error::CustomError,
+    saml::SAML_ACS_PATH,
+    session::service::SessionSvc,
+    utils::{CLIENT_IP_HEADER, ClaimType, Claims, extract_token, resolve_client_ip, verify_jwt},
 };
 use anyhow::{Context, Result, anyhow};
 use request_http_parser::parser::{Method, Request};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
 };
 
 pub struct Middleware {}
 
 impl Middleware {
-    pub async fn new(stream: &mut TcpStream) -> Result<(Request, Option<Claims>)> {
-        let mut buffer = vec![0; CONFIG.request_max_byte];
-        let size = stream
-            .read(&mut buffer)
-            .await
-            .context("Failed to read stream")?;
-        if size >= CONFIG.request_max_byte {
-            let _ = stream
-                .write_all(format!("{}{}", BAD_REQUEST, "Requets too large").as_bytes())
-                .await
-                .context("Failed to write");
+    pub async fn new<DB>(
+        stream: &mut TcpStream,
+        session_svc: &SessionSvc<DB>,
+        api_key_svc: &ApiKeySvc<DB>,
+    ) -> Result<(Request, Option<Claims>)>
+    where
+        DB: DBConn + Send + Sync + 'static,
+    {
+        let buffer = match read_request(stream).await? {
+            Some(buffer) => buffer,
+            None => {
+                let _ = stream
+                    .write_all(format!("{}{}", BAD_REQUEST, "Requets too large").as_bytes())
+                    .await
+                    .context("Failed to write");
 
-            let _ = stream.flush().await.context("Failed to flush");
+                let _ = stream.flush().await.context("Failed to flush");
 
-            return Err(anyhow!("request too large"));
-        }
-        let req_str = String::from_utf8_lossy(&buffer[..size]);
+                return Err(anyhow!("request too large"));
+            }
+        };
+        let req_str = String::from_utf8_lossy(&buffer);
         println!("{}", req_str);
-        let request = match Request::new(&req_str) {
+        let mut request = match Request::new(&req_str) {
             Ok(req) => req,
             Err(e) => {
                 println!("{}", e);
@@ -44,13 +55,37 @@ impl Middleware {
                 return Err(anyhow!("request format invalid"));
             }
         };
+        request.headers.remove(CLIENT_IP_HEADER);
+        if let Ok(peer) = stream.peer_addr() {
+            let client = resolve_client_ip(peer.ip(), &request.headers);
+            request
+                .headers
+                .insert(CLIENT_IP_HEADER.to_string(), client.to_string());
+        }
         if !request.path.contains("protected") || request.method == Method::OPTIONS {
             return Ok((request, None));
         }
 
+        // API keys are opaque, they are looked up instead of verified
+        if let Some(key) = ApiKeySvc::<DB>::extract_key(&request) {
+            return match api_key_svc.authenticate(&key).await {
+                Ok(claims) => Ok((request, Some(claims))),
+                Err(CustomError::ApiKeyNotFound) => {
+                    stream
+                        .write_all(format!("{}{}", UNAUTHORIZED, "401 api key invalid").as_bytes())
+                        .await?;
+                    Err(anyhow!("api key invalid"))
+                }
+                Err(error) => {
+                    stream.write_all(INTERNAL_ERROR.as_bytes()).await?;
+                    Err(anyhow!("api key check failed: {}", error))
+                }
+            };
+        }
+
         let token_opt = match extract_token(&request.headers) {
             Some(token) => Some(token),
-            _ => None,
+            _ => cookie::session_token(&request),
         };
         let token = match token_opt {
             Some(token) => token,
@@ -85,6 +120,179 @@ impl Middleware {
                 return Err(anyhow!("token unathorized"));
             }
         };
+        match session_svc.is_revoked(&token).await {
+            Ok(false) => {}
+            Ok(true) => {
+                stream
+                    .write_all(format!("{}{}", UNAUTHORIZED, "401 token revoked").as_bytes())
+                    .await?;
+                return Err(anyhow!("token revoked"));
+            }
+            Err(error) => {
+                stream.write_all(INTERNAL_ERROR.as_bytes()).await?;
+                return Err(anyhow!("revocation check failed: {}", error));
+            }
+        }
+        // the scope of a service token is checked by the services that
+        // accept it, no route here is open to a service principal
+        if claims.claim_type == ClaimType::Service {
+            stream
+                .write_all(format!("{}{}", FORBIDDEN, "403 service token").as_bytes())
+                .await?;
+            return Err(anyhow!("service token on a protected route"));
+        }
+        // the browser attaches the cookie on its own, make sure the page did
+        // send this request by asking for the CSRF token too
+        if cookie::is_cookie_session(&request)
+            && cookie::requires_csrf(&request.method)
+            && !cookie::csrf_valid(&request)
+        {
+            stream
+                .write_all(format!("{}{}", FORBIDDEN, "403 csrf token invalid").as_bytes())
+                .await?;
+            return Err(anyhow!("csrf token invalid"));
+        }
+        match session_svc.check(&claims).await {
+            Ok(_) => {}
+            Err(CustomError::SessionNotFound) => {
+                stream
+                    .write_all(format!("{}{}", UNAUTHORIZED, "401 session revoked").as_bytes())
+                    .await?;
+                return Err(anyhow!("session revoked or expired"));
+            }
+            Err(error) => {
+                stream.write_all(INTERNAL_ERROR.as_bytes()).await?;
+                return Err(anyhow!("session check failed: {}", error));
+            }
+        }
         Ok((request, Some(claims)))
     }
 }
+
+/// Reads one request, the head and then as much body as its Content-Length
+/// announces. None when the request is over the limit of its route.
+async fn read_request<S>(stream: &mut S) -> Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = Vec::new();
+    let mut chunk = vec![0; CONFIG.request_max_byte];
+    let head_len = loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buffer.len() >= CONFIG.request_max_byte {
+            return Ok(None);
+        }
+        let size = stream
+            .read(&mut chunk)
+            .await
+            .context("Failed to read stream")?;
+        if size == 0 {
+            // closed before the head ended, the parser rejects what came
+            return Ok(Some(buffer));
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+    };
+    let head = String::from_utf8_lossy(&buffer[..head_len]).into_owned();
+    let max_byte = match head.split(' ').nth(1) {
+        Some(target) if target.split('?').next() == Some(SAML_ACS_PATH) => {
+            CONFIG.saml_request_max_byte
+        }
+        _ => CONFIG.request_max_byte,
+    };
+    let total = head_len + content_length(&head);
+    if total >= max_byte {
+        return Ok(None);
+    }
+    while buffer.len() < total {
+        let size = stream
+            .read(&mut chunk)
+            .await
+            .context("Failed to read stream")?;
+        if size == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+    }
+    buffer.truncate(total);
+    Ok(Some(buffer))
+}
+
+fn content_length(head: &str) -> usize {
+    head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use tokio::io::duplex;
+
+    fn post(path: &str, body: &str) -> String {
+        format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            body.len(),
+            body
+        )
+    }
+
+    /// an IdP-signed Response with a few dozen group values
+    fn saml_body() -> String {
+        format!(
+            r#"{{"saml_response":"{}","cookie":false}}"#,
+            STANDARD.encode(include_str!("saml/fixtures/assertion_signed.xml"))
+        )
+    }
+
+    async fn read(request: String, piece: usize) -> Option<Vec<u8>> {
+        crate::cfg::load_test_env();
+        let (mut client, mut server) = duplex(1024);
+        let writer = tokio::spawn(async move {
+            for part in request.as_bytes().chunks(piece) {
+                if client.write_all(part).await.is_err() {
+                    break;
+                }
+            }
+            client
+        });
+        let read = read_request(&mut server).await.unwrap();
+        drop(server);
+        let _ = writer.await;
+        read
+    }
+
+    #[tokio::test]
+    async fn saml_response_is_read_whole() {
+        let request = post(SAML_ACS_PATH, &saml_body());
+        assert!(request.len() > 12 * 1024);
+        let read = read(request.clone(), 1000).await.unwrap();
+        assert_eq!(read, request.as_bytes());
+        let parsed = Request::new(&String::from_utf8_lossy(&read)).unwrap();
+        assert_eq!(parsed.body.unwrap(), saml_body());
+    }
+
+    #[tokio::test]
+    async fn other_routes_keep_the_default_limit() {
+        let request = post("/signin", &saml_body());
+        assert_eq!(read(request, 1000).await, None);
+    }
+
+    #[tokio::test]
+    async fn body_arriving_after_the_head_is_waited_for() {
+        let request = post("/signin", r#"{"username":"alice","password":"secret"}"#);
+        let read = read(request.clone(), 7).await.unwrap();
+        assert_eq!(read, request.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn endless_head_is_refused() {
+        let request = format!("GET / HTTP/1.1\r\nX-Filler: {}", "a".repeat(4096));
+        assert_eq!(read(request, 512).await, None);
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{ClaimType, Claims};
+
+/// Scopes a client can ask for and the claims each one releases
+pub const SCOPE_CLAIMS: &[(&str, &[&str])] = &[
+    ("openid", &["sub"]),
+    ("profile", &["preferred_username"]),
+    ("email", &["email"]),
+    ("org", &["org_id"]),
+];
+
+pub const GRANT_AUTHORIZATION_CODE: &str = "authorization_code";
+pub const GRANT_CLIENT_CREDENTIALS: &str = "client_credentials";
+pub const GRANT_DEVICE_CODE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+pub const DEVICE_PENDING: &str = "pending";
+pub const DEVICE_APPROVED: &str = "approved";
+pub const DEVICE_DENIED: &str = "denied";
+/// tokens were handed to the device, the code cannot be polled again
+pub const DEVICE_REDEEMED: &str = "redeemed";
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub org_id: i32,
+    pub name: String,
+    /// bcrypt hash of the client secret, public clients have none and rely
+    /// on PKCE alone
+    #[serde(skip)]
+    pub secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    /// `authorization_code` for relying parties, `client_credentials` for
+    /// machine clients calling other services, the device code grant for
+    /// apps on devices without a keyboard
+    pub grant_types: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl OAuthClient {
+    pub fn allows_grant(&self, grant_type: &str) -> bool {
+        self.grant_types.iter().any(|grant| grant == grant_type)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateOAuthClient {
+    pub name: String,
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    #[serde(default = "default_grant_types")]
+    pub grant_types: Vec<String>,
+    /// browser and native apps cannot keep a secret
+    #[serde(default)]
+    pub public: bool,
+}
+
+fn default_grant_types() -> Vec<String> {
+    vec![GRANT_AUTHORIZATION_CODE.to_string()]
+}
+
+/// Registration result, the only time the secret is shown
+#[derive(Serialize, Deserialize)]
+pub struct CreatedOAuthClient {
+    #[serde(flatten)]
+    pub client: OAuthClient,
+    pub client_secret: Option<String>,
+}
+
+/// Query of `GET /oauth/authorize`, echoed back by the login form
+#[derive(Debug)]
+pub struct AuthorizationRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+}
+
+impl AuthorizationRequest {
+    pub fn from_params(params: &HashMap<String, String>) -> Self {
+        let get = |key: &str| params.get(key).cloned().unwrap_or_default();
+        AuthorizationRequest {
+            response_type: get("response_type"),
+            client_id: get("client_id"),
+            redirect_uri: get("redirect_uri"),
+            scope: get("scope"),
+            state: params.get("state").cloned(),
+            nonce: params.get("nonce").cloned(),
+            code_challenge: get("code_challenge"),
+            code_challenge_method: get("code_challenge_method"),
+        }
+    }
+
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scope.split_whitespace().collect()
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct AuthorizationCode {
+    pub code_hash: String,
+    pub client_id: String,
+    pub user_id: i32,
+    pub org_id: i32,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Pending sign-in of a device, approved by a user who enters the user code
+/// on another device
+#[derive(Debug, sqlx::FromRow)]
+pub struct DeviceAuthorization {
+    pub device_code_hash: String,
+    /// stored without the separator shown to the user
+    pub user_code: String,
+    pub client_id: String,
+    pub org_id: i32,
+    pub scope: String,
+    pub status: String,
+    /// the user who approved or denied the request
+    pub user_id: Option<i32>,
+    /// minimum seconds between polls, grows when the device polls too fast
+    pub interval_secs: i32,
+    pub last_polled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A poll of the token endpoint, `authorization` is the state before the
+/// poll
+#[derive(Debug)]
+pub struct DevicePoll {
+    pub authorization: DeviceAuthorization,
+    /// polled again before the interval passed
+    pub slow_down: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceAuthorizationResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_in: i64,
+    pub interval: i32,
+}
+
+/// What the user is asked to approve on the verification page
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceRequest {
+    pub user_code: String,
+    pub client_id: String,
+    pub client_name: String,
+    pub scope: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceDecision {
+    pub user_code: String,
+    pub approve: bool,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct UserProfile {
+    pub user_id: i32,
+    pub username: String,
+    pub email: Option<String>,
+}
+
+/// Claims released for the granted scopes, shared by the id token and
+/// `/oauth/userinfo`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScopedClaims {
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub aud: String,
+    pub exp: usize,
+    pub iat: usize,
+    pub auth_time: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(flatten)]
+    pub claims: ScopedClaims,
+}
+
+/// Access tokens handed to relying parties. They are not login tokens and
+/// lack the fields `Claims` requires, so `/protected` routes reject them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OAuthAccessClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: usize,
+    pub iat: usize,
+    pub client_id: String,
+    pub org_id: i32,
+    pub scope: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    pub scope: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OAuthError {
+    pub error: String,
+    pub error_description: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub jwks_uri: String,
+    pub response_types_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub scopes_supported: Vec<String>,
+    pub claims_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    pub code_challenge_methods_supported: Vec<String>,
+    pub introspection_endpoint: String,
+    pub revocation_endpoint: String,
+    pub device_authorization_endpoint: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+    pub alg: String,
+    pub kid: String,
+    pub n: String,
+    pub e: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+/// Any token the provider issued that grants access, as presented to
+/// `/oauth/introspect` or `/oauth/revoke`
+pub enum IssuedToken {
+    /// first-party login token, lives as long as its session
+    Login(Claims),
+    /// client-credentials token of a service principal
+    Service(Claims),
+    /// access token handed to a relying party
+    Access(OAuthAccessClaims),
+}
+
+impl IssuedToken {
+    /// Client the token was issued to, login tokens belong to no client
+    pub fn client_id(&self) -> Option<&str> {
+        match self {
+            IssuedToken::Login(_) => None,
+            IssuedToken::Service(claims) => claims.service_id(),
+            IssuedToken::Access(claims) => Some(&claims.client_id),
+        }
+    }
+
+    pub fn org_id(&self) -> Option<i32> {
+        match self {
+            IssuedToken::Login(claims) | IssuedToken::Service(claims) => claims.org_id,
+            IssuedToken::Access(claims) => Some(claims.org_id),
+        }
+    }
+
+    pub fn exp(&self) -> usize {
+        match self {
+            IssuedToken::Login(claims) | IssuedToken::Service(claims) => claims.exp,
+            IssuedToken::Access(claims) => claims.exp,
+        }
+    }
+
+    pub fn from_claims(claims: Claims) -> Option<Self> {
+        match claims.claim_type {
+            ClaimType::Login => Some(IssuedToken::Login(claims)),
+            ClaimType::Service => Some(IssuedToken::Service(claims)),
+            ClaimType::ForgotPassword | ClaimType::ApiKey | ClaimType::Guest => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RevokedToken {
+    pub token_hash: String,
+    pub client_id: Option<String>,
+    /// expiry of the token itself, the row is useless afterwards
+    pub expires_at: DateTime<Utc>,
+}
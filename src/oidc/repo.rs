@@ -0,0 +1,157 @@
+use super::model::{
+    AuthorizationCode, DeviceAuthorization, DevicePoll, OAuthClient, RevokedToken, UserProfile,
+};
+use crate::{auth::model::User, db::DBConn, error::CustomError};
+
+pub struct OidcRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> OidcRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        OidcRepository { db }
+    }
+
+    pub async fn insert_client(&self, client: &OAuthClient) -> Result<(), CustomError> {
+        self.db
+            .insert_oauth_client(client)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(err) if err.is_foreign_key_violation() => {
+                    CustomError::OrgNotFound
+                }
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn fetch_clients(&self, org_id: i32) -> Result<Vec<OAuthClient>, CustomError> {
+        self.db
+            .fetch_oauth_clients(org_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn fetch_client(&self, client_id: &str) -> Result<OAuthClient, CustomError> {
+        self.db
+            .fetch_oauth_client(client_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::OAuthClientNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn insert_code(&self, code: &AuthorizationCode) -> Result<(), CustomError> {
+        self.db
+            .insert_authorization_code(code)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    /// Redeems a code, AuthorizationCodeInvalid when it is unknown, expired
+    /// or was already redeemed
+    pub async fn consume_code(&self, code_hash: &str) -> Result<AuthorizationCode, CustomError> {
+        self.db
+            .consume_authorization_code(code_hash)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::AuthorizationCodeInvalid,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    /// The user as a member of `org_id`, `org_id` of the result is None when
+    /// they are not a member
+    pub async fn query_user(
+        &self,
+        username: &str,
+        org_id: Option<i32>,
+    ) -> Result<User, CustomError> {
+        self.db
+            .fetch_user(username, org_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn fetch_user_profile(&self, user_id: i32) -> Result<UserProfile, CustomError> {
+        self.db
+            .fetch_user_profile(user_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn revoke_token(&self, token: &RevokedToken) -> Result<(), CustomError> {
+        self.db
+            .insert_revoked_token(token)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn is_token_revoked(&self, token_hash: &str) -> Result<bool, CustomError> {
+        self.db
+            .is_token_revoked(token_hash)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn insert_device_code(
+        &self,
+        device: &DeviceAuthorization,
+    ) -> Result<(), CustomError> {
+        self.db
+            .insert_device_code(device)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    /// A request still waiting for the user, DeviceCodeInvalid otherwise
+    pub async fn fetch_pending_device_code(
+        &self,
+        user_code: &str,
+    ) -> Result<DeviceAuthorization, CustomError> {
+        self.db
+            .fetch_pending_device_code(user_code)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::DeviceCodeInvalid,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    /// Approves or denies a pending request of a client in `org_id`
+    pub async fn decide_device_code(
+        &self,
+        user_code: &str,
+        org_id: i32,
+        user_id: i32,
+        status: &str,
+    ) -> Result<DeviceAuthorization, CustomError> {
+        self.db
+            .decide_device_code(user_code, org_id, user_id, status)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::DeviceCodeInvalid,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn poll_device_code(
+        &self,
+        device_code_hash: &str,
+        client_id: &str,
+    ) -> Result<DevicePoll, CustomError> {
+        self.db
+            .poll_device_code(device_code_hash, client_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::DeviceCodeInvalid,
+                _ => CustomError::DBError(e),
+            })
+    }
+}
@@ -0,0 +1,1342 @@
+use std::{collections::HashMap, sync::Arc};
+
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use request_http_parser::parser::Request;
+use rsa::{RsaPublicKey, pkcs8::DecodePublicKey, traits::PublicKeyParts};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use super::{
+    model::{
+        AuthorizationCode, AuthorizationRequest, CreateOAuthClient, CreatedOAuthClient,
+        DEVICE_APPROVED, DEVICE_DENIED, DEVICE_PENDING, DEVICE_REDEEMED, DeviceAuthorization,
+        DeviceAuthorizationResponse, DeviceDecision, DeviceRequest, Discovery,
+        GRANT_AUTHORIZATION_CODE, GRANT_CLIENT_CREDENTIALS, GRANT_DEVICE_CODE, IdTokenClaims,
+        IssuedToken, Jwk, Jwks, OAuthAccessClaims, OAuthClient, OAuthError, RevokedToken,
+        SCOPE_CLAIMS, ScopedClaims, TokenResponse, UserProfile,
+    },
+    repo::OidcRepository,
+};
+use crate::{
+    audit::{
+        model::{AuditEvent, OUTCOME_FAILURE, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    cfg::CONFIG,
+    constants::{
+        BAD_REQUEST, FORBIDDEN, FOUND, INTERNAL_ERROR, MANAGE_USERS, NO_CONTENT, NOT_FOUND,
+        OK_RESPONSE, OK_STATUS, UNAUTHORIZED,
+    },
+    db::DBConn,
+    error::CustomError,
+    rolepermissions::service::RolePermissionSvc,
+    session::service::SessionSvc,
+    utils::{
+        ClaimType, Claims, SERVICE_SUBJECT_PREFIX, constant_time_eq, decode_jwt, des_from_str,
+        encrypt, extract_token, html_escape, is_password_valid, parse_form, percent_decode,
+        percent_encode, random_token, ser_to_str, sha256_hex, sign_jwt, token_expiry, verify_jwt,
+        with_headers,
+    },
+};
+
+pub const AUTHORIZE_PATH: &str = "/oauth/authorize";
+pub const TOKEN_PATH: &str = "/oauth/token";
+pub const USERINFO_PATH: &str = "/oauth/userinfo";
+pub const JWKS_PATH: &str = "/oauth/jwks";
+pub const INTROSPECT_PATH: &str = "/oauth/introspect";
+pub const REVOKE_PATH: &str = "/oauth/revoke";
+pub const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+pub const DEVICE_CODE_PATH: &str = "/device/code";
+pub const DEVICE_PATH: &str = "/protected/device";
+
+/// Authorization codes are exchanged right after the redirect
+const CODE_LIFETIME_MINUTES: i64 = 10;
+
+/// Time the user has to find a second device and enter the user code
+const DEVICE_CODE_LIFETIME_MINUTES: i64 = 10;
+
+const DEVICE_POLL_INTERVAL_SECS: i32 = 5;
+
+/// Consonants only, user codes cannot spell words and survive being read
+/// aloud (RFC 8628 section 6.1)
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+/// Public half of the signing key, `kid` is its RFC 7638 thumbprint
+static SIGNING_JWK: Lazy<Jwk> = Lazy::new(|| {
+    let pem = CONFIG.jwt_public_key.replace("\\n", "\n");
+    let key = RsaPublicKey::from_public_key_pem(&pem).expect("Invalid public key");
+    let n = URL_SAFE_NO_PAD.encode(key.n().to_bytes_be());
+    let e = URL_SAFE_NO_PAD.encode(key.e().to_bytes_be());
+    let thumbprint = format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, e, n);
+    Jwk {
+        kty: "RSA".to_string(),
+        key_use: "sig".to_string(),
+        alg: "RS256".to_string(),
+        kid: URL_SAFE_NO_PAD.encode(Sha256::digest(thumbprint.as_bytes())),
+        n,
+        e,
+    }
+});
+
+/// What a user authorized a client to, by code or on a device
+struct UserGrant<'a> {
+    user_id: i32,
+    org_id: i32,
+    scope: &'a str,
+    nonce: Option<String>,
+    auth_time: DateTime<Utc>,
+}
+
+pub struct OidcSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: OidcRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
+    sessions: Arc<SessionSvc<DB>>,
+}
+
+impl<DB> OidcSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>, sessions: Arc<SessionSvc<DB>>) -> Self {
+        OidcSvc {
+            repository: OidcRepository::new(pool),
+            audit,
+            sessions,
+        }
+    }
+
+    pub fn discovery(&self) -> (String, String) {
+        let issuer = &CONFIG.oidc_issuer;
+        let discovery = Discovery {
+            issuer: issuer.to_string(),
+            authorization_endpoint: format!("{}{}", issuer, AUTHORIZE_PATH),
+            token_endpoint: format!("{}{}", issuer, TOKEN_PATH),
+            userinfo_endpoint: format!("{}{}", issuer, USERINFO_PATH),
+            jwks_uri: format!("{}{}", issuer, JWKS_PATH),
+            response_types_supported: vec!["code".to_string()],
+            grant_types_supported: vec![
+                GRANT_AUTHORIZATION_CODE.to_string(),
+                GRANT_CLIENT_CREDENTIALS.to_string(),
+                GRANT_DEVICE_CODE.to_string(),
+            ],
+            subject_types_supported: vec!["public".to_string()],
+            id_token_signing_alg_values_supported: vec!["RS256".to_string()],
+            scopes_supported: SCOPE_CLAIMS.iter().map(|(s, _)| s.to_string()).collect(),
+            claims_supported: SCOPE_CLAIMS
+                .iter()
+                .flat_map(|(_, claims)| claims.iter().map(|c| c.to_string()))
+                .collect(),
+            token_endpoint_auth_methods_supported: vec![
+                "client_secret_basic".to_string(),
+                "client_secret_post".to_string(),
+                "none".to_string(),
+            ],
+            code_challenge_methods_supported: vec!["S256".to_string()],
+            introspection_endpoint: format!("{}{}", issuer, INTROSPECT_PATH),
+            revocation_endpoint: format!("{}{}", issuer, REVOKE_PATH),
+            device_authorization_endpoint: format!("{}{}", issuer, DEVICE_CODE_PATH),
+        };
+        match ser_to_str(&discovery) {
+            Ok(json) => (OK_RESPONSE.to_string(), json),
+            Err(_) => {
+                println!("serde error");
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    pub fn jwks(&self) -> (String, String) {
+        let jwks = Jwks {
+            keys: vec![SIGNING_JWK.clone()],
+        };
+        match ser_to_str(&jwks) {
+            Ok(json) => (OK_RESPONSE.to_string(), json),
+            Err(_) => {
+                println!("serde error");
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// `GET /oauth/authorize`, the login and consent page
+    pub async fn authorize_page(&self, request: &Request) -> (String, String) {
+        let params: HashMap<String, String> = request
+            .params
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key.clone(), percent_decode(value)))
+            .collect();
+        let auth = AuthorizationRequest::from_params(&params);
+        match self.validate_authorization(&auth).await {
+            Ok(client) => html(OK_STATUS, login_page(&client, &auth, None)),
+            Err(response) => response,
+        }
+    }
+
+    /// `POST /oauth/authorize`, submitted by the login page. Redirects back
+    /// to the client with a code once the user signed in and consented.
+    pub async fn authorize(&self, request: &Request) -> (String, String) {
+        let form = parse_form(request.body.as_deref().unwrap_or(""));
+        let auth = AuthorizationRequest::from_params(&form);
+        let client = match self.validate_authorization(&auth).await {
+            Ok(client) => client,
+            Err(response) => return response,
+        };
+        if form.get("decision").map(String::as_str) != Some("allow") {
+            return redirect(&auth, &[("error", "access_denied")]);
+        }
+        let username = form.get("username").cloned().unwrap_or_default();
+        let password = form.get("password").cloned().unwrap_or_default();
+
+        let user = match self
+            .repository
+            .query_user(&username, Some(client.org_id))
+            .await
+        {
+            Ok(user) => Some(user),
+            Err(CustomError::UserNotFound) => None,
+            Err(error) => {
+                eprintln!("Error user db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let failure = match &user {
+            None => Some("user_not_found"),
+            Some(user) => match &user.password {
+                Some(hash) if is_password_valid(&password, hash) => {
+                    user.org_id.is_none().then_some("not_a_member")
+                }
+                _ => Some("wrong_password"),
+            },
+        };
+        let (user, user_id) = match (failure, user) {
+            (None, Some(user)) => match user.user_id {
+                Some(user_id) => (user, user_id),
+                None => return (INTERNAL_ERROR.to_string(), "".to_string()),
+            },
+            (reason, _) => {
+                self.audit
+                    .record(AuditEvent {
+                        org_id: Some(client.org_id),
+                        target: Some(format!("oauth_client:{}", client.client_id)),
+                        metadata: json!({
+                            "username": username,
+                            "reason": reason.unwrap_or("user_not_found"),
+                        }),
+                        ..AuditEvent::new("oauth.authorize", OUTCOME_FAILURE, Some(request))
+                    })
+                    .await;
+                return html(
+                    UNAUTHORIZED,
+                    login_page(&client, &auth, Some("Username or password is incorrect")),
+                );
+            }
+        };
+
+        let code = random_token(32);
+        let now = Utc::now();
+        let authorization_code = AuthorizationCode {
+            code_hash: sha256_hex(&code),
+            client_id: client.client_id.clone(),
+            user_id,
+            org_id: client.org_id,
+            redirect_uri: auth.redirect_uri.clone(),
+            scope: auth.scopes().join(" "),
+            nonce: auth.nonce.clone(),
+            code_challenge: auth.code_challenge.clone(),
+            created_at: now,
+            expires_at: now + Duration::minutes(CODE_LIFETIME_MINUTES),
+        };
+        if let Err(error) = self.repository.insert_code(&authorization_code).await {
+            eprintln!("Error oauth db: {:#?}", error);
+            return (INTERNAL_ERROR.to_string(), "".to_string());
+        }
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(client.org_id),
+                actor_id: Some(user_id),
+                target: Some(format!("oauth_client:{}", client.client_id)),
+                metadata: json!({ "username": user.username, "scope": authorization_code.scope }),
+                ..AuditEvent::new("oauth.authorize", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        redirect(&auth, &[("code", &code)])
+    }
+
+    /// `POST /oauth/token`
+    pub async fn token(&self, request: &Request) -> (String, String) {
+        let form = parse_form(request.body.as_deref().unwrap_or(""));
+        let grant_type = form.get("grant_type").cloned().unwrap_or_default();
+        if ![
+            GRANT_AUTHORIZATION_CODE,
+            GRANT_CLIENT_CREDENTIALS,
+            GRANT_DEVICE_CODE,
+        ]
+        .contains(&grant_type.as_str())
+        {
+            return oauth_error(
+                BAD_REQUEST,
+                "unsupported_grant_type",
+                "grant_type must be authorization_code, client_credentials or device_code",
+            );
+        }
+        let client = match self.authenticate_client(request, &form).await {
+            Ok(client) => client,
+            Err(response) => return response,
+        };
+        if !client.allows_grant(&grant_type) {
+            return oauth_error(
+                BAD_REQUEST,
+                "unauthorized_client",
+                "grant_type is not allowed for this client",
+            );
+        }
+        match grant_type.as_str() {
+            GRANT_CLIENT_CREDENTIALS => self.client_credentials(request, &form, client).await,
+            GRANT_DEVICE_CODE => self.poll_device(request, &form, client).await,
+            _ => self.exchange_code(request, &form, client).await,
+        }
+    }
+
+    /// Authorization code grant, tokens for the user who signed in
+    async fn exchange_code(
+        &self,
+        request: &Request,
+        form: &HashMap<String, String>,
+        client: OAuthClient,
+    ) -> (String, String) {
+        let code = form.get("code").cloned().unwrap_or_default();
+        let code = match self.repository.consume_code(&sha256_hex(&code)).await {
+            Ok(code) => code,
+            Err(CustomError::AuthorizationCodeInvalid) => {
+                return oauth_error(BAD_REQUEST, "invalid_grant", "code is invalid or expired");
+            }
+            Err(error) => {
+                eprintln!("Error oauth db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        if code.client_id != client.client_id
+            || form.get("redirect_uri") != Some(&code.redirect_uri)
+        {
+            return oauth_error(
+                BAD_REQUEST,
+                "invalid_grant",
+                "code was issued to another client or redirect_uri",
+            );
+        }
+        let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+        if !(43..=128).contains(&verifier.len())
+            || !constant_time_eq(&pkce_challenge(&verifier), &code.code_challenge)
+        {
+            return oauth_error(BAD_REQUEST, "invalid_grant", "code_verifier does not match");
+        }
+
+        let grant = UserGrant {
+            user_id: code.user_id,
+            org_id: code.org_id,
+            scope: &code.scope,
+            nonce: code.nonce.clone(),
+            auth_time: code.created_at,
+        };
+        self.user_tokens(request, &client, grant, GRANT_AUTHORIZATION_CODE)
+            .await
+    }
+
+    /// `POST /device/code`, starts a device sign-in. The device shows the
+    /// user code and polls `/oauth/token` until a user approved it.
+    pub async fn device_authorization(&self, request: &Request) -> (String, String) {
+        let form = parse_form(request.body.as_deref().unwrap_or(""));
+        let client = match self.authenticate_client(request, &form).await {
+            Ok(client) => client,
+            Err(response) => return response,
+        };
+        if !client.allows_grant(GRANT_DEVICE_CODE) {
+            return oauth_error(
+                BAD_REQUEST,
+                "unauthorized_client",
+                "device_code grant is not allowed for this client",
+            );
+        }
+        let scope = match granted_scope(&client, &form) {
+            Some(scope) => scope,
+            None => return oauth_error(BAD_REQUEST, "invalid_scope", "scope is not allowed"),
+        };
+
+        let device_code = random_token(32);
+        let user_code = user_code();
+        let now = Utc::now();
+        let device = DeviceAuthorization {
+            device_code_hash: sha256_hex(&device_code),
+            user_code: user_code.clone(),
+            client_id: client.client_id.clone(),
+            org_id: client.org_id,
+            scope,
+            status: DEVICE_PENDING.to_string(),
+            user_id: None,
+            interval_secs: DEVICE_POLL_INTERVAL_SECS,
+            last_polled_at: None,
+            created_at: now,
+            expires_at: now + Duration::minutes(DEVICE_CODE_LIFETIME_MINUTES),
+        };
+        if let Err(error) = self.repository.insert_device_code(&device).await {
+            eprintln!("Error oauth db: {:#?}", error);
+            return (INTERNAL_ERROR.to_string(), "".to_string());
+        }
+        let display_code = format_user_code(&user_code);
+        let response = DeviceAuthorizationResponse {
+            device_code,
+            verification_uri: CONFIG.device_verification_uri.clone(),
+            verification_uri_complete: format!(
+                "{}?user_code={}",
+                CONFIG.device_verification_uri, display_code
+            ),
+            user_code: display_code,
+            expires_in: (device.expires_at - now).num_seconds(),
+            interval: device.interval_secs,
+        };
+        match ser_to_str(&response) {
+            Ok(json) => (no_store(OK_RESPONSE), json),
+            Err(_) => {
+                println!("serde error");
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// `GET /protected/device?user_code=`, the pending request behind a user
+    /// code so the user can check what they approve
+    pub async fn device_request(
+        &self,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let org_id = match device_caller(&claims) {
+            Ok((_, org_id)) => org_id,
+            Err(response) => return response,
+        };
+        let user_code = request
+            .params
+            .as_ref()
+            .and_then(|params| params.get("user_code"))
+            .map(|code| normalize_user_code(&percent_decode(code)))
+            .unwrap_or_default();
+        let device = match self.repository.fetch_pending_device_code(&user_code).await {
+            // requests of other organizations' clients are not shown
+            Ok(device) if device.org_id == org_id => device,
+            Ok(_) | Err(CustomError::DeviceCodeInvalid) => {
+                return (NOT_FOUND.to_string(), "".to_string());
+            }
+            Err(error) => {
+                eprintln!("Error oauth db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let client = match self.repository.fetch_client(&device.client_id).await {
+            Ok(client) => client,
+            Err(CustomError::OAuthClientNotFound) => {
+                return (NOT_FOUND.to_string(), "".to_string());
+            }
+            Err(error) => {
+                eprintln!("Error oauth db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response = DeviceRequest {
+            user_code: format_user_code(&device.user_code),
+            client_id: client.client_id,
+            client_name: client.name,
+            scope: device.scope,
+            expires_at: device.expires_at,
+        };
+        match ser_to_str(&response) {
+            Ok(json) => (OK_RESPONSE.to_string(), json),
+            Err(_) => {
+                println!("serde error");
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// `POST /protected/device`, the signed-in user approves or denies the
+    /// device showing the user code
+    pub async fn decide_device(
+        &self,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (user_id, org_id) = match device_caller(&claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let decision: DeviceDecision = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(decision) => decision,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let status = if decision.approve {
+            DEVICE_APPROVED
+        } else {
+            DEVICE_DENIED
+        };
+        let user_code = normalize_user_code(&decision.user_code);
+        let device = match self
+            .repository
+            .decide_device_code(&user_code, org_id, user_id, status)
+            .await
+        {
+            Ok(device) => device,
+            Err(CustomError::DeviceCodeInvalid) => {
+                return (NOT_FOUND.to_string(), "Code invalid or expired".to_string());
+            }
+            Err(error) => {
+                eprintln!("Error oauth db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: Some(user_id),
+                target: Some(format!("oauth_client:{}", device.client_id)),
+                metadata: json!({ "approved": decision.approve, "scope": device.scope }),
+                ..AuditEvent::new("oauth.device_authorize", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        (NO_CONTENT.to_string(), "".to_string())
+    }
+
+    /// Device code grant, the device polls until the user decided
+    async fn poll_device(
+        &self,
+        request: &Request,
+        form: &HashMap<String, String>,
+        client: OAuthClient,
+    ) -> (String, String) {
+        let device_code = form.get("device_code").cloned().unwrap_or_default();
+        let poll = match self
+            .repository
+            .poll_device_code(&sha256_hex(&device_code), &client.client_id)
+            .await
+        {
+            Ok(poll) => poll,
+            // a code issued to another client is unknown to this one
+            Err(CustomError::DeviceCodeInvalid) => {
+                return oauth_error(BAD_REQUEST, "invalid_grant", "device_code is invalid");
+            }
+            Err(error) => {
+                eprintln!("Error oauth db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let device = poll.authorization;
+        if device.status == DEVICE_REDEEMED {
+            return oauth_error(BAD_REQUEST, "invalid_grant", "device_code was already used");
+        }
+        if device.expires_at <= Utc::now() {
+            return oauth_error(BAD_REQUEST, "expired_token", "device_code has expired");
+        }
+        match (device.status.as_str(), device.user_id) {
+            (DEVICE_APPROVED, Some(user_id)) => {
+                let grant = UserGrant {
+                    user_id,
+                    org_id: device.org_id,
+                    scope: &device.scope,
+                    nonce: None,
+                    auth_time: Utc::now(),
+                };
+                self.user_tokens(request, &client, grant, GRANT_DEVICE_CODE)
+                    .await
+            }
+            (DEVICE_DENIED, _) => {
+                oauth_error(BAD_REQUEST, "access_denied", "the user denied the request")
+            }
+            _ if poll.slow_down => oauth_error(
+                BAD_REQUEST,
+                "slow_down",
+                "polling too fast, wait 5 more seconds between requests",
+            ),
+            _ => oauth_error(
+                BAD_REQUEST,
+                "authorization_pending",
+                "the user has not approved the request yet",
+            ),
+        }
+    }
+
+    /// Access token, and an id token when `openid` was granted, for a user
+    /// who authorized `client`
+    async fn user_tokens(
+        &self,
+        request: &Request,
+        client: &OAuthClient,
+        grant: UserGrant<'_>,
+        grant_type: &str,
+    ) -> (String, String) {
+        let UserGrant {
+            user_id,
+            org_id,
+            scope,
+            nonce,
+            auth_time,
+        } = grant;
+        let profile = match self.repository.fetch_user_profile(user_id).await {
+            Ok(profile) => profile,
+            Err(CustomError::UserNotFound) => {
+                return oauth_error(BAD_REQUEST, "invalid_grant", "user no longer exists");
+            }
+            Err(error) => {
+                eprintln!("Error user db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let now = Utc::now();
+        let expires_at = token_expiry(&ClaimType::Login);
+        let access_claims = OAuthAccessClaims {
+            iss: CONFIG.oidc_issuer.clone(),
+            sub: user_id.to_string(),
+            aud: client.client_id.clone(),
+            exp: expires_at.timestamp() as usize,
+            iat: now.timestamp() as usize,
+            client_id: client.client_id.clone(),
+            org_id,
+            scope: scope.to_string(),
+        };
+        let access_token = match sign_jwt(&access_claims, &SIGNING_JWK.kid) {
+            Ok(token) => token,
+            Err(e) => {
+                eprintln!("Error creating JWT: {:#?}", e);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let id_token = if scope.split_whitespace().any(|scope| scope == "openid") {
+            let id_claims = IdTokenClaims {
+                iss: CONFIG.oidc_issuer.clone(),
+                aud: client.client_id.clone(),
+                exp: expires_at.timestamp() as usize,
+                iat: now.timestamp() as usize,
+                auth_time: auth_time.timestamp() as usize,
+                nonce,
+                claims: scoped_claims(&profile, org_id, scope),
+            };
+            match sign_jwt(&id_claims, &SIGNING_JWK.kid) {
+                Ok(token) => Some(token),
+                Err(e) => {
+                    eprintln!("Error creating JWT: {:#?}", e);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            }
+        } else {
+            None
+        };
+        let response = TokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: (expires_at - now).num_seconds(),
+            id_token,
+            scope: scope.to_string(),
+        };
+        let response_json = match ser_to_str(&response) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: Some(user_id),
+                target: Some(format!("oauth_client:{}", client.client_id)),
+                metadata: json!({ "grant_type": grant_type, "scope": scope }),
+                ..AuditEvent::new("oauth.token", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        (no_store(OK_RESPONSE), response_json)
+    }
+
+    /// Client credentials grant, a token for the client itself as a service
+    /// principal. Only confidential clients can use it.
+    async fn client_credentials(
+        &self,
+        request: &Request,
+        form: &HashMap<String, String>,
+        client: OAuthClient,
+    ) -> (String, String) {
+        if client.secret_hash.is_none() {
+            return oauth_error(UNAUTHORIZED, "invalid_client", "client secret required");
+        }
+        let scope = match granted_scope(&client, form) {
+            Some(scope) => scope,
+            None => return oauth_error(BAD_REQUEST, "invalid_scope", "scope is not allowed"),
+        };
+
+        let now = Utc::now();
+        let expires_at = token_expiry(&ClaimType::Service);
+        let claims = Claims {
+            sub: format!("{}{}", SERVICE_SUBJECT_PREFIX, client.client_id),
+            exp: expires_at.timestamp() as usize,
+            username: client.name.clone(),
+            role_ids: vec![],
+            org_id: Some(client.org_id),
+            claim_type: ClaimType::Service,
+            permissions: None,
+            perm_bitmap: None,
+            perm_version: None,
+            sid: None,
+            scope: Some(scope.clone()),
+            api_key_id: None,
+            room_id: None,
+        };
+        let access_token = match sign_jwt(&claims, &SIGNING_JWK.kid) {
+            Ok(token) => token,
+            Err(e) => {
+                eprintln!("Error creating JWT: {:#?}", e);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response = TokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: (expires_at - now).num_seconds(),
+            id_token: None,
+            scope: scope.clone(),
+        };
+        let response_json = match ser_to_str(&response) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(client.org_id),
+                target: Some(format!("oauth_client:{}", client.client_id)),
+                metadata: json!({ "grant_type": GRANT_CLIENT_CREDENTIALS, "scope": scope }),
+                ..AuditEvent::new("oauth.token", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        (no_store(OK_RESPONSE), response_json)
+    }
+
+    /// `GET /oauth/userinfo`, claims of the access token's user limited to
+    /// its scopes
+    pub async fn userinfo(&self, request: &Request) -> (String, String) {
+        let invalid_token = || {
+            (
+                with_headers(
+                    UNAUTHORIZED,
+                    &[r#"WWW-Authenticate: Bearer error="invalid_token""#.to_string()],
+                ),
+                "".to_string(),
+            )
+        };
+        let token = extract_token(&request.headers).unwrap_or_default();
+        let access_claims = match decode_jwt::<OAuthAccessClaims>(&token) {
+            Ok(claims) if claims.iss == CONFIG.oidc_issuer => claims,
+            _ => return invalid_token(),
+        };
+        match self.repository.is_token_revoked(&sha256_hex(&token)).await {
+            Ok(false) => {}
+            Ok(true) => return invalid_token(),
+            Err(error) => {
+                eprintln!("Error oauth db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        let profile = match access_claims.sub.parse::<i32>() {
+            Ok(user_id) => match self.repository.fetch_user_profile(user_id).await {
+                Ok(profile) => profile,
+                Err(CustomError::UserNotFound) => {
+                    return (UNAUTHORIZED.to_string(), "".to_string());
+                }
+                Err(error) => {
+                    eprintln!("Error user db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            },
+            Err(_) => return (UNAUTHORIZED.to_string(), "".to_string()),
+        };
+        let claims = scoped_claims(&profile, access_claims.org_id, &access_claims.scope);
+        match ser_to_str(&claims) {
+            Ok(json) => (no_store(OK_RESPONSE), json),
+            Err(_) => {
+                println!("serde error");
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// `POST /oauth/introspect` (RFC 7662) for gateways that cannot verify
+    /// tokens themselves. Only confidential clients may ask, and only about
+    /// tokens of their own organization.
+    pub async fn introspect(&self, request: &Request) -> (String, String) {
+        let form = parse_form(request.body.as_deref().unwrap_or(""));
+        let client = match self.authenticate_client(request, &form).await {
+            Ok(client) if client.secret_hash.is_some() => client,
+            Ok(_) => return oauth_error(UNAUTHORIZED, "invalid_client", "client secret required"),
+            Err(response) => return response,
+        };
+        let token = match form.get("token") {
+            Some(token) if !token.is_empty() => token,
+            _ => return oauth_error(BAD_REQUEST, "invalid_request", "token is required"),
+        };
+        let inactive = || (no_store(OK_RESPONSE), r#"{"active":false}"#.to_string());
+
+        let issued = match decode_issued(token) {
+            Some(issued) if issued.org_id() == Some(client.org_id) => issued,
+            _ => return inactive(),
+        };
+        match self.repository.is_token_revoked(&sha256_hex(token)).await {
+            Ok(false) => {}
+            Ok(true) => return inactive(),
+            Err(error) => {
+                eprintln!("Error oauth db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        if let IssuedToken::Login(claims) = &issued {
+            match self.sessions.check(claims).await {
+                Ok(_) => {}
+                Err(CustomError::SessionNotFound) => return inactive(),
+                Err(error) => {
+                    eprintln!("Error session db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            }
+        }
+
+        let mut body = match decode_jwt::<serde_json::Map<String, serde_json::Value>>(token) {
+            Ok(body) => body,
+            Err(_) => return inactive(),
+        };
+        body.insert("active".to_string(), json!(true));
+        body.insert("token_type".to_string(), json!("Bearer"));
+        if let Some(client_id) = issued.client_id() {
+            body.insert("client_id".to_string(), json!(client_id));
+        }
+        match ser_to_str(&body) {
+            Ok(json) => (no_store(OK_RESPONSE), json),
+            Err(_) => {
+                println!("serde error");
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// `POST /oauth/revoke` (RFC 7009). Clients can revoke the tokens issued
+    /// to them. We issue no refresh tokens, so a `refresh_token` hint is
+    /// answered like any unknown token, with 200.
+    pub async fn revoke(&self, request: &Request) -> (String, String) {
+        let form = parse_form(request.body.as_deref().unwrap_or(""));
+        let client = match self.authenticate_client(request, &form).await {
+            Ok(client) => client,
+            Err(response) => return response,
+        };
+        let token = match form.get("token") {
+            Some(token) if !token.is_empty() => token,
+            _ => return oauth_error(BAD_REQUEST, "invalid_request", "token is required"),
+        };
+        // invalid or expired tokens need no revoking
+        let issued = match decode_issued(token) {
+            Some(issued) => issued,
+            None => return (OK_RESPONSE.to_string(), "".to_string()),
+        };
+        if issued.client_id() != Some(client.client_id.as_str()) {
+            return oauth_error(
+                BAD_REQUEST,
+                "unauthorized_client",
+                "token was not issued to this client",
+            );
+        }
+        let revoked = RevokedToken {
+            token_hash: sha256_hex(token),
+            client_id: Some(client.client_id.clone()),
+            expires_at: DateTime::from_timestamp(issued.exp() as i64, 0).unwrap_or_else(Utc::now),
+        };
+        if let Err(error) = self.repository.revoke_token(&revoked).await {
+            eprintln!("Error oauth db: {:#?}", error);
+            return (INTERNAL_ERROR.to_string(), "".to_string());
+        }
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(client.org_id),
+                target: Some(format!("oauth_client:{}", client.client_id)),
+                metadata: json!({ "token_hash": revoked.token_hash }),
+                ..AuditEvent::new("oauth.revoke", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        (OK_RESPONSE.to_string(), "".to_string())
+    }
+
+    /// Registers a relying party in the caller's organization
+    pub async fn create_client(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id) = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+        let req_client: CreateOAuthClient = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(client) => client,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if let Err(reason) = validate_client(&req_client) {
+            return (BAD_REQUEST.to_string(), reason.to_string());
+        }
+
+        let client_secret = (!req_client.public).then(|| random_token(32));
+        let client = OAuthClient {
+            client_id: random_token(16),
+            org_id,
+            name: req_client.name,
+            secret_hash: client_secret.as_deref().map(encrypt),
+            redirect_uris: req_client.redirect_uris,
+            allowed_scopes: req_client.allowed_scopes,
+            grant_types: req_client.grant_types,
+            created_at: Utc::now(),
+        };
+        if let Err(error) = self.repository.insert_client(&client).await {
+            eprintln!("Error insert oauth client db: {:#?}", error);
+            return (INTERNAL_ERROR.to_string(), "".to_string());
+        }
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: claims.user_id(),
+                target: Some(format!("oauth_client:{}", client.client_id)),
+                metadata: json!({
+                    "name": client.name,
+                    "redirect_uris": client.redirect_uris,
+                    "grant_types": client.grant_types,
+                    "public": client.secret_hash.is_none(),
+                }),
+                ..AuditEvent::new("oauth_client.create", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        let response = CreatedOAuthClient {
+            client,
+            client_secret,
+        };
+        match ser_to_str(&response) {
+            Ok(json) => (no_store(OK_RESPONSE), json),
+            Err(_) => {
+                println!("serde error");
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    pub async fn get_clients(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let org_id = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok((_, org_id)) => org_id,
+            Err(response) => return response,
+        };
+        let clients = match self.repository.fetch_clients(org_id).await {
+            Ok(clients) => clients,
+            Err(error) => {
+                eprintln!("Error oauth db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        match ser_to_str(&clients) {
+            Ok(json) => (OK_RESPONSE.to_string(), json),
+            Err(_) => {
+                println!("serde error");
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// Checks an authorization request. Until the client and redirect URI
+    /// are trusted errors are shown on our page, afterwards they are
+    /// redirected back to the client.
+    async fn validate_authorization(
+        &self,
+        auth: &AuthorizationRequest,
+    ) -> Result<OAuthClient, (String, String)> {
+        let client = match self.repository.fetch_client(&auth.client_id).await {
+            Ok(client) => client,
+            Err(CustomError::OAuthClientNotFound) => {
+                return Err(html(BAD_REQUEST, error_page("Unknown client")));
+            }
+            Err(error) => {
+                eprintln!("Error oauth db: {:#?}", error);
+                return Err((INTERNAL_ERROR.to_string(), "".to_string()));
+            }
+        };
+        if !client.redirect_uris.contains(&auth.redirect_uri) {
+            return Err(html(
+                BAD_REQUEST,
+                error_page("The redirect URI is not registered for this client"),
+            ));
+        }
+        if !client.allows_grant(GRANT_AUTHORIZATION_CODE) {
+            return Err(redirect(auth, &[("error", "unauthorized_client")]));
+        }
+        if auth.response_type != "code" {
+            return Err(redirect(auth, &[("error", "unsupported_response_type")]));
+        }
+        let scopes = auth.scopes();
+        if !scopes.contains(&"openid")
+            || !scopes
+                .iter()
+                .all(|scope| client.allowed_scopes.iter().any(|allowed| allowed == scope))
+        {
+            return Err(redirect(auth, &[("error", "invalid_scope")]));
+        }
+        if auth.code_challenge.is_empty() || auth.code_challenge_method != "S256" {
+            return Err(redirect(
+                auth,
+                &[
+                    ("error", "invalid_request"),
+                    ("error_description", "PKCE with S256 is required"),
+                ],
+            ));
+        }
+        Ok(client)
+    }
+
+    /// Client authentication at the token endpoint, `client_secret_basic`,
+    /// `client_secret_post` or only `client_id` for public clients
+    async fn authenticate_client(
+        &self,
+        request: &Request,
+        form: &HashMap<String, String>,
+    ) -> Result<OAuthClient, (String, String)> {
+        let basic = request
+            .headers
+            .get("authorization")
+            .and_then(|header| header.strip_prefix("Basic "))
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                decoded
+                    .split_once(':')
+                    .map(|(id, secret)| (percent_decode(id), Some(percent_decode(secret))))
+            });
+        let (client_id, secret) = match basic {
+            Some(credentials) => credentials,
+            None => (
+                form.get("client_id").cloned().unwrap_or_default(),
+                form.get("client_secret").cloned(),
+            ),
+        };
+        let invalid_client = || oauth_error(UNAUTHORIZED, "invalid_client", "unknown client");
+        let client = match self.repository.fetch_client(&client_id).await {
+            Ok(client) => client,
+            Err(CustomError::OAuthClientNotFound) => return Err(invalid_client()),
+            Err(error) => {
+                eprintln!("Error oauth db: {:#?}", error);
+                return Err((INTERNAL_ERROR.to_string(), "".to_string()));
+            }
+        };
+        match (&client.secret_hash, secret) {
+            (None, _) => Ok(client),
+            (Some(hash), Some(secret)) if is_password_valid(&secret, hash) => Ok(client),
+            _ => Err(invalid_client()),
+        }
+    }
+}
+
+/// Verifies a token we issued and tells its kind apart. Password reset
+/// tokens grant no access and are left out.
+fn decode_issued(token: &str) -> Option<IssuedToken> {
+    match verify_jwt(token) {
+        Ok(claims) => IssuedToken::from_claims(claims),
+        Err(_) => decode_jwt::<OAuthAccessClaims>(token)
+            .ok()
+            .filter(|claims| claims.iss == CONFIG.oidc_issuer)
+            .map(IssuedToken::Access),
+    }
+}
+
+/// S256 code challenge of a PKCE verifier
+fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Requested scope when the client may use all of it, every allowed scope
+/// when none was requested
+fn granted_scope(client: &OAuthClient, form: &HashMap<String, String>) -> Option<String> {
+    let requested: Vec<&str> = form
+        .get("scope")
+        .map(|scope| scope.split_whitespace().collect())
+        .unwrap_or_default();
+    if requested.is_empty() {
+        Some(client.allowed_scopes.join(" "))
+    } else if requested
+        .iter()
+        .all(|scope| client.allowed_scopes.iter().any(|allowed| allowed == scope))
+    {
+        Some(requested.join(" "))
+    } else {
+        None
+    }
+}
+
+/// Device requests are decided with a login token in the organization of
+/// the device's client
+fn device_caller(claims: &Option<Claims>) -> Result<(i32, i32), (String, String)> {
+    match claims {
+        Some(claims) if claims.claim_type == ClaimType::Login => {
+            match (claims.user_id(), claims.org_id) {
+                (Some(user_id), Some(org_id)) => Ok((user_id, org_id)),
+                _ => Err((FORBIDDEN.to_string(), "".to_string())),
+            }
+        }
+        _ => Err((FORBIDDEN.to_string(), "".to_string())),
+    }
+}
+
+/// Eight letters, 20^8 codes are plenty for the few requests pending at once
+fn user_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..8)
+        .map(|_| USER_CODE_ALPHABET[rng.gen_range(0..USER_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// `BCDFGHJK` is shown as `BCDF-GHJK`
+fn format_user_code(code: &str) -> String {
+    match code.len() {
+        8 => format!("{}-{}", &code[..4], &code[4..]),
+        _ => code.to_string(),
+    }
+}
+
+/// Users type codes in any case, with or without the dash
+fn normalize_user_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+fn scoped_claims(profile: &UserProfile, org_id: i32, scope: &str) -> ScopedClaims {
+    let scopes: Vec<&str> = scope.split_whitespace().collect();
+    ScopedClaims {
+        sub: profile.user_id.to_string(),
+        preferred_username: scopes
+            .contains(&"profile")
+            .then(|| profile.username.clone()),
+        email: scopes
+            .contains(&"email")
+            .then(|| profile.email.clone())
+            .flatten(),
+        org_id: scopes.contains(&"org").then_some(org_id),
+    }
+}
+
+/// Relying parties need redirect URIs and the openid scope, machine clients
+/// need a secret to authenticate with
+fn validate_client(client: &CreateOAuthClient) -> Result<(), &'static str> {
+    if client.name.trim().is_empty() {
+        return Err("Name is required");
+    }
+    if client.grant_types.is_empty()
+        || !client.grant_types.iter().all(|grant| {
+            [
+                GRANT_AUTHORIZATION_CODE,
+                GRANT_CLIENT_CREDENTIALS,
+                GRANT_DEVICE_CODE,
+            ]
+            .contains(&grant.as_str())
+        })
+    {
+        return Err("Unsupported grant types");
+    }
+    if client.allowed_scopes.is_empty()
+        || !client.allowed_scopes.iter().all(|scope| valid_scope(scope))
+    {
+        return Err("Invalid scopes");
+    }
+    let grants = |grant: &str| client.grant_types.iter().any(|g| g == grant);
+    // scopes of user tokens release claims, only machine clients name
+    // scopes of their own for the services they call
+    if (grants(GRANT_AUTHORIZATION_CODE) || grants(GRANT_DEVICE_CODE))
+        && !client
+            .allowed_scopes
+            .iter()
+            .all(|scope| SCOPE_CLAIMS.iter().any(|(known, _)| known == scope))
+    {
+        return Err("Unknown scopes");
+    }
+    if grants(GRANT_AUTHORIZATION_CODE) {
+        if client.redirect_uris.is_empty()
+            || !client
+                .redirect_uris
+                .iter()
+                .all(|uri| valid_redirect_uri(uri))
+        {
+            return Err("Invalid redirect URIs");
+        }
+        if !client.allowed_scopes.iter().any(|scope| scope == "openid") {
+            return Err("Relying parties need the openid scope");
+        }
+    } else if !client.redirect_uris.is_empty() {
+        return Err("Redirect URIs need the authorization_code grant");
+    }
+    if grants(GRANT_CLIENT_CREDENTIALS) && client.public {
+        return Err("Machine clients must be confidential");
+    }
+    Ok(())
+}
+
+/// RFC 6749 scope token, printable ASCII without space, quote or backslash
+fn valid_scope(scope: &str) -> bool {
+    !scope.is_empty()
+        && scope
+            .chars()
+            .all(|c| c.is_ascii_graphic() && c != '"' && c != '\\')
+}
+
+/// Registered redirect URIs must be absolute https URLs, plain http is
+/// only allowed for loopback during development
+fn valid_redirect_uri(uri: &str) -> bool {
+    let loopback = ["http://localhost", "http://127.0.0.1"]
+        .iter()
+        .any(|prefix| {
+            uri.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with([':', '/']))
+        });
+    (uri.starts_with("https://") || loopback)
+        && !uri.contains('#')
+        && uri.chars().all(|c| c.is_ascii_graphic())
+}
+
+fn redirect(auth: &AuthorizationRequest, params: &[(&str, &str)]) -> (String, String) {
+    let mut location = auth.redirect_uri.clone();
+    let mut separator = if location.contains('?') { '&' } else { '?' };
+    let state = auth.state.as_deref().map(|state| ("state", state));
+    for (key, value) in params.iter().copied().chain(state) {
+        location.push(separator);
+        location.push_str(&format!("{}={}", key, percent_encode(value)));
+        separator = '&';
+    }
+    (
+        with_headers(FOUND, &[format!("Location: {}", location)]),
+        "".to_string(),
+    )
+}
+
+fn oauth_error(status_line: &str, error: &str, description: &str) -> (String, String) {
+    let body = OAuthError {
+        error: error.to_string(),
+        error_description: description.to_string(),
+    };
+    match ser_to_str(&body) {
+        Ok(json) => (
+            with_headers(status_line, &["Content-Type: application/json".to_string()]),
+            json,
+        ),
+        Err(_) => (INTERNAL_ERROR.to_string(), "".to_string()),
+    }
+}
+
+/// Tokens and secrets must not be cached along the way
+fn no_store(status_line: &str) -> String {
+    with_headers(
+        status_line,
+        &[
+            "Cache-Control: no-store".to_string(),
+            "Pragma: no-cache".to_string(),
+        ],
+    )
+}
+
+fn html(status_line: &str, body: String) -> (String, String) {
+    let status_line = with_headers(
+        status_line,
+        &[
+            "Content-Type: text/html; charset=utf-8".to_string(),
+            "Cache-Control: no-store".to_string(),
+            // the consent page must not be framed by another site
+            "X-Frame-Options: DENY".to_string(),
+        ],
+    );
+    (status_line, body)
+}
+
+fn error_page(message: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Sign in</title></head>\
+         <body><h1>Sign in failed</h1><p>{}</p></body></html>",
+        html_escape(message)
+    )
+}
+
+fn login_page(client: &OAuthClient, auth: &AuthorizationRequest, message: Option<&str>) -> String {
+    let hidden = [
+        ("response_type", Some(auth.response_type.as_str())),
+        ("client_id", Some(auth.client_id.as_str())),
+        ("redirect_uri", Some(auth.redirect_uri.as_str())),
+        ("scope", Some(auth.scope.as_str())),
+        ("state", auth.state.as_deref()),
+        ("nonce", auth.nonce.as_deref()),
+        ("code_challenge", Some(auth.code_challenge.as_str())),
+        (
+            "code_challenge_method",
+            Some(auth.code_challenge_method.as_str()),
+        ),
+    ]
+    .iter()
+    .filter_map(|(name, value)| {
+        value.map(|value| {
+            format!(
+                "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+                name,
+                html_escape(value)
+            )
+        })
+    })
+    .collect::<String>();
+    let scopes = auth
+        .scopes()
+        .iter()
+        .map(|scope| format!("<li>{}</li>", html_escape(scope)))
+        .collect::<String>();
+    let message = message
+        .map(|message| format!("<p role=\"alert\">{}</p>", html_escape(message)))
+        .unwrap_or_default();
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Sign in</title></head><body>\
+         <h1>Sign in to {name}</h1>{message}\
+         <p>{name} is asking for:</p><ul>{scopes}</ul>\
+         <form method=\"post\" action=\"{action}\">{hidden}\
+         <label>Username <input name=\"username\" autocomplete=\"username\" required></label>\
+         <label>Password <input name=\"password\" type=\"password\" \
+         autocomplete=\"current-password\" required></label>\
+         <button name=\"decision\" value=\"allow\">Allow</button>\
+         <button name=\"decision\" value=\"deny\" formnovalidate>Deny</button>\
+         </form></body></html>",
+        name = html_escape(&client.name),
+        message = message,
+        scopes = scopes,
+        action = AUTHORIZE_PATH,
+        hidden = hidden,
+    )
+}
@@ -0,0 +1,41 @@
+use super::model::Organization;
+use crate::{db::DBConn, error::CustomError};
+
+pub struct OrgRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> OrgRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        OrgRepository { db }
+    }
+
+    pub async fn fetch_user_orgs(&self, user_id: i32) -> Result<Vec<Organization>, CustomError> {
+        self.db
+            .fetch_user_orgs(user_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn insert_org(
+        &self,
+        new_org: &Organization,
+        owner_user_id: i32,
+        owner_role_ids: &[i32],
+    ) -> Result<i32, CustomError> {
+        let org_id = match self
+            .db
+            .insert_org(new_org, owner_user_id, owner_role_ids)
+            .await
+        {
+            Ok(org_id) => org_id,
+            Err(e) => match e {
+                sqlx::Error::Database(err) if err.is_unique_violation() => {
+                    return Err(CustomError::OrgExists);
+                }
+                _ => return Err(CustomError::DBError(e)),
+            },
+        };
+        Ok(org_id)
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use request_http_parser::parser::Request;
+
+use super::{
+    model::{CreateOrganization, Organization},
+    repo::OrgRepository,
+};
+use crate::{
+    constants::{BAD_REQUEST, INTERNAL_ERROR, MANAGE_USERS, OK_RESPONSE, UNAUTHORIZED},
+    db::DBConn,
+    error::CustomError,
+    rolepermissions::service::RolePermissionSvc,
+    utils::{Claims, des_from_str, ser_to_str},
+};
+
+pub struct OrgSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: OrgRepository<DB>,
+}
+
+impl<DB> OrgSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB) -> Self {
+        OrgSvc {
+            repository: OrgRepository::new(pool),
+        }
+    }
+
+    /// Organizations the caller belongs to, candidates for `/protected/orgs/switch`
+    pub async fn get_orgs(&self, claims: Option<Claims>) -> (String, String) {
+        let user_id = match claims.and_then(|claims| claims.sub.parse::<i32>().ok()) {
+            Some(user_id) => user_id,
+            None => return (UNAUTHORIZED.to_string(), "".to_string()),
+        };
+        let orgs = match self.repository.fetch_user_orgs(user_id).await {
+            Ok(orgs) => orgs,
+            Err(error) => {
+                eprintln!("Error org db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&orgs) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    pub async fn create_org(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id) = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+        let user_id = match claims.sub.parse::<i32>() {
+            Ok(user_id) => user_id,
+            Err(_) => return (UNAUTHORIZED.to_string(), "".to_string()),
+        };
+        let req_org: CreateOrganization = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(org) => org,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+
+        let mut new_org = Organization {
+            org_id: None,
+            name: req_org.name,
+            created_at: Utc::now(),
+        };
+        // the creator keeps their shared roles in the new organization, as
+        // they hold them now rather than when the token was issued
+        let role_ids = match rp_svc.user_role_ids(org_id, user_id).await {
+            Ok(role_ids) => role_ids,
+            Err(error) => {
+                eprintln!("Error role permission db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        match self
+            .repository
+            .insert_org(&new_org, user_id, &role_ids)
+            .await
+        {
+            Ok(org_id) => new_org.org_id = Some(org_id),
+            Err(err) => match err {
+                CustomError::OrgExists => {
+                    eprintln!("Error insert: {:#?}", err);
+                    return (BAD_REQUEST.to_string(), "Already registered".to_string());
+                }
+                error => {
+                    eprintln!("Error insert org db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            },
+        };
+        let response_json = match ser_to_str(&new_org) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+}
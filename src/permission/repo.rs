@@ -10,10 +10,13 @@ impl<DB: DBConn> PermissionRepository<DB> {
         PermissionRepository { db }
     }
 
-    pub async fn fetch_permissions(&self) -> Result<Vec<Permission>, CustomError> {
-        self.db.fetch_permissions().await.map_err(|e| match e {
-            _ => CustomError::DBError(e),
-        })
+    pub async fn fetch_permissions(&self, org_id: i32) -> Result<Vec<Permission>, CustomError> {
+        self.db
+            .fetch_permissions(org_id)
+            .await
+            .map_err(|e| match e {
+                _ => CustomError::DBError(e),
+            })
     }
 
     pub async fn insert_permission(&self, new_permission: &Permission) -> Result<i32, CustomError> {
@@ -1,12 +1,19 @@
+use std::sync::Arc;
+
 use chrono::Utc;
 use request_http_parser::parser::Request;
+use serde_json::json;
 
 use super::{
     model::{CreatePermission, Permission},
     repo::PermissionRepository,
 };
 use crate::{
-    constants::{BAD_REQUEST, INTERNAL_ERROR, NO_CONTENT, OK_RESPONSE},
+    audit::{
+        model::{AuditEvent, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    constants::{BAD_REQUEST, FORBIDDEN, INTERNAL_ERROR, NO_CONTENT, OK_RESPONSE},
     db::DBConn,
     error::CustomError,
     utils::{Claims, des_from_str, ser_to_str},
@@ -17,21 +24,27 @@ where
     DB: DBConn + Send + Sync + 'static,
 {
     repository: PermissionRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
 }
 
 impl<DB> PermissionSvc<DB>
 where
     DB: DBConn + Send + Sync + 'static,
 {
-    pub fn new(pool: DB) -> Self {
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>) -> Self {
         PermissionSvc {
             repository: PermissionRepository::new(pool),
+            audit,
         }
     }
 
-    pub async fn get_permissions(&self) -> (String, String) {
+    pub async fn get_permissions(&self, claims: Option<Claims>) -> (String, String) {
         // only manages users can get all permission
-        let permissions = match self.repository.fetch_permissions().await {
+        let org_id = match claims.and_then(|claims| claims.org_id) {
+            Some(org_id) => org_id,
+            None => return (FORBIDDEN.to_string(), "".to_string()),
+        };
+        let permissions = match self.repository.fetch_permissions(org_id).await {
             Ok(user) => user,
             Err(why) => match why {
                 error => {
@@ -52,7 +65,7 @@ where
 
     pub async fn create_permission(
         &self,
-        _claims: Option<Claims>,
+        claims: Option<Claims>,
         request: &Request,
     ) -> (String, String) {
         // check authorzitaion by cek their role_id
@@ -64,6 +77,14 @@ where
         //         return (INTERNAL_ERROR.to_string(), "".to_string());
         //     }
         // };
+        let (org_id, actor_id) = match claims {
+            Some(Claims {
+                org_id: Some(org_id),
+                ref sub,
+                ..
+            }) => (org_id, sub.parse::<i32>().ok()),
+            _ => return (FORBIDDEN.to_string(), "".to_string()),
+        };
         let req_permission: CreatePermission = match &request.body {
             Some(body) => match des_from_str(body) {
                 Ok(user) => user,
@@ -75,11 +96,23 @@ where
         let new_permission = Permission {
             name: req_permission.name,
             permission_id: None,
+            org_id: Some(org_id),
             description: req_permission.description,
             created_at: Utc::now(),
         };
         match self.repository.insert_permission(&new_permission).await {
-            Ok(_) => (NO_CONTENT.to_string(), "".to_string()),
+            Ok(permission_id) => {
+                self.audit
+                    .record(AuditEvent {
+                        org_id: Some(org_id),
+                        actor_id,
+                        target: Some(format!("permission:{}", permission_id)),
+                        metadata: json!({ "name": new_permission.name }),
+                        ..AuditEvent::new("permission.create", OUTCOME_SUCCESS, Some(request))
+                    })
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
             Err(err) => match err {
                 CustomError::PermissionExists => {
                     eprintln!("Error insert: {:#?}", err);
@@ -0,0 +1,131 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+/// A rule applies when every condition it sets holds, unset conditions match
+/// anything
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PolicyRule {
+    pub name: String,
+    pub effect: Effect,
+    /// permission names the rule covers, empty means every action
+    #[serde(default)]
+    pub actions: Vec<String>,
+    #[serde(default)]
+    pub subject: SubjectCondition,
+    #[serde(default)]
+    pub resource: ResourceCondition,
+    #[serde(default)]
+    pub context: ContextCondition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SubjectCondition {
+    /// any of these role names
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub org_ids: Vec<i32>,
+    #[serde(default)]
+    pub email_domains: Vec<String>,
+    pub min_account_age_days: Option<i64>,
+    pub max_account_age_days: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResourceCondition {
+    #[serde(rename = "type")]
+    pub resource_type: Option<String>,
+    /// every listed attribute must be present with this value
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ContextCondition {
+    /// `[start, end)` in UTC hours, wrapping past midnight when start > end
+    pub hours_utc: Option<(u32, u32)>,
+    /// CIDR ranges such as `10.0.0.0/8`, a bare address matches itself
+    #[serde(default)]
+    pub ip_ranges: Vec<String>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct SubjectAttributes {
+    pub user_id: i32,
+    pub username: String,
+    pub email: Option<String>,
+    pub role_names: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    #[sqlx(skip)]
+    pub org_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResourceAttributes {
+    #[serde(rename = "type")]
+    pub resource_type: Option<String>,
+    pub id: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PolicyContext {
+    pub at: DateTime<Utc>,
+    pub ip: Option<IpAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Allow,
+    Deny,
+    /// no rule applies, the caller falls back to role permissions
+    NotApplicable,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RuleTrace {
+    pub rule: String,
+    pub effect: Effect,
+    pub matched: bool,
+    /// first condition that did not hold
+    pub mismatch: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Evaluation {
+    pub decision: Decision,
+    pub decided_by: Option<String>,
+    pub trace: Vec<RuleTrace>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExplainRequest {
+    /// defaults to the caller
+    pub user_id: Option<i32>,
+    pub action: String,
+    #[serde(default)]
+    pub resource: ResourceAttributes,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Explanation {
+    pub allowed: bool,
+    pub role_allowed: bool,
+    pub evaluation: Evaluation,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReloadedRules {
+    pub rules: usize,
+}
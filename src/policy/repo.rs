@@ -0,0 +1,50 @@
+use super::model::{PolicyRule, SubjectAttributes};
+use crate::{db::DBConn, error::CustomError, utils::des_from_str};
+
+pub struct PolicyRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> PolicyRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        PolicyRepository { db }
+    }
+
+    /// Enabled rules stored in the database, rows that do not parse are
+    /// skipped with a log line rather than failing every authorization
+    pub async fn fetch_policy_rules(&self) -> Result<Vec<PolicyRule>, CustomError> {
+        let definitions = self
+            .db
+            .fetch_policy_rules()
+            .await
+            .map_err(CustomError::DBError)?;
+        Ok(definitions
+            .iter()
+            .filter_map(|definition| match des_from_str::<PolicyRule>(definition) {
+                Ok(rule) => Some(rule),
+                Err(e) => {
+                    eprintln!("Invalid policy rule {}: {}", definition, e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    pub async fn fetch_subject_attributes(
+        &self,
+        org_id: i32,
+        user_id: i32,
+    ) -> Result<SubjectAttributes, CustomError> {
+        self.db
+            .fetch_subject_attributes(org_id, user_id)
+            .await
+            .map(|mut subject| {
+                subject.org_id = org_id;
+                subject
+            })
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+}
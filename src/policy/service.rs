@@ -0,0 +1,361 @@
+use std::sync::Arc;
+
+use chrono::{Timelike, Utc};
+use request_http_parser::parser::Request;
+use tokio::sync::RwLock;
+
+use super::{
+    model::{
+        Decision, Effect, Evaluation, ExplainRequest, Explanation, PolicyContext, PolicyRule,
+        ReloadedRules, ResourceAttributes, RuleTrace, SubjectAttributes,
+    },
+    repo::PolicyRepository,
+};
+use crate::{
+    cfg::CONFIG,
+    constants::{BAD_REQUEST, FORBIDDEN, INTERNAL_ERROR, MANAGE_USERS, OK_RESPONSE, UNAUTHORIZED},
+    db::DBConn,
+    error::CustomError,
+    rolepermissions::service::RolePermissionSvc,
+    utils::{Claims, client_ip, des_from_str, ip_in_range, ser_to_str},
+};
+
+pub struct PolicySvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: PolicyRepository<DB>,
+    rules: RwLock<Option<Arc<Vec<PolicyRule>>>>,
+}
+
+impl<DB> PolicySvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB) -> Self {
+        PolicySvc {
+            repository: PolicyRepository::new(pool),
+            rules: RwLock::new(None),
+        }
+    }
+
+    /// Evaluates the policy rules for a user acting in an organization. With
+    /// no rules configured this returns NotApplicable without touching the
+    /// database.
+    pub async fn evaluate(
+        &self,
+        org_id: i32,
+        user_id: i32,
+        action: &str,
+        resource: &ResourceAttributes,
+        context: &PolicyContext,
+    ) -> Result<Evaluation, CustomError> {
+        let rules = self.rules().await?;
+        if rules.is_empty() {
+            return Ok(Evaluation {
+                decision: Decision::NotApplicable,
+                decided_by: None,
+                trace: vec![],
+            });
+        }
+        let subject = self
+            .repository
+            .fetch_subject_attributes(org_id, user_id)
+            .await?;
+        Ok(evaluate_rules(&rules, action, &subject, resource, context))
+    }
+
+    pub async fn reload(&self) -> Result<usize, CustomError> {
+        let rules = self.load_rules().await?;
+        let count = rules.len();
+        *self.rules.write().await = Some(Arc::new(rules));
+        Ok(count)
+    }
+
+    async fn rules(&self) -> Result<Arc<Vec<PolicyRule>>, CustomError> {
+        if let Some(rules) = self.rules.read().await.as_ref() {
+            return Ok(Arc::clone(rules));
+        }
+        let mut cache = self.rules.write().await;
+        if let Some(rules) = cache.as_ref() {
+            return Ok(Arc::clone(rules));
+        }
+        let rules = Arc::new(self.load_rules().await?);
+        *cache = Some(Arc::clone(&rules));
+        Ok(rules)
+    }
+
+    /// Rules from `policy_file` (a JSON array) followed by the enabled rows of
+    /// `policy_rules`. A configured file that cannot be read fails the load so
+    /// deny rules are never silently dropped.
+    async fn load_rules(&self) -> Result<Vec<PolicyRule>, CustomError> {
+        let mut rules = vec![];
+        if !CONFIG.policy_file.is_empty() {
+            let content = tokio::fs::read_to_string(&CONFIG.policy_file)
+                .await
+                .map_err(|e| CustomError::PolicyLoad(e.to_string()))?;
+            let file_rules: Vec<PolicyRule> =
+                des_from_str(&content).map_err(|e| CustomError::PolicyLoad(e.to_string()))?;
+            rules.extend(file_rules);
+        }
+        rules.extend(self.repository.fetch_policy_rules().await?);
+        println!("Loaded {} policy rules", rules.len());
+        Ok(rules)
+    }
+
+    /// Reports how a decision would be made, rule by rule
+    pub async fn explain(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let claims = match claims {
+            Some(claims) => claims,
+            None => return (UNAUTHORIZED.to_string(), "".to_string()),
+        };
+        let (org_id, user_id) = match (claims.org_id, claims.sub.parse::<i32>()) {
+            (Some(org_id), Ok(user_id)) => (org_id, user_id),
+            _ => return (FORBIDDEN.to_string(), "".to_string()),
+        };
+        let req_explain: ExplainRequest = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(explain) => explain,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        let subject_id = req_explain.user_id.unwrap_or(user_id);
+        if subject_id != user_id {
+            match rp_svc.permits(&claims, request, MANAGE_USERS).await {
+                Ok(true) => {}
+                Ok(false) => return (FORBIDDEN.to_string(), "".to_string()),
+                Err(error) => {
+                    eprintln!("Error role permission db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            }
+        }
+
+        let context = PolicyContext::from_request(request);
+        let explanation = match self
+            .explanation(rp_svc, org_id, subject_id, &req_explain, &context)
+            .await
+        {
+            Ok(explanation) => explanation,
+            Err(error) => {
+                eprintln!("Error policy explain: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&explanation) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    async fn explanation(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        org_id: i32,
+        subject_id: i32,
+        req_explain: &ExplainRequest,
+        context: &PolicyContext,
+    ) -> Result<Explanation, CustomError> {
+        let role_allowed = rp_svc
+            .user_has_permission(org_id, subject_id, &req_explain.action)
+            .await?;
+        let evaluation = self
+            .evaluate(
+                org_id,
+                subject_id,
+                &req_explain.action,
+                &req_explain.resource,
+                context,
+            )
+            .await?;
+        Ok(Explanation {
+            allowed: evaluation.allows(role_allowed),
+            role_allowed,
+            evaluation,
+        })
+    }
+
+    pub async fn reload_rules(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        if let Err(response) = rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            return response;
+        }
+        let rules = match self.reload().await {
+            Ok(rules) => rules,
+            Err(error) => {
+                eprintln!("Error policy reload: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), error.to_string());
+            }
+        };
+        let response_json = match ser_to_str(&ReloadedRules { rules }) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+}
+
+impl Evaluation {
+    /// Combines the policy decision with the role permission check, a deny
+    /// rule wins over everything and an allow rule grants on its own
+    pub fn allows(&self, role_allowed: bool) -> bool {
+        match self.decision {
+            Decision::Deny => false,
+            Decision::Allow => true,
+            Decision::NotApplicable => role_allowed,
+        }
+    }
+}
+
+impl PolicyContext {
+    pub fn from_request(request: &Request) -> Self {
+        PolicyContext {
+            at: Utc::now(),
+            ip: client_ip(request),
+        }
+    }
+}
+
+/// Deny-overrides: any matching deny rule decides, otherwise the first
+/// matching allow rule does
+pub fn evaluate_rules(
+    rules: &[PolicyRule],
+    action: &str,
+    subject: &SubjectAttributes,
+    resource: &ResourceAttributes,
+    context: &PolicyContext,
+) -> Evaluation {
+    let trace: Vec<RuleTrace> = rules
+        .iter()
+        .map(|rule| {
+            let mismatch = rule_mismatch(rule, action, subject, resource, context);
+            RuleTrace {
+                rule: rule.name.clone(),
+                effect: rule.effect,
+                matched: mismatch.is_none(),
+                mismatch,
+            }
+        })
+        .collect();
+    let decided_by = trace
+        .iter()
+        .find(|t| t.matched && t.effect == Effect::Deny)
+        .or_else(|| {
+            trace
+                .iter()
+                .find(|t| t.matched && t.effect == Effect::Allow)
+        });
+    match decided_by {
+        Some(rule) => Evaluation {
+            decision: match rule.effect {
+                Effect::Allow => Decision::Allow,
+                Effect::Deny => Decision::Deny,
+            },
+            decided_by: Some(rule.rule.clone()),
+            trace,
+        },
+        None => Evaluation {
+            decision: Decision::NotApplicable,
+            decided_by: None,
+            trace,
+        },
+    }
+}
+
+/// Returns the first condition of the rule that does not hold
+fn rule_mismatch(
+    rule: &PolicyRule,
+    action: &str,
+    subject: &SubjectAttributes,
+    resource: &ResourceAttributes,
+    context: &PolicyContext,
+) -> Option<String> {
+    if !rule.actions.is_empty() && !rule.actions.iter().any(|a| a == action) {
+        return Some("action".to_string());
+    }
+
+    let cond = &rule.subject;
+    if !cond.roles.is_empty() && !cond.roles.iter().any(|r| subject.role_names.contains(r)) {
+        return Some("subject.roles".to_string());
+    }
+    if !cond.org_ids.is_empty() && !cond.org_ids.contains(&subject.org_id) {
+        return Some("subject.org_ids".to_string());
+    }
+    if !cond.email_domains.is_empty() {
+        let email = subject.email.as_deref().unwrap_or(&subject.username);
+        let domain = email.rsplit_once('@').map(|(_, domain)| domain);
+        let matched = domain.is_some_and(|domain| {
+            cond.email_domains
+                .iter()
+                .any(|d| d.eq_ignore_ascii_case(domain))
+        });
+        if !matched {
+            return Some("subject.email_domains".to_string());
+        }
+    }
+    let account_age_days = (context.at - subject.created_at).num_days();
+    if cond
+        .min_account_age_days
+        .is_some_and(|min| account_age_days < min)
+    {
+        return Some("subject.min_account_age_days".to_string());
+    }
+    if cond
+        .max_account_age_days
+        .is_some_and(|max| account_age_days > max)
+    {
+        return Some("subject.max_account_age_days".to_string());
+    }
+
+    if let Some(resource_type) = &rule.resource.resource_type
+        && resource.resource_type.as_ref() != Some(resource_type)
+    {
+        return Some("resource.type".to_string());
+    }
+    for (key, value) in &rule.resource.attributes {
+        if resource.attributes.get(key) != Some(value) {
+            return Some(format!("resource.attributes.{}", key));
+        }
+    }
+
+    if let Some((start, end)) = rule.context.hours_utc {
+        let hour = context.at.hour();
+        let within = if start <= end {
+            start <= hour && hour < end
+        } else {
+            hour >= start || hour < end
+        };
+        if !within {
+            return Some("context.hours_utc".to_string());
+        }
+    }
+    if !rule.context.ip_ranges.is_empty() {
+        let matched = context.ip.is_some_and(|ip| {
+            rule.context
+                .ip_ranges
+                .iter()
+                .any(|range| ip_in_range(&ip, range))
+        });
+        if !matched {
+            return Some("context.ip_ranges".to_string());
+        }
+    }
+    None
+}
@@ -4,9 +4,13 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
 pub struct Role {
     pub role_id: Option<i32>,
+    pub org_id: Option<i32>,
     pub name: String,
     pub description: String,
     pub created_at: DateTime<Utc>,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub parent_ids: Vec<i32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -14,4 +18,12 @@ pub struct CreateRole {
     pub name: String,
     pub description: String,
     pub permissions: Vec<i32>,
+    #[serde(default)]
+    pub parents: Vec<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RoleParent {
+    pub role_id: i32,
+    pub parent_role_id: i32,
 }
@@ -10,22 +10,69 @@ impl<DB: DBConn> RoleRepository<DB> {
         RoleRepository { db }
     }
 
-    pub async fn fetch_roles(&self) -> Result<Vec<Role>, CustomError> {
-        self.db.fetch_roles().await.map_err(|e| match e {
+    pub async fn fetch_roles(&self, org_id: i32) -> Result<Vec<Role>, CustomError> {
+        self.db.fetch_roles(org_id).await.map_err(|e| match e {
             _ => CustomError::DBError(e),
         })
     }
 
-    pub async fn insert_role(&self, new_role: &Role) -> Result<i32, CustomError> {
-        let role_id = match self.db.insert_role(new_role).await {
+    /// Creates the role together with its parents and permissions, all or
+    /// nothing
+    pub async fn insert_role(
+        &self,
+        new_role: &Role,
+        permission_ids: &[i32],
+    ) -> Result<i32, CustomError> {
+        let role_id = match self.db.insert_role(new_role, permission_ids).await {
             Ok(role_id) => role_id,
             Err(e) => match e {
                 sqlx::Error::Database(err) if err.is_unique_violation() => {
                     return Err(CustomError::RoleExists);
                 }
+                sqlx::Error::RowNotFound => return Err(CustomError::RoleNotFound),
                 _ => return Err(CustomError::DBError(e)),
             },
         };
         Ok(role_id)
     }
+
+    /// Adds `parent_role_id` as a parent of the organization's `role_id`,
+    /// rejecting edges that would make a role its own ancestor
+    pub async fn insert_role_parent(
+        &self,
+        org_id: i32,
+        role_id: i32,
+        parent_role_id: i32,
+    ) -> Result<(), CustomError> {
+        match self
+            .db
+            .insert_role_parent(org_id, role_id, parent_role_id)
+            .await
+        {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(CustomError::RoleCycle),
+            Err(e) => match e {
+                sqlx::Error::Database(err) if err.is_unique_violation() => {
+                    Err(CustomError::RoleParentExists)
+                }
+                sqlx::Error::RowNotFound => Err(CustomError::RoleNotFound),
+                _ => Err(CustomError::DBError(e)),
+            },
+        }
+    }
+
+    pub async fn delete_role_parent(
+        &self,
+        org_id: i32,
+        role_id: i32,
+        parent_role_id: i32,
+    ) -> Result<i32, CustomError> {
+        self.db
+            .delete_role_parent(org_id, role_id, parent_role_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::RoleParentNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
 }
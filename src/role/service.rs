@@ -2,13 +2,20 @@ use std::sync::Arc;
 
 use chrono::Utc;
 use request_http_parser::parser::Request;
+use serde_json::json;
 
 use super::{
-    model::{CreateRole, Role},
+    model::{CreateRole, Role, RoleParent},
     repo::RoleRepository,
 };
 use crate::{
-    constants::{BAD_REQUEST, INTERNAL_ERROR, OK_RESPONSE},
+    audit::{
+        model::{AuditEvent, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    constants::{
+        BAD_REQUEST, FORBIDDEN, INTERNAL_ERROR, MANAGE_USERS, NO_CONTENT, NOT_FOUND, OK_RESPONSE,
+    },
     db::DBConn,
     error::CustomError,
     rolepermissions::service::RolePermissionSvc,
@@ -20,21 +27,27 @@ where
     DB: DBConn + Send + Sync + 'static,
 {
     repository: RoleRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
 }
 
 impl<DB> RoleSvc<DB>
 where
     DB: DBConn + Send + Sync + 'static,
 {
-    pub fn new(pool: DB) -> Self {
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>) -> Self {
         RoleSvc {
             repository: RoleRepository::new(pool),
+            audit,
         }
     }
 
-    pub async fn get_roles(&self, _claims: Option<Claims>) -> (String, String) {
+    pub async fn get_roles(&self, claims: Option<Claims>) -> (String, String) {
         // only manages users can get all permission
-        let roles = match self.repository.fetch_roles().await {
+        let org_id = match claims.and_then(|claims| claims.org_id) {
+            Some(org_id) => org_id,
+            None => return (FORBIDDEN.to_string(), "".to_string()),
+        };
+        let roles = match self.repository.fetch_roles(org_id).await {
             Ok(user) => user,
             Err(why) => match why {
                 error => {
@@ -56,18 +69,13 @@ where
     pub async fn create_role(
         &self,
         rp_svc: &Arc<RolePermissionSvc<DB>>,
-        _claims: Option<Claims>,
+        claims: Option<Claims>,
         request: &Request,
     ) -> (String, String) {
-        // check authorzitaion by cek their role_id
-        // next this should check to redis
-        // let claims = match claims {
-        //     Some(claims) => claims,
-        //     None => {
-        //         println!("serde error");
-        //         return (INTERNAL_ERROR.to_string(), "".to_string());
-        //     }
-        // };
+        let (claims, org_id) = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
         let req_role: CreateRole = match &request.body {
             Some(body) => match des_from_str(body) {
                 Ok(user) => user,
@@ -76,27 +84,160 @@ where
             None => return (BAD_REQUEST.to_string(), "".to_string()),
         };
 
+        let mut parent_ids = req_role.parents.clone();
+        parent_ids.sort_unstable();
+        parent_ids.dedup();
+        let mut permission_ids = req_role.permissions.clone();
+        permission_ids.sort_unstable();
+        permission_ids.dedup();
         let new_role = Role {
             name: req_role.name,
             role_id: None,
+            org_id: Some(org_id),
             description: req_role.description,
             created_at: Utc::now(),
+            parent_ids,
         };
-        let new_role_id = match self.repository.insert_role(&new_role).await {
+        let new_role_id = match self
+            .repository
+            .insert_role(&new_role, &permission_ids)
+            .await
+        {
             Ok(new_role_id) => new_role_id,
             Err(err) => match err {
                 CustomError::RoleExists => {
                     eprintln!("Error insert: {:#?}", err);
                     return (BAD_REQUEST.to_string(), "Already registered".to_string());
                 }
+                // a parent or permission the organization cannot see
+                CustomError::RoleNotFound => {
+                    return (
+                        BAD_REQUEST.to_string(),
+                        "Unknown parent role or permission".to_string(),
+                    );
+                }
                 error => {
                     eprintln!("Error insert role db: {:#?}", error);
                     return (INTERNAL_ERROR.to_string(), "".to_string());
                 }
             },
         };
-        return rp_svc
-            .insert_role_permissions(new_role_id, req_role.permissions)
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: claims.user_id(),
+                target: Some(format!("role:{}", new_role_id)),
+                metadata: json!({
+                    "name": new_role.name,
+                    "parents": req_role.parents,
+                    "permission_ids": req_role.permissions,
+                }),
+                ..AuditEvent::new("role.create", OUTCOME_SUCCESS, Some(request))
+            })
             .await;
+        (NO_CONTENT.to_string(), "".to_string())
+    }
+
+    pub async fn add_role_parent(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id) = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+        let req_parent: RoleParent = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(parent) => parent,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+
+        match self
+            .repository
+            .insert_role_parent(org_id, req_parent.role_id, req_parent.parent_role_id)
+            .await
+        {
+            Ok(_) => {
+                self.audit_role_parent(request, "role.parent.add", &claims, org_id, &req_parent)
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(err) => Self::role_parent_error(err),
+        }
+    }
+
+    pub async fn remove_role_parent(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id) = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+        let req_parent: RoleParent = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(parent) => parent,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+
+        match self
+            .repository
+            .delete_role_parent(org_id, req_parent.role_id, req_parent.parent_role_id)
+            .await
+        {
+            Ok(_) => {
+                self.audit_role_parent(request, "role.parent.remove", &claims, org_id, &req_parent)
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(err) => match err {
+                CustomError::RoleParentNotFound => (NOT_FOUND.to_string(), "".to_string()),
+                error => {
+                    eprintln!("Error delete role parent db: {:#?}", error);
+                    (INTERNAL_ERROR.to_string(), "".to_string())
+                }
+            },
+        }
+    }
+
+    async fn audit_role_parent(
+        &self,
+        request: &Request,
+        action: &str,
+        claims: &Claims,
+        org_id: i32,
+        parent: &RoleParent,
+    ) {
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: claims.user_id(),
+                target: Some(format!("role:{}", parent.role_id)),
+                metadata: json!({ "parent_role_id": parent.parent_role_id }),
+                ..AuditEvent::new(action, OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+    }
+
+    fn role_parent_error(err: CustomError) -> (String, String) {
+        match err {
+            CustomError::RoleCycle | CustomError::RoleParentExists => {
+                eprintln!("Error insert: {:#?}", err);
+                (BAD_REQUEST.to_string(), err.to_string())
+            }
+            CustomError::RoleNotFound => (NOT_FOUND.to_string(), err.to_string()),
+            error => {
+                eprintln!("Error insert role parent db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
     }
 }
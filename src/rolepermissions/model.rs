@@ -1,3 +1,5 @@
+use chrono::{DateTime, Utc};
+
 #[derive(Debug, sqlx::FromRow)]
 pub struct RolePermissions {
     pub role_id: i32,
@@ -6,5 +8,33 @@ pub struct RolePermissions {
 
 #[derive(Debug, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
 pub struct GetRolePermissions {
+    pub permission_id: i32,
     pub name: String,
+    /// true when the permission only comes from a parent role
+    pub inherited: bool,
+    /// latest expiry among the grants providing it, None when one is permanent
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct RolePermissionGrant {
+    pub role_id: i32,
+    pub permission_ids: Vec<i32>,
+    #[serde(default)]
+    pub valid_from: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct ExpiredRolePermission {
+    pub role_id: i32,
+    pub permission_id: i32,
+    pub org_id: Option<i32>,
+    pub valid_until: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PermVersion {
+    pub perm_version: i64,
 }
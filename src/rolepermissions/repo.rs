@@ -1,4 +1,4 @@
-use super::model::GetRolePermissions;
+use super::model::{ExpiredRolePermission, GetRolePermissions, RolePermissionGrant};
 use crate::{db::DBConn, error::CustomError};
 
 pub struct RolePermissionRepository<DB: DBConn> {
@@ -12,10 +12,11 @@ impl<DB: DBConn> RolePermissionRepository<DB> {
 
     pub async fn fetch_role_permissions(
         &self,
-        role_id: i32,
+        org_id: i32,
+        role_ids: &[i32],
     ) -> Result<Vec<GetRolePermissions>, CustomError> {
         self.db
-            .fetch_role_permissions(role_id)
+            .fetch_role_permissions(org_id, role_ids)
             .await
             .map_err(|e| match e {
                 sqlx::Error::RowNotFound => CustomError::RoleNotFound,
@@ -25,14 +26,10 @@ impl<DB: DBConn> RolePermissionRepository<DB> {
 
     pub async fn insert_role_permissions(
         &self,
-        role_id: i32,
-        permission_ids: Vec<i32>,
+        org_id: i32,
+        grant: &RolePermissionGrant,
     ) -> Result<(), CustomError> {
-        match self
-            .db
-            .insert_permission_role(role_id, permission_ids)
-            .await
-        {
+        match self.db.insert_permission_role(org_id, grant).await {
             Ok(role_id) => role_id,
             Err(e) => match e {
                 sqlx::Error::Database(err) if err.is_unique_violation() => {
@@ -43,4 +40,31 @@ impl<DB: DBConn> RolePermissionRepository<DB> {
         };
         Ok(())
     }
+
+    pub async fn fetch_perm_version(&self) -> Result<i64, CustomError> {
+        self.db
+            .fetch_perm_version()
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn fetch_user_role_ids(
+        &self,
+        org_id: i32,
+        user_id: i32,
+    ) -> Result<Vec<i32>, CustomError> {
+        self.db
+            .fetch_user_role_ids(org_id, user_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn delete_expired_role_permissions(
+        &self,
+    ) -> Result<Vec<ExpiredRolePermission>, CustomError> {
+        self.db
+            .delete_expired_role_permissions()
+            .await
+            .map_err(CustomError::DBError)
+    }
 }
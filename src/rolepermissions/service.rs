@@ -1,9 +1,28 @@
-use super::repo::RolePermissionRepository;
+use std::sync::Arc;
+
+use request_http_parser::parser::Request;
+use serde_json::json;
+
+use super::{
+    model::{PermVersion, RolePermissionGrant},
+    repo::RolePermissionRepository,
+};
 use crate::{
-    constants::{BAD_REQUEST, INTERNAL_ERROR, NO_CONTENT, NOT_FOUND, OK_RESPONSE},
+    audit::{
+        model::{AuditEvent, OUTCOME_FAILURE, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    constants::{
+        BAD_REQUEST, FORBIDDEN, INTERNAL_ERROR, MANAGE_USERS, NO_CONTENT, NOT_FOUND, OK_RESPONSE,
+        UNAUTHORIZED,
+    },
     db::DBConn,
     error::CustomError,
-    utils::{Claims, ser_to_str},
+    policy::{
+        model::{PolicyContext, ResourceAttributes},
+        service::PolicySvc,
+    },
+    utils::{ClaimType, Claims, des_from_str, ser_to_str, valid_window},
 };
 
 pub struct RolePermissionSvc<DB>
@@ -11,18 +30,26 @@ where
     DB: DBConn + Send + Sync + 'static,
 {
     repository: RolePermissionRepository<DB>,
+    policy: Arc<PolicySvc<DB>>,
+    audit: Arc<AuditSvc<DB>>,
 }
 
 impl<DB> RolePermissionSvc<DB>
 where
     DB: DBConn + Send + Sync + 'static,
 {
-    pub fn new(pool: DB) -> Self {
+    pub fn new(pool: DB, policy: Arc<PolicySvc<DB>>, audit: Arc<AuditSvc<DB>>) -> Self {
         RolePermissionSvc {
             repository: RolePermissionRepository::new(pool),
+            policy,
+            audit,
         }
     }
 
+    pub fn policy(&self) -> &Arc<PolicySvc<DB>> {
+        &self.policy
+    }
+
     pub async fn get_role_permissions_by_role_id(
         &self,
         claims: Option<Claims>,
@@ -34,8 +61,23 @@ where
                 return (INTERNAL_ERROR.to_string(), "".to_string());
             }
         };
+        let (org_id, user_id) = match (claims.org_id, claims.sub.parse::<i32>()) {
+            (Some(org_id), Ok(user_id)) => (org_id, user_id),
+            _ => return (FORBIDDEN.to_string(), "".to_string()),
+        };
+        let role_ids = match self.user_role_ids(org_id, user_id).await {
+            Ok(role_ids) => role_ids,
+            Err(error) => {
+                eprintln!("Error user db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
 
-        let permissions = match self.repository.fetch_role_permissions(claims.role_id).await {
+        let mut permissions = match self
+            .repository
+            .fetch_role_permissions(org_id, &role_ids)
+            .await
+        {
             Ok(user) => user,
             Err(why) => match why {
                 CustomError::RoleNotFound => {
@@ -47,6 +89,11 @@ where
                 }
             },
         };
+        // an API key only sees the part of its owner's permissions it carries
+        if claims.api_key_id.is_some() {
+            let scope = claims.permissions.unwrap_or_default();
+            permissions.retain(|p| scope.contains(&p.name));
+        }
         let response_json = match ser_to_str(&permissions) {
             Ok(json) => json,
             Err(_) => {
@@ -57,16 +104,219 @@ where
         (OK_RESPONSE.to_string(), response_json)
     }
 
-    pub async fn insert_role_permissions(
+    pub async fn get_perm_version(&self) -> (String, String) {
+        let perm_version = match self.repository.fetch_perm_version().await {
+            Ok(perm_version) => perm_version,
+            Err(error) => {
+                eprintln!("Error perm version db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&PermVersion { perm_version }) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// Checks that the caller holds `permission` through any of their roles in
+    /// the organization of their token, returning the claims and that
+    /// organization. Roles are resolved from the database rather than the
+    /// token so lapsed time-bound grants stop counting right away. Policy
+    /// rules are applied on top, a matching deny rule refuses even a
+    /// permission the roles grant.
+    pub async fn authorize(
+        &self,
+        claims: Option<Claims>,
+        request: &Request,
+        permission: &str,
+    ) -> Result<(Claims, i32), (String, String)> {
+        let claims = match claims {
+            Some(claims) => claims,
+            None => return Err((UNAUTHORIZED.to_string(), "".to_string())),
+        };
+        // service principals hold scopes and guests one room, not roles, and
+        // a password reset token is no credential at all
+        if !matches!(claims.claim_type, ClaimType::Login | ClaimType::ApiKey) {
+            return Err((FORBIDDEN.to_string(), "".to_string()));
+        }
+        let org_id = match claims.org_id {
+            Some(org_id) => org_id,
+            None => return Err((FORBIDDEN.to_string(), "".to_string())),
+        };
+        let user_id = match claims.sub.parse::<i32>() {
+            Ok(user_id) => user_id,
+            Err(_) => return Err((UNAUTHORIZED.to_string(), "".to_string())),
+        };
+        match self.permits(&claims, request, permission).await {
+            Ok(true) => Ok((claims, org_id)),
+            Ok(false) => {
+                println!("User {} lacks permission {}", claims.username, permission);
+                self.audit
+                    .record(AuditEvent {
+                        org_id: Some(org_id),
+                        actor_id: Some(user_id),
+                        target: Some(format!("permission:{}", permission)),
+                        ..AuditEvent::new("access.denied", OUTCOME_FAILURE, Some(request))
+                    })
+                    .await;
+                Err((FORBIDDEN.to_string(), "".to_string()))
+            }
+            Err(error) => {
+                eprintln!("Error permission check: {:#?}", error);
+                Err((INTERNAL_ERROR.to_string(), "".to_string()))
+            }
+        }
+    }
+
+    /// The decision behind `authorize` without its audit trail or response,
+    /// for services that check a permission on top of another rule. Only a
+    /// signed in user or an API key holds permissions, and a key never
+    /// reaches past its own scope whatever roles or policies allow.
+    pub async fn permits(
+        &self,
+        claims: &Claims,
+        request: &Request,
+        permission: &str,
+    ) -> Result<bool, CustomError> {
+        if !matches!(claims.claim_type, ClaimType::Login | ClaimType::ApiKey) {
+            return Ok(false);
+        }
+        let (org_id, user_id) = match (claims.org_id, claims.sub.parse::<i32>()) {
+            (Some(org_id), Ok(user_id)) => (org_id, user_id),
+            _ => return Ok(false),
+        };
+        let key_allowed = claims.api_key_id.is_none()
+            || claims.permissions.iter().flatten().any(|p| p == permission);
+        if !key_allowed {
+            return Ok(false);
+        }
+        let role_allowed = self
+            .user_has_permission(org_id, user_id, permission)
+            .await?;
+        let evaluation = self
+            .policy
+            .evaluate(
+                org_id,
+                user_id,
+                permission,
+                &ResourceAttributes::default(),
+                &PolicyContext::from_request(request),
+            )
+            .await?;
+        Ok(evaluation.allows(role_allowed))
+    }
+
+    pub async fn has_permission(
         &self,
+        org_id: i32,
+        role_ids: &[i32],
+        permission: &str,
+    ) -> Result<bool, CustomError> {
+        let permissions = self
+            .repository
+            .fetch_role_permissions(org_id, role_ids)
+            .await?;
+        Ok(permissions.iter().any(|p| p.name == permission))
+    }
+
+    /// Same as `has_permission` with the roles the user holds right now,
+    /// the ones in a token may have lapsed since it was issued
+    pub async fn user_has_permission(
+        &self,
+        org_id: i32,
+        user_id: i32,
+        permission: &str,
+    ) -> Result<bool, CustomError> {
+        let role_ids = self.user_role_ids(org_id, user_id).await?;
+        self.has_permission(org_id, &role_ids, permission).await
+    }
+
+    /// Permissions `role_id` grants, inherited ones included, that the
+    /// caller could not use themselves. Handing out such a role would pass on
+    /// more than the caller holds.
+    pub async fn permissions_beyond(
+        &self,
+        claims: &Claims,
+        request: &Request,
+        org_id: i32,
         role_id: i32,
-        permission_ids: Vec<i32>,
-    ) -> (String, String) {
-        match self
+    ) -> Result<Vec<String>, CustomError> {
+        let mut names: Vec<String> = self
             .repository
-            .insert_role_permissions(role_id, permission_ids)
-            .await
-        {
+            .fetch_role_permissions(org_id, &[role_id])
+            .await?
+            .into_iter()
+            .map(|permission| permission.name)
+            .collect();
+        names.sort();
+        names.dedup();
+        let mut beyond = vec![];
+        for name in names {
+            if !self.permits(claims, request, &name).await? {
+                beyond.push(name);
+            }
+        }
+        Ok(beyond)
+    }
+
+    /// Roles of the user in the organization, time-bound ones only while
+    /// their window is open
+    pub async fn user_role_ids(&self, org_id: i32, user_id: i32) -> Result<Vec<i32>, CustomError> {
+        self.repository.fetch_user_role_ids(org_id, user_id).await
+    }
+
+    /// Adds permissions to an existing role, optionally for a limited window
+    pub async fn grant_role_permissions(
+        &self,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id) = match self.authorize(claims, request, MANAGE_USERS).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+        let grant: RolePermissionGrant = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(grant) => grant,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if !valid_window(grant.valid_from, grant.valid_until) {
+            return (
+                BAD_REQUEST.to_string(),
+                "Invalid validity window".to_string(),
+            );
+        }
+        let response = self.insert_role_permissions(org_id, &grant).await;
+        if response.0 == NO_CONTENT {
+            self.audit
+                .record(AuditEvent {
+                    org_id: Some(org_id),
+                    actor_id: claims.user_id(),
+                    target: Some(format!("role:{}", grant.role_id)),
+                    metadata: json!({
+                        "permission_ids": grant.permission_ids,
+                        "valid_from": grant.valid_from,
+                        "valid_until": grant.valid_until,
+                    }),
+                    ..AuditEvent::new("role_permission.grant", OUTCOME_SUCCESS, Some(request))
+                })
+                .await;
+        }
+        response
+    }
+
+    pub async fn insert_role_permissions(
+        &self,
+        org_id: i32,
+        grant: &RolePermissionGrant,
+    ) -> (String, String) {
+        match self.repository.insert_role_permissions(org_id, grant).await {
             Ok(_) => (NO_CONTENT.to_string(), "".to_string()),
             Err(err) => match err {
                 CustomError::RolePermissionExists => {
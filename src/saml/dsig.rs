@@ -0,0 +1,134 @@
+//! Enveloped XML signatures as SAML IdPs produce them: one reference to the
+//! signed element by ID, exclusive canonicalization, RSA with SHA-256 or
+//! SHA-512. Anything else is refused rather than half supported.
+
+use super::{SamlError, xml::Element};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Verifier, x509::X509};
+
+pub const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+
+fn digest_method(algorithm: &str) -> Option<MessageDigest> {
+    match algorithm {
+        "http://www.w3.org/2001/04/xmlenc#sha256" => Some(MessageDigest::sha256()),
+        "http://www.w3.org/2001/04/xmlenc#sha512" => Some(MessageDigest::sha512()),
+        _ => None,
+    }
+}
+
+fn signature_method(algorithm: &str) -> Option<MessageDigest> {
+    match algorithm {
+        "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256" => Some(MessageDigest::sha256()),
+        "http://www.w3.org/2001/04/xmldsig-more#rsa-sha512" => Some(MessageDigest::sha512()),
+        _ => None,
+    }
+}
+
+fn algorithm(element: &Element, name: &str) -> Result<String, SamlError> {
+    element
+        .child(DSIG_NS, name)
+        .and_then(|method| method.attribute("Algorithm"))
+        .map(String::from)
+        .ok_or(SamlError::Signature("algorithm missing"))
+}
+
+/// PrefixList of an exclusive canonicalization's InclusiveNamespaces
+fn inclusive_prefixes(method: &Element) -> Vec<String> {
+    method
+        .child(EXC_C14N, "InclusiveNamespaces")
+        .and_then(|inclusive| inclusive.attribute("PrefixList"))
+        .map(|list| list.split_whitespace().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>, SamlError> {
+    let compact: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    STANDARD
+        .decode(compact)
+        .map_err(|_| SamlError::Signature("invalid base64"))
+}
+
+/// Whether `element` carries its own signature, the signature must then be
+/// valid for `certificate`. A signature over any other element than the
+/// one holding it is refused, the usual signature wrapping attack.
+pub fn verify_enveloped(element: &Element, certificate: &X509) -> Result<bool, SamlError> {
+    let mut signatures = element.children_named(DSIG_NS, "Signature");
+    let signature = match signatures.next() {
+        Some(signature) => signature,
+        None => return Ok(false),
+    };
+    if signatures.next().is_some() {
+        return Err(SamlError::Signature("several signatures"));
+    }
+    let signed_info = signature
+        .child(DSIG_NS, "SignedInfo")
+        .ok_or(SamlError::Signature("SignedInfo missing"))?;
+    let canonicalization = signed_info
+        .child(DSIG_NS, "CanonicalizationMethod")
+        .ok_or(SamlError::Signature("CanonicalizationMethod missing"))?;
+    if canonicalization.attribute("Algorithm") != Some(EXC_C14N) {
+        return Err(SamlError::Signature("unsupported canonicalization"));
+    }
+    let signature_digest = signature_method(&algorithm(signed_info, "SignatureMethod")?)
+        .ok_or(SamlError::Signature("unsupported signature algorithm"))?;
+
+    let reference = signed_info
+        .child(DSIG_NS, "Reference")
+        .ok_or(SamlError::Signature("exactly one Reference expected"))?;
+    let id = element
+        .attribute("ID")
+        .filter(|id| !id.is_empty())
+        .ok_or(SamlError::Signature("signed element has no ID"))?;
+    if reference.attribute("URI") != Some(format!("#{}", id).as_str()) {
+        return Err(SamlError::Signature(
+            "Reference does not point at its element",
+        ));
+    }
+    let transforms: Vec<&Element> = reference
+        .child(DSIG_NS, "Transforms")
+        .map(|transforms| transforms.children_named(DSIG_NS, "Transform").collect())
+        .unwrap_or_default();
+    let mut prefixes = vec![];
+    let mut enveloped = false;
+    for transform in &transforms {
+        match transform.attribute("Algorithm") {
+            Some(ENVELOPED_SIGNATURE) => enveloped = true,
+            Some(EXC_C14N) => prefixes = inclusive_prefixes(transform),
+            _ => return Err(SamlError::Signature("unsupported transform")),
+        }
+    }
+    if !enveloped {
+        return Err(SamlError::Signature("signature is not enveloped"));
+    }
+    let digest = digest_method(&algorithm(reference, "DigestMethod")?)
+        .ok_or(SamlError::Signature("unsupported digest algorithm"))?;
+    let expected_digest = decode_base64(
+        &reference
+            .child(DSIG_NS, "DigestValue")
+            .ok_or(SamlError::Signature("DigestValue missing"))?
+            .text(),
+    )?;
+    let canonical = element.canonicalize(Some(signature), &prefixes);
+    let actual_digest = openssl::hash::hash(digest, canonical.as_bytes())?;
+    if *actual_digest != *expected_digest {
+        return Err(SamlError::Signature("digest mismatch"));
+    }
+
+    let signature_value = decode_base64(
+        &signature
+            .child(DSIG_NS, "SignatureValue")
+            .ok_or(SamlError::Signature("SignatureValue missing"))?
+            .text(),
+    )?;
+    let public_key: PKey<_> = certificate.public_key()?;
+    let mut verifier = Verifier::new(signature_digest, &public_key)?;
+    let signed_info = signed_info.canonicalize(None, &inclusive_prefixes(canonicalization));
+    verifier.update(signed_info.as_bytes())?;
+    if verifier.verify(&signature_value)? {
+        Ok(true)
+    } else {
+        Err(SamlError::Signature("signature mismatch"))
+    }
+}
@@ -0,0 +1,849 @@
+//! SAML 2.0 single sign-on as a service provider.
+//!
+//! Configured through `saml_config_file` (a JSON [`SamlConfig`]) for one
+//! IdP. Sign-in starts with an AuthnRequest over the redirect binding, the
+//! IdP posts its Response to the frontend's ACS page, which hands it to
+//! `POST /saml/acs`. Only SP-initiated sign-ins are accepted, every
+//! Response must answer a request we sent.
+
+pub mod dsig;
+pub mod xml;
+
+use crate::{
+    auth::verifier::ExternalAccount,
+    cfg::CONFIG,
+    error::CustomError,
+    utils::{des_from_str, html_escape, percent_encode, random_token},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use openssl::x509::X509;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use xml::Element;
+
+pub const SAML: &str = "saml";
+pub const SAML_METADATA_PATH: &str = "/saml/metadata";
+pub const SAML_LOGIN_PATH: &str = "/saml/login";
+pub const SAML_ACS_PATH: &str = "/saml/acs";
+
+const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const STATUS_SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+const POST_BINDING: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SamlError {
+    #[error("Malformed XML: {0}")]
+    Xml(&'static str),
+
+    #[error("Signature invalid: {0}")]
+    Signature(&'static str),
+
+    #[error("Response rejected: {0}")]
+    Invalid(&'static str),
+
+    #[error("IdP answered {0}")]
+    Status(String),
+
+    #[error("Crypto error: {0}")]
+    Crypto(#[from] openssl::error::ErrorStack),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamlConfig {
+    pub idp_entity_id: String,
+    pub idp_sso_url: String,
+    /// PEM file of the certificate the IdP signs with
+    pub idp_certificate_file: String,
+    pub sp_entity_id: String,
+    /// frontend page the IdP posts the Response to
+    pub acs_url: String,
+    /// attribute holding the email, the NameID is used when missing
+    pub email_attribute: String,
+    pub role_attribute: String,
+    /// value of `role_attribute` to the roles it grants
+    pub attribute_roles: HashMap<String, Vec<i32>>,
+    /// organization users are provisioned into, `default_org_id` when unset
+    pub org_id: Option<i32>,
+    pub clock_skew_secs: i64,
+}
+
+impl Default for SamlConfig {
+    fn default() -> Self {
+        SamlConfig {
+            idp_entity_id: "".to_string(),
+            idp_sso_url: "".to_string(),
+            idp_certificate_file: "".to_string(),
+            sp_entity_id: "".to_string(),
+            acs_url: "".to_string(),
+            email_attribute: "email".to_string(),
+            role_attribute: "role".to_string(),
+            attribute_roles: HashMap::new(),
+            org_id: None,
+            clock_skew_secs: 60,
+        }
+    }
+}
+
+/// Identity a verified assertion carries
+pub struct SamlLogin {
+    /// ID of the AuthnRequest the Response answers
+    pub in_response_to: String,
+    pub username: String,
+    pub account: ExternalAccount,
+}
+
+pub struct SamlProvider {
+    config: SamlConfig,
+    certificate: X509,
+}
+
+impl SamlProvider {
+    pub fn new(config: SamlConfig, certificate: X509) -> Result<Self, CustomError> {
+        if [
+            &config.idp_entity_id,
+            &config.idp_sso_url,
+            &config.sp_entity_id,
+            &config.acs_url,
+        ]
+        .iter()
+        .any(|value| value.is_empty())
+        {
+            return Err(CustomError::ProviderLoad(
+                "SAML needs idp_entity_id, idp_sso_url, sp_entity_id and acs_url".to_string(),
+            ));
+        }
+        Ok(SamlProvider {
+            config,
+            certificate,
+        })
+    }
+
+    /// Configured when `saml_config_file` is set
+    pub fn from_config() -> Result<Option<Self>, CustomError> {
+        if CONFIG.saml_config_file.is_empty() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&CONFIG.saml_config_file)
+            .map_err(|e| CustomError::ProviderLoad(e.to_string()))?;
+        let config: SamlConfig =
+            des_from_str(&content).map_err(|e| CustomError::ProviderLoad(e.to_string()))?;
+        let pem = std::fs::read(&config.idp_certificate_file)
+            .map_err(|e| CustomError::ProviderLoad(e.to_string()))?;
+        let certificate =
+            X509::from_pem(&pem).map_err(|e| CustomError::ProviderLoad(e.to_string()))?;
+        Self::new(config, certificate).map(Some)
+    }
+
+    /// SP metadata to register with the IdP
+    pub fn metadata(&self) -> String {
+        format!(
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<md:EntityDescriptor xmlns:md="urn:oasis:names:tc:SAML:2.0:metadata" entityID="{}">"#,
+                r#"<md:SPSSODescriptor AuthnRequestsSigned="false" WantAssertionsSigned="true" protocolSupportEnumeration="{}">"#,
+                r#"<md:NameIDFormat>urn:oasis:names:tc:SAML:1.1:nameid-format:unspecified</md:NameIDFormat>"#,
+                r#"<md:AssertionConsumerService Binding="{}" Location="{}" index="0" isDefault="true"/>"#,
+                r#"</md:SPSSODescriptor></md:EntityDescriptor>"#,
+            ),
+            html_escape(&self.config.sp_entity_id),
+            PROTOCOL_NS,
+            POST_BINDING,
+            html_escape(&self.config.acs_url),
+        )
+    }
+
+    /// ID of a fresh AuthnRequest and the redirect-binding URL carrying it
+    pub fn authn_request(&self) -> (String, String) {
+        let request_id = format!("_{}", random_token(20));
+        let request = format!(
+            concat!(
+                r#"<samlp:AuthnRequest xmlns:samlp="{}" xmlns:saml="{}" ID="{}" Version="2.0" "#,
+                r#"IssueInstant="{}" Destination="{}" AssertionConsumerServiceURL="{}" ProtocolBinding="{}">"#,
+                r#"<saml:Issuer>{}</saml:Issuer><samlp:NameIDPolicy AllowCreate="true"/>"#,
+                r#"</samlp:AuthnRequest>"#,
+            ),
+            PROTOCOL_NS,
+            ASSERTION_NS,
+            request_id,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            html_escape(&self.config.idp_sso_url),
+            html_escape(&self.config.acs_url),
+            POST_BINDING,
+            html_escape(&self.config.sp_entity_id),
+        );
+        let encoded = STANDARD.encode(deflate_stored(request.as_bytes()));
+        let separator = if self.config.idp_sso_url.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let url = format!(
+            "{}{}SAMLRequest={}",
+            self.config.idp_sso_url,
+            separator,
+            percent_encode(&encoded)
+        );
+        (request_id, url)
+    }
+
+    /// Verifies a base64 `SAMLResponse` and reads the identity out of its
+    /// assertion. Only elements covered by a valid signature are read.
+    pub fn login(&self, saml_response: &str, now: DateTime<Utc>) -> Result<SamlLogin, SamlError> {
+        let compact: String = saml_response
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let decoded = STANDARD
+            .decode(compact)
+            .map_err(|_| SamlError::Invalid("SAMLResponse is not base64"))?;
+        let document = String::from_utf8(decoded)
+            .map_err(|_| SamlError::Invalid("SAMLResponse is not UTF-8"))?;
+        let response = xml::parse(&document)?;
+        if !response.is(PROTOCOL_NS, "Response") {
+            return Err(SamlError::Invalid("not a Response"));
+        }
+        if response
+            .attribute("Destination")
+            .is_some_and(|destination| destination != self.config.acs_url)
+        {
+            return Err(SamlError::Invalid("wrong Destination"));
+        }
+        let status = response
+            .child(PROTOCOL_NS, "Status")
+            .and_then(|status| status.child(PROTOCOL_NS, "StatusCode"))
+            .and_then(|code| code.attribute("Value"))
+            .unwrap_or_default();
+        if status != STATUS_SUCCESS {
+            return Err(SamlError::Status(status.to_string()));
+        }
+        let in_response_to = response
+            .attribute("InResponseTo")
+            .ok_or(SamlError::Invalid("unsolicited Response"))?;
+
+        if response.child(ASSERTION_NS, "EncryptedAssertion").is_some() {
+            return Err(SamlError::Invalid("encrypted assertions are not supported"));
+        }
+        if response.children_named(ASSERTION_NS, "Assertion").count() != 1 {
+            return Err(SamlError::Invalid("exactly one Assertion expected"));
+        }
+        let assertion = response
+            .child(ASSERTION_NS, "Assertion")
+            .ok_or(SamlError::Invalid("Assertion missing"))?;
+        let response_signed = dsig::verify_enveloped(&response, &self.certificate)?;
+        let assertion_signed = dsig::verify_enveloped(assertion, &self.certificate)?;
+        if !response_signed && !assertion_signed {
+            return Err(SamlError::Signature("Response is not signed"));
+        }
+
+        let issuer = assertion
+            .child(ASSERTION_NS, "Issuer")
+            .map(|issuer| issuer.text())
+            .unwrap_or_default();
+        if issuer.trim() != self.config.idp_entity_id {
+            return Err(SamlError::Invalid("wrong Issuer"));
+        }
+        self.check_conditions(assertion, now)?;
+        let subject = assertion
+            .child(ASSERTION_NS, "Subject")
+            .ok_or(SamlError::Invalid("Subject missing"))?;
+        self.check_confirmation(subject, in_response_to, now)?;
+        let name_id = subject
+            .child(ASSERTION_NS, "NameID")
+            .map(|name_id| name_id.text().trim().to_string())
+            .filter(|name_id| !name_id.is_empty())
+            .ok_or(SamlError::Invalid("NameID missing"))?;
+
+        let attributes = attributes(assertion);
+        let values = |name: &str| attributes.get(name).cloned().unwrap_or_default();
+        let email = values(&self.config.email_attribute).into_iter().next();
+        let role_values = values(&self.config.role_attribute);
+        let mut role_ids: Vec<i32> = self
+            .config
+            .attribute_roles
+            .iter()
+            .filter(|(value, _)| role_values.contains(value))
+            .flat_map(|(_, role_ids)| role_ids.iter().copied())
+            .collect();
+        role_ids.sort_unstable();
+        role_ids.dedup();
+        let mut managed_role_ids: Vec<i32> = self
+            .config
+            .attribute_roles
+            .values()
+            .flatten()
+            .copied()
+            .collect();
+        managed_role_ids.sort_unstable();
+        managed_role_ids.dedup();
+
+        Ok(SamlLogin {
+            in_response_to: in_response_to.to_string(),
+            username: email.clone().unwrap_or_else(|| name_id.clone()),
+            account: ExternalAccount {
+                provider: SAML.to_string(),
+                provider_id: name_id,
+                email,
+                org_id: self.config.org_id.unwrap_or(CONFIG.default_org_id),
+                role_ids,
+                managed_role_ids,
+            },
+        })
+    }
+
+    /// Validity window and audience of the assertion
+    fn check_conditions(&self, assertion: &Element, now: DateTime<Utc>) -> Result<(), SamlError> {
+        let skew = Duration::seconds(self.config.clock_skew_secs);
+        let conditions = assertion
+            .child(ASSERTION_NS, "Conditions")
+            .ok_or(SamlError::Invalid("Conditions missing"))?;
+        if let Some(not_before) = conditions.attribute("NotBefore")
+            && now + skew < timestamp(not_before)?
+        {
+            return Err(SamlError::Invalid("assertion not yet valid"));
+        }
+        if let Some(not_on_or_after) = conditions.attribute("NotOnOrAfter")
+            && now - skew >= timestamp(not_on_or_after)?
+        {
+            return Err(SamlError::Invalid("assertion expired"));
+        }
+        // every AudienceRestriction must name us
+        let restrictions: Vec<&Element> = conditions
+            .children_named(ASSERTION_NS, "AudienceRestriction")
+            .collect();
+        if restrictions.is_empty()
+            || !restrictions.iter().all(|restriction| {
+                restriction
+                    .children_named(ASSERTION_NS, "Audience")
+                    .any(|audience| audience.text().trim() == self.config.sp_entity_id)
+            })
+        {
+            return Err(SamlError::Invalid("wrong Audience"));
+        }
+        Ok(())
+    }
+
+    /// A bearer confirmation for our ACS, answering our request
+    fn check_confirmation(
+        &self,
+        subject: &Element,
+        in_response_to: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), SamlError> {
+        let skew = Duration::seconds(self.config.clock_skew_secs);
+        let confirmed = subject
+            .children_named(ASSERTION_NS, "SubjectConfirmation")
+            .filter(|confirmation| confirmation.attribute("Method") == Some(BEARER))
+            .filter_map(|confirmation| confirmation.child(ASSERTION_NS, "SubjectConfirmationData"))
+            .any(|data| {
+                data.attribute("Recipient") == Some(self.config.acs_url.as_str())
+                    && data.attribute("InResponseTo") == Some(in_response_to)
+                    && data
+                        .attribute("NotOnOrAfter")
+                        .and_then(|value| timestamp(value).ok())
+                        .is_some_and(|not_on_or_after| now - skew < not_on_or_after)
+            });
+        if confirmed {
+            Ok(())
+        } else {
+            Err(SamlError::Invalid("no valid SubjectConfirmation"))
+        }
+    }
+}
+
+/// Values of the assertion's attributes by name
+fn attributes(assertion: &Element) -> HashMap<String, Vec<String>> {
+    let mut attributes: HashMap<String, Vec<String>> = HashMap::new();
+    for statement in assertion.children_named(ASSERTION_NS, "AttributeStatement") {
+        for attribute in statement.children_named(ASSERTION_NS, "Attribute") {
+            let Some(name) = attribute.attribute("Name") else {
+                continue;
+            };
+            attributes.entry(name.to_string()).or_default().extend(
+                attribute
+                    .children_named(ASSERTION_NS, "AttributeValue")
+                    .map(|value| value.text().trim().to_string()),
+            );
+        }
+    }
+    attributes
+}
+
+fn timestamp(value: &str) -> Result<DateTime<Utc>, SamlError> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(|_| SamlError::Invalid("invalid timestamp"))
+}
+
+/// Raw DEFLATE with stored blocks, the redirect binding requires DEFLATE
+/// but not that it compresses
+fn deflate_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 5 * (data.len() / 0xffff + 1));
+    let mut chunks = data.chunks(0xffff).peekable();
+    if chunks.peek().is_none() {
+        return vec![0x01, 0x00, 0x00, 0xff, 0xff];
+    }
+    while let Some(chunk) = chunks.next() {
+        // BFINAL on the last block, BTYPE 00 (stored)
+        out.push(u8::from(chunks.peek().is_none()));
+        let len = chunk.len() as u16;
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use once_cell::sync::Lazy;
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        sign::Signer,
+        x509::{X509, X509NameBuilder},
+    };
+
+    use super::*;
+    use crate::cfg::load_test_env;
+
+    const IDP: &str = "https://idp.example.com";
+    const SP: &str = "https://sp.example.com";
+    const ACS: &str = "https://sp.example.com/en/saml/acs";
+    const REQUEST_ID: &str = "_request";
+    const RESPONSE_ID: &str = "_response";
+    const ASSERTION_ID: &str = "_assertion";
+
+    /// Signing key of the test IdP with its self-signed certificate
+    static IDP_KEY: Lazy<(PKey<Private>, X509)> = Lazy::new(|| {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "idp.example.com").unwrap();
+        let name = name.build();
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate.set_subject_name(&name).unwrap();
+        certificate.set_issuer_name(&name).unwrap();
+        certificate.set_pubkey(&key).unwrap();
+        certificate
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        certificate
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        certificate.sign(&key, MessageDigest::sha256()).unwrap();
+        (key, certificate.build())
+    });
+
+    fn provider() -> SamlProvider {
+        load_test_env();
+        SamlProvider::new(
+            SamlConfig {
+                idp_entity_id: IDP.to_string(),
+                idp_sso_url: format!("{}/sso", IDP),
+                sp_entity_id: SP.to_string(),
+                acs_url: ACS.to_string(),
+                attribute_roles: HashMap::from([("staff".to_string(), vec![3])]),
+                org_id: Some(1),
+                ..SamlConfig::default()
+            },
+            IDP_KEY.1.clone(),
+        )
+        .unwrap()
+    }
+
+    fn instant(offset_secs: i64) -> String {
+        (Utc::now() + Duration::seconds(offset_secs)).to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+
+    /// Enveloped signature template for the element of `id`, pointing at
+    /// `uri`. `sign` fills in the digest and the signature value.
+    fn signature(id: &str, uri: &str) -> String {
+        format!(
+            concat!(
+                r#"<ds:Signature xmlns:ds="http://www.w3.org/2000/09/xmldsig#"><ds:SignedInfo>"#,
+                r#"<ds:CanonicalizationMethod Algorithm="http://www.w3.org/2001/10/xml-exc-c14n#"/>"#,
+                r#"<ds:SignatureMethod Algorithm="http://www.w3.org/2001/04/xmldsig-more#rsa-sha256"/>"#,
+                r##"<ds:Reference URI="#{}"><ds:Transforms>"##,
+                r#"<ds:Transform Algorithm="http://www.w3.org/2000/09/xmldsig#enveloped-signature"/>"#,
+                r#"<ds:Transform Algorithm="http://www.w3.org/2001/10/xml-exc-c14n#"/>"#,
+                r#"</ds:Transforms><ds:DigestMethod Algorithm="http://www.w3.org/2001/04/xmlenc#sha256"/>"#,
+                r#"<ds:DigestValue>digest{}</ds:DigestValue></ds:Reference></ds:SignedInfo>"#,
+                r#"<ds:SignatureValue>value{}</ds:SignatureValue></ds:Signature>"#,
+            ),
+            uri, id, id
+        )
+    }
+
+    fn assertion(id: &str, signature: &str, confirmation_until: i64) -> String {
+        format!(
+            concat!(
+                r#"<saml:Assertion ID="{id}" Version="2.0" IssueInstant="{now}">"#,
+                r#"<saml:Issuer>{idp}</saml:Issuer>{signature}<saml:Subject>"#,
+                r#"<saml:NameID>jo-1</saml:NameID>"#,
+                r#"<saml:SubjectConfirmation Method="{bearer}"><saml:SubjectConfirmationData "#,
+                r#"Recipient="{acs}" InResponseTo="{request}" NotOnOrAfter="{until}"/>"#,
+                r#"</saml:SubjectConfirmation></saml:Subject>"#,
+                r#"<saml:Conditions NotBefore="{before}" NotOnOrAfter="{after}">"#,
+                r#"<saml:AudienceRestriction><saml:Audience>{sp}</saml:Audience>"#,
+                r#"</saml:AudienceRestriction></saml:Conditions><saml:AttributeStatement>"#,
+                r#"<saml:Attribute Name="email"><saml:AttributeValue>jo@example.com</saml:AttributeValue></saml:Attribute>"#,
+                r#"<saml:Attribute Name="role"><saml:AttributeValue>staff</saml:AttributeValue></saml:Attribute>"#,
+                r#"</saml:AttributeStatement></saml:Assertion>"#,
+            ),
+            id = id,
+            now = instant(0),
+            idp = IDP,
+            signature = signature,
+            bearer = BEARER,
+            acs = ACS,
+            request = REQUEST_ID,
+            until = instant(confirmation_until),
+            before = instant(-60),
+            after = instant(300),
+            sp = SP,
+        )
+    }
+
+    fn response(signature: &str, assertions: &str) -> String {
+        format!(
+            concat!(
+                r#"<samlp:Response xmlns:samlp="{protocol}" xmlns:saml="{assertion}" ID="{id}" "#,
+                r#"Version="2.0" IssueInstant="{now}" Destination="{acs}" InResponseTo="{request}">"#,
+                r#"<saml:Issuer>{idp}</saml:Issuer>{signature}<samlp:Status>"#,
+                r#"<samlp:StatusCode Value="{success}"/></samlp:Status>{assertions}</samlp:Response>"#,
+            ),
+            protocol = PROTOCOL_NS,
+            assertion = ASSERTION_NS,
+            id = RESPONSE_ID,
+            now = instant(0),
+            acs = ACS,
+            request = REQUEST_ID,
+            idp = IDP,
+            signature = signature,
+            success = STATUS_SUCCESS,
+            assertions = assertions,
+        )
+    }
+
+    fn find_by_id<'a>(element: &'a Element, id: &str) -> Option<&'a Element> {
+        if element.attribute("ID") == Some(id) {
+            return Some(element);
+        }
+        element.elements().find_map(|child| find_by_id(child, id))
+    }
+
+    /// Signs the element of `id` the way an IdP does, digest over its
+    /// canonical form without the signature, then RSA over the SignedInfo
+    fn sign(document: &str, id: &str) -> String {
+        let root = xml::parse(document).unwrap();
+        let element = find_by_id(&root, id).unwrap();
+        let signature = element.child(dsig::DSIG_NS, "Signature").unwrap();
+        let canonical = element.canonicalize(Some(signature), &[]);
+        let digest = openssl::hash::hash(MessageDigest::sha256(), canonical.as_bytes()).unwrap();
+        let document = document.replace(
+            &format!("digest{}<", id),
+            &format!("{}<", STANDARD.encode(digest)),
+        );
+
+        let root = xml::parse(&document).unwrap();
+        let signed_info = find_by_id(&root, id)
+            .and_then(|element| element.child(dsig::DSIG_NS, "Signature"))
+            .and_then(|signature| signature.child(dsig::DSIG_NS, "SignedInfo"))
+            .unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &IDP_KEY.0).unwrap();
+        signer
+            .update(signed_info.canonicalize(None, &[]).as_bytes())
+            .unwrap();
+        let value = STANDARD.encode(signer.sign_to_vec().unwrap());
+        document.replace(&format!("value{}<", id), &format!("{}<", value))
+    }
+
+    fn login(document: &str) -> Result<SamlLogin, SamlError> {
+        provider().login(&STANDARD.encode(document), Utc::now())
+    }
+
+    fn rejection(document: &str) -> SamlError {
+        login(document).err().expect("the Response was accepted")
+    }
+
+    fn signed_response() -> String {
+        let document = response(
+            &signature(RESPONSE_ID, RESPONSE_ID),
+            &assertion(ASSERTION_ID, "", 300),
+        );
+        sign(&document, RESPONSE_ID)
+    }
+
+    fn signed_assertion() -> String {
+        let document = response(
+            "",
+            &assertion(ASSERTION_ID, &signature(ASSERTION_ID, ASSERTION_ID), 300),
+        );
+        sign(&document, ASSERTION_ID)
+    }
+
+    #[test]
+    fn signed_response_is_accepted() {
+        let login = login(&signed_response()).unwrap();
+        assert_eq!(login.in_response_to, REQUEST_ID);
+        assert_eq!(login.username, "jo@example.com");
+        assert_eq!(login.account.provider_id, "jo-1");
+        assert_eq!(login.account.role_ids, vec![3]);
+    }
+
+    #[test]
+    fn signed_assertion_is_accepted() {
+        let login = login(&signed_assertion()).unwrap();
+        assert_eq!(login.account.provider_id, "jo-1");
+        assert_eq!(login.account.email.as_deref(), Some("jo@example.com"));
+    }
+
+    #[test]
+    fn unsigned_response_is_refused() {
+        let document = response("", &assertion(ASSERTION_ID, "", 300));
+        assert!(matches!(
+            rejection(&document),
+            SamlError::Signature("Response is not signed")
+        ));
+    }
+
+    #[test]
+    fn tampered_content_fails_the_digest() {
+        for document in [signed_response(), signed_assertion()] {
+            let tampered = document.replace(">jo-1<", ">admin<");
+            assert_ne!(tampered, document);
+            assert!(matches!(
+                rejection(&tampered),
+                SamlError::Signature("digest mismatch")
+            ));
+        }
+    }
+
+    #[test]
+    fn altered_signature_value_is_refused() {
+        let document = signed_assertion();
+        let start = document.find("<ds:SignatureValue>").unwrap() + "<ds:SignatureValue>".len();
+        let replacement = if &document[start..start + 1] == "A" {
+            "B"
+        } else {
+            "A"
+        };
+        let altered = format!(
+            "{}{}{}",
+            &document[..start],
+            replacement,
+            &document[start + 1..]
+        );
+        assert!(matches!(
+            rejection(&altered),
+            SamlError::Signature("signature mismatch")
+        ));
+    }
+
+    #[test]
+    fn wrapped_signed_assertion_is_not_read() {
+        // the signed assertion moves into an extension, an unsigned one
+        // takes its place
+        let signed = sign(
+            &assertion(ASSERTION_ID, &signature(ASSERTION_ID, ASSERTION_ID), 300).replace(
+                "<saml:Assertion ",
+                &format!("<saml:Assertion xmlns:saml=\"{}\" ", ASSERTION_NS),
+            ),
+            ASSERTION_ID,
+        );
+        let evil = assertion("_evil", "", 300).replace(">jo-1<", ">admin<");
+        let document = response(
+            "",
+            &format!("<samlp:Extensions>{}</samlp:Extensions>{}", signed, evil),
+        );
+        assert!(matches!(
+            rejection(&document),
+            SamlError::Signature("Response is not signed")
+        ));
+    }
+
+    #[test]
+    fn signature_moved_onto_another_assertion_is_refused() {
+        let document = signed_assertion();
+        let signature_start = document.find("<ds:Signature").unwrap();
+        let signature_end = document.find("</ds:Signature>").unwrap() + "</ds:Signature>".len();
+        let signature = &document[signature_start..signature_end];
+
+        // an unsigned assertion of its own ID carrying the copied signature
+        let moved = response(
+            "",
+            &assertion("_evil", signature, 300).replace(">jo-1<", ">admin<"),
+        );
+        assert!(matches!(
+            rejection(&moved),
+            SamlError::Signature("Reference does not point at its element")
+        ));
+        // the same ID as the signed one with other content
+        let cloned = response(
+            "",
+            &assertion(ASSERTION_ID, signature, 300).replace(">jo-1<", ">admin<"),
+        );
+        assert!(matches!(
+            rejection(&cloned),
+            SamlError::Signature("digest mismatch")
+        ));
+        // the assertion's signature moved up onto the Response
+        let lifted = response(signature, &assertion(ASSERTION_ID, "", 300));
+        assert!(matches!(
+            rejection(&lifted),
+            SamlError::Signature("Reference does not point at its element")
+        ));
+    }
+
+    #[test]
+    fn reference_to_another_id_is_refused() {
+        let document = response(
+            "",
+            &assertion(ASSERTION_ID, &signature(ASSERTION_ID, RESPONSE_ID), 300),
+        );
+        assert!(matches!(
+            rejection(&sign(&document, ASSERTION_ID)),
+            SamlError::Signature("Reference does not point at its element")
+        ));
+    }
+
+    #[test]
+    fn several_signatures_are_refused() {
+        let signatures = format!(
+            "{}{}",
+            signature(ASSERTION_ID, ASSERTION_ID),
+            signature("_second", ASSERTION_ID)
+        );
+        let document = response("", &assertion(ASSERTION_ID, &signatures, 300));
+        assert!(matches!(
+            rejection(&document),
+            SamlError::Signature("several signatures")
+        ));
+    }
+
+    #[test]
+    fn several_assertions_are_refused() {
+        let document = signed_response().replace(
+            "</samlp:Response>",
+            &format!("{}</samlp:Response>", assertion("_second", "", 300)),
+        );
+        assert!(matches!(
+            rejection(&document),
+            SamlError::Invalid("exactly one Assertion expected")
+        ));
+    }
+
+    #[test]
+    fn dtd_is_refused() {
+        let document = format!(
+            "<!DOCTYPE samlp:Response [<!ENTITY name \"admin\">]>{}",
+            signed_response()
+        );
+        assert!(matches!(
+            rejection(&document),
+            SamlError::Xml("DTDs are not allowed")
+        ));
+    }
+
+    #[test]
+    fn expired_subject_confirmation_is_refused() {
+        let document = response(
+            &signature(RESPONSE_ID, RESPONSE_ID),
+            &assertion(ASSERTION_ID, "", -120),
+        );
+        assert!(matches!(
+            rejection(&sign(&document, RESPONSE_ID)),
+            SamlError::Invalid("no valid SubjectConfirmation")
+        ));
+    }
+
+    #[test]
+    fn response_for_another_acs_is_refused() {
+        let document = signed_response().replace(
+            &format!("Destination=\"{}\"", ACS),
+            "Destination=\"https://evil.example.com/acs\"",
+        );
+        assert!(matches!(
+            rejection(&document),
+            SamlError::Invalid("wrong Destination")
+        ));
+    }
+
+    /// Responses in `fixtures/`, signed by libxmlsec1 1.2.37 (the library
+    /// behind `xmlsec1 --sign`) with the key of `fixtures/idp.pem`, so the
+    /// canonicalization above is checked against another implementation.
+    /// One signs its assertion with an InclusiveNamespaces prefix list and
+    /// indentation, the other its Response with SHA-512 in one line.
+    const FIXTURE_ASSERTION_SIGNED: &str = include_str!("fixtures/assertion_signed.xml");
+    const FIXTURE_RESPONSE_SIGNED: &str = include_str!("fixtures/response_signed.xml");
+
+    fn fixture_login(document: &str) -> Result<SamlLogin, SamlError> {
+        load_test_env();
+        let certificate = X509::from_pem(include_bytes!("fixtures/idp.pem")).unwrap();
+        let provider = SamlProvider::new(
+            SamlConfig {
+                idp_entity_id: IDP.to_string(),
+                idp_sso_url: format!("{}/sso", IDP),
+                sp_entity_id: SP.to_string(),
+                acs_url: ACS.to_string(),
+                attribute_roles: HashMap::from([
+                    ("staff".to_string(), vec![3]),
+                    ("contractor".to_string(), vec![4]),
+                ]),
+                org_id: Some(1),
+                ..SamlConfig::default()
+            },
+            certificate,
+        )
+        .unwrap();
+        let now = timestamp("2026-10-18T09:01:00Z").unwrap();
+        provider.login(&STANDARD.encode(document), now)
+    }
+
+    #[test]
+    fn xmlsec_signed_assertion_is_accepted() {
+        let login = fixture_login(FIXTURE_ASSERTION_SIGNED).unwrap();
+        assert_eq!(login.in_response_to, "_fixture_request");
+        assert_eq!(login.username, "jo@example.com");
+        assert_eq!(login.account.provider_id, "00u1fixture7Jo");
+        assert_eq!(login.account.role_ids, vec![3]);
+    }
+
+    #[test]
+    fn xmlsec_signed_response_is_accepted() {
+        let login = fixture_login(FIXTURE_RESPONSE_SIGNED).unwrap();
+        assert_eq!(login.username, "sam@example.com");
+        assert_eq!(login.account.provider_id, "S-1-5-21-fixture-1104");
+        assert_eq!(login.account.role_ids, vec![4]);
+    }
+
+    #[test]
+    fn xmlsec_signed_responses_are_tamper_evident() {
+        for (document, original) in [
+            (FIXTURE_ASSERTION_SIGNED, ">staff<"),
+            (FIXTURE_RESPONSE_SIGNED, ">contractor<"),
+        ] {
+            let tampered = document.replace(original, ">admin<");
+            assert_ne!(tampered, document);
+            assert!(matches!(
+                fixture_login(&tampered)
+                    .err()
+                    .expect("the Response was accepted"),
+                SamlError::Signature("digest mismatch")
+            ));
+        }
+    }
+
+    #[test]
+    fn xmlsec_signed_response_needs_the_configured_certificate() {
+        // signed by the fixture key, verified against another IdP's
+        let error = login(FIXTURE_RESPONSE_SIGNED).err().unwrap();
+        assert!(matches!(error, SamlError::Signature("signature mismatch")));
+    }
+}
@@ -0,0 +1,569 @@
+//! A small XML reader for SAML messages and the exclusive canonicalization
+//! (`xml-exc-c14n`, without comments) XML signatures are computed over.
+//! Documents with a DTD are refused, no entity is ever expanded.
+
+use super::SamlError;
+
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+/// Deepest element nesting a document may have. SAML messages stay around
+/// ten levels, the limit keeps the recursive parser, canonicalization and
+/// `text` off the end of the stack, elements only come out of [`parse`].
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug)]
+pub struct Attribute {
+    pub prefix: Option<String>,
+    pub name: String,
+    pub namespace: Option<String>,
+    pub value: String,
+}
+
+#[derive(Debug)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+#[derive(Debug)]
+pub struct Element {
+    pub prefix: Option<String>,
+    pub name: String,
+    pub namespace: Option<String>,
+    /// namespace declarations, `xmlns` ones are not in here
+    pub attributes: Vec<Attribute>,
+    pub children: Vec<Node>,
+    /// namespaces in scope, later entries shadow earlier ones. A None prefix
+    /// is the default namespace, an empty URI undeclares it.
+    scope: Vec<(Option<String>, String)>,
+}
+
+impl Element {
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.name == name && self.namespace.as_deref() == Some(namespace)
+    }
+
+    /// Value of an attribute without a namespace
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.namespace.is_none() && attribute.name == name)
+            .map(|attribute| attribute.value.as_str())
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    pub fn children_named<'a, 'b>(
+        &'a self,
+        namespace: &'b str,
+        name: &'b str,
+    ) -> impl Iterator<Item = &'a Element> + 'b
+    where
+        'a: 'b,
+    {
+        self.elements()
+            .filter(move |element| element.is(namespace, name))
+    }
+
+    /// The only child of that name, None when missing or repeated
+    pub fn child(&self, namespace: &str, name: &str) -> Option<&Element> {
+        let mut children = self.children_named(namespace, name);
+        let child = children.next()?;
+        children.next().is_none().then_some(child)
+    }
+
+    /// Text content of the element, markup of children left out
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for node in &self.children {
+            match node {
+                Node::Text(value) => text.push_str(value),
+                Node::Element(element) => text.push_str(&element.text()),
+            }
+        }
+        text
+    }
+
+    fn lookup(&self, prefix: Option<&str>) -> Option<&str> {
+        if prefix == Some("xml") {
+            return Some(XML_NAMESPACE);
+        }
+        self.scope
+            .iter()
+            .rev()
+            .find(|(declared, _)| declared.as_deref() == prefix)
+            .map(|(_, uri)| uri.as_str())
+    }
+
+    fn qualified_name(prefix: &Option<String>, name: &str) -> String {
+        match prefix {
+            Some(prefix) => format!("{}:{}", prefix, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Exclusive canonical form of the element, leaving out `skip` (the
+    /// enveloped signature). `inclusive_prefixes` is the transform's
+    /// InclusiveNamespaces PrefixList, `#default` standing for the default
+    /// namespace.
+    pub fn canonicalize(&self, skip: Option<&Element>, inclusive_prefixes: &[String]) -> String {
+        let mut out = String::new();
+        self.write_canonical(&mut out, &[], skip, inclusive_prefixes);
+        out
+    }
+
+    fn write_canonical(
+        &self,
+        out: &mut String,
+        rendered: &[(Option<String>, String)],
+        skip: Option<&Element>,
+        inclusive_prefixes: &[String],
+    ) {
+        // prefixes the element visibly uses, plus the inclusive ones
+        let mut prefixes: Vec<Option<String>> = vec![self.prefix.clone()];
+        prefixes.extend(
+            self.attributes
+                .iter()
+                .filter(|attribute| attribute.prefix.as_deref() != Some("xml"))
+                .filter_map(|attribute| attribute.prefix.clone().map(Some)),
+        );
+        prefixes.extend(inclusive_prefixes.iter().filter_map(|prefix| {
+            if prefix == "#default" {
+                Some(None)
+            } else {
+                self.lookup(Some(prefix)).map(|_| Some(prefix.clone()))
+            }
+        }));
+        prefixes.sort();
+        prefixes.dedup();
+
+        let mut now_rendered = rendered.to_vec();
+        let mut declarations = vec![];
+        for prefix in prefixes {
+            let value = self.lookup(prefix.as_deref()).unwrap_or_default();
+            let rendered_value = rendered
+                .iter()
+                .rev()
+                .find(|(declared, _)| *declared == prefix)
+                .map(|(_, uri)| uri.as_str())
+                .unwrap_or_default();
+            if value != rendered_value {
+                declarations.push((prefix.clone(), value.to_string()));
+                now_rendered.push((prefix, value.to_string()));
+            }
+        }
+
+        let qualified_name = Self::qualified_name(&self.prefix, &self.name);
+        out.push('<');
+        out.push_str(&qualified_name);
+        for (prefix, uri) in &declarations {
+            match prefix {
+                Some(prefix) => out.push_str(&format!(" xmlns:{}=\"", prefix)),
+                None => out.push_str(" xmlns=\""),
+            }
+            out.push_str(&escape_attribute(uri));
+            out.push('"');
+        }
+        let mut attributes: Vec<&Attribute> = self.attributes.iter().collect();
+        attributes.sort_by(|a, b| {
+            (a.namespace.as_deref().unwrap_or_default(), &a.name)
+                .cmp(&(b.namespace.as_deref().unwrap_or_default(), &b.name))
+        });
+        for attribute in attributes {
+            out.push(' ');
+            out.push_str(&Self::qualified_name(&attribute.prefix, &attribute.name));
+            out.push_str("=\"");
+            out.push_str(&escape_attribute(&attribute.value));
+            out.push('"');
+        }
+        out.push('>');
+        for node in &self.children {
+            match node {
+                Node::Text(text) => out.push_str(&escape_text(text)),
+                Node::Element(child) if skip.is_some_and(|skip| std::ptr::eq(skip, child)) => {}
+                Node::Element(child) => {
+                    child.write_canonical(out, &now_rendered, skip, inclusive_prefixes)
+                }
+            }
+        }
+        out.push_str("</");
+        out.push_str(&qualified_name);
+        out.push('>');
+    }
+}
+
+fn escape_text(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\r', "&#xD;")
+}
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+        .replace('\t', "&#x9;")
+        .replace('\n', "&#xA;")
+        .replace('\r', "&#xD;")
+}
+
+/// Parses a whole document into its root element
+pub fn parse(xml: &str) -> Result<Element, SamlError> {
+    let normalized = xml.replace("\r\n", "\n").replace('\r', "\n");
+    let mut parser = Parser {
+        input: normalized.trim_start_matches('\u{feff}'),
+        pos: 0,
+    };
+    parser.skip_misc()?;
+    let root = parser.element(&[], 1)?;
+    parser.skip_misc()?;
+    if parser.pos != parser.input.len() {
+        return Err(SamlError::Xml("content after the root element"));
+    }
+    Ok(root)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Moves past `end`, returns what came before it
+    fn until(&mut self, end: &str) -> Result<&str, SamlError> {
+        let start = self.pos;
+        let offset = self.input[start..]
+            .find(end)
+            .ok_or(SamlError::Xml("unexpected end of document"))?;
+        self.pos = start + offset + end.len();
+        Ok(&self.input[start..start + offset])
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), SamlError> {
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(SamlError::Xml("unexpected character"))
+        }
+    }
+
+    /// Whitespace, comments and processing instructions around the root
+    fn skip_misc(&mut self) -> Result<(), SamlError> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<?") {
+                self.until("?>")?;
+            } else if self.rest().starts_with("<!--") {
+                self.until("-->")?;
+            } else if self.rest().starts_with("<!") {
+                return Err(SamlError::Xml("DTDs are not allowed"));
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, SamlError> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '=' | '<'))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(SamlError::Xml("missing name"));
+        }
+        let name = rest[..len].to_string();
+        self.pos += len;
+        Ok(name)
+    }
+
+    fn element(
+        &mut self,
+        parent_scope: &[(Option<String>, String)],
+        depth: usize,
+    ) -> Result<Element, SamlError> {
+        if depth > MAX_DEPTH {
+            return Err(SamlError::Xml("elements nested too deeply"));
+        }
+        self.expect("<")?;
+        let qualified_name = self.name()?;
+        let mut raw_attributes: Vec<(String, String)> = vec![];
+        let empty = loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                break true;
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break false;
+            }
+            let name = self.name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(quote @ ('"' | '\'')) => quote,
+                _ => return Err(SamlError::Xml("unquoted attribute")),
+            };
+            self.pos += 1;
+            let raw = self.until(&quote.to_string())?;
+            if raw.contains('<') {
+                return Err(SamlError::Xml("'<' in attribute value"));
+            }
+            let value = unescape(&raw.replace(['\t', '\n'], " "))?;
+            if raw_attributes.iter().any(|(existing, _)| *existing == name) {
+                return Err(SamlError::Xml("duplicate attribute"));
+            }
+            raw_attributes.push((name, value));
+        };
+
+        let mut scope = parent_scope.to_vec();
+        let mut plain_attributes = vec![];
+        for (name, value) in raw_attributes {
+            if name == "xmlns" {
+                scope.push((None, value));
+            } else if let Some(prefix) = name.strip_prefix("xmlns:") {
+                scope.push((Some(prefix.to_string()), value));
+            } else {
+                plain_attributes.push((name, value));
+            }
+        }
+        let mut element = Element {
+            prefix: None,
+            name: String::new(),
+            namespace: None,
+            attributes: vec![],
+            children: vec![],
+            scope,
+        };
+        let (prefix, name) = split_name(&qualified_name);
+        element.namespace = match element.lookup(prefix.as_deref()) {
+            Some("") | None if prefix.is_none() => None,
+            Some(uri) => Some(uri.to_string()),
+            None => return Err(SamlError::Xml("unbound namespace prefix")),
+        };
+        element.prefix = prefix;
+        element.name = name;
+        for (qualified_name, value) in plain_attributes {
+            let (prefix, name) = split_name(&qualified_name);
+            let namespace = match &prefix {
+                Some(prefix) => Some(
+                    element
+                        .lookup(Some(prefix))
+                        .filter(|uri| !uri.is_empty())
+                        .ok_or(SamlError::Xml("unbound namespace prefix"))?
+                        .to_string(),
+                ),
+                None => None,
+            };
+            element.attributes.push(Attribute {
+                prefix,
+                name,
+                namespace,
+                value,
+            });
+        }
+        if empty {
+            return Ok(element);
+        }
+
+        loop {
+            if self.rest().starts_with("</") {
+                self.pos += 2;
+                if self.name()? != qualified_name {
+                    return Err(SamlError::Xml("mismatched end tag"));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(element);
+            } else if self.rest().starts_with("<!--") {
+                self.until("-->")?;
+            } else if self.rest().starts_with("<![CDATA[") {
+                self.pos += "<![CDATA[".len();
+                let text = self.until("]]>")?.to_string();
+                element.push_text(&text);
+            } else if self.rest().starts_with("<?") {
+                self.until("?>")?;
+            } else if self.rest().starts_with("<!") {
+                return Err(SamlError::Xml("DTDs are not allowed"));
+            } else if self.rest().starts_with('<') {
+                let child = self.element(&element.scope, depth + 1)?;
+                element.children.push(Node::Element(child));
+            } else if self.rest().is_empty() {
+                return Err(SamlError::Xml("unexpected end of document"));
+            } else {
+                let len = self.rest().find('<').unwrap_or(self.rest().len());
+                let text = unescape(&self.rest()[..len])?;
+                self.pos += len;
+                element.push_text(&text);
+            }
+        }
+    }
+}
+
+impl Element {
+    fn push_text(&mut self, text: &str) {
+        match self.children.last_mut() {
+            Some(Node::Text(existing)) => existing.push_str(text),
+            _ => self.children.push(Node::Text(text.to_string())),
+        }
+    }
+}
+
+fn split_name(qualified_name: &str) -> (Option<String>, String) {
+    match qualified_name.split_once(':') {
+        Some((prefix, name)) => (Some(prefix.to_string()), name.to_string()),
+        None => (None, qualified_name.to_string()),
+    }
+}
+
+/// Resolves the predefined entities and character references
+fn unescape(value: &str) -> Result<String, SamlError> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find(';')
+            .ok_or(SamlError::Xml("unterminated reference"))?;
+        let reference = &rest[start + 1..start + end];
+        let resolved = match reference {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match (reference.strip_prefix("#x"), reference.strip_prefix('#')) {
+                    (Some(hex), _) => u32::from_str_radix(hex, 16).ok(),
+                    (None, Some(decimal)) => decimal.parse().ok(),
+                    _ => None,
+                };
+                code.and_then(char::from_u32)
+                    .ok_or(SamlError::Xml("unknown entity"))?
+            }
+        };
+        out.push(resolved);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested(depth: usize) -> String {
+        format!("{}text{}", "<a>".repeat(depth), "</a>".repeat(depth))
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let root = parse(&nested(MAX_DEPTH)).unwrap();
+        assert_eq!(root.text(), "text");
+        assert_eq!(root.canonicalize(None, &[]), nested(MAX_DEPTH));
+        assert!(matches!(
+            parse(&nested(MAX_DEPTH + 1)),
+            Err(SamlError::Xml("elements nested too deeply"))
+        ));
+        // refused long before the stack runs out
+        assert!(parse(&nested(1_000_000)).is_err());
+    }
+
+    #[test]
+    fn dtds_are_refused() {
+        for document in [
+            r#"<!DOCTYPE a [<!ENTITY x "boom">]><a>&x;</a>"#,
+            r#"<?xml version="1.0"?><!DOCTYPE a SYSTEM "http://example.com/a.dtd"><a/>"#,
+            r#"<a><!DOCTYPE a></a>"#,
+            r#"<a><!ENTITY x "boom"></a>"#,
+        ] {
+            assert!(
+                matches!(parse(document), Err(SamlError::Xml("DTDs are not allowed"))),
+                "{}",
+                document
+            );
+        }
+        assert!(matches!(
+            parse("<a>&x;</a>"),
+            Err(SamlError::Xml("unknown entity"))
+        ));
+    }
+
+    #[test]
+    fn canonical_form_orders_attributes_and_declares_used_namespaces() {
+        let root = parse(concat!(
+            r#"<?xml version="1.0"?>"#,
+            "\n<a:root xmlns:a=\"urn:a\" xmlns:b=\"urn:b\" xmlns:unused=\"urn:u\" a:y='2' z=\"x\ty\">",
+            "<a:child>t&amp;&lt;&#x3E;<![CDATA[<c>]]></a:child><!-- gone --><b:x/>",
+            "</a:root>"
+        ))
+        .unwrap();
+        assert_eq!(
+            root.canonicalize(None, &[]),
+            concat!(
+                r#"<a:root xmlns:a="urn:a" z="x y" a:y="2">"#,
+                "<a:child>t&amp;&lt;&gt;&lt;c&gt;</a:child>",
+                r#"<b:x xmlns:b="urn:b"></b:x></a:root>"#
+            )
+        );
+        // inclusive prefixes are declared even when unused
+        assert_eq!(
+            root.canonicalize(None, &["unused".to_string()]),
+            concat!(
+                r#"<a:root xmlns:a="urn:a" xmlns:unused="urn:u" z="x y" a:y="2">"#,
+                "<a:child>t&amp;&lt;&gt;&lt;c&gt;</a:child>",
+                r#"<b:x xmlns:b="urn:b"></b:x></a:root>"#
+            )
+        );
+    }
+
+    #[test]
+    fn canonical_form_of_a_subtree_carries_its_namespaces() {
+        let root =
+            parse(r#"<root xmlns="urn:d" xmlns:s="urn:s"><s:sig/><child a="1"/></root>"#).unwrap();
+        let signature = root.elements().next().unwrap();
+        let child = root.elements().nth(1).unwrap();
+        assert_eq!(
+            root.canonicalize(Some(signature), &[]),
+            r#"<root xmlns="urn:d"><child a="1"></child></root>"#
+        );
+        assert_eq!(
+            child.canonicalize(None, &[]),
+            r#"<child xmlns="urn:d" a="1"></child>"#
+        );
+    }
+
+    #[test]
+    fn malformed_documents_are_refused() {
+        for document in [
+            "<a></b>",
+            "<a>",
+            "<a/><b/>",
+            r#"<a x="1" x="2"/>"#,
+            "<a x=1/>",
+            "<p:a/>",
+            r#"<a x="<"/>"#,
+        ] {
+            assert!(parse(document).is_err(), "{}", document);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Every token starts with this, so it is recognisable in a leaked config
+pub const SCIM_TOKEN_PREFIX: &str = "ks_";
+
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+pub const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+/// Bearer token of one directory integration
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct ScimToken {
+    pub scim_token_id: Option<i64>,
+    pub org_id: i32,
+    pub name: String,
+    /// first characters of the token, enough to recognise it in a list
+    pub prefix: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub created_by: Option<i32>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateScimToken {
+    pub name: String,
+}
+
+/// Creation result, the only time the token itself is shown
+#[derive(Serialize, Deserialize)]
+pub struct CreatedScimToken {
+    #[serde(flatten)]
+    pub scim_token: ScimToken,
+    pub token: String,
+}
+
+/// Integration a SCIM request authenticated as
+#[derive(Debug, sqlx::FromRow)]
+pub struct ScimClient {
+    pub scim_token_id: i64,
+    pub org_id: i32,
+}
+
+/// A member of the organization as SCIM sees it
+#[derive(Debug, sqlx::FromRow)]
+pub struct ScimMember {
+    pub user_id: Option<i32>,
+    pub username: String,
+    pub email: Option<String>,
+    pub active: bool,
+    pub external_id: Option<String>,
+    /// also a member of another organization, the account itself is then
+    /// not the directory's to rename
+    pub shared: bool,
+    pub role_ids: Vec<i32>,
+    pub role_names: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A role visible to the organization with its members there
+#[derive(Debug, sqlx::FromRow)]
+pub struct ScimRole {
+    pub role_id: i32,
+    /// None for shared roles, which the directory can only assign
+    pub org_id: Option<i32>,
+    pub name: String,
+    pub member_ids: Vec<i32>,
+    pub member_names: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Equality filters of a list request, the only kind supported
+#[derive(Debug, Default)]
+pub struct ScimFilter {
+    pub user_name: Option<String>,
+    pub external_id: Option<String>,
+    pub email: Option<String>,
+    pub display_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScimEmail {
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+/// `{ "value": id, "display": name }` pointing at a user or group
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScimRef {
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimMeta {
+    pub resource_type: String,
+    pub created: DateTime<Utc>,
+    pub location: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUser {
+    pub schemas: Vec<String>,
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    pub user_name: String,
+    pub emails: Vec<ScimEmail>,
+    pub active: bool,
+    pub groups: Vec<ScimRef>,
+    pub meta: ScimMeta,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimGroup {
+    pub schemas: Vec<String>,
+    pub id: String,
+    pub display_name: String,
+    pub members: Vec<ScimRef>,
+    pub meta: ScimMeta,
+}
+
+/// Body of `POST` and `PUT /scim/v2/Users`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUserInput {
+    pub user_name: String,
+    #[serde(default)]
+    pub external_id: Option<String>,
+    #[serde(default)]
+    pub emails: Vec<ScimEmail>,
+    #[serde(default = "active_default")]
+    pub active: bool,
+}
+
+fn active_default() -> bool {
+    true
+}
+
+/// Body of `POST` and `PUT /scim/v2/Groups`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimGroupInput {
+    pub display_name: String,
+    #[serde(default)]
+    pub members: Vec<ScimRef>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PatchRequest {
+    #[serde(rename = "Operations")]
+    pub operations: Vec<PatchOperation>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PatchOperation {
+    /// `add`, `replace` or `remove`, directories differ in case
+    pub op: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub value: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListResponse<T> {
+    pub schemas: Vec<String>,
+    pub total_results: i64,
+    pub start_index: i64,
+    pub items_per_page: usize,
+    #[serde(rename = "Resources")]
+    pub resources: Vec<T>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimErrorBody {
+    pub schemas: Vec<String>,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scim_type: Option<String>,
+    pub detail: String,
+}
@@ -0,0 +1,189 @@
+use super::model::{ScimClient, ScimFilter, ScimMember, ScimRole, ScimToken};
+use crate::{db::DBConn, error::CustomError, role::model::Role};
+
+pub struct ScimRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> ScimRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        ScimRepository { db }
+    }
+
+    pub async fn insert_scim_token(&self, scim_token: &ScimToken) -> Result<i64, CustomError> {
+        self.db
+            .insert_scim_token(scim_token)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn fetch_scim_tokens(&self, org_id: i32) -> Result<Vec<ScimToken>, CustomError> {
+        self.db
+            .fetch_scim_tokens(org_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn revoke_scim_token(
+        &self,
+        org_id: i32,
+        scim_token_id: i64,
+    ) -> Result<(), CustomError> {
+        self.db
+            .revoke_scim_token(org_id, scim_token_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::ScimTokenNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    /// Looks up a live token and records its use
+    pub async fn use_scim_token(&self, token_hash: &str) -> Result<ScimClient, CustomError> {
+        self.db
+            .use_scim_token(token_hash)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::ScimTokenNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn fetch_members(
+        &self,
+        org_id: i32,
+        filter: &ScimFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(i64, Vec<ScimMember>), CustomError> {
+        self.db
+            .fetch_scim_members(org_id, filter, offset, limit)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn fetch_member(&self, org_id: i32, user_id: i32) -> Result<ScimMember, CustomError> {
+        self.db
+            .fetch_scim_member(org_id, user_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn insert_member(
+        &self,
+        org_id: i32,
+        member: &ScimMember,
+    ) -> Result<i32, CustomError> {
+        self.db
+            .insert_scim_member(org_id, member)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(err) if err.is_unique_violation() => {
+                    CustomError::UsernameExists
+                }
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn update_member(&self, org_id: i32, member: &ScimMember) -> Result<(), CustomError> {
+        self.db
+            .update_scim_member(org_id, member)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserNotFound,
+                sqlx::Error::Database(err) if err.is_unique_violation() => {
+                    CustomError::UsernameExists
+                }
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn delete_member(&self, org_id: i32, user_id: i32) -> Result<(), CustomError> {
+        self.db
+            .delete_scim_member(org_id, user_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn fetch_roles(
+        &self,
+        org_id: i32,
+        filter: &ScimFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(i64, Vec<ScimRole>), CustomError> {
+        self.db
+            .fetch_scim_roles(org_id, filter, offset, limit)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn fetch_role(&self, org_id: i32, role_id: i32) -> Result<ScimRole, CustomError> {
+        self.db
+            .fetch_scim_role(org_id, role_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::RoleNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn insert_role(&self, role: &Role) -> Result<i32, CustomError> {
+        self.db.insert_role(role, &[]).await.map_err(|e| match e {
+            sqlx::Error::Database(err) if err.is_unique_violation() => CustomError::RoleExists,
+            _ => CustomError::DBError(e),
+        })
+    }
+
+    pub async fn rename_role(
+        &self,
+        org_id: i32,
+        role_id: i32,
+        name: &str,
+    ) -> Result<(), CustomError> {
+        self.db
+            .rename_role(org_id, role_id, name)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::RoleNotFound,
+                sqlx::Error::Database(err) if err.is_unique_violation() => CustomError::RoleExists,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn delete_role(&self, org_id: i32, role_id: i32) -> Result<(), CustomError> {
+        self.db
+            .delete_role(org_id, role_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::RoleNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    /// UserNotFound when one of `user_ids` is not a member
+    pub async fn set_role_members(
+        &self,
+        org_id: i32,
+        role_id: i32,
+        user_ids: &[i32],
+    ) -> Result<(), CustomError> {
+        self.db
+            .set_role_members(org_id, role_id, user_ids)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+}
@@ -0,0 +1,1349 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use request_http_parser::parser::{Method, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{
+    model::{
+        CreateScimToken, CreatedScimToken, ERROR_SCHEMA, GROUP_SCHEMA, LIST_SCHEMA, ListResponse,
+        PatchOperation, PatchRequest, SCIM_TOKEN_PREFIX, ScimClient, ScimEmail, ScimErrorBody,
+        ScimFilter, ScimGroup, ScimGroupInput, ScimMember, ScimMeta, ScimRef, ScimRole, ScimToken,
+        ScimUser, ScimUserInput, USER_SCHEMA,
+    },
+    repo::ScimRepository,
+};
+use crate::{
+    audit::{
+        model::{AuditEvent, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    cfg::CONFIG,
+    constants::{
+        BAD_REQUEST, CONFLICT, CREATED, FORBIDDEN, INTERNAL_ERROR, MANAGE_USERS, NO_CONTENT,
+        NOT_FOUND, OK_RESPONSE, OK_STATUS, UNAUTHORIZED,
+    },
+    db::DBConn,
+    error::CustomError,
+    role::model::Role,
+    rolepermissions::service::RolePermissionSvc,
+    utils::{
+        ClaimType, Claims, des_from_str, extract_token, percent_decode, random_token, ser_to_str,
+        sha256_hex, with_headers,
+    },
+};
+
+pub const SCIM_PATH: &str = "/scim/v2";
+pub const SCIM_TOKENS_PATH: &str = "/protected/orgs/scim-tokens";
+
+const USERS: &str = "Users";
+const GROUPS: &str = "Groups";
+const SCIM_CONTENT_TYPE: &str = "Content-Type: application/scim+json";
+
+/// Page size when the directory asks for none, and the largest it gets
+const DEFAULT_COUNT: i64 = 100;
+const MAX_COUNT: i64 = 200;
+
+pub struct ScimSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: ScimRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
+}
+
+impl<DB> ScimSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>) -> Self {
+        ScimSvc {
+            repository: ScimRepository::new(pool),
+            audit,
+        }
+    }
+
+    pub async fn get_scim_tokens(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (_, org_id) = match Self::admin(rp_svc, claims, request).await {
+            Ok(admin) => admin,
+            Err(response) => return response,
+        };
+        let scim_tokens = match self.repository.fetch_scim_tokens(org_id).await {
+            Ok(scim_tokens) => scim_tokens,
+            Err(error) => {
+                eprintln!("Error scim token db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&scim_tokens) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// Token for one directory integration, provisioning into the caller's
+    /// organization
+    pub async fn create_scim_token(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (user_id, org_id) = match Self::admin(rp_svc, claims, request).await {
+            Ok(admin) => admin,
+            Err(response) => return response,
+        };
+        let req_token: CreateScimToken = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(req_token) => req_token,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        if req_token.name.trim().is_empty() || req_token.name.len() > 100 {
+            return (BAD_REQUEST.to_string(), "Invalid name".to_string());
+        }
+
+        let token = format!("{}{}", SCIM_TOKEN_PREFIX, random_token(32));
+        let mut scim_token = ScimToken {
+            scim_token_id: None,
+            org_id,
+            name: req_token.name,
+            prefix: token[..SCIM_TOKEN_PREFIX.len() + 8].to_string(),
+            token_hash: sha256_hex(&token),
+            created_by: Some(user_id),
+            last_used_at: None,
+            created_at: Utc::now(),
+            revoked_at: None,
+        };
+        match self.repository.insert_scim_token(&scim_token).await {
+            Ok(scim_token_id) => scim_token.scim_token_id = Some(scim_token_id),
+            Err(error) => {
+                eprintln!("Error insert scim token db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: Some(user_id),
+                target: Some(format!(
+                    "scim_token:{}",
+                    scim_token.scim_token_id.unwrap_or_default()
+                )),
+                metadata: json!({ "name": scim_token.name, "prefix": scim_token.prefix }),
+                ..AuditEvent::new("scim_token.create", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        let response_json = match ser_to_str(&CreatedScimToken { scim_token, token }) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// `DELETE /protected/orgs/scim-tokens/{id}`
+    pub async fn revoke_scim_token(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (user_id, org_id) = match Self::admin(rp_svc, claims, request).await {
+            Ok(admin) => admin,
+            Err(response) => return response,
+        };
+        let scim_token_id = match request
+            .path
+            .strip_prefix(SCIM_TOKENS_PATH)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .and_then(|id| id.parse::<i64>().ok())
+        {
+            Some(scim_token_id) => scim_token_id,
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        match self
+            .repository
+            .revoke_scim_token(org_id, scim_token_id)
+            .await
+        {
+            Ok(_) => {
+                self.audit
+                    .record(AuditEvent {
+                        org_id: Some(org_id),
+                        actor_id: Some(user_id),
+                        target: Some(format!("scim_token:{}", scim_token_id)),
+                        ..AuditEvent::new("scim_token.revoke", OUTCOME_SUCCESS, Some(request))
+                    })
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(CustomError::ScimTokenNotFound) => (NOT_FOUND.to_string(), "".to_string()),
+            Err(error) => {
+                eprintln!("Error scim token db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// Every `/scim/v2` request, authenticated with an integration's token
+    /// instead of a user's
+    pub async fn handle(&self, request: &Request) -> (String, String) {
+        let client = match extract_token(&request.headers)
+            .filter(|token| token.starts_with(SCIM_TOKEN_PREFIX))
+        {
+            Some(token) => match self.repository.use_scim_token(&sha256_hex(&token)).await {
+                Ok(client) => client,
+                Err(CustomError::ScimTokenNotFound) => {
+                    return scim_error(UNAUTHORIZED, None, "Token invalid or revoked");
+                }
+                Err(error) => {
+                    eprintln!("Error scim token db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            },
+            None => return scim_error(UNAUTHORIZED, None, "SCIM bearer token required"),
+        };
+        let rest = request.path[SCIM_PATH.len()..].trim_matches('/');
+        let (resource, id) = match rest.split_once('/') {
+            Some((resource, id)) => match id.parse::<i32>() {
+                Ok(id) => (resource, Some(id)),
+                Err(_) => return scim_error(NOT_FOUND, None, "Resource not found"),
+            },
+            None => (rest, None),
+        };
+        match (&request.method, resource, id) {
+            (Method::GET, USERS, None) => self.list_users(&client, request).await,
+            (Method::POST, USERS, None) => self.create_user(&client, request).await,
+            (Method::GET, USERS, Some(user_id)) => self.get_user(&client, user_id).await,
+            (Method::PUT, USERS, Some(user_id)) => {
+                self.replace_user(&client, user_id, request).await
+            }
+            (Method::PATCH, USERS, Some(user_id)) => {
+                self.patch_user(&client, user_id, request).await
+            }
+            (Method::DELETE, USERS, Some(user_id)) => {
+                self.delete_user(&client, user_id, request).await
+            }
+            (Method::GET, GROUPS, None) => self.list_groups(&client, request).await,
+            (Method::POST, GROUPS, None) => self.create_group(&client, request).await,
+            (Method::GET, GROUPS, Some(role_id)) => self.get_group(&client, role_id).await,
+            (Method::PUT, GROUPS, Some(role_id)) => {
+                self.replace_group(&client, role_id, request).await
+            }
+            (Method::PATCH, GROUPS, Some(role_id)) => {
+                self.patch_group(&client, role_id, request).await
+            }
+            (Method::DELETE, GROUPS, Some(role_id)) => {
+                self.delete_group(&client, role_id, request).await
+            }
+            _ => scim_error(NOT_FOUND, None, "Unknown endpoint"),
+        }
+    }
+
+    async fn list_users(&self, client: &ScimClient, request: &Request) -> (String, String) {
+        let (filter, start_index, count) = match list_query(request, USERS) {
+            Ok(query) => query,
+            Err(response) => return response,
+        };
+        let (total, members) = match self
+            .repository
+            .fetch_members(client.org_id, &filter, start_index - 1, count)
+            .await
+        {
+            Ok(page) => page,
+            Err(error) => {
+                eprintln!("Error scim user db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let resources: Vec<ScimUser> = members.into_iter().map(user_resource).collect();
+        scim_response(
+            OK_STATUS,
+            &ListResponse {
+                schemas: vec![LIST_SCHEMA.to_string()],
+                total_results: total,
+                start_index,
+                items_per_page: resources.len(),
+                resources,
+            },
+        )
+    }
+
+    async fn get_user(&self, client: &ScimClient, user_id: i32) -> (String, String) {
+        match self.repository.fetch_member(client.org_id, user_id).await {
+            Ok(member) => scim_response(OK_STATUS, &user_resource(member)),
+            Err(CustomError::UserNotFound) => scim_error(NOT_FOUND, None, "User not found"),
+            Err(error) => {
+                eprintln!("Error scim user db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// New accounts have no password, they sign in through the directory's
+    /// identity provider
+    async fn create_user(&self, client: &ScimClient, request: &Request) -> (String, String) {
+        let input: ScimUserInput = match scim_body(request) {
+            Ok(input) => input,
+            Err(response) => return response,
+        };
+        let mut member = ScimMember {
+            user_id: None,
+            username: input.user_name.trim().to_string(),
+            email: primary_email(&input.emails),
+            active: input.active,
+            external_id: input.external_id,
+            shared: false,
+            role_ids: vec![],
+            role_names: vec![],
+            created_at: Utc::now(),
+        };
+        if let Err(detail) = check_member(&member) {
+            return scim_error(BAD_REQUEST, Some("invalidValue"), detail);
+        }
+        match self.repository.insert_member(client.org_id, &member).await {
+            Ok(user_id) => member.user_id = Some(user_id),
+            Err(CustomError::UsernameExists) => {
+                return scim_error(
+                    CONFLICT,
+                    Some("uniqueness"),
+                    "userName, email or externalId already in use",
+                );
+            }
+            Err(error) => {
+                eprintln!("Error insert scim user db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        self.record(
+            request,
+            client,
+            "scim.user.create",
+            format!("user:{}", member.user_id.unwrap_or_default()),
+            json!({ "username": member.username, "active": member.active }),
+        )
+        .await;
+        scim_response(CREATED, &user_resource(member))
+    }
+
+    async fn replace_user(
+        &self,
+        client: &ScimClient,
+        user_id: i32,
+        request: &Request,
+    ) -> (String, String) {
+        let input: ScimUserInput = match scim_body(request) {
+            Ok(input) => input,
+            Err(response) => return response,
+        };
+        let mut member = match self.repository.fetch_member(client.org_id, user_id).await {
+            Ok(member) => member,
+            Err(CustomError::UserNotFound) => {
+                return scim_error(NOT_FOUND, None, "User not found");
+            }
+            Err(error) => {
+                eprintln!("Error scim user db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let account = (member.username.clone(), member.email.clone());
+        member.username = input.user_name.trim().to_string();
+        member.email = primary_email(&input.emails);
+        member.external_id = input.external_id;
+        member.active = input.active;
+        self.save_member(client, member, account, request).await
+    }
+
+    async fn patch_user(
+        &self,
+        client: &ScimClient,
+        user_id: i32,
+        request: &Request,
+    ) -> (String, String) {
+        let patch: PatchRequest = match scim_body(request) {
+            Ok(patch) => patch,
+            Err(response) => return response,
+        };
+        let mut member = match self.repository.fetch_member(client.org_id, user_id).await {
+            Ok(member) => member,
+            Err(CustomError::UserNotFound) => {
+                return scim_error(NOT_FOUND, None, "User not found");
+            }
+            Err(error) => {
+                eprintln!("Error scim user db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let account = (member.username.clone(), member.email.clone());
+        for operation in &patch.operations {
+            if let Err(detail) = patch_member(&mut member, operation) {
+                return scim_error(BAD_REQUEST, Some("invalidValue"), detail);
+            }
+        }
+        self.save_member(client, member, account, request).await
+    }
+
+    /// `account` is the username and email before the change. They belong
+    /// to every organization of the user, so only a directory that owns
+    /// the account alone may change them.
+    async fn save_member(
+        &self,
+        client: &ScimClient,
+        member: ScimMember,
+        account: (String, Option<String>),
+        request: &Request,
+    ) -> (String, String) {
+        if let Err(detail) = check_member(&member) {
+            return scim_error(BAD_REQUEST, Some("invalidValue"), detail);
+        }
+        if member.shared && (&member.username, &member.email) != (&account.0, &account.1) {
+            return scim_error(
+                BAD_REQUEST,
+                Some("mutability"),
+                "The account is shared with other organizations, userName and email cannot change",
+            );
+        }
+        match self.repository.update_member(client.org_id, &member).await {
+            Ok(_) => {}
+            Err(CustomError::UserNotFound) => {
+                return scim_error(NOT_FOUND, None, "User not found");
+            }
+            Err(CustomError::UsernameExists) => {
+                return scim_error(
+                    CONFLICT,
+                    Some("uniqueness"),
+                    "userName, email or externalId already in use",
+                );
+            }
+            Err(error) => {
+                eprintln!("Error update scim user db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        self.record(
+            request,
+            client,
+            "scim.user.update",
+            format!("user:{}", member.user_id.unwrap_or_default()),
+            json!({ "username": member.username, "active": member.active }),
+        )
+        .await;
+        scim_response(OK_STATUS, &user_resource(member))
+    }
+
+    /// Ends the membership, the account stays for its other organizations
+    async fn delete_user(
+        &self,
+        client: &ScimClient,
+        user_id: i32,
+        request: &Request,
+    ) -> (String, String) {
+        match self.repository.delete_member(client.org_id, user_id).await {
+            Ok(_) => {
+                self.record(
+                    request,
+                    client,
+                    "scim.user.delete",
+                    format!("user:{}", user_id),
+                    json!({}),
+                )
+                .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(CustomError::UserNotFound) => scim_error(NOT_FOUND, None, "User not found"),
+            Err(error) => {
+                eprintln!("Error delete scim user db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    async fn list_groups(&self, client: &ScimClient, request: &Request) -> (String, String) {
+        let (filter, start_index, count) = match list_query(request, GROUPS) {
+            Ok(query) => query,
+            Err(response) => return response,
+        };
+        let (total, roles) = match self
+            .repository
+            .fetch_roles(client.org_id, &filter, start_index - 1, count)
+            .await
+        {
+            Ok(page) => page,
+            Err(error) => {
+                eprintln!("Error scim group db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let resources: Vec<ScimGroup> = roles.into_iter().map(group_resource).collect();
+        scim_response(
+            OK_STATUS,
+            &ListResponse {
+                schemas: vec![LIST_SCHEMA.to_string()],
+                total_results: total,
+                start_index,
+                items_per_page: resources.len(),
+                resources,
+            },
+        )
+    }
+
+    async fn get_group(&self, client: &ScimClient, role_id: i32) -> (String, String) {
+        self.group_response(client, role_id, OK_STATUS).await
+    }
+
+    /// Creates a role of the organization
+    async fn create_group(&self, client: &ScimClient, request: &Request) -> (String, String) {
+        let input: ScimGroupInput = match scim_body(request) {
+            Ok(input) => input,
+            Err(response) => return response,
+        };
+        let name = input.display_name.trim().to_string();
+        if let Err(detail) = check_role_name(&name) {
+            return scim_error(BAD_REQUEST, Some("invalidValue"), detail);
+        }
+        let user_ids = match member_ids(&input.members) {
+            Some(user_ids) => user_ids,
+            None => return scim_error(BAD_REQUEST, Some("invalidValue"), "Unknown member"),
+        };
+        let role = Role {
+            role_id: None,
+            org_id: Some(client.org_id),
+            name,
+            description: "Provisioned by SCIM".to_string(),
+            created_at: Utc::now(),
+            parent_ids: vec![],
+        };
+        let role_id = match self.repository.insert_role(&role).await {
+            Ok(role_id) => role_id,
+            Err(CustomError::RoleExists) => {
+                return scim_error(CONFLICT, Some("uniqueness"), "displayName already in use");
+            }
+            Err(error) => {
+                eprintln!("Error insert scim group db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        if let Err(response) = self.set_members(client, role_id, &user_ids, &[]).await {
+            // leave nothing behind that would make the directory's retry
+            // conflict
+            if let Err(error) = self.repository.delete_role(client.org_id, role_id).await {
+                eprintln!("Error delete scim group db: {:#?}", error);
+            }
+            return response;
+        }
+        self.record(
+            request,
+            client,
+            "scim.group.create",
+            format!("role:{}", role_id),
+            json!({ "name": role.name, "members": user_ids }),
+        )
+        .await;
+        self.group_response(client, role_id, CREATED).await
+    }
+
+    async fn replace_group(
+        &self,
+        client: &ScimClient,
+        role_id: i32,
+        request: &Request,
+    ) -> (String, String) {
+        let input: ScimGroupInput = match scim_body(request) {
+            Ok(input) => input,
+            Err(response) => return response,
+        };
+        let role = match self.repository.fetch_role(client.org_id, role_id).await {
+            Ok(role) => role,
+            Err(CustomError::RoleNotFound) => {
+                return scim_error(NOT_FOUND, None, "Group not found");
+            }
+            Err(error) => {
+                eprintln!("Error scim group db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let user_ids = match member_ids(&input.members) {
+            Some(user_ids) => user_ids,
+            None => return scim_error(BAD_REQUEST, Some("invalidValue"), "Unknown member"),
+        };
+        let name = input.display_name.trim().to_string();
+        self.save_group(client, role, name, user_ids, request).await
+    }
+
+    async fn patch_group(
+        &self,
+        client: &ScimClient,
+        role_id: i32,
+        request: &Request,
+    ) -> (String, String) {
+        let patch: PatchRequest = match scim_body(request) {
+            Ok(patch) => patch,
+            Err(response) => return response,
+        };
+        let role = match self.repository.fetch_role(client.org_id, role_id).await {
+            Ok(role) => role,
+            Err(CustomError::RoleNotFound) => {
+                return scim_error(NOT_FOUND, None, "Group not found");
+            }
+            Err(error) => {
+                eprintln!("Error scim group db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let mut name = role.name.clone();
+        let mut user_ids = role.member_ids.clone();
+        for operation in &patch.operations {
+            if let Err(detail) = patch_role(&mut name, &mut user_ids, operation) {
+                return scim_error(BAD_REQUEST, Some("invalidValue"), detail);
+            }
+        }
+        user_ids.sort_unstable();
+        user_ids.dedup();
+        self.save_group(client, role, name, user_ids, request).await
+    }
+
+    /// Shared roles can be assigned but not renamed, they are not the
+    /// organization's own
+    async fn save_group(
+        &self,
+        client: &ScimClient,
+        role: ScimRole,
+        name: String,
+        user_ids: Vec<i32>,
+        request: &Request,
+    ) -> (String, String) {
+        if name != role.name {
+            if role.org_id.is_none() {
+                return scim_error(
+                    BAD_REQUEST,
+                    Some("mutability"),
+                    "Shared roles cannot be renamed",
+                );
+            }
+            if let Err(detail) = check_role_name(&name) {
+                return scim_error(BAD_REQUEST, Some("invalidValue"), detail);
+            }
+            match self
+                .repository
+                .rename_role(client.org_id, role.role_id, &name)
+                .await
+            {
+                Ok(_) => {}
+                Err(CustomError::RoleExists) => {
+                    return scim_error(CONFLICT, Some("uniqueness"), "displayName already in use");
+                }
+                Err(CustomError::RoleNotFound) => {
+                    return scim_error(NOT_FOUND, None, "Group not found");
+                }
+                Err(error) => {
+                    eprintln!("Error update scim group db: {:#?}", error);
+                    return (INTERNAL_ERROR.to_string(), "".to_string());
+                }
+            }
+        }
+        if let Err(response) = self
+            .set_members(client, role.role_id, &user_ids, &role.member_ids)
+            .await
+        {
+            return response;
+        }
+        self.record(
+            request,
+            client,
+            "scim.group.update",
+            format!("role:{}", role.role_id),
+            json!({ "name": name, "members": user_ids }),
+        )
+        .await;
+        self.group_response(client, role.role_id, OK_STATUS).await
+    }
+
+    /// Only the organization's own roles can be deleted
+    async fn delete_group(
+        &self,
+        client: &ScimClient,
+        role_id: i32,
+        request: &Request,
+    ) -> (String, String) {
+        match self.repository.fetch_role(client.org_id, role_id).await {
+            Ok(role) if role.org_id.is_none() => {
+                return scim_error(
+                    BAD_REQUEST,
+                    Some("mutability"),
+                    "Shared roles cannot be deleted",
+                );
+            }
+            Ok(_) => {}
+            Err(CustomError::RoleNotFound) => {
+                return scim_error(NOT_FOUND, None, "Group not found");
+            }
+            Err(error) => {
+                eprintln!("Error scim group db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        match self.repository.delete_role(client.org_id, role_id).await {
+            Ok(_) => {
+                self.record(
+                    request,
+                    client,
+                    "scim.group.delete",
+                    format!("role:{}", role_id),
+                    json!({}),
+                )
+                .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(CustomError::RoleNotFound) => scim_error(NOT_FOUND, None, "Group not found"),
+            Err(error) => {
+                eprintln!("Error delete scim group db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    /// Grants the role to exactly `user_ids`, skipped when they are the
+    /// `current` members already
+    async fn set_members(
+        &self,
+        client: &ScimClient,
+        role_id: i32,
+        user_ids: &[i32],
+        current: &[i32],
+    ) -> Result<(), (String, String)> {
+        if user_ids == current {
+            return Ok(());
+        }
+        match self
+            .repository
+            .set_role_members(client.org_id, role_id, user_ids)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(CustomError::UserNotFound) => Err(scim_error(
+                BAD_REQUEST,
+                Some("invalidValue"),
+                "Members must be users of the organization",
+            )),
+            Err(error) => {
+                eprintln!("Error scim group members db: {:#?}", error);
+                Err((INTERNAL_ERROR.to_string(), "".to_string()))
+            }
+        }
+    }
+
+    async fn group_response(
+        &self,
+        client: &ScimClient,
+        role_id: i32,
+        status_line: &str,
+    ) -> (String, String) {
+        match self.repository.fetch_role(client.org_id, role_id).await {
+            Ok(role) => scim_response(status_line, &group_resource(role)),
+            Err(CustomError::RoleNotFound) => scim_error(NOT_FOUND, None, "Group not found"),
+            Err(error) => {
+                eprintln!("Error scim group db: {:#?}", error);
+                (INTERNAL_ERROR.to_string(), "".to_string())
+            }
+        }
+    }
+
+    async fn record(
+        &self,
+        request: &Request,
+        client: &ScimClient,
+        action: &str,
+        target: String,
+        mut metadata: Value,
+    ) {
+        metadata["scim_token_id"] = json!(client.scim_token_id);
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(client.org_id),
+                target: Some(target),
+                metadata,
+                ..AuditEvent::new(action, OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+    }
+
+    /// Tokens provision accounts, so they are managed with a login token by
+    /// someone allowed to manage users
+    async fn admin(
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> Result<(i32, i32), (String, String)> {
+        let (claims, org_id) = rp_svc.authorize(claims, request, MANAGE_USERS).await?;
+        match claims.user_id() {
+            Some(user_id) if claims.claim_type == ClaimType::Login => Ok((user_id, org_id)),
+            _ => Err((FORBIDDEN.to_string(), "".to_string())),
+        }
+    }
+}
+
+fn scim_response<T: for<'a> Deserialize<'a> + Serialize>(
+    status_line: &str,
+    body: &T,
+) -> (String, String) {
+    match ser_to_str(body) {
+        Ok(json) => (
+            with_headers(status_line, &[SCIM_CONTENT_TYPE.to_string()]),
+            json,
+        ),
+        Err(_) => {
+            println!("serde error");
+            (INTERNAL_ERROR.to_string(), "".to_string())
+        }
+    }
+}
+
+fn scim_error(status_line: &str, scim_type: Option<&str>, detail: &str) -> (String, String) {
+    scim_response(
+        status_line,
+        &ScimErrorBody {
+            schemas: vec![ERROR_SCHEMA.to_string()],
+            status: status_line
+                .split_whitespace()
+                .nth(1)
+                .unwrap_or_default()
+                .to_string(),
+            scim_type: scim_type.map(String::from),
+            detail: detail.to_string(),
+        },
+    )
+}
+
+fn scim_body<T: for<'a> Deserialize<'a> + Serialize>(
+    request: &Request,
+) -> Result<T, (String, String)> {
+    match &request.body {
+        Some(body) => des_from_str(body)
+            .map_err(|_| scim_error(BAD_REQUEST, Some("invalidSyntax"), "Body does not parse")),
+        None => Err(scim_error(
+            BAD_REQUEST,
+            Some("invalidSyntax"),
+            "Body missing",
+        )),
+    }
+}
+
+/// Filter, 1-based `startIndex` and `count` of a list request
+fn list_query(
+    request: &Request,
+    resource: &str,
+) -> Result<(ScimFilter, i64, i64), (String, String)> {
+    let param = |key: &str| {
+        request
+            .params
+            .as_ref()
+            .and_then(|params| params.get(key))
+            .map(|value| percent_decode(value))
+    };
+    let filter = match param("filter") {
+        Some(filter) => parse_filter(&filter, resource).ok_or_else(|| {
+            scim_error(
+                BAD_REQUEST,
+                Some("invalidFilter"),
+                "Only `attribute eq \"value\"` filters are supported",
+            )
+        })?,
+        None => ScimFilter::default(),
+    };
+    let start_index = param("startIndex")
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(1)
+        .max(1);
+    let count = param("count")
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_COUNT)
+        .clamp(0, MAX_COUNT);
+    Ok((filter, start_index, count))
+}
+
+/// `attribute eq "value"` as attribute and value
+fn equality(filter: &str) -> Option<(String, String)> {
+    let (attribute, rest) = filter.trim().split_once(char::is_whitespace)?;
+    let (operator, value) = rest.trim_start().split_once(char::is_whitespace)?;
+    if !operator.eq_ignore_ascii_case("eq") {
+        return None;
+    }
+    let value: String = serde_json::from_str(value.trim()).ok()?;
+    Some((attribute.to_ascii_lowercase(), value))
+}
+
+/// The lookup directories make before creating an account or group
+fn parse_filter(filter: &str, resource: &str) -> Option<ScimFilter> {
+    let (attribute, value) = equality(filter)?;
+    let mut scim_filter = ScimFilter::default();
+    match (resource, attribute.as_str()) {
+        (USERS, "username") => scim_filter.user_name = Some(value),
+        (USERS, "externalid") => scim_filter.external_id = Some(value),
+        (USERS, "emails" | "emails.value") => scim_filter.email = Some(value),
+        (GROUPS, "displayname") => scim_filter.display_name = Some(value),
+        _ => return None,
+    }
+    Some(scim_filter)
+}
+
+fn location(resource: &str, id: i32) -> String {
+    format!("{}{}/{}/{}", CONFIG.oidc_issuer, SCIM_PATH, resource, id)
+}
+
+fn user_resource(member: ScimMember) -> ScimUser {
+    let user_id = member.user_id.unwrap_or_default();
+    ScimUser {
+        schemas: vec![USER_SCHEMA.to_string()],
+        id: user_id.to_string(),
+        external_id: member.external_id,
+        user_name: member.username,
+        emails: member
+            .email
+            .into_iter()
+            .map(|value| ScimEmail {
+                value,
+                r#type: Some("work".to_string()),
+                primary: true,
+            })
+            .collect(),
+        active: member.active,
+        groups: member
+            .role_ids
+            .into_iter()
+            .zip(member.role_names)
+            .map(|(role_id, name)| ScimRef {
+                value: role_id.to_string(),
+                display: Some(name),
+            })
+            .collect(),
+        meta: ScimMeta {
+            resource_type: "User".to_string(),
+            created: member.created_at,
+            location: location(USERS, user_id),
+        },
+    }
+}
+
+fn group_resource(role: ScimRole) -> ScimGroup {
+    ScimGroup {
+        schemas: vec![GROUP_SCHEMA.to_string()],
+        id: role.role_id.to_string(),
+        display_name: role.name,
+        members: role
+            .member_ids
+            .into_iter()
+            .zip(role.member_names)
+            .map(|(user_id, username)| ScimRef {
+                value: user_id.to_string(),
+                display: Some(username),
+            })
+            .collect(),
+        meta: ScimMeta {
+            resource_type: "Group".to_string(),
+            created: role.created_at,
+            location: location(GROUPS, role.role_id),
+        },
+    }
+}
+
+/// Limits of the `users` columns
+fn check_member(member: &ScimMember) -> Result<(), &'static str> {
+    if member.username.is_empty() || member.username.chars().count() > 50 {
+        return Err("userName must have 1 to 50 characters");
+    }
+    if member.email.as_ref().is_some_and(|email| email.len() > 255) {
+        return Err("email is too long");
+    }
+    if member.external_id.as_ref().is_some_and(String::is_empty) {
+        return Err("externalId is empty");
+    }
+    Ok(())
+}
+
+fn check_role_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.chars().count() > 50 {
+        return Err("displayName must have 1 to 50 characters");
+    }
+    Ok(())
+}
+
+/// The primary address, or the first one when none is marked
+fn primary_email(emails: &[ScimEmail]) -> Option<String> {
+    emails
+        .iter()
+        .find(|email| email.primary)
+        .or_else(|| emails.first())
+        .map(|email| email.value.trim().to_string())
+        .filter(|email| !email.is_empty())
+}
+
+/// User ids of member references, None when one is not an id
+fn member_ids(members: &[ScimRef]) -> Option<Vec<i32>> {
+    let mut user_ids = members
+        .iter()
+        .map(|member| member.value.parse::<i32>().ok())
+        .collect::<Option<Vec<i32>>>()?;
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    Some(user_ids)
+}
+
+/// Some directories send booleans as `"True"` and `"False"`
+fn bool_value(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(value) => Some(*value),
+        Value::String(value) if value.eq_ignore_ascii_case("true") => Some(true),
+        Value::String(value) if value.eq_ignore_ascii_case("false") => Some(false),
+        _ => None,
+    }
+}
+
+fn patch_member(member: &mut ScimMember, operation: &PatchOperation) -> Result<(), &'static str> {
+    match (
+        operation.op.to_ascii_lowercase().as_str(),
+        operation.path.as_deref(),
+        &operation.value,
+    ) {
+        ("add" | "replace", None, Some(Value::Object(values))) => {
+            for (attribute, value) in values {
+                set_user_attribute(member, attribute, Some(value))?;
+            }
+            Ok(())
+        }
+        ("add" | "replace", Some(path), Some(value)) => {
+            set_user_attribute(member, path, Some(value))
+        }
+        ("remove", Some(path), _) => set_user_attribute(member, path, None),
+        _ => Err("unsupported patch operation"),
+    }
+}
+
+/// Sets or, without a value, removes an attribute. Attributes this service
+/// does not keep, such as `name`, are ignored.
+fn set_user_attribute(
+    member: &mut ScimMember,
+    path: &str,
+    value: Option<&Value>,
+) -> Result<(), &'static str> {
+    let path = path.trim().to_ascii_lowercase();
+    match (path.as_str(), value) {
+        ("active", Some(value)) => {
+            member.active = bool_value(value).ok_or("active must be a boolean")?;
+        }
+        ("username", Some(value)) => {
+            member.username = value
+                .as_str()
+                .ok_or("userName must be a string")?
+                .trim()
+                .to_string();
+        }
+        ("active" | "username", None) => return Err("attribute is required"),
+        ("externalid", value) => {
+            member.external_id = match value {
+                Some(value) => Some(
+                    value
+                        .as_str()
+                        .ok_or("externalId must be a string")?
+                        .to_string(),
+                ),
+                None => None,
+            };
+        }
+        ("emails", Some(value)) => {
+            let emails: Vec<ScimEmail> =
+                serde_json::from_value(value.clone()).map_err(|_| "emails must be a list")?;
+            member.email = primary_email(&emails);
+        }
+        // `emails[type eq "work"].value`, the one address we keep
+        (path, value) if path == "emails" || path.starts_with("emails[") => {
+            member.email = match value {
+                Some(value) => Some(
+                    value
+                        .as_str()
+                        .ok_or("email must be a string")?
+                        .trim()
+                        .to_string(),
+                ),
+                None => None,
+            };
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn patch_role(
+    name: &mut String,
+    user_ids: &mut Vec<i32>,
+    operation: &PatchOperation,
+) -> Result<(), &'static str> {
+    let op = operation.op.to_ascii_lowercase();
+    match (op.as_str(), operation.path.as_deref(), &operation.value) {
+        ("add" | "replace", None, Some(Value::Object(values))) => {
+            for (attribute, value) in values {
+                set_role_attribute(name, user_ids, &op, attribute, value)?;
+            }
+            Ok(())
+        }
+        ("add" | "replace", Some(path), Some(value)) => {
+            set_role_attribute(name, user_ids, &op, path, value)
+        }
+        ("remove", Some(path), value) => remove_members(user_ids, path, value.as_ref()),
+        _ => Err("unsupported patch operation"),
+    }
+}
+
+/// `add` on members adds to them, `replace` replaces them
+fn set_role_attribute(
+    name: &mut String,
+    user_ids: &mut Vec<i32>,
+    op: &str,
+    path: &str,
+    value: &Value,
+) -> Result<(), &'static str> {
+    match path.trim().to_ascii_lowercase().as_str() {
+        "displayname" => {
+            *name = value
+                .as_str()
+                .ok_or("displayName must be a string")?
+                .trim()
+                .to_string();
+        }
+        "members" => {
+            let members: Vec<ScimRef> =
+                serde_json::from_value(value.clone()).map_err(|_| "members must be a list")?;
+            let added = member_ids(&members).ok_or("Unknown member")?;
+            if op == "replace" {
+                user_ids.clear();
+            }
+            user_ids.extend(added);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// `members` with a list removes those, without one every member;
+/// `members[value eq "id"]` removes one
+fn remove_members(
+    user_ids: &mut Vec<i32>,
+    path: &str,
+    value: Option<&Value>,
+) -> Result<(), &'static str> {
+    let path = path.trim();
+    let removed: Vec<i32> = if path.eq_ignore_ascii_case("members") {
+        match value {
+            Some(value) => {
+                let members: Vec<ScimRef> =
+                    serde_json::from_value(value.clone()).map_err(|_| "members must be a list")?;
+                member_ids(&members).ok_or("Unknown member")?
+            }
+            None => {
+                user_ids.clear();
+                return Ok(());
+            }
+        }
+    } else {
+        let filter = path
+            .get(..8)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("members["))
+            .and_then(|_| path[8..].strip_suffix(']'))
+            .ok_or("unsupported path")?;
+        match equality(filter) {
+            Some((attribute, value)) if attribute == "value" => {
+                vec![value.parse().map_err(|_| "Unknown member")?]
+            }
+            _ => return Err("unsupported path"),
+        }
+    };
+    user_ids.retain(|user_id| !removed.contains(user_id));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member() -> ScimMember {
+        ScimMember {
+            user_id: Some(7),
+            username: "jo".to_string(),
+            email: Some("jo@example.com".to_string()),
+            active: true,
+            external_id: None,
+            shared: false,
+            role_ids: vec![],
+            role_names: vec![],
+            created_at: Utc::now(),
+        }
+    }
+
+    fn operation(op: &str, path: Option<&str>, value: Option<Value>) -> PatchOperation {
+        PatchOperation {
+            op: op.to_string(),
+            path: path.map(String::from),
+            value,
+        }
+    }
+
+    fn list_request(query: &str) -> Request {
+        Request::new(&format!(
+            "GET {}/{}?{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            SCIM_PATH, USERS, query
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn filter_attributes_are_case_insensitive() {
+        let filter = parse_filter(r#"UserName eq "jo@example.com""#, USERS).unwrap();
+        assert_eq!(filter.user_name.as_deref(), Some("jo@example.com"));
+        let filter = parse_filter(r#"externalId EQ "00u1""#, USERS).unwrap();
+        assert_eq!(filter.external_id.as_deref(), Some("00u1"));
+        let filter = parse_filter(r#"emails.Value eq "jo@example.com""#, USERS).unwrap();
+        assert_eq!(filter.email.as_deref(), Some("jo@example.com"));
+        let filter = parse_filter(r#"DISPLAYNAME eq "staff""#, GROUPS).unwrap();
+        assert_eq!(filter.display_name.as_deref(), Some("staff"));
+    }
+
+    #[test]
+    fn filter_values_are_json_strings() {
+        assert_eq!(
+            equality(r#"userName eq "jo \"the\" one""#),
+            Some(("username".to_string(), r#"jo "the" one"#.to_string()))
+        );
+        assert_eq!(
+            equality(r#"  displayName   eq   "two words"  "#),
+            Some(("displayname".to_string(), "two words".to_string()))
+        );
+        // unquoted or unterminated values are refused
+        assert_eq!(equality("userName eq jo"), None);
+        assert_eq!(equality(r#"userName eq "jo"#), None);
+    }
+
+    #[test]
+    fn unsupported_filters_are_refused() {
+        for filter in [
+            r#"userName co "jo""#,
+            r#"userName sw "jo""#,
+            r#"userName eq "jo" and active eq "true""#,
+            "userName pr",
+            r#"title eq "boss""#,
+        ] {
+            assert!(parse_filter(filter, USERS).is_none(), "{}", filter);
+        }
+        // each resource has its own attributes
+        assert!(parse_filter(r#"displayName eq "staff""#, USERS).is_none());
+        assert!(parse_filter(r#"userName eq "jo""#, GROUPS).is_none());
+    }
+
+    #[test]
+    fn patch_sets_active_from_booleans_and_strings() {
+        let mut jo = member();
+        patch_member(
+            &mut jo,
+            &operation("replace", Some("active"), Some(json!(false))),
+        )
+        .unwrap();
+        assert!(!jo.active);
+        // Entra ID sends `"True"` and no path
+        patch_member(
+            &mut jo,
+            &operation("Replace", None, Some(json!({ "active": "True" }))),
+        )
+        .unwrap();
+        assert!(jo.active);
+        assert_eq!(
+            patch_member(
+                &mut jo,
+                &operation("replace", Some("active"), Some(json!("yes")))
+            ),
+            Err("active must be a boolean")
+        );
+    }
+
+    #[test]
+    fn patch_renames_the_user() {
+        let mut jo = member();
+        patch_member(
+            &mut jo,
+            &operation("replace", Some("userName"), Some(json!(" joanna "))),
+        )
+        .unwrap();
+        assert_eq!(jo.username, "joanna");
+        assert_eq!(
+            patch_member(
+                &mut jo,
+                &operation("replace", Some("userName"), Some(json!(42)))
+            ),
+            Err("userName must be a string")
+        );
+    }
+
+    #[test]
+    fn patch_without_a_value_is_refused() {
+        let mut jo = member();
+        for path in ["active", "userName"] {
+            assert_eq!(
+                patch_member(&mut jo, &operation("remove", Some(path), None)),
+                Err("attribute is required")
+            );
+        }
+        assert_eq!(
+            patch_member(&mut jo, &operation("replace", Some("active"), None)),
+            Err("unsupported patch operation")
+        );
+        assert_eq!(
+            patch_member(
+                &mut jo,
+                &operation("move", Some("active"), Some(json!(true)))
+            ),
+            Err("unsupported patch operation")
+        );
+        assert!(jo.active);
+        assert_eq!(jo.username, "jo");
+    }
+
+    #[test]
+    fn list_paging_is_bounded() {
+        let (_, start_index, count) = list_query(&list_request("x=1"), USERS).unwrap();
+        assert_eq!((start_index, count), (1, DEFAULT_COUNT));
+        let (_, start_index, count) =
+            list_query(&list_request("startIndex=0&count=-5"), USERS).unwrap();
+        assert_eq!((start_index, count), (1, 0));
+        let (_, start_index, count) =
+            list_query(&list_request("startIndex=11&count=100000"), USERS).unwrap();
+        assert_eq!((start_index, count), (11, MAX_COUNT));
+        let (_, start_index, count) =
+            list_query(&list_request("startIndex=abc&count=ten"), USERS).unwrap();
+        assert_eq!((start_index, count), (1, DEFAULT_COUNT));
+    }
+
+    #[test]
+    fn list_filter_is_decoded() {
+        let (filter, _, _) = list_query(
+            &list_request("filter=userName%20eq%20%22jo%40example.com%22"),
+            USERS,
+        )
+        .unwrap();
+        assert_eq!(filter.user_name.as_deref(), Some("jo@example.com"));
+        let (status_line, body) =
+            list_query(&list_request("filter=userName%20co%20%22jo%22"), USERS).unwrap_err();
+        assert!(status_line.starts_with(BAD_REQUEST.trim_end()));
+        assert!(body.contains("invalidFilter"));
+    }
+}
@@ -1,31 +1,71 @@
+use crate::apikey::service::{API_KEYS_PATH, ApiKeySvc};
+use crate::audit::service::AuditSvc;
 use crate::auth::service::AuthService;
+use crate::auth::verifier::{CredentialVerifier, PasswordVerifier};
 use crate::cfg::CONFIG;
-use crate::constants::{NOT_FOUND, OPTIONS_CORS};
+use crate::constants::{GOOGLE, NOT_FOUND, OPTIONS_CORS};
 use crate::db::DBConn;
-use crate::google::GoogleTokenVerifier;
+use crate::grant::service::GrantSvc;
+use crate::guest::service::{GUEST_TOKEN_PATH, GUESTS_PATH, GuestSvc, ROOM_CODES_PATH};
+use crate::identity::service::{IDENTITIES_PATH, IdentitySvc};
+use crate::idp::github::{GITHUB_AUTHORIZE_PATH, GITHUB_CALLBACK_PATH};
+use crate::idp::{IdentityProviders, REGISTER_PATH, SIGNIN_PATH};
+use crate::invitation::service::{INVITATIONS_PATH, InvitationSvc};
+use crate::ldap::LdapVerifier;
 use crate::mdw::Middleware;
+use crate::oidc::service::{
+    AUTHORIZE_PATH, DEVICE_CODE_PATH, DEVICE_PATH, DISCOVERY_PATH, INTROSPECT_PATH, JWKS_PATH,
+    OidcSvc, REVOKE_PATH, TOKEN_PATH, USERINFO_PATH,
+};
+use crate::org::service::OrgSvc;
 use crate::permission::service::PermissionSvc;
+use crate::policy::service::PolicySvc;
 use crate::role::service::RoleSvc;
 use crate::rolepermissions::service::RolePermissionSvc;
+use crate::saml::{SAML_ACS_PATH, SAML_LOGIN_PATH, SAML_METADATA_PATH};
+use crate::scim::service::{SCIM_PATH, SCIM_TOKENS_PATH, ScimSvc};
+use crate::session::service::{SESSIONS_PATH, SessionSvc};
+use crate::sweeper::GrantSweeper;
 use crate::user::service::UserSvc;
 use anyhow::{Context, Result};
 use request_http_parser::parser::Method;
 
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::oneshot::Receiver;
 
+/// Services shared by every connection
+pub struct Services<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub auth_svc: Arc<AuthService<DB>>,
+    pub rp_svc: Arc<RolePermissionSvc<DB>>,
+    pub permission_svc: Arc<PermissionSvc<DB>>,
+    pub role_svc: Arc<RoleSvc<DB>>,
+    pub user_svc: Arc<UserSvc<DB>>,
+    pub org_svc: Arc<OrgSvc<DB>>,
+    pub grant_svc: Arc<GrantSvc<DB>>,
+    pub policy_svc: Arc<PolicySvc<DB>>,
+    pub audit_svc: Arc<AuditSvc<DB>>,
+    pub session_svc: Arc<SessionSvc<DB>>,
+    pub oidc_svc: Arc<OidcSvc<DB>>,
+    pub api_key_svc: Arc<ApiKeySvc<DB>>,
+    pub identity_svc: Arc<IdentitySvc<DB>>,
+    pub scim_svc: Arc<ScimSvc<DB>>,
+    pub invitation_svc: Arc<InvitationSvc<DB>>,
+    pub guest_svc: Arc<GuestSvc<DB>>,
+    pub idps: Arc<IdentityProviders>,
+}
+
 pub struct Server<DB>
 where
     DB: DBConn + Send + Sync + 'static,
 {
-    auth_svc: Arc<AuthService<DB>>,
-    rp_svc: Arc<RolePermissionSvc<DB>>,
-    permission_svc: Arc<PermissionSvc<DB>>,
-    role_svc: Arc<RoleSvc<DB>>,
-    user_svc: Arc<UserSvc<DB>>,
-    go_ver: Arc<GoogleTokenVerifier>,
+    services: Arc<Services<DB>>,
+    sweeper: Arc<GrantSweeper<DB>>,
 }
 
 impl<DB> Server<DB>
@@ -33,21 +73,64 @@ where
     DB: DBConn + Send + Sync + 'static,
 {
     pub fn new(pool: DB) -> Self {
-        let auth_svc = Arc::new(AuthService::new(pool.clone()));
-        let permission_svc = Arc::new(PermissionSvc::new(pool.clone()));
-        let role_svc = Arc::new(RoleSvc::new(pool.clone()));
-        let rp_svc = Arc::new(RolePermissionSvc::new(pool.clone()));
-        let user_svc = Arc::new(UserSvc::new(pool));
+        let audit_svc = Arc::new(AuditSvc::new(pool.clone()));
+        let session_svc = Arc::new(SessionSvc::new(pool.clone(), Arc::clone(&audit_svc)));
+        let mut verifiers: Vec<Arc<dyn CredentialVerifier>> = vec![Arc::new(PasswordVerifier)];
+        if let Some(ldap) = LdapVerifier::from_config().expect("ldap config") {
+            verifiers.push(Arc::new(ldap));
+        }
+        let auth_svc = Arc::new(AuthService::new(
+            pool.clone(),
+            Arc::clone(&audit_svc),
+            Arc::clone(&session_svc),
+            verifiers,
+        ));
+        let permission_svc = Arc::new(PermissionSvc::new(pool.clone(), Arc::clone(&audit_svc)));
+        let role_svc = Arc::new(RoleSvc::new(pool.clone(), Arc::clone(&audit_svc)));
+        let policy_svc = Arc::new(PolicySvc::new(pool.clone()));
+        let rp_svc = Arc::new(RolePermissionSvc::new(
+            pool.clone(),
+            Arc::clone(&policy_svc),
+            Arc::clone(&audit_svc),
+        ));
+        let user_svc = Arc::new(UserSvc::new(pool.clone(), Arc::clone(&audit_svc)));
+        let org_svc = Arc::new(OrgSvc::new(pool.clone()));
+        let grant_svc = Arc::new(GrantSvc::new(pool.clone()));
+        let oidc_svc = Arc::new(OidcSvc::new(
+            pool.clone(),
+            Arc::clone(&audit_svc),
+            Arc::clone(&session_svc),
+        ));
+        let api_key_svc = Arc::new(ApiKeySvc::new(pool.clone(), Arc::clone(&audit_svc)));
+        let identity_svc = Arc::new(IdentitySvc::new(pool.clone(), Arc::clone(&audit_svc)));
+        let scim_svc = Arc::new(ScimSvc::new(pool.clone(), Arc::clone(&audit_svc)));
+        let invitation_svc = Arc::new(InvitationSvc::new(pool.clone(), Arc::clone(&audit_svc)));
+        let guest_svc = Arc::new(GuestSvc::new(pool.clone(), Arc::clone(&audit_svc)));
+        let sweeper = Arc::new(GrantSweeper::new(pool, Arc::clone(&audit_svc)));
 
-        let go_ver = Arc::new(GoogleTokenVerifier::new(CONFIG.google_client_id.clone()));
+        let idps = Arc::new(IdentityProviders::from_config().expect("identity providers"));
 
         Self {
-            auth_svc,
-            rp_svc,
-            permission_svc,
-            role_svc,
-            go_ver,
-            user_svc,
+            services: Arc::new(Services {
+                auth_svc,
+                rp_svc,
+                permission_svc,
+                role_svc,
+                idps,
+                user_svc,
+                org_svc,
+                grant_svc,
+                policy_svc,
+                audit_svc,
+                session_svc,
+                oidc_svc,
+                api_key_svc,
+                identity_svc,
+                scim_svc,
+                invitation_svc,
+                guest_svc,
+            }),
+            sweeper,
         }
     }
 
@@ -57,21 +140,22 @@ where
             .expect("failed to binding port");
         println!("Server running on http://127.0.0.1:7879");
 
+        let sweeper = Arc::clone(&self.sweeper);
+        let sweeper_handle = tokio::spawn(async move {
+            sweeper
+                .run(Duration::from_secs(CONFIG.grant_sweep_interval_secs))
+                .await
+        });
+
         loop {
             tokio::select! {
                 conn = listener.accept() => {
                     let (stream, _) = conn?;
 
-                    let auth_svc = Arc::clone(&self.auth_svc);
-                    let rp_svc = Arc::clone(&self.rp_svc);
-                    let permission_svc = Arc::clone(&self.permission_svc);
-                    let role_svc = Arc::clone(&self.role_svc);
-                                        let user_svc = Arc::clone(&self.user_svc);
-
-                    let go_ver = Arc::clone(&self.go_ver);
+                    let services = Arc::clone(&self.services);
 
                     tokio::spawn(async move {
-                        if let Err(e) = Server::handle_client(stream, &auth_svc, &rp_svc, &permission_svc, &role_svc, &user_svc, &go_ver).await {
+                        if let Err(e) = Server::handle_client(stream, &services).await {
                             eprintln!("Connection error: {}", e);
                         }
                     });
@@ -79,6 +163,7 @@ where
                 // Shutdown signal check
                 _ = &mut shutdown_rx => {
                     println!("Shutting down server...");
+                    sweeper_handle.abort();
                     break;
                 }
             }
@@ -86,16 +171,27 @@ where
         Ok(())
     }
 
-    pub async fn handle_client(
-        mut stream: TcpStream,
-        auth_svc: &Arc<AuthService<DB>>,
-        rp_svc: &Arc<RolePermissionSvc<DB>>,
-        permission_svc: &Arc<PermissionSvc<DB>>,
-        role_svc: &Arc<RoleSvc<DB>>,
-        user_svc: &Arc<UserSvc<DB>>,
-        go_ver: &Arc<GoogleTokenVerifier>,
-    ) -> Result<()> {
-        let (request, claims) = match Middleware::new(&mut stream).await {
+    pub async fn handle_client(mut stream: TcpStream, services: &Services<DB>) -> Result<()> {
+        let Services {
+            auth_svc,
+            rp_svc,
+            permission_svc,
+            role_svc,
+            user_svc,
+            org_svc,
+            grant_svc,
+            policy_svc,
+            audit_svc,
+            session_svc,
+            oidc_svc,
+            api_key_svc,
+            identity_svc,
+            scim_svc,
+            invitation_svc,
+            guest_svc,
+            idps,
+        } = services;
+        let (request, claims) = match Middleware::new(&mut stream, session_svc, api_key_svc).await {
             Ok((request, user_id)) => (request, user_id),
             Err(e) => {
                 println!("{:?}", e);
@@ -107,25 +203,166 @@ where
         // Route
         let (status_line, content) = match (&request.method, request.path.as_str()) {
             (Method::OPTIONS, _) => ("".to_string(), OPTIONS_CORS.to_string()),
+            (_, path) if path.starts_with(SCIM_PATH) => scim_svc.handle(&request).await,
             (Method::POST, "/login") => auth_svc.login(&request).await,
             (Method::POST, "/register") => auth_svc.register(&request).await,
             (Method::POST, "/reset-password") => auth_svc.reset_password(&request).await,
             (Method::POST, "/forgot-password") => auth_svc.forgot_password(&request).await,
-            (Method::POST, "/signin-google") => auth_svc.signin_google(&request, &go_ver).await,
-            (Method::POST, "/register-google") => auth_svc.register_google(&request, &go_ver).await,
+            (Method::POST, "/signin-google") => {
+                auth_svc.signin_provider(&request, idps, GOOGLE).await
+            }
+            (Method::POST, "/register-google") => {
+                auth_svc.register_provider(&request, idps, GOOGLE).await
+            }
+            (Method::GET, SAML_METADATA_PATH) => auth_svc.saml_metadata(idps),
+            (Method::GET, SAML_LOGIN_PATH) => auth_svc.saml_login(idps).await,
+            (Method::POST, SAML_ACS_PATH) => auth_svc.saml_acs(&request, idps).await,
+            (Method::GET, GITHUB_AUTHORIZE_PATH) => auth_svc.github_authorize(&request, idps).await,
+            (Method::POST, GITHUB_CALLBACK_PATH) => auth_svc.github_callback(&request, idps).await,
+            (Method::POST, path) if path.starts_with(SIGNIN_PATH) => {
+                let provider = &path[SIGNIN_PATH.len()..];
+                auth_svc.signin_provider(&request, idps, provider).await
+            }
+            (Method::POST, path) if path.starts_with(REGISTER_PATH) => {
+                let provider = &path[REGISTER_PATH.len()..];
+                auth_svc.register_provider(&request, idps, provider).await
+            }
             (Method::GET, "/protected/validate") => auth_svc.validate(&request),
             (Method::GET, "/protected/user/role-permissions") => {
                 rp_svc.get_role_permissions_by_role_id(claims).await
             }
-            (Method::GET, "/protected/user/permissions") => permission_svc.get_permissions().await,
+            (Method::GET, "/protected/user/perm-version") => rp_svc.get_perm_version().await,
+            (Method::GET, "/protected/user/permissions") => {
+                permission_svc.get_permissions(claims).await
+            }
             (Method::POST, "/protected/user/permissions") => {
                 permission_svc.create_permission(claims, &request).await
             }
             (Method::POST, "/protected/user/roles") => {
-                role_svc.create_role(&rp_svc, claims, &request).await
+                role_svc.create_role(rp_svc, claims, &request).await
             }
             (Method::GET, "/protected/user/roles") => role_svc.get_roles(claims).await,
+            (Method::POST, "/protected/user/roles/permissions") => {
+                rp_svc.grant_role_permissions(claims, &request).await
+            }
+            (Method::POST, "/protected/user/roles/parents") => {
+                role_svc.add_role_parent(rp_svc, claims, &request).await
+            }
+            (Method::DELETE, "/protected/user/roles/parents") => {
+                role_svc.remove_role_parent(rp_svc, claims, &request).await
+            }
             (Method::GET, "/protected/users") => user_svc.get_users(claims).await,
+            (Method::GET, "/protected/users/roles") => {
+                user_svc.get_role_grants(rp_svc, claims, &request).await
+            }
+            (Method::POST, "/protected/users/roles") => {
+                user_svc.grant_role(rp_svc, claims, &request).await
+            }
+            (Method::DELETE, "/protected/users/roles") => {
+                user_svc.revoke_role(rp_svc, claims, &request).await
+            }
+            (Method::GET, "/protected/orgs") => org_svc.get_orgs(claims).await,
+            (Method::POST, "/protected/orgs") => org_svc.create_org(rp_svc, claims, &request).await,
+            (Method::POST, "/protected/orgs/switch") => auth_svc.switch_org(claims, &request).await,
+            (Method::GET, "/protected/grants") => {
+                grant_svc.get_grants(rp_svc, claims, &request).await
+            }
+            (Method::POST, "/protected/grants") => {
+                grant_svc.create_grant(rp_svc, claims, &request).await
+            }
+            (Method::DELETE, "/protected/grants") => {
+                grant_svc.revoke_grant(rp_svc, claims, &request).await
+            }
+            (Method::POST, "/protected/authorize") => {
+                grant_svc.check(rp_svc, claims, &request).await
+            }
+            (Method::GET, DISCOVERY_PATH) => oidc_svc.discovery(),
+            (Method::GET, JWKS_PATH) => oidc_svc.jwks(),
+            (Method::GET, AUTHORIZE_PATH) => oidc_svc.authorize_page(&request).await,
+            (Method::POST, AUTHORIZE_PATH) => oidc_svc.authorize(&request).await,
+            (Method::POST, TOKEN_PATH) => oidc_svc.token(&request).await,
+            (Method::POST, INTROSPECT_PATH) => oidc_svc.introspect(&request).await,
+            (Method::POST, REVOKE_PATH) => oidc_svc.revoke(&request).await,
+            (Method::GET | Method::POST, USERINFO_PATH) => oidc_svc.userinfo(&request).await,
+            (Method::POST, DEVICE_CODE_PATH) => oidc_svc.device_authorization(&request).await,
+            (Method::GET, DEVICE_PATH) => oidc_svc.device_request(claims, &request).await,
+            (Method::POST, DEVICE_PATH) => oidc_svc.decide_device(claims, &request).await,
+            (Method::GET, "/protected/oauth/clients") => {
+                oidc_svc.get_clients(rp_svc, claims, &request).await
+            }
+            (Method::POST, "/protected/oauth/clients") => {
+                oidc_svc.create_client(rp_svc, claims, &request).await
+            }
+            (Method::GET, API_KEYS_PATH) => api_key_svc.get_api_keys(claims).await,
+            (Method::POST, API_KEYS_PATH) => {
+                api_key_svc.create_api_key(rp_svc, claims, &request).await
+            }
+            (Method::DELETE, path) if path.starts_with(API_KEYS_PATH) => {
+                api_key_svc.revoke_api_key(claims, &request).await
+            }
+            (Method::GET, SCIM_TOKENS_PATH) => {
+                scim_svc.get_scim_tokens(rp_svc, claims, &request).await
+            }
+            (Method::POST, SCIM_TOKENS_PATH) => {
+                scim_svc.create_scim_token(rp_svc, claims, &request).await
+            }
+            (Method::DELETE, path) if path.starts_with(SCIM_TOKENS_PATH) => {
+                scim_svc.revoke_scim_token(rp_svc, claims, &request).await
+            }
+            (Method::GET, INVITATIONS_PATH) => {
+                invitation_svc
+                    .get_invitations(rp_svc, claims, &request)
+                    .await
+            }
+            (Method::POST, INVITATIONS_PATH) => {
+                invitation_svc
+                    .create_invitation(rp_svc, claims, &request)
+                    .await
+            }
+            (Method::DELETE, path) if path.starts_with(INVITATIONS_PATH) => {
+                invitation_svc
+                    .revoke_invitation(rp_svc, claims, &request)
+                    .await
+            }
+            (Method::POST, GUEST_TOKEN_PATH) => guest_svc.guest_token(&request).await,
+            (Method::POST, GUESTS_PATH) => guest_svc.upgrade_guest(claims, &request).await,
+            (Method::POST, ROOM_CODES_PATH) => {
+                guest_svc
+                    .create_room_code(grant_svc, rp_svc, claims, &request)
+                    .await
+            }
+            (Method::DELETE, path) if path.starts_with(ROOM_CODES_PATH) => {
+                guest_svc
+                    .revoke_room_code(grant_svc, rp_svc, claims, &request)
+                    .await
+            }
+            (Method::GET, IDENTITIES_PATH) => identity_svc.get_identities(claims).await,
+            (Method::POST, IDENTITIES_PATH) => {
+                identity_svc.link_identity(idps, claims, &request).await
+            }
+            (Method::DELETE, path) if path.starts_with(IDENTITIES_PATH) => {
+                identity_svc.unlink_identity(idps, claims, &request).await
+            }
+            (Method::POST, "/protected/logout") => session_svc.logout(claims, &request).await,
+            (Method::GET, SESSIONS_PATH) => session_svc.get_sessions(claims).await,
+            (Method::DELETE, SESSIONS_PATH) => {
+                session_svc.revoke_other_sessions(claims, &request).await
+            }
+            (Method::DELETE, path) if path.starts_with(SESSIONS_PATH) => {
+                session_svc.revoke_session(claims, &request).await
+            }
+            (Method::GET, "/protected/audit") => {
+                audit_svc.get_events(rp_svc, claims, &request).await
+            }
+            (Method::GET, "/protected/audit/verify") => {
+                audit_svc.verify_chain(rp_svc, claims, &request).await
+            }
+            (Method::POST, "/protected/policy/explain") => {
+                policy_svc.explain(rp_svc, claims, &request).await
+            }
+            (Method::POST, "/protected/policy/reload") => {
+                policy_svc.reload_rules(rp_svc, claims, &request).await
+            }
 
             _ => (NOT_FOUND.to_string(), "404 Not Found".to_string()),
         };
@@ -0,0 +1,24 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct Session {
+    pub session_id: Option<i64>,
+    pub user_id: i32,
+    pub org_id: Option<i32>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// the session of the token making the request
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub current: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RevokedSessions {
+    pub revoked: u64,
+}
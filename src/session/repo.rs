@@ -0,0 +1,86 @@
+use chrono::{DateTime, Utc};
+
+use super::model::Session;
+use crate::{db::DBConn, error::CustomError};
+
+pub struct SessionRepository<DB: DBConn> {
+    db: DB,
+}
+
+impl<DB: DBConn> SessionRepository<DB> {
+    pub fn new(db: DB) -> Self {
+        SessionRepository { db }
+    }
+
+    pub async fn insert_session(&self, session: &Session) -> Result<i64, CustomError> {
+        self.db
+            .insert_session(session)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn is_token_revoked(&self, token_hash: &str) -> Result<bool, CustomError> {
+        self.db
+            .is_token_revoked(token_hash)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    /// Bumps last_seen_at of a live session, SessionNotFound when it was
+    /// revoked, expired or belongs to someone else
+    pub async fn touch_session(&self, session_id: i64, user_id: i32) -> Result<(), CustomError> {
+        self.db
+            .touch_session(session_id, user_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::SessionNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn extend_session(
+        &self,
+        session_id: i64,
+        org_id: Option<i32>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), CustomError> {
+        self.db
+            .extend_session(session_id, org_id, expires_at)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::SessionNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn fetch_user_sessions(&self, user_id: i32) -> Result<Vec<Session>, CustomError> {
+        self.db
+            .fetch_user_sessions(user_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn revoke_session(&self, user_id: i32, session_id: i64) -> Result<(), CustomError> {
+        self.db
+            .revoke_session(user_id, session_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::SessionNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn revoke_other_sessions(
+        &self,
+        user_id: i32,
+        keep_session_id: Option<i64>,
+    ) -> Result<u64, CustomError> {
+        self.db
+            .revoke_other_sessions(user_id, keep_session_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+}
@@ -0,0 +1,242 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use request_http_parser::parser::Request;
+use serde_json::json;
+
+use super::{
+    model::{RevokedSessions, Session},
+    repo::SessionRepository,
+};
+use crate::{
+    audit::{
+        model::{AuditEvent, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    auth::model::User,
+    constants::{BAD_REQUEST, INTERNAL_ERROR, NO_CONTENT, NOT_FOUND, OK_RESPONSE, UNAUTHORIZED},
+    cookie,
+    db::DBConn,
+    error::CustomError,
+    utils::{ClaimType, Claims, client_ip, ser_to_str, sha256_hex, token_expiry},
+};
+
+pub const SESSIONS_PATH: &str = "/protected/me/sessions";
+
+pub struct SessionSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    repository: SessionRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
+}
+
+impl<DB> SessionSvc<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>) -> Self {
+        SessionSvc {
+            repository: SessionRepository::new(pool),
+            audit,
+        }
+    }
+
+    /// Opens a session for a successful sign-in, it lives as long as the
+    /// login token issued with it
+    pub async fn start(&self, user: &User, request: &Request) -> Result<i64, CustomError> {
+        let now = Utc::now();
+        let session = Session {
+            session_id: None,
+            user_id: user.user_id.ok_or(CustomError::UserNotFound)?,
+            org_id: user.org_id,
+            user_agent: request.headers.get("user-agent").cloned(),
+            ip: client_ip(request).map(|ip| ip.to_string()),
+            created_at: now,
+            last_seen_at: now,
+            expires_at: token_expiry(&ClaimType::Login),
+            revoked_at: None,
+            current: false,
+        };
+        self.repository.insert_session(&session).await
+    }
+
+    /// Keeps the session alive for a token reissued within it
+    pub async fn extend(&self, session_id: i64, org_id: Option<i32>) -> Result<(), CustomError> {
+        self.repository
+            .extend_session(session_id, org_id, token_expiry(&ClaimType::Login))
+            .await
+    }
+
+    /// Tokens revoked through `/oauth/revoke` before they expired
+    pub async fn is_revoked(&self, token: &str) -> Result<bool, CustomError> {
+        self.repository.is_token_revoked(&sha256_hex(token)).await
+    }
+
+    /// Login tokens are only honored while their session is live. A
+    /// password reset token is never a credential, the reset endpoint reads
+    /// it from the body. Other token types are not tied to a session.
+    pub async fn check(&self, claims: &Claims) -> Result<(), CustomError> {
+        match claims.claim_type {
+            ClaimType::Login => match (claims.sid, claims.user_id()) {
+                (Some(session_id), Some(user_id)) => {
+                    self.repository.touch_session(session_id, user_id).await
+                }
+                _ => Err(CustomError::SessionNotFound),
+            },
+            ClaimType::ForgotPassword => Err(CustomError::SessionNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sign-in history of the caller, newest first
+    pub async fn get_sessions(&self, claims: Option<Claims>) -> (String, String) {
+        let (claims, user_id) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let mut sessions = match self.repository.fetch_user_sessions(user_id).await {
+            Ok(sessions) => sessions,
+            Err(error) => {
+                eprintln!("Error session db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        for session in sessions.iter_mut() {
+            session.current = session.session_id.is_some() && session.session_id == claims.sid;
+        }
+        let response_json = match ser_to_str(&sessions) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// `DELETE /protected/me/sessions/{id}`
+    pub async fn revoke_session(
+        &self,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, user_id) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let session_id = match request
+            .path
+            .strip_prefix(SESSIONS_PATH)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .and_then(|id| id.parse::<i64>().ok())
+        {
+            Some(session_id) => session_id,
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+
+        match self.repository.revoke_session(user_id, session_id).await {
+            Ok(_) => {
+                self.audit
+                    .record(AuditEvent {
+                        org_id: claims.org_id,
+                        actor_id: Some(user_id),
+                        target: Some(format!("session:{}", session_id)),
+                        ..AuditEvent::new("session.revoke", OUTCOME_SUCCESS, Some(request))
+                    })
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(err) => match err {
+                CustomError::SessionNotFound => (NOT_FOUND.to_string(), "".to_string()),
+                error => {
+                    eprintln!("Error session db: {:#?}", error);
+                    (INTERNAL_ERROR.to_string(), "".to_string())
+                }
+            },
+        }
+    }
+
+    /// "Sign out everywhere else", every session but the caller's own
+    pub async fn revoke_other_sessions(
+        &self,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, user_id) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let revoked = match self
+            .repository
+            .revoke_other_sessions(user_id, claims.sid)
+            .await
+        {
+            Ok(revoked) => revoked,
+            Err(error) => {
+                eprintln!("Error session db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        self.audit
+            .record(AuditEvent {
+                org_id: claims.org_id,
+                actor_id: Some(user_id),
+                target: Some(format!("user:{}", claims.username)),
+                metadata: json!({ "revoked": revoked, "kept_session_id": claims.sid }),
+                ..AuditEvent::new("session.revoke_others", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        let response_json = match ser_to_str(&RevokedSessions { revoked }) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    /// Ends the caller's own session, browser sessions also get their
+    /// cookies cleared
+    pub async fn logout(&self, claims: Option<Claims>, request: &Request) -> (String, String) {
+        let (claims, user_id) = match Self::caller(claims) {
+            Ok(caller) => caller,
+            Err(response) => return response,
+        };
+        let session_id = match claims.sid {
+            Some(session_id) => session_id,
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+        match self.repository.revoke_session(user_id, session_id).await {
+            Ok(_) | Err(CustomError::SessionNotFound) => {}
+            Err(error) => {
+                eprintln!("Error session db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        }
+        self.audit
+            .record(AuditEvent {
+                org_id: claims.org_id,
+                actor_id: Some(user_id),
+                target: Some(format!("session:{}", session_id)),
+                ..AuditEvent::new("session.logout", OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+        if cookie::is_cookie_session(request) {
+            return (cookie::clear_session(NO_CONTENT), "".to_string());
+        }
+        (NO_CONTENT.to_string(), "".to_string())
+    }
+
+    fn caller(claims: Option<Claims>) -> Result<(Claims, i32), (String, String)> {
+        let claims = match claims {
+            Some(claims) => claims,
+            None => return Err((UNAUTHORIZED.to_string(), "".to_string())),
+        };
+        match claims.user_id() {
+            Some(user_id) => Ok((claims, user_id)),
+            None => Err((UNAUTHORIZED.to_string(), "".to_string())),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::{
+    audit::{
+        model::{AuditEvent, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    db::DBConn,
+    error::CustomError,
+    rolepermissions::repo::RolePermissionRepository,
+    user::repo::UserRepository,
+};
+
+/// Removes time-bound role and permission grants once they lapse and records
+/// an audit event for each. Deleting the rows also bumps perm_version so
+/// consumers caching permissions notice the change.
+pub struct GrantSweeper<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    users: UserRepository<DB>,
+    role_permissions: RolePermissionRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
+}
+
+impl<DB> GrantSweeper<DB>
+where
+    DB: DBConn + Send + Sync + 'static,
+{
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>) -> Self {
+        GrantSweeper {
+            users: UserRepository::new(pool.clone()),
+            role_permissions: RolePermissionRepository::new(pool),
+            audit,
+        }
+    }
+
+    pub async fn run(&self, every: Duration) {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            match self.sweep().await {
+                Ok(0) => {}
+                Ok(swept) => println!("Swept {} expired grants", swept),
+                Err(error) => eprintln!("Error grant sweep: {:#?}", error),
+            }
+        }
+    }
+
+    pub async fn sweep(&self) -> Result<usize, CustomError> {
+        let user_roles = self.users.delete_expired_user_roles().await?;
+        for grant in &user_roles {
+            self.audit
+                .record(AuditEvent {
+                    org_id: Some(grant.org_id),
+                    target: Some(format!("user:{}", grant.user_id)),
+                    metadata: json!({
+                        "role_id": grant.role_id,
+                        "valid_from": grant.valid_from,
+                        "valid_until": grant.valid_until,
+                    }),
+                    ..AuditEvent::new("user_role.expired", OUTCOME_SUCCESS, None)
+                })
+                .await;
+        }
+
+        let role_permissions = self
+            .role_permissions
+            .delete_expired_role_permissions()
+            .await?;
+        for grant in &role_permissions {
+            self.audit
+                .record(AuditEvent {
+                    org_id: grant.org_id,
+                    target: Some(format!("role:{}", grant.role_id)),
+                    metadata: json!({
+                        "permission_id": grant.permission_id,
+                        "valid_until": grant.valid_until,
+                    }),
+                    ..AuditEvent::new("role_permission.expired", OUTCOME_SUCCESS, None)
+                })
+                .await;
+        }
+        Ok(user_roles.len() + role_permissions.len())
+    }
+}
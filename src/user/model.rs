@@ -6,7 +6,28 @@ pub struct GetUsers {
     pub user_id: Option<i32>,
     pub username: String,
     pub email: Option<String>,
-    pub provider: String,
-    pub role_id: i32,
+    /// `local` when the user has a password, then every linked provider
+    pub providers: Vec<String>,
+    pub role_ids: Vec<i32>,
     pub created_at: DateTime<Utc>,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserRole {
+    pub user_id: i32,
+    pub role_id: i32,
+    #[serde(default)]
+    pub valid_from: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+/// A role held by a user in an organization together with its validity window
+#[derive(Serialize, Deserialize, sqlx::FromRow, Debug)]
+pub struct UserRoleGrant {
+    pub user_id: i32,
+    pub org_id: i32,
+    pub role_id: i32,
+    pub valid_from: Option<DateTime<Utc>>,
+    pub valid_until: Option<DateTime<Utc>>,
+}
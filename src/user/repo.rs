@@ -1,4 +1,4 @@
-use super::model::GetUsers;
+use super::model::{GetUsers, UserRole, UserRoleGrant};
 use crate::{db::DBConn, error::CustomError};
 
 pub struct UserRepository<DB: DBConn> {
@@ -10,9 +10,62 @@ impl<DB: DBConn> UserRepository<DB> {
         UserRepository { db }
     }
 
-    pub async fn fetch_users(&self) -> Result<Vec<GetUsers>, CustomError> {
-        self.db.fetch_users().await.map_err(|e| match e {
+    pub async fn fetch_users(&self, org_id: i32) -> Result<Vec<GetUsers>, CustomError> {
+        self.db.fetch_users(org_id).await.map_err(|e| match e {
             _ => CustomError::DBError(e),
         })
     }
+
+    pub async fn insert_user_role(
+        &self,
+        org_id: i32,
+        user_role: &UserRole,
+    ) -> Result<(), CustomError> {
+        match self.db.insert_user_role(org_id, user_role).await {
+            Ok(_) => Ok(()),
+            Err(e) => match e {
+                sqlx::Error::Database(err) if err.is_unique_violation() => {
+                    Err(CustomError::UserRoleExists)
+                }
+                sqlx::Error::Database(err) if err.is_foreign_key_violation() => {
+                    Err(CustomError::UserNotFound)
+                }
+                sqlx::Error::RowNotFound => Err(CustomError::RoleNotFound),
+                _ => Err(CustomError::DBError(e)),
+            },
+        }
+    }
+
+    pub async fn delete_user_role(
+        &self,
+        org_id: i32,
+        user_id: i32,
+        role_id: i32,
+    ) -> Result<i32, CustomError> {
+        self.db
+            .delete_user_role(org_id, user_id, role_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => CustomError::UserRoleNotFound,
+                _ => CustomError::DBError(e),
+            })
+    }
+
+    pub async fn fetch_user_role_grants(
+        &self,
+        org_id: i32,
+        user_id: Option<i32>,
+    ) -> Result<Vec<UserRoleGrant>, CustomError> {
+        self.db
+            .fetch_user_role_grants(org_id, user_id)
+            .await
+            .map_err(CustomError::DBError)
+    }
+
+    pub async fn delete_expired_user_roles(&self) -> Result<Vec<UserRoleGrant>, CustomError> {
+        self.db
+            .delete_expired_user_roles()
+            .await
+            .map_err(CustomError::DBError)
+    }
 }
@@ -1,8 +1,21 @@
-use super::repo::UserRepository;
+use std::sync::Arc;
+
+use request_http_parser::parser::Request;
+use serde_json::json;
+
+use super::{model::UserRole, repo::UserRepository};
 use crate::{
-    constants::{INTERNAL_ERROR, OK_RESPONSE},
+    audit::{
+        model::{AuditEvent, OUTCOME_SUCCESS},
+        service::AuditSvc,
+    },
+    constants::{
+        BAD_REQUEST, FORBIDDEN, INTERNAL_ERROR, MANAGE_USERS, NO_CONTENT, NOT_FOUND, OK_RESPONSE,
+    },
     db::DBConn,
-    utils::{Claims, ser_to_str},
+    error::CustomError,
+    rolepermissions::service::RolePermissionSvc,
+    utils::{Claims, des_from_str, ser_to_str, valid_window},
 };
 
 pub struct UserSvc<DB>
@@ -10,21 +23,50 @@ where
     DB: DBConn + Send + Sync + 'static,
 {
     repository: UserRepository<DB>,
+    audit: Arc<AuditSvc<DB>>,
 }
 
 impl<DB> UserSvc<DB>
 where
     DB: DBConn + Send + Sync + 'static,
 {
-    pub fn new(pool: DB) -> Self {
+    pub fn new(pool: DB, audit: Arc<AuditSvc<DB>>) -> Self {
         UserSvc {
             repository: UserRepository::new(pool),
+            audit,
         }
     }
 
-    pub async fn get_users(&self, _claims: Option<Claims>) -> (String, String) {
+    async fn audit_user_role(
+        &self,
+        request: &Request,
+        action: &str,
+        claims: &Claims,
+        org_id: i32,
+        user_role: &UserRole,
+    ) {
+        self.audit
+            .record(AuditEvent {
+                org_id: Some(org_id),
+                actor_id: claims.user_id(),
+                target: Some(format!("user:{}", user_role.user_id)),
+                metadata: json!({
+                    "role_id": user_role.role_id,
+                    "valid_from": user_role.valid_from,
+                    "valid_until": user_role.valid_until,
+                }),
+                ..AuditEvent::new(action, OUTCOME_SUCCESS, Some(request))
+            })
+            .await;
+    }
+
+    pub async fn get_users(&self, claims: Option<Claims>) -> (String, String) {
         // only manages users can get all permission
-        let users = match self.repository.fetch_users().await {
+        let org_id = match claims.and_then(|claims| claims.org_id) {
+            Some(org_id) => org_id,
+            None => return (FORBIDDEN.to_string(), "".to_string()),
+        };
+        let users = match self.repository.fetch_users(org_id).await {
             Ok(user) => user,
             Err(why) => match why {
                 error => {
@@ -43,4 +85,135 @@ where
         tokio::time::sleep(std::time::Duration::from_secs(2)).await;
         (OK_RESPONSE.to_string(), response_json)
     }
+
+    pub async fn grant_role(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id) = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+        let req_user_role: UserRole = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(user_role) => user_role,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+
+        if !valid_window(req_user_role.valid_from, req_user_role.valid_until) {
+            return (
+                BAD_REQUEST.to_string(),
+                "Invalid validity window".to_string(),
+            );
+        }
+
+        match self
+            .repository
+            .insert_user_role(org_id, &req_user_role)
+            .await
+        {
+            Ok(_) => {
+                self.audit_user_role(request, "user_role.grant", &claims, org_id, &req_user_role)
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(err) => match err {
+                CustomError::UserRoleExists => {
+                    eprintln!("Error insert: {:#?}", err);
+                    (BAD_REQUEST.to_string(), "Already granted".to_string())
+                }
+                CustomError::UserNotFound | CustomError::RoleNotFound => {
+                    eprintln!("Error insert: {:#?}", err);
+                    (NOT_FOUND.to_string(), err.to_string())
+                }
+                error => {
+                    eprintln!("Error insert user role db: {:#?}", error);
+                    (INTERNAL_ERROR.to_string(), "".to_string())
+                }
+            },
+        }
+    }
+
+    /// Role grants of the organization with their validity window, limited to
+    /// one user with the `user_id` query param
+    pub async fn get_role_grants(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let org_id = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok((_, org_id)) => org_id,
+            Err(response) => return response,
+        };
+        let user_id = match request.params.as_ref().and_then(|p| p.get("user_id")) {
+            Some(user_id) => match user_id.parse::<i32>() {
+                Ok(user_id) => Some(user_id),
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => None,
+        };
+
+        let grants = match self
+            .repository
+            .fetch_user_role_grants(org_id, user_id)
+            .await
+        {
+            Ok(grants) => grants,
+            Err(error) => {
+                eprintln!("Error user role db: {:#?}", error);
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        let response_json = match ser_to_str(&grants) {
+            Ok(json) => json,
+            Err(_) => {
+                println!("serde error");
+                return (INTERNAL_ERROR.to_string(), "".to_string());
+            }
+        };
+        (OK_RESPONSE.to_string(), response_json)
+    }
+
+    pub async fn revoke_role(
+        &self,
+        rp_svc: &Arc<RolePermissionSvc<DB>>,
+        claims: Option<Claims>,
+        request: &Request,
+    ) -> (String, String) {
+        let (claims, org_id) = match rp_svc.authorize(claims, request, MANAGE_USERS).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+        let req_user_role: UserRole = match &request.body {
+            Some(body) => match des_from_str(body) {
+                Ok(user_role) => user_role,
+                Err(_) => return (BAD_REQUEST.to_string(), "".to_string()),
+            },
+            None => return (BAD_REQUEST.to_string(), "".to_string()),
+        };
+
+        match self
+            .repository
+            .delete_user_role(org_id, req_user_role.user_id, req_user_role.role_id)
+            .await
+        {
+            Ok(_) => {
+                self.audit_user_role(request, "user_role.revoke", &claims, org_id, &req_user_role)
+                    .await;
+                (NO_CONTENT.to_string(), "".to_string())
+            }
+            Err(err) => match err {
+                CustomError::UserRoleNotFound => (NOT_FOUND.to_string(), "".to_string()),
+                error => {
+                    eprintln!("Error delete user role db: {:#?}", error);
+                    (INTERNAL_ERROR.to_string(), "".to_string())
+                }
+            },
+        }
+    }
 }
@@ -1,17 +1,31 @@
 use crate::auth;
 use crate::cfg::CONFIG;
+use crate::constants::GUEST_ACTIONS;
 use crate::error::CustomError;
+use crate::rolepermissions::model::GetRolePermissions;
 use anyhow::{Context, Result};
 use auth::model::User;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use bcrypt::{DEFAULT_COST, hash, verify};
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use jsonwebtoken::{EncodingKey, Header, encode};
+use rand::RngCore;
+use request_http_parser::parser::Request;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub enum ClaimType {
     Login,
     ForgotPassword,
+    /// issued to a machine client through the client-credentials grant
+    Service,
+    /// built per request from an API key, never encoded as a JWT
+    ApiKey,
+    /// learner without an account, limited to one room
+    Guest,
 }
 
 impl TryFrom<&str> for ClaimType {
@@ -21,6 +35,9 @@ impl TryFrom<&str> for ClaimType {
         match value {
             "ForgotPassword" => Ok(ClaimType::ForgotPassword),
             "Login" => Ok(ClaimType::Login),
+            "Service" => Ok(ClaimType::Service),
+            "ApiKey" => Ok(ClaimType::ApiKey),
+            "Guest" => Ok(ClaimType::Guest),
             _ => Err(anyhow::anyhow!("Claim type not found")),
         }
     }
@@ -31,17 +48,196 @@ impl ToString for ClaimType {
         match self {
             ClaimType::ForgotPassword => "ForgotPassword".to_string(),
             ClaimType::Login => "Login".to_string(),
+            ClaimType::Service => "Service".to_string(),
+            ClaimType::ApiKey => "ApiKey".to_string(),
+            ClaimType::Guest => "Guest".to_string(),
         }
     }
 }
 
+/// How much of the effective permission set goes into login tokens
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionClaimMode {
+    /// No permissions, clients call `/protected/user/role-permissions`
+    #[default]
+    None,
+    /// Permission names in `permissions`
+    Names,
+    /// Permission ids packed into `perm_bitmap`
+    Bitmap,
+    /// Names while they fit in `jwt_permission_claims_max_bytes`, bitmap otherwise
+    Auto,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
     pub username: String,
-    pub role_id: i32,
+    pub role_ids: Vec<i32>,
+    #[serde(default)]
+    pub org_id: Option<i32>,
     pub claim_type: ClaimType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Vec<String>>,
+    /// base64url bitmap where bit `id % 8` of byte `id / 8` marks permission `id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perm_bitmap: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perm_version: Option<i64>,
+    /// session the login token belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<i64>,
+    /// space separated scopes granted to a service principal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// API key the request authenticated with, `permissions` then holds the
+    /// key's scope
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_id: Option<i64>,
+    /// the only room a guest token is good for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+}
+
+/// `sub` of service tokens, `service:<client_id>` never parses as a user id
+pub const SERVICE_SUBJECT_PREFIX: &str = "service:";
+
+/// `sub` of guest tokens, `guest:<guest_id>`
+pub const GUEST_SUBJECT_PREFIX: &str = "guest:";
+
+impl Claims {
+    pub fn user_id(&self) -> Option<i32> {
+        self.sub.parse().ok()
+    }
+
+    /// Client id of the service principal the token was issued to
+    pub fn service_id(&self) -> Option<&str> {
+        match self.claim_type {
+            ClaimType::Service => self.sub.strip_prefix(SERVICE_SUBJECT_PREFIX),
+            _ => None,
+        }
+    }
+
+    pub fn guest_id(&self) -> Option<i64> {
+        match self.claim_type {
+            ClaimType::Guest => self
+                .sub
+                .strip_prefix(GUEST_SUBJECT_PREFIX)
+                .and_then(|id| id.parse().ok()),
+            _ => None,
+        }
+    }
+}
+
+/// Effective permissions of a user at the time a token is issued
+pub struct TokenPermissions {
+    pub permissions: Vec<GetRolePermissions>,
+    pub version: i64,
+}
+
+impl TokenPermissions {
+    fn names(&self) -> Vec<String> {
+        self.permissions.iter().map(|p| p.name.clone()).collect()
+    }
+
+    fn bitmap(&self) -> String {
+        let max_id = self
+            .permissions
+            .iter()
+            .map(|p| p.permission_id.max(0) as usize)
+            .max()
+            .unwrap_or(0);
+        let mut bytes = vec![0u8; max_id / 8 + 1];
+        for permission in &self.permissions {
+            let id = permission.permission_id.max(0) as usize;
+            bytes[id / 8] |= 1 << (id % 8);
+        }
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+}
+
+/// Set by the middleware on every request, whatever the client sent under
+/// that name is dropped first
+pub const CLIENT_IP_HEADER: &str = "x-koois-client-ip";
+
+/// Address of the client as resolved by the middleware
+pub fn client_ip(request: &Request) -> Option<IpAddr> {
+    request
+        .headers
+        .get(CLIENT_IP_HEADER)
+        .and_then(|ip| ip.parse::<IpAddr>().ok())
+}
+
+/// The peer of the connection, unless it is one of the trusted proxies.
+/// Then `X-Forwarded-For` is walked from the right, every hop appended by
+/// a trusted proxy is skipped and the first other one is the client. The
+/// entries left of it were written by the client and mean nothing.
+pub fn resolve_client_ip(peer: IpAddr, headers: &HashMap<String, String>) -> IpAddr {
+    let trusted = |ip: &IpAddr| {
+        CONFIG
+            .trusted_proxies
+            .split(',')
+            .map(str::trim)
+            .filter(|range| !range.is_empty())
+            .any(|range| ip_in_range(ip, range))
+    };
+    if !trusted(&peer) {
+        return peer;
+    }
+    let forwarded = match headers.get("x-forwarded-for") {
+        Some(forwarded) => forwarded,
+        None => {
+            return headers
+                .get("x-real-ip")
+                .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+                .unwrap_or(peer);
+        }
+    };
+    let mut client = peer;
+    for hop in forwarded.rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+        if !trusted(&client) {
+            break;
+        }
+    }
+    client
+}
+
+pub fn ip_in_range(ip: &IpAddr, range: &str) -> bool {
+    let (addr, prefix) = match range.split_once('/') {
+        Some((addr, prefix)) => match prefix.parse::<u32>() {
+            Ok(prefix) => (addr, Some(prefix)),
+            Err(_) => return false,
+        },
+        None => (range, None),
+    };
+    match (ip, addr.parse::<IpAddr>()) {
+        (IpAddr::V4(ip), Ok(IpAddr::V4(net))) => {
+            let bits = prefix.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            u32::from(*ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), Ok(IpAddr::V6(net))) => {
+            let bits = prefix.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            u128::from(*ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+/// A grant window is usable when it is ordered and has not already ended
+pub fn valid_window(valid_from: Option<DateTime<Utc>>, valid_until: Option<DateTime<Utc>>) -> bool {
+    match (valid_from, valid_until) {
+        (Some(from), Some(until)) if from >= until => false,
+        (_, Some(until)) => until > Utc::now(),
+        _ => true,
+    }
 }
 
 pub fn des_from_str<T: for<'a> Deserialize<'a> + Serialize>(
@@ -71,26 +267,46 @@ fn get_private_key() -> Result<EncodingKey, CustomError> {
     Ok(enc_key)
 }
 
-pub fn create_jwt(user: &User, claim_type: ClaimType) -> Result<String> {
-    let private_key = get_private_key().context("Failed Get Private Key")?;
-    let expiration = match claim_type {
-        ClaimType::Login => Utc::now()
-            .checked_add_signed(Duration::hours(1)) // Token valid for 1 hours
-            .expect("Invalid timestamp")
-            .timestamp() as usize,
-        ClaimType::ForgotPassword => Utc::now()
-            .checked_add_signed(Duration::minutes(15)) // Token valid for 15
-            // minutes
-            .expect("Invalid timestamp")
-            .timestamp() as usize,
+/// When a token of `claim_type` issued now expires
+pub fn token_expiry(claim_type: &ClaimType) -> DateTime<Utc> {
+    let lifetime = match claim_type {
+        ClaimType::Login => Duration::hours(1), // Token valid for 1 hours
+        ClaimType::ForgotPassword => Duration::minutes(15), // Token valid for 15 minutes
+        ClaimType::Service => Duration::hours(1), // clients fetch a new one when it expires
+        ClaimType::ApiKey => Duration::hours(1), // the key itself carries the real expiry
+        ClaimType::Guest => Duration::hours(2), // long enough for one quiz session
     };
-    let claims = Claims {
+    Utc::now()
+        .checked_add_signed(lifetime)
+        .expect("Invalid timestamp")
+}
+
+pub fn create_jwt(
+    user: &User,
+    claim_type: ClaimType,
+    token_permissions: Option<&TokenPermissions>,
+    session_id: Option<i64>,
+) -> Result<String> {
+    let private_key = get_private_key().context("Failed Get Private Key")?;
+    let expiration = token_expiry(&claim_type).timestamp() as usize;
+    let mut claims = Claims {
         sub: user.user_id.unwrap().to_string(),
         exp: expiration,
         username: user.username.to_string(),
-        role_id: user.role_id,
+        role_ids: user.role_ids.clone(),
+        org_id: user.org_id,
         claim_type,
+        permissions: None,
+        perm_bitmap: None,
+        perm_version: None,
+        sid: session_id,
+        scope: None,
+        api_key_id: None,
+        room_id: None,
     };
+    if let Some(token_permissions) = token_permissions {
+        embed_permissions(&mut claims, token_permissions);
+    }
 
     encode(
         &Header::new(jsonwebtoken::Algorithm::RS256),
@@ -100,6 +316,62 @@ pub fn create_jwt(user: &User, claim_type: ClaimType) -> Result<String> {
     .context("Failed to Encode the JWT")
 }
 
+/// Token of a guest in their room, holding only [`GUEST_ACTIONS`]
+pub fn create_guest_jwt(
+    guest_id: i64,
+    display_name: &str,
+    org_id: i32,
+    room_id: &str,
+) -> Result<String> {
+    let private_key = get_private_key().context("Failed Get Private Key")?;
+    let claims = Claims {
+        sub: format!("{}{}", GUEST_SUBJECT_PREFIX, guest_id),
+        exp: token_expiry(&ClaimType::Guest).timestamp() as usize,
+        username: display_name.to_string(),
+        role_ids: vec![],
+        org_id: Some(org_id),
+        claim_type: ClaimType::Guest,
+        permissions: Some(
+            GUEST_ACTIONS
+                .iter()
+                .map(|action| action.to_string())
+                .collect(),
+        ),
+        perm_bitmap: None,
+        perm_version: None,
+        sid: None,
+        scope: None,
+        api_key_id: None,
+        room_id: Some(room_id.to_string()),
+    };
+
+    encode(
+        &Header::new(jsonwebtoken::Algorithm::RS256),
+        &claims,
+        &private_key,
+    )
+    .context("Failed to Encode the JWT")
+}
+
+fn embed_permissions(claims: &mut Claims, token_permissions: &TokenPermissions) {
+    claims.perm_version = Some(token_permissions.version);
+    match CONFIG.jwt_permission_claims {
+        PermissionClaimMode::None => {}
+        PermissionClaimMode::Names => claims.permissions = Some(token_permissions.names()),
+        PermissionClaimMode::Bitmap => claims.perm_bitmap = Some(token_permissions.bitmap()),
+        PermissionClaimMode::Auto => {
+            let names = token_permissions.names();
+            // each name costs its length plus quotes and a comma in the payload
+            let size: usize = names.iter().map(|name| name.len() + 3).sum();
+            if size <= CONFIG.jwt_permission_claims_max_bytes {
+                claims.permissions = Some(names);
+            } else {
+                claims.perm_bitmap = Some(token_permissions.bitmap());
+            }
+        }
+    }
+}
+
 pub fn extract_token(
     headers: &std::collections::HashMap<std::string::String, std::string::String>,
 ) -> Option<String> {
@@ -112,7 +384,49 @@ pub fn extract_token(
     })
 }
 
+/// Inserts extra header lines into a status line from `constants`
+pub fn with_headers(status_line: &str, headers: &[String]) -> String {
+    let mut response = status_line
+        .strip_suffix("\r\n")
+        .unwrap_or(status_line)
+        .to_string();
+    for header in headers {
+        response.push_str(header);
+        response.push_str("\r\n");
+    }
+    response.push_str("\r\n");
+    response
+}
+
+/// Hex encoded random value of `bytes` bytes
+pub fn random_token(bytes: usize) -> String {
+    let mut buffer = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buffer);
+    hex::encode(buffer)
+}
+
+/// Codes, tokens and keys with enough entropy are stored as a plain sha256,
+/// which unlike bcrypt can be looked up
+pub fn sha256_hex(value: &str) -> String {
+    hex::encode(Sha256::digest(value.as_bytes()))
+}
+
+/// Compares secrets without leaking where they differ
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
 pub fn verify_jwt(token: &str) -> Result<Claims, &'static str> {
+    decode_jwt::<Claims>(token)
+}
+
+/// Verifies a token signed with our key and reads its payload as `T`,
+/// tokens of a different shape are rejected
+pub fn decode_jwt<T: for<'a> Deserialize<'a>>(token: &str) -> Result<T, &'static str> {
     let public_key = jsonwebtoken::DecodingKey::from_rsa_pem(
         &CONFIG.jwt_public_key.replace("\\n", "\n").as_bytes(),
     )
@@ -121,11 +435,74 @@ pub fn verify_jwt(token: &str) -> Result<Claims, &'static str> {
     validation.validate_exp = true;
     validation.validate_aud = false;
 
-    let token_data = jsonwebtoken::decode::<crate::utils::Claims>(token, &public_key, &validation)
-        .map_err(|e| {
-            println!("JWT error: {:?}", e);
-            "Invalid token"
-        })?;
+    let token_data = jsonwebtoken::decode::<T>(token, &public_key, &validation).map_err(|e| {
+        println!("JWT error: {:?}", e);
+        "Invalid token"
+    })?;
 
     Ok(token_data.claims)
 }
+
+/// Signs arbitrary claims with our key, `kid` lets relying parties pick the
+/// matching entry of the published key set
+pub fn sign_jwt<T: Serialize>(claims: &T, kid: &str) -> Result<String> {
+    let private_key = get_private_key().context("Failed Get Private Key")?;
+    let mut header = Header::new(jsonwebtoken::Algorithm::RS256);
+    header.kid = Some(kid.to_string());
+    encode(&header, claims, &private_key).context("Failed to Encode the JWT")
+}
+
+/// Decodes `%XX` escapes, `+` is a space as in form bodies
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Escapes everything but unreserved characters for use in a query string
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// `application/x-www-form-urlencoded` body into its fields
+pub fn parse_form(body: &str) -> std::collections::HashMap<String, String> {
+    body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+        .collect()
+}
+
+pub fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}